@@ -0,0 +1,390 @@
+//! Receives Alertmanager webhooks, so alerts show up on the lights without any scripts in
+//! between.
+//!
+//! Rules in the config file pick the led each alert is shown on by its labels. While any alert
+//! for an led is firing, its foreground shows the color of the worst one, and once every alert
+//! has resolved the foreground is cleared. Severities are ranked by the order they're listed in,
+//! worst first.
+//!
+//! ```toml
+//! [[alertmanager.severities]]
+//! name = "critical"
+//! color = "red@10%"
+//!
+//! [[alertmanager.severities]]
+//! name = "warning"
+//! color = "orange@10%"
+//!
+//! [[alertmanager.rules]]
+//! labels = { alertname = "NodeDown", instance = "pi-1*" }
+//! device = "ttyACM0"
+//! led = 0
+//! ```
+
+use glob::Pattern;
+use serde::Deserialize;
+use status_lights_messages::Rgb;
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, PoisonError};
+
+use crate::args;
+use crate::config::ConfigError;
+use crate::device::LEDS_PER_DEVICE;
+use crate::output::DeviceInfo;
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AlertmanagerConfig {
+    /// Worst first. Alerts with any other severity rank below all of these.
+    pub severities: Vec<Severity>,
+    pub rules: Vec<AlertRule>,
+}
+
+impl Default for AlertmanagerConfig {
+    fn default() -> Self {
+        let severity = |name: &str, color: Rgb| Severity {
+            name: name.to_string(),
+            color: color.scale(0.1),
+        };
+        AlertmanagerConfig {
+            severities: vec![
+                severity("critical", Rgb::new(255, 0, 0)),
+                severity("warning", Rgb::new(255, 165, 0)),
+                severity("info", Rgb::new(0, 0, 255)),
+            ],
+            rules: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Severity {
+    pub name: String,
+    #[serde(deserialize_with = "args::deserialize_color")]
+    pub color: Rgb,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AlertRule {
+    /// Glob patterns for labels, every one of which must match for the rule to apply
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// A glob pattern matched against each device's id, path, serial number and USB port. The
+    /// rule applies to every device if this isn't set.
+    pub device: Option<String>,
+    pub led: u8,
+    /// Used instead of the severity's color
+    #[serde(default, deserialize_with = "args::deserialize_optional_color")]
+    pub color: Option<Rgb>,
+}
+
+/// The body of an Alertmanager webhook. Only the fields that are used are read.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct Webhook {
+    pub alerts: Vec<Alert>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct Alert {
+    /// `firing` or `resolved`
+    pub status: String,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// Identifies the alert across webhooks. Older versions of Alertmanager don't send it, in
+    /// which case the labels are used.
+    pub fingerprint: Option<String>,
+}
+
+impl Alert {
+    fn fingerprint(&self) -> String {
+        match &self.fingerprint {
+            Some(fingerprint) => fingerprint.clone(),
+            None => serde_json::to_string(&self.labels).expect("labels are always valid JSON"),
+        }
+    }
+}
+
+/// What an led should show now that a webhook has changed the alerts on it
+#[derive(Clone, Debug, PartialEq)]
+pub struct LedAlert {
+    /// The devices it applies to, every device if `None`
+    pub device: Option<Pattern>,
+    pub led: u8,
+    /// The color of the worst alert, or `None` if no alerts are firing
+    pub color: Option<Rgb>,
+    pub firing: usize,
+}
+
+impl LedAlert {
+    pub fn applies_to(&self, info: &DeviceInfo) -> bool {
+        match &self.device {
+            Some(pattern) => info.matches_pattern(pattern),
+            None => true,
+        }
+    }
+}
+
+struct Rule {
+    labels: Vec<(String, Pattern)>,
+    device: Option<Pattern>,
+    led: u8,
+    color: Option<Rgb>,
+}
+
+impl Rule {
+    fn matches(&self, alert: &Alert) -> bool {
+        self.labels.iter().all(|(name, pattern)| {
+            alert
+                .labels
+                .get(name)
+                .is_some_and(|value| pattern.matches(value))
+        })
+    }
+}
+
+/// A firing alert on an led
+#[derive(Clone, Copy, Debug)]
+struct Firing {
+    /// Lower is worse
+    rank: usize,
+    color: Rgb,
+}
+
+/// The device pattern and led that rules show alerts on. Rules with the same target share it.
+type Target = (Option<String>, u8);
+
+/// Keeps track of the alerts firing on each led
+pub struct Alerts {
+    severities: Vec<Severity>,
+    rules: Vec<Rule>,
+    firing: Mutex<HashMap<Target, HashMap<String, Firing>>>,
+}
+
+impl Alerts {
+    pub fn new(config: &AlertmanagerConfig) -> Result<Alerts, ConfigError> {
+        let pattern = |pattern: &str| {
+            Pattern::new(pattern).map_err(|e| {
+                ConfigError::Invalid(format!("Alert rule pattern '{}': {}", pattern, e))
+            })
+        };
+        let rules = config
+            .rules
+            .iter()
+            .map(|rule| {
+                if rule.led >= LEDS_PER_DEVICE {
+                    return Err(ConfigError::Invalid(format!(
+                        "Alert rule led {} doesn't exist, devices have leds 0 to {}",
+                        rule.led,
+                        LEDS_PER_DEVICE - 1
+                    )));
+                }
+                Ok(Rule {
+                    labels: rule
+                        .labels
+                        .iter()
+                        .map(|(name, value)| Ok((name.clone(), pattern(value)?)))
+                        .collect::<Result<_, ConfigError>>()?,
+                    device: rule.device.as_deref().map(pattern).transpose()?,
+                    led: rule.led,
+                    color: rule.color,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        if config.severities.is_empty() {
+            return Err(ConfigError::Invalid(
+                "At least one alert severity is needed".to_string(),
+            ));
+        }
+        Ok(Alerts {
+            severities: config.severities.clone(),
+            rules,
+            firing: Mutex::default(),
+        })
+    }
+
+    /// How bad the alert is and the color to show it in, as decided by the rule
+    fn firing(&self, alert: &Alert, rule: &Rule) -> Firing {
+        let severity = alert.labels.get("severity");
+        let (rank, severity) = self
+            .severities
+            .iter()
+            .enumerate()
+            .find(|(_, s)| Some(&s.name) == severity)
+            .unwrap_or_else(|| {
+                let last = self.severities.len() - 1;
+                (last + 1, &self.severities[last])
+            });
+        Firing {
+            rank,
+            color: rule.color.unwrap_or(severity.color),
+        }
+    }
+
+    /// Records the webhook's alerts, returning what each led they touched should show now
+    pub fn receive(&self, webhook: &Webhook) -> Vec<LedAlert> {
+        let mut firing = self.firing.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut touched = BTreeMap::new();
+        for alert in &webhook.alerts {
+            let fingerprint = alert.fingerprint();
+            for rule in self.rules.iter().filter(|rule| rule.matches(alert)) {
+                let target = (rule.device.as_ref().map(|p| p.to_string()), rule.led);
+                let alerts = firing.entry(target.clone()).or_default();
+                match alert.status.as_str() {
+                    "resolved" => alerts.remove(&fingerprint),
+                    _ => alerts.insert(fingerprint.clone(), self.firing(alert, rule)),
+                };
+                touched.insert(target, rule.device.clone());
+            }
+        }
+        touched
+            .into_iter()
+            .map(|(target, device)| {
+                let count = firing.get(&target).map(HashMap::len).unwrap_or(0);
+                let worst = firing
+                    .get(&target)
+                    .and_then(|alerts| alerts.values().min_by_key(|f| f.rank))
+                    .map(|f| f.color);
+                if count == 0 {
+                    firing.remove(&target);
+                }
+                LedAlert {
+                    device,
+                    led: target.1,
+                    color: worst,
+                    firing: count,
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::Config;
+
+    use std::path::Path;
+
+    fn alerts() -> Alerts {
+        let config = Config::parse(
+            r#"
+            [[alertmanager.severities]]
+            name = "critical"
+            color = "red"
+
+            [[alertmanager.severities]]
+            name = "warning"
+            color = "orange"
+
+            [[alertmanager.rules]]
+            labels = { instance = "pi-1*" }
+            device = "ttyACM0"
+            led = 0
+
+            [[alertmanager.rules]]
+            labels = { alertname = "Watchdog" }
+            led = 3
+            color = "green"
+            "#,
+            Path::new("config.toml"),
+        )
+        .unwrap();
+        Alerts::new(&config.alertmanager).unwrap()
+    }
+
+    fn alert(status: &str, fingerprint: &str, labels: &[(&str, &str)]) -> Alert {
+        Alert {
+            status: status.to_string(),
+            labels: labels
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            fingerprint: Some(fingerprint.to_string()),
+        }
+    }
+
+    fn receive(alerts: &Alerts, alert: Alert) -> Vec<(u8, Option<Rgb>, usize)> {
+        alerts
+            .receive(&Webhook {
+                alerts: vec![alert],
+            })
+            .into_iter()
+            .map(|led| (led.led, led.color, led.firing))
+            .collect()
+    }
+
+    const RED: Rgb = Rgb::new(255, 0, 0);
+    const ORANGE: Rgb = Rgb::new(255, 165, 0);
+
+    #[test]
+    fn test_worst_severity_wins() {
+        let alerts = alerts();
+        let disk = alert(
+            "firing",
+            "a",
+            &[("instance", "pi-1"), ("severity", "warning")],
+        );
+        let down = alert(
+            "firing",
+            "b",
+            &[("instance", "pi-12"), ("severity", "critical")],
+        );
+        assert_eq!(receive(&alerts, disk.clone()), vec![(0, Some(ORANGE), 1)]);
+        assert_eq!(receive(&alerts, down.clone()), vec![(0, Some(RED), 2)]);
+        // Alertmanager repeats firing alerts, which shouldn't count twice
+        assert_eq!(receive(&alerts, disk.clone()), vec![(0, Some(RED), 2)]);
+
+        let down = Alert {
+            status: "resolved".to_string(),
+            ..down
+        };
+        assert_eq!(receive(&alerts, down), vec![(0, Some(ORANGE), 1)]);
+        let disk = Alert {
+            status: "resolved".to_string(),
+            ..disk
+        };
+        assert_eq!(receive(&alerts, disk), vec![(0, None, 0)]);
+    }
+
+    #[test]
+    fn test_rules() {
+        let alerts = alerts();
+        assert_eq!(
+            receive(&alerts, alert("firing", "a", &[("instance", "pi-2")])),
+            vec![]
+        );
+        let watchdog = alert("firing", "b", &[("alertname", "Watchdog")]);
+        let changes = alerts.receive(&Webhook {
+            alerts: vec![watchdog],
+        });
+        assert_eq!(changes[0].led, 3);
+        assert_eq!(changes[0].color, Some(Rgb::new(0, 128, 0)));
+        assert!(changes[0].applies_to(&DeviceInfo {
+            path: "/dev/ttyACM5".to_string(),
+            ..DeviceInfo::default()
+        }));
+
+        // Unknown severities still show, below every known severity
+        let unknown = alert("firing", "c", &[("instance", "pi-1"), ("severity", "page")]);
+        assert_eq!(receive(&alerts, unknown), vec![(0, Some(ORANGE), 1)]);
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let rule = |led: u8, device: &str| AlertmanagerConfig {
+            rules: vec![AlertRule {
+                labels: BTreeMap::new(),
+                device: Some(device.to_string()),
+                led,
+                color: None,
+            }],
+            ..AlertmanagerConfig::default()
+        };
+        assert!(Alerts::new(&rule(0, "ttyACM0")).is_ok());
+        assert!(Alerts::new(&rule(4, "ttyACM0")).is_err());
+        assert!(Alerts::new(&rule(0, "[")).is_err());
+    }
+}
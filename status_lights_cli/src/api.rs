@@ -0,0 +1,791 @@
+//! The JSON API the daemon serves over HTTP, so that other machines can set the lights.
+//!
+//! Devices are identified by the last part of their path, eg `ttyACM0` for `/dev/ttyACM0`.
+//!
+//! | Method   | Path                                  | Body                                   |
+//! |----------|---------------------------------------|----------------------------------------|
+//! | `GET`    | `/devices`                            |                                        |
+//! | `GET`    | `/devices/<id>`                       |                                        |
+//! | `GET`    | `/devices/<id>/leds`                  |                                        |
+//! | `GET`    | `/devices/<id>/leds/<led>`            |                                        |
+//! | `PUT`    | `/devices/<id>/leds/<led>/background` | `{"color": "green@10%"}`               |
+//! | `PUT`    | `/devices/<id>/leds/<led>/foreground` | `{"color": "red", "for": "2m"}`        |
+//! | `DELETE` | `/devices/<id>/leds/<led>/foreground` |                                        |
+//! | `PUT`    | `/devices/<id>/leds/<led>/effect`     | `{"effect": "blink", "color": "red"}`  |
+//! | `DELETE` | `/devices/<id>/leds/<led>/effect`     |                                        |
+//! | `POST`   | `/alertmanager`                       | An Alertmanager webhook                |
+//! | `GET`    | `/heartbeats`                         |                                        |
+//! | `POST`   | `/heartbeats/<name>`                  |                                        |
+//! | `POST`   | `/heartbeats/<name>/fail`             |                                        |
+//!
+//! Changing an led responds with its new state. If the device fails, the response is a `502`
+//! with the same report the cli prints with `--json`. A foreground can be given an `until` time
+//! instead of `for`, and either can be longer than the device can hold a color for, in which
+//! case the daemon holds it as a lease.
+//!
+//! When tokens are configured, every request needs an `Authorization: Bearer <token>` header.
+//! Devices outside the token's scope are treated as if they don't exist, and changes it isn't
+//! allowed to make are forbidden.
+//!
+//! `/alertmanager` is only served when there are alert rules in the config file, see
+//! `alertmanager`. Alerts are only shown on leds the token is allowed to change.
+//!
+//! `/heartbeats` is only served when there are heartbeat checks in the config file, see
+//! `heartbeat`. Pings respond with the check's status, and can also be sent with `GET` so that
+//! jobs can use the simplest possible `curl`. Any token that isn't read only can send them.
+
+use serde::{Deserialize, Serialize};
+use status_lights_messages::{LedColor, LedColorTimed, Rgb};
+use thiserror::Error;
+
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::alertmanager::{Alerts, Webhook};
+use crate::args;
+use crate::auth::{Auth, Scope};
+use crate::daemon::Daemon;
+use crate::device::LEDS_PER_DEVICE;
+use crate::effect::Effect;
+use crate::http::{Handler, Request, Response};
+use crate::ipc::{Action, DaemonRequest, DaemonResponse, HeartbeatPing, LedEffect, LedLease};
+use crate::output::DeviceInfo;
+use crate::report::{DeviceReport, Outcome, Report};
+use crate::selector::DeviceSelector;
+use crate::state::LedState;
+
+#[derive(Error, Debug)]
+enum ApiError {
+    #[error("Not found")]
+    NotFound,
+    #[error("Method not allowed")]
+    MethodNotAllowed,
+    #[error("{0}")]
+    Unauthorized(String),
+    #[error("{0}")]
+    Forbidden(String),
+    #[error("No device with id '{0}'")]
+    NoSuchDevice(String),
+    #[error("There's no heartbeat check named '{0}'")]
+    NoSuchHeartbeat(String),
+    #[error("There is no led '{}', devices have leds 0 to {}", .0, LEDS_PER_DEVICE - 1)]
+    NoSuchLed(String),
+    #[error("Led {0} is claimed by '{1}'")]
+    Claimed(u8, String),
+    #[error("Invalid request body: {0}")]
+    InvalidBody(String),
+    #[error("The device failed")]
+    Device(Report),
+}
+
+impl ApiError {
+    fn into_response(self) -> Response {
+        match self {
+            ApiError::NotFound
+            | ApiError::NoSuchDevice(_)
+            | ApiError::NoSuchHeartbeat(_)
+            | ApiError::NoSuchLed(_) => Response::error(404, &self.to_string()),
+            ApiError::MethodNotAllowed => Response::error(405, &self.to_string()),
+            ApiError::Unauthorized(_) => {
+                Response::error(401, &self.to_string()).with_header("WWW-Authenticate", "Bearer")
+            }
+            ApiError::Forbidden(_) => Response::error(403, &self.to_string()),
+            ApiError::Claimed(..) => Response::error(409, &self.to_string()),
+            ApiError::InvalidBody(_) => Response::error(400, &self.to_string()),
+            ApiError::Device(report) => Response::json(502, &report),
+        }
+    }
+}
+
+/// A device along with what its leds were last set to
+#[derive(Debug, Serialize)]
+struct ApiDevice {
+    id: String,
+    #[serde(flatten)]
+    info: DeviceInfo,
+    leds: Vec<LedState>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ColorBody {
+    #[serde(deserialize_with = "args::deserialize_color")]
+    color: Rgb,
+    /// How long to show a foreground color for, indefinitely if not set
+    #[serde(default, rename = "for", with = "humantime_serde")]
+    duration: Option<Duration>,
+    /// When to stop showing a foreground color, instead of `for`
+    #[serde(default, with = "humantime_serde")]
+    until: Option<SystemTime>,
+}
+
+pub struct Api {
+    daemon: Arc<Daemon>,
+    auth: Auth,
+    alerts: Option<Alerts>,
+}
+
+impl Api {
+    /// An API that anyone can use
+    pub fn new(daemon: Arc<Daemon>) -> Api {
+        Api {
+            daemon,
+            auth: Auth::open(),
+            alerts: None,
+        }
+    }
+
+    pub fn with_auth(mut self, auth: Auth) -> Api {
+        self.auth = auth;
+        self
+    }
+
+    /// Also receives Alertmanager webhooks
+    pub fn with_alerts(mut self, alerts: Alerts) -> Api {
+        self.alerts = Some(alerts);
+        self
+    }
+
+    fn route(&self, request: &Request, scope: &Scope) -> Result<Response, ApiError> {
+        let segments: Vec<&str> = request
+            .path
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .collect();
+        match (request.method.as_str(), segments.as_slice()) {
+            ("GET", ["devices"]) => self.list_devices(scope),
+            ("GET", ["devices", id]) => {
+                let info = self.find(id, scope)?;
+                Ok(Response::json(200, &self.api_device(info)))
+            }
+            ("GET", ["devices", id, "leds"]) => {
+                let info = self.find(id, scope)?;
+                Ok(Response::json(200, &self.api_device(info).leds))
+            }
+            ("GET", ["devices", id, "leds", led]) => {
+                let (info, led) = (self.find(id, scope)?, parse_led(led)?);
+                self.led_response(&info, led)
+            }
+            ("PUT", ["devices", id, "leds", led, "background"]) => {
+                let (info, led) = (self.find(id, scope)?, parse_led(led)?);
+                self.check_can_change(scope, &info, led)?;
+                let body = parse_body::<ColorBody>(request)?;
+                self.run(&info, Action::Background(LedColor::new(led, body.color)))?;
+                self.led_response(&info, led)
+            }
+            ("PUT", ["devices", id, "leds", led, "foreground"]) => {
+                let (info, led) = (self.find(id, scope)?, parse_led(led)?);
+                self.check_can_change(scope, &info, led)?;
+                let body = parse_body::<ColorBody>(request)?;
+                let action = match (body.duration, body.until) {
+                    (Some(_), Some(_)) => {
+                        return Err(ApiError::InvalidBody(
+                            "Only one of 'for' and 'until' can be given".to_string(),
+                        ))
+                    }
+                    (duration, None) => Action::foreground_for(led, body.color, duration)
+                        .map_err(|e| ApiError::InvalidBody(e.to_string()))?,
+                    (None, Some(until)) if until <= SystemTime::now() => {
+                        return Err(ApiError::InvalidBody(format!(
+                            "'until' is {}, which has already passed",
+                            humantime::format_rfc3339_seconds(until)
+                        )))
+                    }
+                    (None, Some(until)) => Action::Lease(LedLease {
+                        led,
+                        color: body.color,
+                        until,
+                    }),
+                };
+                self.run(&info, action)?;
+                self.led_response(&info, led)
+            }
+            ("DELETE", ["devices", id, "leds", led, "foreground"]) => {
+                let (info, led) = (self.find(id, scope)?, parse_led(led)?);
+                self.check_can_change(scope, &info, led)?;
+                let led_color = LedColorTimed::new(led, Rgb::OFF, 0);
+                self.run(&info, Action::Foreground(led_color))?;
+                self.led_response(&info, led)
+            }
+            ("PUT", ["devices", id, "leds", led, "effect"]) => {
+                let (info, led) = (self.find(id, scope)?, parse_led(led)?);
+                self.check_can_change(scope, &info, led)?;
+                let effect = parse_body::<Effect>(request)?;
+                self.set_effect(&info, led, Some(effect))
+            }
+            ("DELETE", ["devices", id, "leds", led, "effect"]) => {
+                let (info, led) = (self.find(id, scope)?, parse_led(led)?);
+                self.check_can_change(scope, &info, led)?;
+                self.set_effect(&info, led, None)
+            }
+            ("POST", ["alertmanager"]) if self.alerts.is_some() => {
+                self.receive_alerts(request, scope)
+            }
+            (_, ["alertmanager"]) if self.alerts.is_some() => Err(ApiError::MethodNotAllowed),
+            ("GET", ["heartbeats"]) => {
+                let states = self.daemon.heartbeat_states().ok_or(ApiError::NotFound)?;
+                Ok(Response::json(200, &states))
+            }
+            ("GET", ["heartbeats", name]) | ("POST", ["heartbeats", name]) => {
+                self.ping(name, false, scope)
+            }
+            ("GET", ["heartbeats", name, "fail"]) | ("POST", ["heartbeats", name, "fail"]) => {
+                self.ping(name, true, scope)
+            }
+            (_, ["heartbeats"]) | (_, ["heartbeats", _]) | (_, ["heartbeats", _, "fail"])
+                if self.daemon.heartbeat_states().is_some() =>
+            {
+                Err(ApiError::MethodNotAllowed)
+            }
+            (_, ["devices"])
+            | (_, ["devices", _])
+            | (_, ["devices", _, "leds"])
+            | (_, ["devices", _, "leds", _])
+            | (_, ["devices", _, "leds", _, "background"])
+            | (_, ["devices", _, "leds", _, "foreground"])
+            | (_, ["devices", _, "leds", _, "effect"]) => Err(ApiError::MethodNotAllowed),
+            _ => Err(ApiError::NotFound),
+        }
+    }
+
+    fn list_devices(&self, scope: &Scope) -> Result<Response, ApiError> {
+        let request = DaemonRequest {
+            selector: DeviceSelector::all(),
+            action: Action::List,
+            owner: None,
+        };
+        let devices = match self.daemon.handle(&request) {
+            DaemonResponse::Devices(devices) => devices,
+            DaemonResponse::Report(report) => return Err(ApiError::Device(report)),
+            DaemonResponse::Claims(_) | DaemonResponse::Snapshot(_) => {
+                unreachable!("list only responds with devices or a report")
+            }
+        };
+        let devices: Vec<ApiDevice> = devices
+            .into_iter()
+            .filter(|info| scope.can_see(info))
+            .map(|info| self.api_device(info))
+            .collect();
+        Ok(Response::json(200, &devices))
+    }
+
+    fn api_device(&self, info: DeviceInfo) -> ApiDevice {
+        ApiDevice {
+            id: info.id().to_string(),
+            leds: self.daemon.led_states(&info.path).unwrap_or_default(),
+            info,
+        }
+    }
+
+    fn find(&self, id: &str, scope: &Scope) -> Result<DeviceInfo, ApiError> {
+        self.daemon
+            .describe(&DeviceSelector::all())
+            .unwrap_or_default()
+            .into_iter()
+            .find(|info| info.id() == id && scope.can_see(info))
+            .ok_or_else(|| ApiError::NoSuchDevice(id.to_string()))
+    }
+
+    fn run(&self, info: &DeviceInfo, action: Action) -> Result<(), ApiError> {
+        let request = DaemonRequest {
+            selector: DeviceSelector::only(&info.path),
+            action,
+            owner: None,
+        };
+        match self.daemon.handle(&request) {
+            DaemonResponse::Report(report) if report.outcome == Outcome::Success => Ok(()),
+            DaemonResponse::Report(report) => Err(ApiError::Device(report)),
+            DaemonResponse::Devices(_)
+            | DaemonResponse::Claims(_)
+            | DaemonResponse::Snapshot(_) => {
+                unreachable!("only list, claims and snapshot don't respond with a report")
+            }
+        }
+    }
+
+    fn set_effect(
+        &self,
+        info: &DeviceInfo,
+        led: u8,
+        effect: Option<Effect>,
+    ) -> Result<Response, ApiError> {
+        self.run(info, Action::Effect(LedEffect { led, effect }))?;
+        self.led_response(info, led)
+    }
+
+    /// Shows the worst alert firing on each led the webhook changed, on every device the token
+    /// can change it on
+    fn receive_alerts(&self, request: &Request, scope: &Scope) -> Result<Response, ApiError> {
+        if scope.read_only() {
+            return Err(ApiError::Forbidden("This token is read only".to_string()));
+        }
+        let alerts = self.alerts.as_ref().ok_or(ApiError::NotFound)?;
+        let webhook = parse_body::<Webhook>(request)?;
+        let devices = self
+            .daemon
+            .describe(&DeviceSelector::all())
+            .unwrap_or_default();
+        let mut reports: Vec<DeviceReport> = Vec::new();
+        for led_alert in alerts.receive(&webhook) {
+            let color = led_alert.color.unwrap_or(Rgb::OFF);
+            for info in devices
+                .iter()
+                .filter(|info| led_alert.applies_to(info) && scope.can_change(info, led_alert.led))
+            {
+                let request = DaemonRequest {
+                    selector: DeviceSelector::only(&info.path),
+                    action: Action::Foreground(LedColorTimed::new(led_alert.led, color, 0)),
+                    owner: None,
+                };
+                match self.daemon.handle(&request) {
+                    DaemonResponse::Report(report) => reports.extend(report.devices),
+                    DaemonResponse::Devices(_)
+                    | DaemonResponse::Claims(_)
+                    | DaemonResponse::Snapshot(_) => {
+                        unreachable!("only list, claims and snapshot don't respond with a report")
+                    }
+                }
+            }
+        }
+        let report = Report::from_devices(reports);
+        match report.outcome {
+            // Alertmanager tries again if this fails, which shows the alerts once the device is
+            // back
+            Outcome::DeviceError | Outcome::PartialFailure => Err(ApiError::Device(report)),
+            _ => Ok(Response::json(200, &report)),
+        }
+    }
+
+    /// Pings a heartbeat check, responding with its status
+    fn ping(&self, name: &str, failed: bool, scope: &Scope) -> Result<Response, ApiError> {
+        let states = self.daemon.heartbeat_states().ok_or(ApiError::NotFound)?;
+        if !states.iter().any(|state| state.name == name) {
+            return Err(ApiError::NoSuchHeartbeat(name.to_string()));
+        }
+        if scope.read_only() {
+            return Err(ApiError::Forbidden("This token is read only".to_string()));
+        }
+        let report = self.daemon.heartbeat(&HeartbeatPing {
+            check: name.to_string(),
+            failed,
+        });
+        if let Outcome::DeviceError | Outcome::PartialFailure = report.outcome {
+            return Err(ApiError::Device(report));
+        }
+        let state = self
+            .daemon
+            .heartbeat_states()
+            .unwrap_or_default()
+            .into_iter()
+            .find(|state| state.name == name);
+        Ok(Response::json(200, &state))
+    }
+
+    /// Checks that the token is allowed to change the led, and that no one has claimed it. The
+    /// API's requests don't have an owner, so they can't change claimed leds.
+    fn check_can_change(&self, scope: &Scope, info: &DeviceInfo, led: u8) -> Result<(), ApiError> {
+        check_change(scope, info, led)?;
+        let owner = self
+            .daemon
+            .led_states(&info.path)
+            .and_then(|mut leds| leds.drain(..).nth(led as usize))
+            .and_then(|state| state.owner);
+        match owner {
+            Some(owner) => Err(ApiError::Claimed(led, owner)),
+            None => Ok(()),
+        }
+    }
+
+    fn led_response(&self, info: &DeviceInfo, led: u8) -> Result<Response, ApiError> {
+        let state = self
+            .daemon
+            .led_states(&info.path)
+            .and_then(|mut leds| leds.drain(..).nth(led as usize))
+            .ok_or_else(|| ApiError::NoSuchDevice(info.id().to_string()))?;
+        Ok(Response::json(200, &state))
+    }
+}
+
+impl Handler for Api {
+    fn handle(&self, request: &Request) -> Response {
+        self.auth
+            .authorize(request)
+            .map_err(|e| ApiError::Unauthorized(e.to_string()))
+            .and_then(|scope| self.route(request, scope))
+            .unwrap_or_else(ApiError::into_response)
+    }
+}
+
+/// Checks that the token is allowed to change the led
+fn check_change(scope: &Scope, info: &DeviceInfo, led: u8) -> Result<(), ApiError> {
+    match scope.can_change(info, led) {
+        true => Ok(()),
+        false if scope.read_only() => {
+            Err(ApiError::Forbidden("This token is read only".to_string()))
+        }
+        false => Err(ApiError::Forbidden(format!(
+            "This token can't change led {} on '{}'",
+            led,
+            info.id()
+        ))),
+    }
+}
+
+fn parse_led(led: &str) -> Result<u8, ApiError> {
+    led.parse::<u8>()
+        .ok()
+        .filter(|led| *led < LEDS_PER_DEVICE)
+        .ok_or_else(|| ApiError::NoSuchLed(led.to_string()))
+}
+
+fn parse_body<T: for<'de> Deserialize<'de>>(request: &Request) -> Result<T, ApiError> {
+    serde_json::from_slice(&request.body).map_err(|e| ApiError::InvalidBody(e.to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::Config;
+    use crate::heartbeat::Heartbeats;
+    use crate::ipc::LedClaim;
+    use crate::mock::{MockDevice, MockSource};
+    use serde_json::json;
+
+    use std::path::Path;
+    use std::time::Instant;
+
+    fn api(devices: Vec<MockDevice>) -> Api {
+        Api::new(Arc::new(Daemon::new(Box::new(MockSource::new(devices)))))
+    }
+
+    #[test]
+    fn test_list_devices() {
+        let api = api(vec![MockDevice::new("/dev/ttyACM0").with_serial("abc")]);
+        let response = api.handle(&Request::new("GET", "/devices"));
+        assert_eq!(response.status, 200);
+        let body = response.json_body();
+        assert_eq!(body[0]["id"], "ttyACM0");
+        assert_eq!(body[0]["path"], "/dev/ttyACM0");
+        assert_eq!(body[0]["serial"], "abc");
+        assert_eq!(body[0]["firmware_version"], "v0.2.0");
+        assert_eq!(body[0]["leds"].as_array().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_set_background_and_foreground() {
+        let device = MockDevice::new("/dev/ttyACM0");
+        let api = api(vec![device.clone()]);
+
+        let request = Request::new("PUT", "/devices/ttyACM0/leds/1/background")
+            .with_body(r#"{"color": "green"}"#);
+        let response = api.handle(&request);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.json_body()["background"], "#008000");
+        assert_eq!(device.state().background[1], Rgb::new(0, 128, 0));
+
+        let request = Request::new("PUT", "/devices/ttyACM0/leds/1/foreground")
+            .with_body(r#"{"color": "red@50%", "for": "2m"}"#);
+        let response = api.handle(&request);
+        assert_eq!(
+            response.json_body(),
+            json!({
+                "led": 1,
+                "background": "#008000",
+                "foreground": "#250000",
+                "foreground_remaining": "2m"
+            })
+        );
+        assert_eq!(device.state().foreground_seconds[1], 120);
+
+        let response = api.handle(&Request::new(
+            "DELETE",
+            "/devices/ttyACM0/leds/1/foreground",
+        ));
+        assert_eq!(response.json_body()["foreground"], "#000000");
+        assert_eq!(device.state().foreground[1], Rgb::OFF);
+
+        let response = api.handle(&Request::new("GET", "/devices/ttyACM0/leds/1"));
+        assert_eq!(response.json_body()["background"], "#008000");
+    }
+
+    #[test]
+    fn test_lease() {
+        let device = MockDevice::new("/dev/ttyACM0");
+        let api = api(vec![device.clone()]);
+
+        let request = Request::new("PUT", "/devices/ttyACM0/leds/1/foreground")
+            .with_body(r#"{"color": "red", "for": "1h"}"#);
+        let response = api.handle(&request);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.json_body()["foreground_remaining"], "1h");
+        assert_eq!(device.state().foreground[1], Rgb::new(255, 0, 0));
+        assert_eq!(device.state().foreground_seconds[1], 30);
+
+        let until = humantime::format_rfc3339_seconds(SystemTime::now() + Duration::from_secs(600));
+        let request = Request::new("PUT", "/devices/ttyACM0/leds/1/foreground")
+            .with_body(&format!(r#"{{"color": "blue", "until": "{}"}}"#, until));
+        let response = api.handle(&request);
+        assert_eq!(response.status, 200);
+        assert_eq!(device.state().foreground[1], Rgb::new(0, 0, 255));
+
+        let request = Request::new("PUT", "/devices/ttyACM0/leds/1/foreground")
+            .with_body(r#"{"color": "blue", "until": "2020-01-01T00:00:00Z"}"#);
+        assert_eq!(api.handle(&request).status, 400);
+        let request = Request::new("PUT", "/devices/ttyACM0/leds/1/foreground").with_body(
+            &format!(r#"{{"color": "blue", "for": "1h", "until": "{}"}}"#, until),
+        );
+        assert_eq!(api.handle(&request).status, 400);
+    }
+
+    #[test]
+    fn test_effects() {
+        let device = MockDevice::new("/dev/ttyACM0");
+        let api = api(vec![device.clone()]);
+
+        let request = Request::new("PUT", "/devices/ttyACM0/leds/0/effect")
+            .with_body(r#"{"effect": "blink", "color": "blue", "period": "10s"}"#);
+        let response = api.handle(&request);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.json_body()["effect"]["effect"], "blink");
+        assert_eq!(device.state().foreground[0], Rgb::new(0, 0, 255));
+        assert_eq!(device.state().foreground_seconds[0], 2);
+
+        let response = api.handle(&Request::new("DELETE", "/devices/ttyACM0/leds/0/effect"));
+        assert_eq!(response.json_body().get("effect"), None);
+        assert_eq!(device.state().foreground[0], Rgb::OFF);
+    }
+
+    #[test]
+    fn test_claimed() {
+        let device = MockDevice::new("/dev/ttyACM0");
+        let api = api(vec![device.clone()]);
+        api.daemon.handle(&DaemonRequest {
+            selector: DeviceSelector::all(),
+            action: Action::Claim(LedClaim {
+                led: 1,
+                priority: 0,
+            }),
+            owner: Some("alarm".to_string()),
+        });
+
+        let response = api.handle(&Request::new("GET", "/devices/ttyACM0/leds/1"));
+        assert_eq!(response.json_body()["owner"], "alarm");
+        let request = Request::new("PUT", "/devices/ttyACM0/leds/1/foreground")
+            .with_body(r#"{"color": "red"}"#);
+        let response = api.handle(&request);
+        assert_eq!(response.status, 409);
+        assert_eq!(response.json_body()["error"], "Led 1 is claimed by 'alarm'");
+        assert_eq!(device.state().foreground[1], Rgb::OFF);
+
+        let request = Request::new("PUT", "/devices/ttyACM0/leds/2/foreground")
+            .with_body(r#"{"color": "red"}"#);
+        assert_eq!(api.handle(&request).status, 200);
+    }
+
+    #[test]
+    fn test_errors() {
+        let device = MockDevice::new("/dev/ttyACM0");
+        let api = api(vec![device.clone()]);
+
+        let response = api.handle(&Request::new("GET", "/devices/ttyACM9"));
+        assert_eq!(response.status, 404);
+        assert_eq!(
+            response.json_body(),
+            json!({"error": "No device with id 'ttyACM9'"})
+        );
+        let response = api.handle(&Request::new("GET", "/devices/ttyACM0/leds/4"));
+        assert_eq!(response.status, 404);
+        let response = api.handle(&Request::new("POST", "/devices"));
+        assert_eq!(response.status, 405);
+        let response = api.handle(&Request::new("GET", "/lights"));
+        assert_eq!(response.status, 404);
+
+        let request = Request::new("PUT", "/devices/ttyACM0/leds/0/background")
+            .with_body(r#"{"color": "blurple"}"#);
+        let response = api.handle(&request);
+        assert_eq!(response.status, 400);
+
+        device.set_unresponsive(true);
+        let request = Request::new("PUT", "/devices/ttyACM0/leds/0/background")
+            .with_body(r#"{"color": "red"}"#);
+        let response = api.handle(&request);
+        assert_eq!(response.status, 502);
+        assert_eq!(response.json_body()["outcome"], "device_error");
+    }
+
+    #[test]
+    fn test_tokens() {
+        let config = Config::parse(
+            r#"
+            [[http.tokens]]
+            name = "dashboard"
+            token = "read-token"
+            read_only = true
+
+            [[http.tokens]]
+            name = "alerts"
+            token = "alert-token"
+            devices = ["ttyACM1"]
+            leds = [2]
+            "#,
+            Path::new("config.toml"),
+        )
+        .unwrap();
+        let api = api(vec![
+            MockDevice::new("/dev/ttyACM0"),
+            MockDevice::new("/dev/ttyACM1"),
+        ])
+        .with_auth(Auth::new(config.http.tokens).unwrap());
+        let request = |method: &str, path: &str, token: &str| {
+            Request::new(method, path)
+                .with_header("Authorization", &format!("Bearer {}", token))
+                .with_body(r#"{"color": "red"}"#)
+        };
+
+        let response = api.handle(&Request::new("GET", "/devices"));
+        assert_eq!(response.status, 401);
+        assert!(response
+            .headers
+            .contains(&("WWW-Authenticate".to_string(), "Bearer".to_string())));
+        let response = api.handle(&request("GET", "/devices", "wrong-token"));
+        assert_eq!(response.status, 401);
+
+        let response = api.handle(&request("GET", "/devices", "read-token"));
+        assert_eq!(response.json_body().as_array().unwrap().len(), 2);
+        let response = api.handle(&request(
+            "PUT",
+            "/devices/ttyACM0/leds/0/background",
+            "read-token",
+        ));
+        assert_eq!(response.status, 403);
+        assert_eq!(
+            response.json_body(),
+            json!({"error": "This token is read only"})
+        );
+
+        let response = api.handle(&request("GET", "/devices", "alert-token"));
+        assert_eq!(response.json_body()[0]["id"], "ttyACM1");
+        assert_eq!(response.json_body().as_array().unwrap().len(), 1);
+        let response = api.handle(&request("GET", "/devices/ttyACM0", "alert-token"));
+        assert_eq!(response.status, 404);
+        let response = api.handle(&request(
+            "PUT",
+            "/devices/ttyACM1/leds/2/foreground",
+            "alert-token",
+        ));
+        assert_eq!(response.status, 200);
+        let response = api.handle(&request(
+            "PUT",
+            "/devices/ttyACM1/leds/0/foreground",
+            "alert-token",
+        ));
+        assert_eq!(response.status, 403);
+    }
+
+    #[test]
+    fn test_alertmanager() {
+        let config = Config::parse(
+            r#"
+            [[alertmanager.rules]]
+            labels = { instance = "pi-1" }
+            device = "ttyACM1"
+            led = 2
+            "#,
+            Path::new("config.toml"),
+        )
+        .unwrap();
+        let devices = vec![
+            MockDevice::new("/dev/ttyACM0"),
+            MockDevice::new("/dev/ttyACM1"),
+        ];
+        let response = api(devices.clone()).handle(&Request::new("POST", "/alertmanager"));
+        assert_eq!(response.status, 404);
+
+        let api = api(devices.clone()).with_alerts(Alerts::new(&config.alertmanager).unwrap());
+        let webhook = |status: &str| {
+            Request::new("POST", "/alertmanager").with_body(
+                &json!({
+                    "version": "4",
+                    "status": status,
+                    "alerts": [{
+                        "status": status,
+                        "labels": {"alertname": "NodeDown", "instance": "pi-1", "severity": "critical"},
+                        "fingerprint": "5ef77f1f8a3ecfa5"
+                    }]
+                })
+                .to_string(),
+            )
+        };
+        let response = api.handle(&webhook("firing"));
+        assert_eq!(response.status, 200);
+        assert_eq!(response.json_body()["outcome"], "success");
+        let critical = Rgb::new(255, 0, 0).scale(0.1);
+        assert_eq!(devices[1].state().foreground[2], critical);
+        assert_eq!(devices[1].state().foreground_seconds[2], 0);
+        assert_eq!(devices[0].state().foreground[2], Rgb::OFF);
+
+        let response = api.handle(&webhook("resolved"));
+        assert_eq!(response.status, 200);
+        assert_eq!(devices[1].state().foreground[2], Rgb::OFF);
+
+        let response = api.handle(&Request::new("GET", "/alertmanager"));
+        assert_eq!(response.status, 405);
+    }
+
+    #[test]
+    fn test_heartbeats() {
+        let config = Config::parse(
+            r#"
+            [[heartbeat.checks]]
+            name = "backup"
+            period = "1h"
+            device = "ttyACM0"
+            led = 1
+
+            [[heartbeat.checks]]
+            name = "flaky"
+            period = "10ms"
+            grace = "0s"
+            led = 3
+            "#,
+            Path::new("config.toml"),
+        )
+        .unwrap();
+        let devices = vec![
+            MockDevice::new("/dev/ttyACM0"),
+            MockDevice::new("/dev/ttyACM1"),
+        ];
+        let response = api(devices.clone()).handle(&Request::new("GET", "/heartbeats"));
+        assert_eq!(response.status, 404);
+
+        let heartbeats = Heartbeats::new(&config.heartbeat, Instant::now()).unwrap();
+        let daemon = Arc::new(
+            Daemon::new(Box::new(MockSource::new(devices.clone()))).with_heartbeats(heartbeats),
+        );
+        let api = Api::new(Arc::clone(&daemon));
+        let ok = Rgb::new(0, 128, 0).scale(0.1);
+        let failed = Rgb::new(255, 0, 0).scale(0.1);
+
+        let response = api.handle(&Request::new("POST", "/heartbeats/backup"));
+        assert_eq!(response.status, 200);
+        assert_eq!(response.json_body()["status"], "ok");
+        assert_eq!(response.json_body()["due_in"], "1h 1m");
+        assert_eq!(devices[0].state().foreground[1], ok);
+        assert_eq!(devices[1].state().foreground[1], Rgb::OFF);
+
+        let response = api.handle(&Request::new("GET", "/heartbeats/backup/fail"));
+        assert_eq!(response.json_body()["status"], "failed");
+        assert_eq!(devices[0].state().foreground[1], failed);
+
+        let response = api.handle(&Request::new("POST", "/heartbeats/restore"));
+        assert_eq!(response.status, 404);
+        let response = api.handle(&Request::new("DELETE", "/heartbeats/backup"));
+        assert_eq!(response.status, 405);
+
+        // flaky hasn't been pinged, so it's late once its period has passed
+        std::thread::sleep(Duration::from_millis(20));
+        daemon.tick();
+        assert_eq!(devices[1].state().foreground[3], failed);
+        let response = api.handle(&Request::new("GET", "/heartbeats"));
+        assert_eq!(response.json_body()[1]["status"], "late");
+    }
+}
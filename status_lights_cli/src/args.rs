@@ -0,0 +1,369 @@
+//! Parsing for the color and duration arguments taken by the cli commands.
+//!
+//! Colors can be given as a single value (see `status_lights_messages::Rgb` for the accepted
+//! forms), optionally followed by `@` and a brightness, eg `green@10%`. The older form of three
+//! separate red, green and blue numbers is still accepted, and for foreground colors can be
+//! followed by a number of seconds.
+//!
+//! Times of day and dates are read in local time, unless they're in RFC 3339 form ending in `Z`.
+
+use serde::{Deserialize, Deserializer};
+use status_lights_messages::{ColorParseError, Rgb};
+use thiserror::Error;
+
+use std::convert::TryFrom;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The longest time the USB device can hold a foreground color for
+pub const MAX_DEVICE_DURATION: Duration = Duration::from_secs(u8::MAX as u64);
+
+#[derive(Error, Debug, PartialEq)]
+pub enum ArgError {
+    #[error("Invalid color '{0}': {1}")]
+    InvalidColor(String, ColorParseError),
+    #[error("Invalid brightness '{0}', expected a percentage between 0% and 100%")]
+    InvalidBrightness(String),
+    #[error("Invalid percentage '{0}', expected a number between 0 and 100")]
+    InvalidPercent(String),
+    #[error("Invalid duration '{0}', expected something like 90s, 2m or 1h")]
+    InvalidDuration(String),
+    #[error("Invalid time '{0}', expected something like 18:30, 2024-06-01 18:30 or 2024-06-01T17:30:00Z")]
+    InvalidTime(String),
+    #[error("Expected a color or red, green and blue values, got {0:?}")]
+    WrongNumberOfValues(Vec<String>),
+    #[error("A duration was given twice")]
+    DuplicateDuration,
+    #[error("Invalid gradient '{0}': {1}")]
+    InvalidGradient(String, String),
+    #[error("Duration '{}' is too long, the device can hold a color for at most {}", humantime::format_duration(*.0), humantime::format_duration(MAX_DEVICE_DURATION))]
+    DurationTooLong(Duration),
+    #[error("Duration '{}' is too long to show a color for", humantime::format_duration(*.0))]
+    DurationOutOfRange(Duration),
+}
+
+/// A color, and how long to show it for if the arguments included a time
+#[derive(Debug, PartialEq)]
+pub struct ColorValues {
+    pub color: Rgb,
+    pub duration: Option<Duration>,
+}
+
+/// Parses a single color with an optional `@<brightness>` suffix
+pub fn parse_color(s: &str) -> Result<Rgb, ArgError> {
+    let (color, brightness) = match s.rsplit_once('@') {
+        Some((color, brightness)) => (color, Some(brightness)),
+        None => (s, None),
+    };
+    let color: Rgb = color
+        .parse()
+        .map_err(|e| ArgError::InvalidColor(color.to_string(), e))?;
+    match brightness {
+        Some(brightness) => Ok(color.scale(parse_brightness(brightness)?)),
+        None => Ok(color),
+    }
+}
+
+/// Reads a color in any form `parse_color` accepts from a string, for requests and config files
+pub fn deserialize_color<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Rgb, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    parse_color(&s).map_err(serde::de::Error::custom)
+}
+
+/// Like `deserialize_color`, for colors that don't have to be given
+pub fn deserialize_optional_color<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Rgb>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|s| parse_color(&s).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+/// Parses `50%` or `0.5` into a value between 0.0 and 1.0
+pub fn parse_brightness(s: &str) -> Result<f32, ArgError> {
+    let invalid = || ArgError::InvalidBrightness(s.to_string());
+    let brightness = match s.trim().strip_suffix('%') {
+        Some(percent) => percent.trim().parse::<f32>().map_err(|_| invalid())? / 100.0,
+        None => s.trim().parse::<f32>().map_err(|_| invalid())?,
+    };
+    if (0.0..=1.0).contains(&brightness) {
+        Ok(brightness)
+    } else {
+        Err(invalid())
+    }
+}
+
+/// Parses a percentage from 0 to 100, with or without a `%`
+pub fn parse_percent(s: &str) -> Result<f64, ArgError> {
+    let invalid = || ArgError::InvalidPercent(s.to_string());
+    let trimmed = s.trim();
+    let percent = trimmed
+        .strip_suffix('%')
+        .unwrap_or(trimmed)
+        .trim()
+        .parse::<f64>()
+        .map_err(|_| invalid())?;
+    if (0.0..=100.0).contains(&percent) {
+        Ok(percent)
+    } else {
+        Err(invalid())
+    }
+}
+
+/// Parses a human readable duration such as `90s`, `2m` or `1h 30m`. A bare number is treated as
+/// seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, ArgError> {
+    if let Ok(seconds) = s.trim().parse::<u64>() {
+        return Ok(Duration::from_secs(seconds));
+    }
+    humantime::parse_duration(s).map_err(|_| ArgError::InvalidDuration(s.to_string()))
+}
+
+/// Parses a time, either a time of day such as `18:30` (the next time it comes round), a date
+/// and time such as `2024-06-01 18:30`, or an RFC 3339 time in UTC such as `2024-06-01T17:30:00Z`
+pub fn parse_time(s: &str) -> Result<SystemTime, ArgError> {
+    parse_time_from(s, SystemTime::now())
+}
+
+fn parse_time_from(s: &str, now: SystemTime) -> Result<SystemTime, ArgError> {
+    let invalid = || ArgError::InvalidTime(s.to_string());
+    let trimmed = s.trim();
+    if trimmed.ends_with(['Z', 'z']) {
+        return humantime::parse_rfc3339_weak(trimmed).map_err(|_| invalid());
+    }
+    let numbers = |part: &str, separator| {
+        part.split(separator)
+            .map(|number| number.parse::<i32>().ok())
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(invalid)
+    };
+    let (date, time) = match trimmed.split_once([' ', 'T']) {
+        Some((date, time)) => (Some(numbers(date, '-')?), numbers(time, ':')?),
+        None => (None, numbers(trimmed, ':')?),
+    };
+    let (hour, minute, second) = match time.as_slice() {
+        [hour, minute] => (*hour, *minute, 0),
+        [hour, minute, second] => (*hour, *minute, *second),
+        _ => return Err(invalid()),
+    };
+    if !(0..24).contains(&hour) || !(0..60).contains(&minute) || !(0..60).contains(&second) {
+        return Err(invalid());
+    }
+    let mut tm = local_time(now).ok_or_else(invalid)?;
+    if let Some(date) = &date {
+        match date.as_slice() {
+            [year, month, day] if (1..=12).contains(month) && (1..=31).contains(day) => {
+                tm.tm_year = year - 1900;
+                tm.tm_mon = month - 1;
+                tm.tm_mday = *day;
+            }
+            _ => return Err(invalid()),
+        }
+    }
+    tm.tm_hour = hour;
+    tm.tm_min = minute;
+    tm.tm_sec = second;
+    let mut time = from_local_time(tm).ok_or_else(invalid)?;
+    if date.is_none() && time <= now {
+        tm.tm_mday += 1;
+        time = from_local_time(tm).ok_or_else(invalid)?;
+    }
+    // mktime quietly moves times that don't exist, such as 31 February, on to ones that do
+    let local = local_time(time).ok_or_else(invalid)?;
+    let moved_day =
+        (local.tm_year, local.tm_mon, local.tm_mday) != (tm.tm_year, tm.tm_mon, tm.tm_mday);
+    let moved_time = (local.tm_hour, local.tm_min, local.tm_sec) != (hour, minute, second);
+    if (date.is_some() && moved_day) || moved_time {
+        return Err(invalid());
+    }
+    Ok(time)
+}
+
+/// Splits a time up into the local date and time of day
+fn local_time(time: SystemTime) -> Option<libc::tm> {
+    let seconds = time.duration_since(UNIX_EPOCH).ok()?.as_secs() as libc::time_t;
+    // Safety: localtime_r only writes to the tm it's given, which is plain data
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    match unsafe { libc::localtime_r(&seconds, &mut tm) }.is_null() {
+        true => None,
+        false => Some(tm),
+    }
+}
+
+/// Puts a local date and time of day back together, working out for itself whether daylight
+/// saving applies
+fn from_local_time(mut tm: libc::tm) -> Option<SystemTime> {
+    tm.tm_isdst = -1;
+    // Safety: mktime only reads and normalises the tm it's given
+    let seconds = unsafe { libc::mktime(&mut tm) };
+    let seconds = u64::try_from(seconds).ok()?;
+    Some(UNIX_EPOCH + Duration::from_secs(seconds))
+}
+
+/// Parses the positional values given for a color, either a single color or the older red, green,
+/// blue [seconds] form
+pub fn parse_color_values(values: &[String]) -> Result<ColorValues, ArgError> {
+    let numbers: Option<Vec<u8>> = values.iter().map(|v| v.parse().ok()).collect();
+    match (values, numbers.as_deref()) {
+        ([color], _) => Ok(ColorValues {
+            color: parse_color(color)?,
+            duration: None,
+        }),
+        (_, Some([red, green, blue])) => Ok(ColorValues {
+            color: Rgb::new(*red, *green, *blue),
+            duration: None,
+        }),
+        (_, Some([red, green, blue, seconds])) => Ok(ColorValues {
+            color: Rgb::new(*red, *green, *blue),
+            duration: Some(Duration::from_secs(*seconds as u64)),
+        }),
+        _ => Err(ArgError::WrongNumberOfValues(values.to_vec())),
+    }
+}
+
+/// Converts a duration into the whole number of seconds sent to the device, rounding up
+pub fn to_device_seconds(duration: Duration) -> Result<u8, ArgError> {
+    let mut seconds = duration.as_secs();
+    if duration.subsec_nanos() > 0 {
+        seconds += 1;
+    }
+    u8::try_from(seconds).map_err(|_| ArgError::DurationTooLong(duration))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn values(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_color() {
+        assert_eq!(parse_color("green"), Ok(Rgb::new(0, 128, 0)));
+        assert_eq!(parse_color("#100000"), Ok(Rgb::new(16, 0, 0)));
+        assert_eq!(parse_color("red@50%"), Ok(Rgb::new(37, 0, 0)));
+        assert_eq!(parse_color("red@1%"), Ok(Rgb::new(1, 0, 0)));
+        assert_eq!(
+            parse_color("red@150%"),
+            Err(ArgError::InvalidBrightness("150%".to_string()))
+        );
+        assert_eq!(
+            parse_color("blurple"),
+            Err(ArgError::InvalidColor(
+                "blurple".to_string(),
+                ColorParseError::UnknownColor
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_percent() {
+        assert_eq!(parse_percent("42"), Ok(42.0));
+        assert_eq!(parse_percent(" 12.5% "), Ok(12.5));
+        assert_eq!(parse_percent("100%"), Ok(100.0));
+        assert_eq!(
+            parse_percent("101"),
+            Err(ArgError::InvalidPercent("101".to_string()))
+        );
+        assert!(parse_percent("half").is_err());
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("120"), Ok(Duration::from_secs(120)));
+        assert_eq!(parse_duration("2m"), Ok(Duration::from_secs(120)));
+        assert_eq!(parse_duration("1m 30s"), Ok(Duration::from_secs(90)));
+        assert_eq!(
+            parse_duration("soon"),
+            Err(ArgError::InvalidDuration("soon".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_parse_color_values() {
+        assert_eq!(
+            parse_color_values(&values(&["green"])),
+            Ok(ColorValues {
+                color: Rgb::new(0, 128, 0),
+                duration: None
+            })
+        );
+        assert_eq!(
+            parse_color_values(&values(&["0", "1", "0"])),
+            Ok(ColorValues {
+                color: Rgb::new(0, 1, 0),
+                duration: None
+            })
+        );
+        assert_eq!(
+            parse_color_values(&values(&["0", "1", "0", "120"])),
+            Ok(ColorValues {
+                color: Rgb::new(0, 1, 0),
+                duration: Some(Duration::from_secs(120))
+            })
+        );
+        assert_eq!(
+            parse_color_values(&values(&["green", "120"])),
+            Err(ArgError::WrongNumberOfValues(values(&["green", "120"])))
+        );
+        assert_eq!(
+            parse_color_values(&values(&["0", "256", "0"])),
+            Err(ArgError::WrongNumberOfValues(values(&["0", "256", "0"])))
+        );
+    }
+
+    #[test]
+    fn test_to_device_seconds() {
+        assert_eq!(to_device_seconds(Duration::from_secs(120)), Ok(120));
+        assert_eq!(to_device_seconds(Duration::from_millis(1500)), Ok(2));
+        assert_eq!(
+            to_device_seconds(Duration::from_secs(256)),
+            Err(ArgError::DurationTooLong(Duration::from_secs(256)))
+        );
+    }
+
+    #[test]
+    fn test_parse_time() {
+        let now = humantime::parse_rfc3339("2024-06-01T12:00:00Z").unwrap();
+        assert_eq!(
+            parse_time_from("2024-06-01T17:30:00Z", now),
+            humantime::parse_rfc3339("2024-06-01T17:30:00Z").map_err(|_| unreachable!())
+        );
+
+        // Local times are within a day of the same time in UTC, whatever the time zone is
+        let utc = humantime::parse_rfc3339("2024-06-01T18:30:00Z").unwrap();
+        let local = parse_time_from("2024-06-01 18:30", now).unwrap();
+        let day = Duration::from_secs(24 * 60 * 60);
+        assert!(local > utc - day && local < utc + day);
+        assert_eq!(parse_time_from("2024-06-01T18:30", now), Ok(local));
+        assert!(parse_time_from("2024-02-29 10:00", now).is_ok());
+        assert_eq!(
+            parse_time_from("2024-06-01 18:30:15", now),
+            Ok(local + Duration::from_secs(15))
+        );
+
+        // A time of day is the next time it comes round
+        for time in ["00:00", "11:59", "12:00", "12:01", "23:59:59"] {
+            let next = parse_time_from(time, now).unwrap();
+            assert!(next > now && next <= now + day, "{} gave {:?}", time, next);
+        }
+
+        for invalid in [
+            "",
+            "18",
+            "24:00",
+            "18:60",
+            "tomorrow",
+            "2024-13-01 18:30",
+            "2024-02-30 10:00",
+            "2023-02-29 10:00",
+            "2024-06-31 18:30",
+            "2024-06-01",
+        ] {
+            assert_eq!(
+                parse_time_from(invalid, now),
+                Err(ArgError::InvalidTime(invalid.to_string()))
+            );
+        }
+    }
+}
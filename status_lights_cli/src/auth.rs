@@ -0,0 +1,248 @@
+//! Bearer tokens for the HTTP API. Each token has a scope that limits which devices its holder
+//! can see, and whether (and which leds) they can change.
+
+use serde::Deserialize;
+use thiserror::Error;
+
+use std::fmt;
+
+use crate::config::ConfigError;
+use crate::http::Request;
+use crate::output::DeviceInfo;
+
+/// A token as written in the config file
+#[derive(Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Token {
+    /// Identifies the token in errors without giving it away
+    pub name: String,
+    pub token: String,
+    /// Only allow requests that don't change anything
+    #[serde(default)]
+    pub read_only: bool,
+    /// Glob patterns matched against each device's id, path, serial number and USB port. Every
+    /// device is allowed if this is empty.
+    #[serde(default)]
+    pub devices: Vec<String>,
+    /// The leds that can be changed. Every led is allowed if this is empty.
+    #[serde(default)]
+    pub leds: Vec<u8>,
+}
+
+impl fmt::Debug for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Token")
+            .field("name", &self.name)
+            .field("token", &"<redacted>")
+            .field("read_only", &self.read_only)
+            .field("devices", &self.devices)
+            .field("leds", &self.leds)
+            .finish()
+    }
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum AuthError {
+    #[error("A bearer token is required")]
+    Missing,
+    #[error("Unknown bearer token")]
+    Unknown,
+}
+
+/// What a request is allowed to do
+#[derive(Clone, Debug, Default)]
+pub struct Scope {
+    read_only: bool,
+    devices: Vec<glob::Pattern>,
+    leds: Vec<u8>,
+}
+
+impl Scope {
+    /// Allows everything
+    pub fn full() -> Scope {
+        Scope::default()
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn can_see(&self, info: &DeviceInfo) -> bool {
+        self.devices.is_empty()
+            || self
+                .devices
+                .iter()
+                .any(|pattern| info.matches_pattern(pattern))
+    }
+
+    pub fn can_change(&self, info: &DeviceInfo, led: u8) -> bool {
+        !self.read_only && self.can_see(info) && (self.leds.is_empty() || self.leds.contains(&led))
+    }
+}
+
+/// Checks requests against the configured tokens
+#[derive(Debug, Default)]
+pub struct Auth {
+    tokens: Vec<(Token, Scope)>,
+    /// Used for every request when there are no tokens
+    open: Scope,
+}
+
+impl Auth {
+    /// Lets every request do everything
+    pub fn open() -> Auth {
+        Auth::default()
+    }
+
+    pub fn new(tokens: Vec<Token>) -> Result<Auth, ConfigError> {
+        let mut auth = Auth::open();
+        for token in tokens {
+            if token.token.is_empty() {
+                return Err(ConfigError::Invalid(format!(
+                    "Token '{}' is empty",
+                    token.name
+                )));
+            }
+            if auth.tokens.iter().any(|(t, _)| t.token == token.token) {
+                return Err(ConfigError::Invalid(format!(
+                    "Token '{}' is the same as another token",
+                    token.name
+                )));
+            }
+            let devices = token
+                .devices
+                .iter()
+                .map(|pattern| glob::Pattern::new(pattern))
+                .collect::<Result<_, _>>()
+                .map_err(|e| ConfigError::Invalid(format!("Token '{}' has {}", token.name, e)))?;
+            let scope = Scope {
+                read_only: token.read_only,
+                devices,
+                leds: token.leds.clone(),
+            };
+            auth.tokens.push((token, scope));
+        }
+        Ok(auth)
+    }
+
+    /// True when no token is needed
+    pub fn is_open(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    /// Finds the scope for the request's `Authorization: Bearer` token
+    pub fn authorize(&self, request: &Request) -> Result<&Scope, AuthError> {
+        if self.is_open() {
+            return Ok(&self.open);
+        }
+        let header = request.header("authorization").ok_or(AuthError::Missing)?;
+        let presented = match header.split_once(' ') {
+            Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => token.trim(),
+            _ => return Err(AuthError::Missing),
+        };
+        // Every token is compared, so the time taken doesn't give away which one nearly matched
+        self.tokens
+            .iter()
+            .fold(None, |found, (token, scope)| {
+                match constant_time_eq(token.token.as_bytes(), presented.as_bytes()) {
+                    true => Some(scope),
+                    false => found,
+                }
+            })
+            .ok_or(AuthError::Unknown)
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn token(name: &str, token: &str) -> Token {
+        Token {
+            name: name.to_string(),
+            token: token.to_string(),
+            read_only: false,
+            devices: Vec::new(),
+            leds: Vec::new(),
+        }
+    }
+
+    fn device(path: &str, usb_port: &str) -> DeviceInfo {
+        DeviceInfo {
+            path: path.to_string(),
+            usb_port: Some(usb_port.to_string()),
+            ..DeviceInfo::default()
+        }
+    }
+
+    #[test]
+    fn test_authorize() {
+        let auth = Auth::new(vec![
+            token("admin", "admin-token"),
+            Token {
+                read_only: true,
+                ..token("grafana", "grafana-token")
+            },
+        ])
+        .unwrap();
+        let request = Request::new("GET", "/devices");
+        assert_eq!(auth.authorize(&request).unwrap_err(), AuthError::Missing);
+        let basic = request.clone().with_header("Authorization", "Basic YTpi");
+        assert_eq!(auth.authorize(&basic).unwrap_err(), AuthError::Missing);
+        let wrong = request.clone().with_header("Authorization", "Bearer admin");
+        assert_eq!(auth.authorize(&wrong).unwrap_err(), AuthError::Unknown);
+
+        let admin = request
+            .clone()
+            .with_header("Authorization", "Bearer admin-token");
+        assert!(!auth.authorize(&admin).unwrap().read_only());
+        let grafana = request.with_header("authorization", "bearer grafana-token");
+        assert!(auth.authorize(&grafana).unwrap().read_only());
+    }
+
+    #[test]
+    fn test_open() {
+        let auth = Auth::new(Vec::new()).unwrap();
+        assert!(auth.is_open());
+        let scope = auth.authorize(&Request::new("GET", "/devices")).unwrap();
+        assert!(scope.can_change(&device("/dev/ttyACM0", "1-1"), 3));
+    }
+
+    #[test]
+    fn test_scope() {
+        let auth = Auth::new(vec![Token {
+            devices: vec!["ttyACM0".to_string(), "1-1.2".to_string()],
+            leds: vec![1],
+            ..token("alerts", "alerts-token")
+        }])
+        .unwrap();
+        let request =
+            Request::new("GET", "/devices").with_header("Authorization", "Bearer alerts-token");
+        let scope = auth.authorize(&request).unwrap();
+
+        let by_id = device("/dev/ttyACM0", "1-1.1");
+        let by_port = device("/dev/ttyACM1", "1-1.2");
+        let other = device("/dev/ttyACM2", "1-1.3");
+        assert!(scope.can_see(&by_id));
+        assert!(scope.can_see(&by_port));
+        assert!(!scope.can_see(&other));
+        assert!(scope.can_change(&by_id, 1));
+        assert!(!scope.can_change(&by_id, 0));
+        assert!(!scope.can_change(&other, 1));
+    }
+
+    #[test]
+    fn test_invalid_tokens() {
+        assert!(Auth::new(vec![token("empty", "")]).is_err());
+        assert!(Auth::new(vec![token("a", "same"), token("b", "same")]).is_err());
+        let bad_pattern = Token {
+            devices: vec!["[".to_string()],
+            ..token("bad", "bad-token")
+        };
+        assert!(Auth::new(vec![bad_pattern]).is_err());
+    }
+}
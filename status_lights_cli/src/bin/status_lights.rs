@@ -1,173 +1,945 @@
-use status_lights_cli::{Client, ClientError};
-use status_lights_messages::{LedColor, LedColorTimed, VersionNumber};
+use serialport::SerialPortInfo;
+use status_lights_cli::alertmanager::Alerts;
+use status_lights_cli::api::Api;
+use status_lights_cli::args::{self, ArgError};
+use status_lights_cli::auth::Auth;
+use status_lights_cli::claims::LedClaims;
+use status_lights_cli::config::Config;
+use status_lights_cli::daemon::{self, Daemon};
+use status_lights_cli::device::{Device, SerialSource};
+use status_lights_cli::effect::{Effect, EFFECTS};
+use status_lights_cli::gradient::Gradient;
+use status_lights_cli::heartbeat::Heartbeats;
+use status_lights_cli::http;
+use status_lights_cli::ipc::{
+    self, Action, DaemonClient, DaemonRequest, DaemonResponse, HeartbeatPing, LedClaim, LedLease,
+    LedRelease,
+};
+use status_lights_cli::monitor::{Monitor, MonitorConfig};
+use status_lights_cli::mqtt::{self, MqttConfig};
+use status_lights_cli::output::{self, DeviceInfo, Format, FORMATS};
+use status_lights_cli::progress::{Bar, Order, ORDERS};
+use status_lights_cli::report::{Outcome, Report};
+use status_lights_cli::selector::{DeviceSelector, Selector, SelectorError};
+use status_lights_cli::state::{self, Snapshot};
+use status_lights_cli::tls::TlsConfig;
+use status_lights_cli::watcher::{self, DeviceEvent};
+use status_lights_cli::wrapper::Wrapper;
+use status_lights_cli::Client;
+use status_lights_messages::{LedColor, Rgb};
+use structopt::clap::AppSettings;
 use structopt::StructOpt;
 
+use std::convert::TryFrom;
+use std::fmt::Display;
+use std::fs;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
 #[derive(Clone, Debug, StructOpt)]
 struct BackgroundOptions {
     led: u8,
-    red: u8,
-    green: u8,
-    blue: u8,
+    /// A color name, hex code (#rrggbb) or hsv(h, s%, v%), optionally followed by @<brightness>%.
+    /// Separate red, green and blue values are also accepted.
+    #[structopt(required = true, min_values = 1, max_values = 3)]
+    color: Vec<String>,
+    #[structopt(flatten)]
+    selector: SelectorOptions,
+}
+
+impl TryFrom<BackgroundOptions> for LedColor {
+    type Error = ArgError;
+
+    fn try_from(bg: BackgroundOptions) -> Result<Self, Self::Error> {
+        let values = args::parse_color_values(&bg.color)?;
+        Ok(Self::new(bg.led, values.color))
+    }
+}
+
+#[derive(Clone, Debug, StructOpt)]
+struct ForegroundOptions {
+    led: u8,
+    /// A color name, hex code (#rrggbb) or hsv(h, s%, v%), optionally followed by @<brightness>%.
+    /// Separate red, green and blue values, followed by optional seconds, are also accepted.
+    #[structopt(required = true, min_values = 1, max_values = 4)]
+    color: Vec<String>,
+    /// How long to show the color for, eg 90s, 2m or 3h. Shows indefinitely if not set. Anything
+    /// over 4m 15s is held by the daemon.
+    #[structopt(long = "for", parse(try_from_str = args::parse_duration))]
+    duration: Option<Duration>,
+    /// When to stop showing the color, eg 18:30 or 2024-06-01 18:30. This is held by the daemon.
+    #[structopt(long, parse(try_from_str = args::parse_time), conflicts_with = "duration")]
+    until: Option<SystemTime>,
+    #[structopt(flatten)]
+    selector: SelectorOptions,
+}
+
+impl TryFrom<ForegroundOptions> for Action {
+    type Error = ArgError;
+
+    fn try_from(fg: ForegroundOptions) -> Result<Self, Self::Error> {
+        let values = args::parse_color_values(&fg.color)?;
+        if let Some(until) = fg.until {
+            if values.duration.is_some() {
+                return Err(ArgError::DuplicateDuration);
+            }
+            return Ok(Action::Lease(LedLease {
+                led: fg.led,
+                color: values.color,
+                until,
+            }));
+        }
+        let duration = match (values.duration, fg.duration) {
+            (Some(_), Some(_)) => return Err(ArgError::DuplicateDuration),
+            (duration, None) | (None, duration) => duration,
+        };
+        Action::foreground_for(fg.led, values.color, duration)
+    }
+}
+
+#[derive(Clone, Debug, StructOpt)]
+#[structopt(setting = AppSettings::AllowNegativeNumbers)]
+struct ValueOptions {
+    led: u8,
+    /// The number to show, which is compared with the gradient's stops
+    value: f64,
+    /// A preset (traffic-light, reverse-traffic-light, cool-warm or brightness) or stops such as
+    /// 0=green,70%=orange,90%=red
+    #[structopt(long, default_value = "traffic-light")]
+    gradient: Gradient,
+    /// Dims the color, eg 10%
+    #[structopt(long, parse(try_from_str = args::parse_brightness))]
+    brightness: Option<f32>,
+    /// How long to show the color for, eg 90s, 2m or 3h. Shows indefinitely if not set. Anything
+    /// over 4m 15s is held by the daemon.
+    #[structopt(long = "for", parse(try_from_str = args::parse_duration))]
+    duration: Option<Duration>,
+    #[structopt(flatten)]
+    selector: SelectorOptions,
+}
+
+impl TryFrom<ValueOptions> for Action {
+    type Error = ArgError;
+
+    fn try_from(value: ValueOptions) -> Result<Self, Self::Error> {
+        let color = value.gradient.color_at(value.value);
+        let color = match value.brightness {
+            Some(brightness) => color.scale(brightness),
+            None => color,
+        };
+        Action::foreground_for(value.led, color, value.duration)
+    }
+}
+
+#[derive(Clone, Debug, StructOpt)]
+struct ProgressOptions {
+    /// How far through the job is, from 0 to 100
+    #[structopt(parse(try_from_str = args::parse_percent), required_unless = "busy")]
+    percent: Option<f64>,
+    /// Show a light chasing along the leds instead, for jobs that can't tell how far through they
+    /// are. This needs the daemon to be running.
+    #[structopt(long, conflicts_with = "percent")]
+    busy: bool,
+    /// The color of the filled leds
+    #[structopt(long, default_value = "green@10%", parse(try_from_str = args::parse_color))]
+    color: Rgb,
+    /// The color of the leds that aren't filled yet. They show their background if not set.
+    #[structopt(long, parse(try_from_str = args::parse_color))]
+    empty: Option<Rgb>,
+    /// Make one bar across all of the selected devices, instead of one on each
     #[structopt(long)]
-    device: Option<String>,
+    span: bool,
+    /// The order of the devices in a bar that spans them
+    #[structopt(long, default_value = "path", possible_values = ORDERS)]
+    order: Order,
+    /// How long the busy light takes to go along the bar, eg 2s. Defaults to half a second for
+    /// each led.
+    #[structopt(long, parse(try_from_str = args::parse_duration), requires = "busy")]
+    period: Option<Duration>,
+    /// How long to show the bar for, eg 90s or 2m. Shows indefinitely if not set.
+    #[structopt(long = "for", parse(try_from_str = args::parse_duration), conflicts_with = "busy")]
+    duration: Option<Duration>,
+    #[structopt(flatten)]
+    selector: SelectorOptions,
 }
 
-impl From<BackgroundOptions> for LedColor {
-    fn from(bg: BackgroundOptions) -> Self {
-        Self {
-            led: bg.led,
-            red: bg.red,
-            green: bg.green,
-            blue: bg.blue,
+impl TryFrom<&ProgressOptions> for Bar {
+    type Error = ArgError;
+
+    fn try_from(progress: &ProgressOptions) -> Result<Self, Self::Error> {
+        if progress.busy {
+            return Ok(Bar::Busy {
+                color: progress.color,
+                period: progress.period,
+            });
         }
+        let seconds = progress.duration.map(args::to_device_seconds).transpose()?;
+        Ok(Bar::Fill {
+            percent: progress.percent.unwrap_or_default(),
+            color: progress.color,
+            empty: progress.empty.unwrap_or(Rgb::OFF),
+            seconds: seconds.unwrap_or(0),
+        })
     }
 }
 
 #[derive(Clone, Debug, StructOpt)]
-struct ForegroundOptions {
+#[structopt(setting = AppSettings::TrailingVarArg)]
+struct RunOptions {
+    /// The led to show the command's status on
+    #[structopt(long, default_value = "0")]
     led: u8,
-    red: u8,
-    green: u8,
-    blue: u8,
-    seconds: Option<u8>,
+    /// The color to show while the command runs
+    #[structopt(long, default_value = "blue@10%", parse(try_from_str = args::parse_color))]
+    busy: Rgb,
+    /// Play this effect in the busy color while the command runs, if the daemon is running
+    #[structopt(long, possible_values = EFFECTS)]
+    effect: Option<String>,
+    /// The color to show if the command succeeds
+    #[structopt(long, default_value = "green@10%", parse(try_from_str = args::parse_color))]
+    success: Rgb,
+    /// The color to show if the command fails
+    #[structopt(long, default_value = "red@10%", parse(try_from_str = args::parse_color))]
+    failure: Rgb,
+    /// How long to show whether the command succeeded, eg 90s or 2m. 0 shows it until it's
+    /// changed.
+    #[structopt(long, default_value = "2m", parse(try_from_str = args::parse_duration))]
+    hold: Duration,
+    #[structopt(flatten)]
+    selector: SelectorOptions,
+    /// The command to run, followed by its arguments
+    #[structopt(required = true)]
+    command: Vec<String>,
+}
+
+#[derive(Clone, Debug, StructOpt)]
+struct HeartbeatOptions {
+    /// The name of the check in the daemon's config file
+    check: String,
+    /// Report that the job failed, rather than that it ran
     #[structopt(long)]
-    device: Option<String>,
+    fail: bool,
+}
+
+#[derive(Clone, Debug, StructOpt)]
+#[structopt(setting = AppSettings::AllowNegativeNumbers)]
+struct ClaimOptions {
+    led: u8,
+    /// Claims with a higher priority take the led from lower ones, which wait for it back
+    #[structopt(long, default_value = "0")]
+    priority: i32,
+    #[structopt(flatten)]
+    selector: SelectorOptions,
+}
+
+#[derive(Clone, Debug, StructOpt)]
+struct ReleaseOptions {
+    led: u8,
+    #[structopt(flatten)]
+    selector: SelectorOptions,
+}
+
+#[derive(Clone, Debug, StructOpt)]
+struct StateFileOptions {
+    #[structopt(parse(from_os_str))]
+    file: PathBuf,
+    #[structopt(flatten)]
+    selector: SelectorOptions,
 }
 
-impl From<ForegroundOptions> for LedColorTimed {
-    fn from(fg: ForegroundOptions) -> Self {
-        Self {
-            led: fg.led,
-            red: fg.red,
-            green: fg.green,
-            blue: fg.blue,
-            seconds: fg.seconds.unwrap_or(0),
+#[derive(Clone, Debug, StructOpt)]
+enum StateCommand {
+    /// Save what the leds of each device are set to in a file
+    Save(StateFileOptions),
+    /// Put the leds back the way they were when the file was saved
+    Restore(StateFileOptions),
+}
+
+impl StateCommand {
+    fn options(&self) -> &StateFileOptions {
+        match self {
+            StateCommand::Save(options) | StateCommand::Restore(options) => options,
+        }
+    }
+}
+
+#[derive(Clone, Debug, StructOpt)]
+struct FormatOptions {
+    #[structopt(long, default_value = "text", possible_values = FORMATS)]
+    format: Format,
+}
+
+#[derive(Clone, Debug, StructOpt)]
+struct ListOptions {
+    #[structopt(flatten)]
+    format: FormatOptions,
+    #[structopt(flatten)]
+    selector: SelectorOptions,
+}
+
+// Every option can be given more than once, and accepts glob patterns such as `/dev/ttyACM*`
+#[derive(Clone, Debug, StructOpt)]
+struct SelectorOptions {
+    /// Only use the device at this path
+    #[structopt(long = "device", value_name = "path", number_of_values = 1)]
+    devices: Vec<String>,
+    /// Only use the device with this USB serial number, which is also its name
+    #[structopt(
+        long = "serial",
+        alias = "name",
+        value_name = "serial",
+        number_of_values = 1
+    )]
+    serials: Vec<String>,
+    /// Only use the device plugged into this USB port, eg 1-1.2
+    #[structopt(long = "usb-port", value_name = "port", number_of_values = 1)]
+    usb_ports: Vec<String>,
+    /// Skip devices whose path, serial number or USB port matches
+    #[structopt(long = "exclude", value_name = "pattern", number_of_values = 1)]
+    exclude: Vec<String>,
+}
+
+impl TryFrom<&SelectorOptions> for DeviceSelector {
+    type Error = SelectorError;
+
+    fn try_from(options: &SelectorOptions) -> Result<Self, Self::Error> {
+        let include = options
+            .devices
+            .iter()
+            .map(|path| Selector::path(path))
+            .chain(
+                options
+                    .serials
+                    .iter()
+                    .map(|serial| Selector::serial(serial)),
+            )
+            .chain(
+                options
+                    .usb_ports
+                    .iter()
+                    .map(|port| Selector::usb_port(port)),
+            );
+        let mut selector = DeviceSelector::all();
+        for include in include {
+            selector = selector.include(include?);
         }
+        for value in &options.exclude {
+            selector = selector.exclude(Selector::any(value)?);
+        }
+        Ok(selector)
     }
 }
 
+#[derive(Clone, Debug, StructOpt)]
+struct DaemonOptions {
+    /// Also serve the HTTP API on this address, eg 0.0.0.0:8080. Overrides `listen` in the
+    /// config file.
+    #[structopt(long, value_name = "address")]
+    http: Option<String>,
+    /// Where to keep what every led was set to, so it can be put back after the daemon or the
+    /// host restarts. Defaults to $STATUS_LIGHTS_STATE, or status_lights/state.json in
+    /// $XDG_STATE_HOME or ~/.local/state
+    #[structopt(long, value_name = "path", parse(from_os_str))]
+    state_file: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, StructOpt)]
+struct MqttOptions {
+    #[structopt(flatten)]
+    daemon: DaemonOptions,
+    /// The MQTT broker to connect to. Overrides `host` in the config file.
+    #[structopt(long)]
+    host: Option<String>,
+    /// Overrides `port` in the config file
+    #[structopt(long)]
+    port: Option<u16>,
+    /// The start of every topic, eg `home/status_lights`. Overrides `prefix` in the config file.
+    #[structopt(long)]
+    prefix: Option<String>,
+    /// Publish Home Assistant discovery configs, so every led shows up there as a light
+    #[structopt(long)]
+    home_assistant: bool,
+}
+
 #[derive(Debug, StructOpt)]
-#[structopt(name = "status_lights", about = "Control status lights")]
-enum Opt {
-    List,
-    DebugList,
+enum Command {
+    List(ListOptions),
+    DebugList(FormatOptions),
     Background(BackgroundOptions),
     Foreground(ForegroundOptions),
+    /// Show a number as a color from a gradient, in the foreground
+    Value(ValueOptions),
+    /// Fill the leds in proportion to how far through a job is, or show that it's busy
+    Progress(ProgressOptions),
+    /// Run a command, showing that it's busy on an led and then whether it succeeded. Exits with
+    /// the command's exit code.
+    Run(RunOptions),
+    /// Tell the daemon that a job in the config file's `[[heartbeat.checks]]` has run, or failed
+    Heartbeat(HeartbeatOptions),
+    /// Claim an led for --owner, so that no one else can change it until it's released
+    Claim(ClaimOptions),
+    /// Release --owner's claim on an led, handing it to the next claim
+    Release(ReleaseOptions),
+    /// Show who has claimed each led
+    Claims,
+    /// Save what the daemon has set the leds to, or put them back the way they were
+    State(StateCommand),
+    /// Hold every device open and accept commands from other invocations over a Unix socket,
+    /// and optionally from other machines over HTTP
+    Daemon(DaemonOptions),
+    /// Run the daemon and bridge it to an MQTT broker, publishing the state of each led and
+    /// taking changes from `<prefix>/<device>/<led>/set`
+    Mqtt(MqttOptions),
+    /// Run the health checks in the config file's `[[monitor.checks]]`, showing each result on
+    /// its led
+    Monitor,
+    /// Show this machine's load, memory, disk and temperature on the leds, or run the checks in
+    /// the config file's `[[sysmon.checks]]` if there are any
+    Sysmon,
 }
 
-impl Opt {
-    pub fn get_device(&self) -> Option<&String> {
+impl Command {
+    fn get_selector_options(&self) -> Option<&SelectorOptions> {
         match self {
-            Opt::List => None,
-            Opt::DebugList => None,
-            Opt::Background(bg) => bg.device.as_ref(),
-            Opt::Foreground(fg) => fg.device.as_ref(),
+            Command::List(list) => Some(&list.selector),
+            Command::Background(bg) => Some(&bg.selector),
+            Command::Foreground(fg) => Some(&fg.selector),
+            Command::Value(value) => Some(&value.selector),
+            Command::Progress(progress) => Some(&progress.selector),
+            Command::Run(run) => Some(&run.selector),
+            Command::Claim(claim) => Some(&claim.selector),
+            Command::Release(release) => Some(&release.selector),
+            Command::State(state) => Some(&state.options().selector),
+            Command::DebugList(_)
+            | Command::Heartbeat(_)
+            | Command::Claims
+            | Command::Daemon(_)
+            | Command::Mqtt(_)
+            | Command::Monitor
+            | Command::Sysmon => None,
+        }
+    }
+
+    /// The action this command runs against the selected devices, if it has one
+    fn get_action(&self) -> Result<Option<Action>, ArgError> {
+        match self {
+            Command::List(_) => Ok(Some(Action::List)),
+            Command::Background(bg) => {
+                Ok(Some(Action::Background(LedColor::try_from(bg.clone())?)))
+            }
+            Command::Foreground(fg) => Ok(Some(Action::try_from(fg.clone())?)),
+            Command::Value(value) => Ok(Some(Action::try_from(value.clone())?)),
+            Command::Heartbeat(heartbeat) => Ok(Some(Action::Heartbeat(HeartbeatPing {
+                check: heartbeat.check.clone(),
+                failed: heartbeat.fail,
+            }))),
+            Command::Claim(claim) => Ok(Some(Action::Claim(LedClaim {
+                led: claim.led,
+                priority: claim.priority,
+            }))),
+            Command::Release(release) => Ok(Some(Action::Release(LedRelease { led: release.led }))),
+            Command::Claims => Ok(Some(Action::Claims)),
+            Command::State(StateCommand::Save(_)) => Ok(Some(Action::Snapshot)),
+            Command::DebugList(_)
+            | Command::State(StateCommand::Restore(_))
+            | Command::Progress(_)
+            | Command::Run(_)
+            | Command::Daemon(_)
+            | Command::Mqtt(_)
+            | Command::Monitor
+            | Command::Sysmon => Ok(None),
         }
     }
 }
 
-fn main() {
-    let opt = Opt::from_args();
-    let device = opt.get_device();
-
-    if let Opt::DebugList = opt {
-        match Client::list_all_usb_devices() {
-            Ok(devices) => {
-                println!("Currently attached USB devices:");
-                for device in devices {
-                    println!("{:?}", device);
+#[derive(Debug, StructOpt)]
+#[structopt(name = "status_lights", about = "Control status lights")]
+struct Opt {
+    /// Only print errors
+    #[structopt(long, short, global = true)]
+    quiet: bool,
+    /// Print a JSON report of what happened to each device instead of text
+    #[structopt(long, global = true, conflicts_with = "quiet")]
+    json: bool,
+    /// Where the daemon listens. Defaults to $STATUS_LIGHTS_SOCKET, or status_lights.sock in
+    /// $XDG_RUNTIME_DIR or /tmp
+    #[structopt(long, global = true, parse(from_os_str))]
+    socket: Option<PathBuf>,
+    /// Talk to the devices directly, even if the daemon is running
+    #[structopt(long, global = true)]
+    no_daemon: bool,
+    /// The config file to use instead of $STATUS_LIGHTS_CONFIG or
+    /// ~/.config/status_lights/config.toml
+    #[structopt(long, global = true, parse(from_os_str))]
+    config: Option<PathBuf>,
+    /// Who is making changes, so that leds they've claimed can only be changed by them. Defaults
+    /// to $STATUS_LIGHTS_OWNER.
+    #[structopt(long, global = true)]
+    owner: Option<String>,
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+impl Opt {
+    /// The format for commands that print device information, `--json` is a shorthand for
+    /// `--format json`
+    fn format(&self, options: &FormatOptions) -> Format {
+        match options.format {
+            Format::Text if self.json => Format::Json,
+            format => format,
+        }
+    }
+
+    fn socket_path(&self) -> PathBuf {
+        self.socket.clone().unwrap_or_else(ipc::default_socket_path)
+    }
+
+    fn owner(&self) -> Option<String> {
+        self.owner.clone().or_else(ipc::default_owner)
+    }
+
+    /// Prints the report in the format that was asked for, then exits with its exit code
+    fn report_and_exit(&self, report: Report) -> ! {
+        if self.json {
+            println!("{}", report.to_json());
+        } else {
+            for device in &report.devices {
+                if device.ok && !self.quiet {
+                    println!("{}", device.summary());
+                } else if !device.ok {
+                    eprintln!("{}", device.summary());
                 }
             }
-            Err(e) => {
-                panic!("{:?}", e);
+            if let Some(error) = &report.error {
+                eprintln!("{}", error);
             }
         }
-        return;
+        report.outcome.exit()
     }
+}
 
-    let mut clients: Vec<Client> = Client::collect_clients()
-        .unwrap()
-        .into_iter()
-        .filter(move |c| device.is_none() || Some(c.get_path()) == device)
-        .collect();
-
-    if clients.is_empty() {
-        eprintln!("No devices found");
-        std::process::exit(-1);
+/// Reports bad arguments and exits. This looks at the raw arguments for `--json`, as the
+/// arguments may be too broken to parse.
+fn usage_error(error: impl Display) -> ! {
+    let json = std::env::args()
+        .skip(1)
+        .take_while(|arg| arg != "--")
+        .any(|arg| arg == "--json");
+    if json {
+        let report = Report::failed(Outcome::UsageError, error.to_string());
+        println!("{}", report.to_json());
+    } else {
+        eprintln!("error: {}", error);
     }
+    Outcome::UsageError.exit()
+}
+
+fn main() {
+    let opt = Opt::from_args_safe().unwrap_or_else(|e| {
+        if e.use_stderr() {
+            usage_error(e.message.trim_start_matches("error: "));
+        }
+        e.exit()
+    });
+    let action = opt.command.get_action().unwrap_or_else(|e| usage_error(e));
+    let selector = opt
+        .command
+        .get_selector_options()
+        .map(DeviceSelector::try_from)
+        .transpose()
+        .unwrap_or_else(|e| usage_error(e))
+        .unwrap_or_default();
+
+    let action = match (&opt.command, action) {
+        (Command::DebugList(list_options), _) => {
+            match Client::list_all_usb_devices() {
+                Ok(ports) => print_all_ports(ports, opt.format(list_options)),
+                Err(e) => {
+                    opt.report_and_exit(Report::failed(Outcome::DeviceError, e.to_string()));
+                }
+            }
+            return;
+        }
+        (Command::Progress(progress_options), _) => run_progress(&opt, progress_options, &selector),
+        (Command::Run(run_options), _) => run_wrapped(&opt, run_options, selector),
+        (Command::Daemon(daemon_options), _) => run_daemon(&opt, daemon_options),
+        (Command::Mqtt(mqtt_options), _) => run_mqtt(&opt, mqtt_options),
+        (Command::Monitor, _) => {
+            let config = Config::load(opt.config.as_deref()).unwrap_or_else(|e| usage_error(e));
+            run_monitor(&opt, &config.monitor)
+        }
+        (Command::Sysmon, _) => {
+            let config = Config::load(opt.config.as_deref()).unwrap_or_else(|e| usage_error(e));
+            run_monitor(&opt, &config.sysmon.monitor_config())
+        }
+        (Command::State(StateCommand::Restore(options)), _) => {
+            Action::Restore(read_snapshot(&options.file))
+        }
+        (_, Some(action)) => action,
+        (_, None) => unreachable!("every other command has an action"),
+    };
 
-    match opt {
-        Opt::List => print_devices_addresses(clients),
-        Opt::DebugList => panic!("Should not be reachable"),
-        Opt::Background(background_options) => {
-            let results = set_background(&mut clients, background_options);
-            handle_results_and_exit(results);
+    let request = DaemonRequest {
+        selector,
+        action,
+        owner: None,
+    };
+    let response = send_to_daemon(&opt, &request).unwrap_or_else(|| run_directly(&request));
+    match (&opt.command, response) {
+        (Command::List(list_options), DaemonResponse::Devices(devices)) => {
+            print_devices(&devices, opt.format(&list_options.format)).exit()
         }
-        Opt::Foreground(foreground_options) => {
-            let results = set_foreground(&mut clients, foreground_options);
-            handle_results_and_exit(results);
+        (_, DaemonResponse::Report(report)) => opt.report_and_exit(report),
+        (Command::Claims, DaemonResponse::Claims(claims)) => {
+            print_claims(&opt, &claims);
+            Outcome::Success.exit()
         }
+        (_, DaemonResponse::Devices(_)) => opt.report_and_exit(Report::failed(
+            Outcome::DeviceError,
+            "The daemon sent a device list instead of a report".to_string(),
+        )),
+        (Command::State(StateCommand::Save(options)), DaemonResponse::Snapshot(snapshot)) => {
+            save_snapshot(&opt, &options.file, &snapshot)
+        }
+        (_, DaemonResponse::Claims(_)) => opt.report_and_exit(Report::failed(
+            Outcome::DeviceError,
+            "The daemon sent a list of claims instead of a report".to_string(),
+        )),
+        (_, DaemonResponse::Snapshot(_)) => opt.report_and_exit(Report::failed(
+            Outcome::DeviceError,
+            "The daemon sent a snapshot instead of a report".to_string(),
+        )),
     }
 }
 
-fn set_background(
-    clients: &mut [Client],
-    background_options: BackgroundOptions,
-) -> Vec<Result<(), ClientError>> {
-    clients
-        .iter_mut()
-        .map(|client| {
+fn read_snapshot(path: &Path) -> Snapshot {
+    Snapshot::read(path)
+        .unwrap_or_else(|e| usage_error(format!("Unable to read '{}': {}", path.display(), e)))
+}
+
+fn save_snapshot(opt: &Opt, path: &Path, snapshot: &Snapshot) -> ! {
+    let json = serde_json::to_string_pretty(snapshot).expect("snapshots are always serializable");
+    if let Err(e) = fs::write(path, json + "\n") {
+        opt.report_and_exit(Report::failed(
+            Outcome::UsageError,
+            format!("Unable to write '{}': {}", path.display(), e),
+        ))
+    }
+    if !opt.quiet && !opt.json {
+        for device in &snapshot.devices {
             println!(
-                "Changing device '{}' at '{}'",
-                client.get_name(),
-                client.get_path()
+                "Saved device '{}' at '{}'",
+                device.name.as_deref().unwrap_or_default(),
+                device.path
             );
-            client.request_background(background_options.clone().into())
-        })
-        .collect()
+        }
+    }
+    Outcome::Success.exit()
 }
 
-fn set_foreground(
-    clients: &mut [Client],
-    foreground_options: ForegroundOptions,
-) -> Vec<Result<(), ClientError>> {
-    clients
-        .iter_mut()
-        .map(|client| {
-            println!(
-                "Changing device '{}' at '{}'",
-                client.get_name(),
-                client.get_path()
+/// Sends the request to the daemon if it's running. Returns `None` if it isn't, so the cli can
+/// talk to the devices itself.
+fn send_to_daemon(opt: &Opt, request: &DaemonRequest) -> Option<DaemonResponse> {
+    if opt.no_daemon {
+        return None;
+    }
+    let mut daemon = DaemonClient::connect(&opt.socket_path()).ok()?;
+    let request = DaemonRequest {
+        owner: request.owner.clone().or_else(|| opt.owner()),
+        ..request.clone()
+    };
+    Some(daemon.send(&request).unwrap_or_else(|e| {
+        DaemonResponse::Report(Report::failed(
+            Outcome::DeviceError,
+            format!("Lost connection to the daemon: {}", e),
+        ))
+    }))
+}
+
+fn run_directly(request: &DaemonRequest) -> DaemonResponse {
+    let clients = match Client::collect_clients() {
+        Ok(clients) => clients,
+        Err(e) => {
+            return DaemonResponse::Report(Report::failed(Outcome::DeviceError, e.to_string()))
+        }
+    };
+    match request.selector.select(clients) {
+        Ok(mut clients) => request
+            .action
+            .run(clients.iter_mut().map(|client| client as &mut dyn Device)),
+        Err(e) => DaemonResponse::Report(Report::failed(Outcome::NoDevices, e.to_string())),
+    }
+}
+
+/// Shows a progress bar, sending each led's part through the daemon if it's running
+fn run_progress(opt: &Opt, options: &ProgressOptions, selector: &DeviceSelector) -> ! {
+    let bar = Bar::try_from(options).unwrap_or_else(|e| usage_error(e));
+    let apply = |request: &DaemonRequest| {
+        send_to_daemon(opt, request).unwrap_or_else(|| run_directly(request))
+    };
+    let report = match options.span {
+        true => bar.show_across(selector, options.order, apply),
+        false => bar.show(selector, apply),
+    };
+    opt.report_and_exit(report)
+}
+
+/// Runs the command, showing its status through the daemon if it's running
+fn run_wrapped(opt: &Opt, options: &RunOptions, selector: DeviceSelector) -> ! {
+    let wrapper = Wrapper {
+        selector,
+        led: options.led,
+        busy: options.busy,
+        effect: options
+            .effect
+            .as_deref()
+            .and_then(|name| Effect::from_name(name, options.busy)),
+        success: options.success,
+        failure: options.failure,
+        hold: args::to_device_seconds(options.hold).unwrap_or_else(|e| usage_error(e)),
+    };
+    let code = wrapper.run(
+        process::Command::new(&options.command[0]).args(&options.command[1..]),
+        |request| send_to_daemon(opt, request).unwrap_or_else(|| run_directly(request)),
+        |line| eprintln!("status_lights: {}", line),
+    );
+    process::exit(code)
+}
+
+/// Runs the checks, sending each result through the daemon if it's running
+fn run_monitor(opt: &Opt, config: &MonitorConfig) -> ! {
+    let mut monitor = Monitor::new(config).unwrap_or_else(|e| usage_error(e));
+    if !opt.quiet {
+        eprintln!("Running {} checks", config.checks.len());
+    }
+    monitor.run(
+        |request| send_to_daemon(opt, request).unwrap_or_else(|| run_directly(request)),
+        |line| {
+            if !opt.quiet {
+                eprintln!("{}", line);
+            }
+        },
+    );
+    opt.report_and_exit(Report::failed(
+        Outcome::DeviceError,
+        "Every check stopped".to_string(),
+    ))
+}
+
+fn run_mqtt(opt: &Opt, options: &MqttOptions) -> ! {
+    let mut config = Config::load(opt.config.as_deref()).unwrap_or_else(|e| usage_error(e));
+    if let Some(host) = &options.host {
+        config.mqtt.host = host.clone();
+    }
+    if let Some(port) = options.port {
+        config.mqtt.port = port;
+    }
+    if let Some(prefix) = &options.prefix {
+        config.mqtt.prefix = prefix.clone();
+    }
+    config.mqtt.home_assistant |= options.home_assistant;
+    run_daemon_with_config(opt, &options.daemon, config, true)
+}
+
+fn run_daemon(opt: &Opt, options: &DaemonOptions) -> ! {
+    let config = Config::load(opt.config.as_deref()).unwrap_or_else(|e| usage_error(e));
+    run_daemon_with_config(opt, options, config, false)
+}
+
+/// Runs the daemon, along with the HTTP API if it has an address and the MQTT bridge if asked for
+fn run_daemon_with_config(opt: &Opt, options: &DaemonOptions, config: Config, mqtt: bool) -> ! {
+    let mut daemon = Daemon::new(Box::new(SerialSource));
+    if !config.heartbeat.checks.is_empty() {
+        let heartbeats =
+            Heartbeats::new(&config.heartbeat, Instant::now()).unwrap_or_else(|e| usage_error(e));
+        daemon = daemon.with_heartbeats(heartbeats);
+    }
+    if let Some(path) = options
+        .state_file
+        .clone()
+        .or_else(state::default_state_path)
+    {
+        daemon = daemon
+            .with_state_file(path.clone())
+            .unwrap_or_else(|e| usage_error(format!("Unable to use '{}': {}", path.display(), e)));
+    }
+    let path = opt.socket_path();
+    let listener = daemon::bind(&path).unwrap_or_else(|e| {
+        opt.report_and_exit(Report::failed(
+            Outcome::DeviceError,
+            format!("Unable to listen on '{}': {}", path.display(), e),
+        ))
+    });
+    if !opt.quiet {
+        eprintln!("Listening on '{}'", path.display());
+    }
+    let daemon = Arc::new(daemon);
+    daemon.start_ticking();
+    if !opt.quiet {
+        log_device_events(&daemon);
+    }
+    if !daemon.start_watching() && cfg!(target_os = "linux") && !opt.quiet {
+        eprintln!(
+            "Warning: unable to hear from udev, looking for devices every {} instead",
+            humantime::format_duration(watcher::POLL)
+        );
+    }
+    if let Some(address) = options.http.as_ref().or(config.http.listen.as_ref()) {
+        serve_http(opt, &daemon, address, &config);
+    } else if !config.alertmanager.rules.is_empty() && !opt.quiet {
+        eprintln!("Warning: alert rules are configured, but webhooks need the HTTP API");
+    }
+    if mqtt {
+        start_mqtt(opt, &daemon, config.mqtt);
+    }
+    let result = daemon.serve(listener);
+    opt.report_and_exit(Report::failed(
+        Outcome::DeviceError,
+        format!("Daemon stopped: {:?}", result),
+    ))
+}
+
+/// Prints each device as it's plugged in or unplugged, on a background thread
+fn log_device_events(daemon: &Daemon) {
+    let events = daemon.subscribe();
+    thread::spawn(move || {
+        for event in events {
+            let (verb, info) = match &event {
+                DeviceEvent::Attached(info) => ("Opened", info),
+                DeviceEvent::Detached(info) => ("Closed", info),
+            };
+            eprintln!(
+                "{} device '{}' at '{}'",
+                verb,
+                info.name.as_deref().unwrap_or_default(),
+                info.path
             );
-            client.request_foreground(foreground_options.clone().into())
-        })
-        .collect()
+        }
+    });
 }
 
-fn handle_results_and_exit<T>(results: Vec<Result<T, ClientError>>) {
-    results.iter().for_each(|r| {
-        if let Err(e) = r.as_ref() {
-            eprintln!("Error: {:?}", e)
+/// Serves the HTTP API on a background thread
+fn serve_http(opt: &Opt, daemon: &Arc<Daemon>, address: &str, config: &Config) {
+    let auth = Auth::new(config.http.tokens.clone()).unwrap_or_else(|e| usage_error(e));
+    let tls = config
+        .http
+        .tls
+        .as_ref()
+        .map(TlsConfig::server_config)
+        .transpose()
+        .unwrap_or_else(|e| usage_error(e));
+    let listener = TcpListener::bind(address).unwrap_or_else(|e| {
+        opt.report_and_exit(Report::failed(
+            Outcome::DeviceError,
+            format!("Unable to listen on '{}': {}", address, e),
+        ))
+    });
+    if !opt.quiet {
+        let scheme = if tls.is_some() { "https" } else { "http" };
+        eprintln!("Serving the HTTP API on {}://{}", scheme, address);
+        if auth.is_open() {
+            eprintln!("Warning: no tokens are configured, anyone who can reach the API can use it");
+        }
+    }
+    let mut api = Api::new(Arc::clone(daemon)).with_auth(auth);
+    if !config.alertmanager.rules.is_empty() {
+        let alerts = Alerts::new(&config.alertmanager).unwrap_or_else(|e| usage_error(e));
+        if !opt.quiet {
+            eprintln!("Receiving Alertmanager webhooks on /alertmanager");
+        }
+        api = api.with_alerts(alerts);
+    }
+    let api = Arc::new(api);
+    thread::spawn(move || {
+        if let Err(e) = http::serve(listener, tls, api) {
+            eprintln!("HTTP API stopped: {}", e);
         }
     });
+}
 
-    std::process::exit(results.len() as i32);
+fn start_mqtt(opt: &Opt, daemon: &Arc<Daemon>, config: MqttConfig) {
+    if !opt.quiet {
+        eprintln!(
+            "Bridging to MQTT broker {}:{} under '{}/'",
+            config.host, config.port, config.prefix
+        );
+    }
+    mqtt::start(Arc::clone(daemon), config);
 }
 
-fn format_version_number(version: &VersionNumber) -> String {
-    format!("v{}.{}.{}", version.major, version.minor, version.patch)
+fn print_all_ports(ports: Vec<SerialPortInfo>, format: Format) {
+    let devices: Vec<DeviceInfo> = ports.iter().map(DeviceInfo::from_port).collect();
+    match output::render(format, &devices) {
+        Some(rendered) => println!("{}", rendered),
+        None => {
+            println!("Currently attached USB devices:");
+            for port in ports {
+                println!("{:?}", port);
+            }
+        }
+    }
 }
 
-fn print_devices_addresses(mut clients: Vec<Client>) {
-    println!("Found {} devices", clients.len());
-    clients.iter_mut().for_each(|client| {
-        if let Ok(version_number) = client.request_version() {
+/// Prints who controls each claimed led, and who is waiting for it
+fn print_claims(opt: &Opt, claims: &[LedClaims]) {
+    if opt.json {
+        println!(
+            "{}",
+            serde_json::to_string(claims).expect("claims are always serializable")
+        );
+        return;
+    }
+    if claims.is_empty() {
+        println!("No leds have been claimed");
+    }
+    for led_claims in claims {
+        for (index, claim) in led_claims.claims.iter().enumerate() {
+            let queued = match claim.queued {
+                0 => String::new(),
+                1 => ", with 1 change queued".to_string(),
+                queued => format!(", with {} changes queued", queued),
+            };
             println!(
-                "{}, {}, {}",
-                client.get_path(),
-                client.get_name(),
-                format_version_number(&version_number)
-            )
+                "{} led {} {} '{}' (priority {}) since {}{}",
+                led_claims.path,
+                led_claims.led,
+                if index == 0 {
+                    "is held by"
+                } else {
+                    "is wanted by"
+                },
+                claim.owner,
+                claim.priority,
+                humantime::format_rfc3339_seconds(claim.since),
+                queued
+            );
         }
-    });
+    }
+}
+
+fn print_devices(devices: &[DeviceInfo], format: Format) -> Outcome {
+    match output::render(format, devices) {
+        Some(rendered) => println!("{}", rendered),
+        None => {
+            println!("Found {} devices", devices.len());
+            for device in devices {
+                let name = device.name.as_deref().unwrap_or_default();
+                match (&device.firmware_version, &device.error) {
+                    (Some(version), _) => println!("{}, {}, {}", device.path, name, version),
+                    (None, error) => println!(
+                        "{}, {}, error: {}",
+                        device.path,
+                        name,
+                        error.as_deref().unwrap_or_default()
+                    ),
+                }
+            }
+        }
+    }
+    let failures = devices.iter().filter(|d| d.error.is_some()).count();
+    Outcome::from_failures(devices.len(), failures)
 }
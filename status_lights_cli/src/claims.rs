@@ -0,0 +1,244 @@
+//! Claims stop several writers on the same machine from stomping on each other's colors. A
+//! writer names itself as the owner of its requests (`--owner` or `$STATUS_LIGHTS_OWNER`) and
+//! claims an led with a priority. The highest priority claim controls the led, with ties going
+//! to whoever claimed it first, and only its owner can change the led. Changes from anyone else
+//! are refused, unless they have a claim of their own waiting for its turn, in which case they're
+//! queued and made once it gets control.
+//!
+//! Owners aren't authenticated, claims are there to keep well behaved scripts apart. Like
+//! everything else the daemon remembers about a device, they're forgotten if it's unplugged.
+
+use serde::{Deserialize, Serialize};
+
+use std::time::SystemTime;
+
+use crate::ipc::Action;
+
+/// A claim, as shown by the `claims` command
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ClaimState {
+    pub owner: String,
+    pub priority: i32,
+    #[serde(with = "humantime_serde")]
+    pub since: SystemTime,
+    /// How many changes are waiting for the claim to get control
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub queued: usize,
+}
+
+fn is_zero(queued: &usize) -> bool {
+    *queued == 0
+}
+
+/// The claims on one led, the first of which controls it
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LedClaims {
+    pub path: String,
+    pub led: u8,
+    pub claims: Vec<ClaimState>,
+}
+
+/// Whether an owner can change an led
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Access {
+    Allowed,
+    /// The owner's claim is waiting behind the claim of the owner given
+    Queued(String),
+    /// The owner given has the led, and there's no claim to wait with
+    Refused(String),
+}
+
+#[derive(Clone, Debug)]
+struct Claim {
+    owner: String,
+    priority: i32,
+    since: SystemTime,
+    queued: Vec<Action>,
+}
+
+/// The claims on an led, highest priority first
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Claims {
+    claims: Vec<Claim>,
+}
+
+impl Claims {
+    /// The owner in control of the led, if it's claimed
+    pub fn owner(&self) -> Option<&str> {
+        self.claims.first().map(|claim| claim.owner.as_str())
+    }
+
+    pub fn access(&self, owner: Option<&str>) -> Access {
+        match (self.owner(), owner) {
+            (None, _) => Access::Allowed,
+            (Some(controller), Some(owner)) if controller == owner => Access::Allowed,
+            (Some(controller), Some(owner)) if self.position(owner).is_some() => {
+                Access::Queued(controller.to_string())
+            }
+            (Some(controller), _) => Access::Refused(controller.to_string()),
+        }
+    }
+
+    /// Claims the led, or changes the priority of an existing claim. Returns whether the owner
+    /// now controls it, along with the changes to make if that's new.
+    pub fn claim(&mut self, owner: &str, priority: i32, now: SystemTime) -> (Access, Vec<Action>) {
+        let previous = self.owner().map(str::to_string);
+        let claim = match self.position(owner) {
+            Some(index) => Claim {
+                priority,
+                ..self.claims.remove(index)
+            },
+            None => Claim {
+                owner: owner.to_string(),
+                priority,
+                since: now,
+                queued: Vec::new(),
+            },
+        };
+        // After every claim of the same priority, so earlier claims keep their place
+        let index = self
+            .claims
+            .iter()
+            .position(|other| other.priority < priority)
+            .unwrap_or(self.claims.len());
+        self.claims.insert(index, claim);
+        let access = self.access(Some(owner));
+        let changes = match previous.as_deref() == Some(owner) {
+            true => Vec::new(),
+            false => self.take_queued(),
+        };
+        (access, changes)
+    }
+
+    /// Drops the owner's claim. Returns the next owner and the changes it queued, if it's taken
+    /// over the led.
+    pub fn release(&mut self, owner: &str) -> Option<(String, Vec<Action>)> {
+        let index = self.position(owner)?;
+        self.claims.remove(index);
+        if index != 0 {
+            return None;
+        }
+        let next = self.owner()?.to_string();
+        Some((next, self.take_queued()))
+    }
+
+    /// Keeps a change to make once the owner's claim gets control. A change replaces any earlier
+    /// one it would have overwritten.
+    pub fn queue(&mut self, owner: &str, action: Action) {
+        if let Some(index) = self.position(owner) {
+            let queued = &mut self.claims[index].queued;
+            queued.retain(|earlier| is_background(earlier) != is_background(&action));
+            queued.push(action);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.claims.is_empty()
+    }
+
+    pub fn states(&self) -> Vec<ClaimState> {
+        self.claims
+            .iter()
+            .map(|claim| ClaimState {
+                owner: claim.owner.clone(),
+                priority: claim.priority,
+                since: claim.since,
+                queued: claim.queued.len(),
+            })
+            .collect()
+    }
+
+    fn position(&self, owner: &str) -> Option<usize> {
+        self.claims.iter().position(|claim| claim.owner == owner)
+    }
+
+    /// The changes queued by the owner in control
+    fn take_queued(&mut self) -> Vec<Action> {
+        self.claims
+            .first_mut()
+            .map(|claim| std::mem::take(&mut claim.queued))
+            .unwrap_or_default()
+    }
+}
+
+/// Backgrounds are kept separately from foregrounds, effects and leases, which replace each other
+fn is_background(action: &Action) -> bool {
+    matches!(action, Action::Background(_))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use status_lights_messages::{LedColor, LedColorTimed, Rgb};
+
+    use std::time::Duration;
+
+    fn foreground(color: Rgb) -> Action {
+        Action::Foreground(LedColorTimed::new(1, color, 0))
+    }
+
+    #[test]
+    fn test_priority() {
+        let now = SystemTime::now();
+        let mut claims = Claims::default();
+        assert_eq!(claims.access(None), Access::Allowed);
+
+        assert_eq!(claims.claim("ci", 0, now), (Access::Allowed, vec![]));
+        let (access, _) = claims.claim("backup", 0, now + Duration::from_secs(1));
+        assert_eq!(access, Access::Queued("ci".to_string()));
+        assert_eq!(claims.access(None), Access::Refused("ci".to_string()));
+        assert_eq!(
+            claims.access(Some("cron")),
+            Access::Refused("ci".to_string())
+        );
+        assert_eq!(
+            claims.access(Some("backup")),
+            Access::Queued("ci".to_string())
+        );
+
+        // A higher priority takes over, the others wait in order
+        assert_eq!(claims.claim("alarm", 10, now).0, Access::Allowed);
+        let owners = |claims: &Claims| -> Vec<String> {
+            claims
+                .states()
+                .into_iter()
+                .map(|state| state.owner)
+                .collect()
+        };
+        assert_eq!(owners(&claims), ["alarm", "ci", "backup"]);
+
+        assert_eq!(claims.release("cron"), None);
+        assert_eq!(claims.release("ci"), None);
+        assert_eq!(
+            claims.release("alarm"),
+            Some(("backup".to_string(), vec![]))
+        );
+        assert_eq!(claims.release("backup"), None);
+        assert!(claims.is_empty());
+    }
+
+    #[test]
+    fn test_queue() {
+        let now = SystemTime::now();
+        let mut claims = Claims::default();
+        claims.claim("ci", 0, now);
+        claims.claim("backup", 0, now);
+        let background = Action::Background(LedColor::new(1, Rgb::new(0, 1, 0)));
+        claims.queue("backup", foreground(Rgb::new(1, 0, 0)));
+        claims.queue("backup", background.clone());
+        claims.queue("backup", foreground(Rgb::new(0, 0, 1)));
+        claims.queue("cron", foreground(Rgb::new(1, 1, 1)));
+        assert_eq!(claims.states()[1].queued, 2);
+
+        let changes = vec![background, foreground(Rgb::new(0, 0, 1))];
+        assert_eq!(claims.release("ci"), Some(("backup".to_string(), changes)));
+        assert_eq!(claims.states()[0].queued, 0);
+
+        // Raising a waiting claim above the one in control makes its changes straight away
+        claims.claim("ci", 0, now);
+        claims.queue("ci", foreground(Rgb::new(1, 0, 0)));
+        let (access, changes) = claims.claim("ci", 5, now);
+        assert_eq!(access, Access::Allowed);
+        assert_eq!(changes, vec![foreground(Rgb::new(1, 0, 0))]);
+    }
+}
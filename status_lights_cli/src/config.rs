@@ -0,0 +1,205 @@
+//! The cli's config file. Unless `--config` or `$STATUS_LIGHTS_CONFIG` says otherwise it is read
+//! from `status_lights/config.toml` in `$XDG_CONFIG_HOME` (or `~/.config`), and it's fine for it
+//! not to exist. Every section is optional.
+//!
+//! ```toml
+//! [http]
+//! listen = "0.0.0.0:8080"
+//!
+//! [http.tls]
+//! cert = "server.pem"
+//! key = "server.key"
+//! client_ca = "ca.pem"
+//!
+//! [[http.tokens]]
+//! name = "grafana"
+//! token = "a long random string"
+//! read_only = true
+//!
+//! [mqtt]
+//! host = "broker.local"
+//!
+//! [[alertmanager.rules]]
+//! labels = { severity = "critical" }
+//! led = 0
+//!
+//! [[monitor.checks]]
+//! name = "internet"
+//! type = "ping"
+//! host = "1.1.1.1"
+//! led = 1
+//!
+//! [[heartbeat.checks]]
+//! name = "backup"
+//! period = "1d"
+//! led = 2
+//! ```
+
+use serde::Deserialize;
+use thiserror::Error;
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::alertmanager::AlertmanagerConfig;
+use crate::auth::Token;
+use crate::heartbeat::HeartbeatConfig;
+use crate::monitor::MonitorConfig;
+use crate::mqtt::MqttConfig;
+use crate::sysmon::SysmonConfig;
+use crate::tls::TlsConfig;
+
+/// Overrides where the config file is read from
+pub const CONFIG_ENV: &str = "STATUS_LIGHTS_CONFIG";
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Unable to read '{path}': {source}")]
+    Read {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Invalid config in '{path}': {source}")]
+    Parse {
+        path: PathBuf,
+        #[source]
+        source: toml::de::Error,
+    },
+    #[error("Invalid config: {0}")]
+    Invalid(String),
+    #[error("Unable to set up TLS: {0}")]
+    Tls(String),
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub http: HttpConfig,
+    pub mqtt: MqttConfig,
+    pub alertmanager: AlertmanagerConfig,
+    pub monitor: MonitorConfig,
+    pub sysmon: SysmonConfig,
+    pub heartbeat: HeartbeatConfig,
+}
+
+/// Settings for the daemon's HTTP API
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    /// Where to serve the API when `--http` isn't given
+    pub listen: Option<String>,
+    pub tls: Option<TlsConfig>,
+    /// If there are no tokens, anyone who can reach the API can use it
+    pub tokens: Vec<Token>,
+}
+
+/// `$STATUS_LIGHTS_CONFIG`, or `status_lights/config.toml` in `$XDG_CONFIG_HOME` (falling back
+/// to `~/.config`)
+pub fn default_config_path() -> Option<PathBuf> {
+    if let Some(path) = env::var_os(CONFIG_ENV) {
+        return Some(PathBuf::from(path));
+    }
+    env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .map(|dir| dir.join("status_lights").join("config.toml"))
+}
+
+impl Config {
+    /// Reads the config file. A file that was asked for must exist, but the default one doesn't
+    /// have to.
+    pub fn load(path: Option<&Path>) -> Result<Config, ConfigError> {
+        let (path, required) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => match default_config_path() {
+                Some(path) => (path, false),
+                None => return Ok(Config::default()),
+            },
+        };
+        match fs::read_to_string(&path) {
+            Ok(contents) => Config::parse(&contents, &path),
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !required => Ok(Config::default()),
+            Err(source) => Err(ConfigError::Read { path, source }),
+        }
+    }
+
+    /// Parses the contents of a config file. Relative paths in it are relative to the file.
+    pub fn parse(contents: &str, path: &Path) -> Result<Config, ConfigError> {
+        let mut config: Config = toml::from_str(contents).map_err(|source| ConfigError::Parse {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        if let Some(dir) = path.parent() {
+            if let Some(tls) = &mut config.http.tls {
+                tls.resolve_paths(dir);
+            }
+            config.monitor.resolve_paths(dir);
+            config.sysmon.resolve_paths(dir);
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let config = Config::parse(
+            r#"
+            [http]
+            listen = "0.0.0.0:8080"
+
+            [http.tls]
+            cert = "server.pem"
+            key = "/etc/status_lights/server.key"
+
+            [[http.tokens]]
+            name = "grafana"
+            token = "secret"
+            read_only = true
+
+            [mqtt]
+            host = "broker.local"
+            keep_alive = "1m"
+            "#,
+            Path::new("/home/pi/.config/status_lights/config.toml"),
+        )
+        .unwrap();
+        assert_eq!(config.http.listen.as_deref(), Some("0.0.0.0:8080"));
+        let tls = config.http.tls.unwrap();
+        assert_eq!(
+            tls.cert,
+            PathBuf::from("/home/pi/.config/status_lights/server.pem")
+        );
+        assert_eq!(tls.key, PathBuf::from("/etc/status_lights/server.key"));
+        assert_eq!(tls.client_ca, None);
+        assert_eq!(config.http.tokens[0].name, "grafana");
+        assert!(config.http.tokens[0].read_only);
+        assert_eq!(config.mqtt.host, "broker.local");
+        assert_eq!(config.mqtt.port, 1883);
+        assert_eq!(config.mqtt.keep_alive, std::time::Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = Config::parse("[htp]\nlisten = 1", Path::new("config.toml")).unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("Invalid config in 'config.toml'"));
+        assert_eq!(Config::parse("", Path::new("")).unwrap(), Config::default());
+    }
+
+    #[test]
+    fn test_load_missing() {
+        let path = std::env::temp_dir().join("status_lights_missing_config.toml");
+        assert!(matches!(
+            Config::load(Some(&path)),
+            Err(ConfigError::Read { .. })
+        ));
+    }
+}
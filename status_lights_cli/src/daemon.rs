@@ -0,0 +1,1529 @@
+//! The daemon holds every device open so that cli invocations (or anything else talking to the
+//! devices) never interleave bytes on the same serial port. Requests for a device are handled one
+//! at a time, while requests for different devices can run side by side.
+//!
+//! Devices are opened as they're plugged in and closed once they're unplugged (see `watcher`).
+//! Commands arrive over a Unix domain socket using the protocol in `ipc`, or over HTTP through
+//! `api`. The daemon also remembers what each led was last set to, putting it back when a device
+//! is plugged back in or restarts, plays effects, holds foreground colors for longer than the
+//! devices can, keeps track of heartbeat checks, and arbitrates between writers that have claimed
+//! leds (see `claims`).
+//!
+//! A device that restarts quickly enough can come back under the same path without the daemon
+//! seeing it go, so every device is asked for its uptime every `BOOT_CHECK`. If it hasn't gone up
+//! by as much as it should have, the device has restarted and forgotten its leds. Firmware too
+//! old to answer isn't asked again, and is only put back when it's seen to be plugged back in.
+
+use status_lights_messages::{LedColorTimed, Rgb};
+
+use std::collections::HashMap;
+use std::io::{self, BufReader};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant, SystemTime};
+use std::{fs, thread};
+
+use crate::claims::{Access, LedClaims};
+use crate::device::{Device, DeviceSource, FIRST_UPTIME_FIRMWARE, LEDS_PER_DEVICE};
+use crate::effect::Effect;
+use crate::heartbeat::{Change, HeartbeatState, Heartbeats};
+use crate::ipc::{
+    self, Action, DaemonRequest, DaemonResponse, HeartbeatPing, LedClaim, LedLease, LedRelease,
+};
+use crate::output::DeviceInfo;
+use crate::report::{DeviceReport, Outcome, Report};
+use crate::selector::{DeviceSelector, Selectable};
+use crate::state::{DeviceLeds, DeviceSnapshot, LedState, Snapshot, EFFECT_HOLD_SECONDS};
+use crate::watcher::{self, DeviceEvent, DeviceHandle, DeviceWatcher};
+use crate::ClientError;
+
+/// How often running effects are updated
+pub const TICK: Duration = Duration::from_millis(250);
+
+/// How often devices are asked whether they've restarted
+pub const BOOT_CHECK: Duration = Duration::from_secs(5);
+
+pub struct Daemon {
+    watcher: Mutex<DeviceWatcher>,
+    /// What each led was last set to, by device path. This is never held while talking to a
+    /// device, so a device that's slow to answer can't hold up requests for the others.
+    leds: Mutex<HashMap<String, DeviceLeds>>,
+    /// The records of devices that have gone away, by unique id, to put back if they return.
+    /// Locked after `leds`.
+    unplugged: Mutex<HashMap<String, (DeviceInfo, DeviceLeds)>>,
+    subscribers: Mutex<Vec<Sender<DeviceEvent>>>,
+    heartbeats: Option<Heartbeats>,
+    /// Where the records are written whenever they change, so they outlive the daemon. Locked
+    /// while writing, before `leds`.
+    state_file: Option<Mutex<PathBuf>>,
+    /// Whether the records have changed since the state file was last written
+    changed: AtomicBool,
+    /// When each device was last asked for its uptime and what it said, by device path. Devices
+    /// that can't say are kept as `None`, and aren't asked again until they're plugged back in.
+    uptimes: Mutex<HashMap<String, Option<(Instant, Duration)>>>,
+    next_boot_check: Mutex<Instant>,
+}
+
+impl Daemon {
+    pub fn new(source: Box<dyn DeviceSource>) -> Daemon {
+        let daemon = Daemon {
+            watcher: Mutex::new(DeviceWatcher::new(source)),
+            leds: Mutex::default(),
+            unplugged: Mutex::default(),
+            subscribers: Mutex::default(),
+            heartbeats: None,
+            state_file: None,
+            changed: AtomicBool::new(false),
+            uptimes: Mutex::default(),
+            next_boot_check: Mutex::new(Instant::now()),
+        };
+        daemon.rescan();
+        daemon
+    }
+
+    /// Also keeps track of heartbeat checks
+    pub fn with_heartbeats(mut self, heartbeats: Heartbeats) -> Daemon {
+        self.heartbeats = Some(heartbeats);
+        self
+    }
+
+    /// Also writes the records to a state file whenever they change, starting from the records
+    /// the last daemon left there. Devices that are already plugged in are put back straight
+    /// away, and the rest when they're plugged in.
+    pub fn with_state_file(mut self, path: PathBuf) -> io::Result<Daemon> {
+        let saved = match Snapshot::read(&path) {
+            Ok(saved) => saved,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Snapshot::default(),
+            Err(e) => return Err(e),
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let now = Instant::now();
+        let wall_now = SystemTime::now();
+        self.unplugged().extend(saved.devices.iter().map(|device| {
+            let info = device.info();
+            (info.unique_id(), (info, device.records(now, wall_now)))
+        }));
+        for handle in self.devices() {
+            self.replay(&handle.info);
+        }
+        self.state_file = Some(Mutex::new(path));
+        Ok(self)
+    }
+
+    fn watcher(&self) -> MutexGuard<'_, DeviceWatcher> {
+        self.watcher.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn devices(&self) -> Vec<DeviceHandle> {
+        self.watcher().devices().to_vec()
+    }
+
+    fn leds(&self) -> MutexGuard<'_, HashMap<String, DeviceLeds>> {
+        self.leds.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn unplugged(&self) -> MutexGuard<'_, HashMap<String, (DeviceInfo, DeviceLeds)>> {
+        self.unplugged
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn uptimes(&self) -> MutexGuard<'_, HashMap<String, Option<(Instant, Duration)>>> {
+        self.uptimes.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Devices as they're plugged in and unplugged, from now until the daemon is dropped
+    pub fn subscribe(&self) -> Receiver<DeviceEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(sender);
+        receiver
+    }
+
+    /// Opens any newly attached devices and closes any that have gone away
+    pub fn rescan(&self) {
+        let events = self.watcher().scan();
+        self.attach_and_detach(events);
+    }
+
+    /// Closes a device, which is opened again on the next rescan if it's still attached
+    fn close(&self, path: &str) {
+        let event = self.watcher().close(path);
+        self.attach_and_detach(event.into_iter().collect());
+    }
+
+    /// Puts back the leds of devices that have been seen before, keeps the records of devices
+    /// that have gone away in case they come back, and tells any subscribers
+    fn attach_and_detach(&self, events: Vec<DeviceEvent>) {
+        for event in events {
+            match &event {
+                DeviceEvent::Attached(info) => self.replay(info),
+                DeviceEvent::Detached(info) => {
+                    self.uptimes().remove(&info.path);
+                    let mut leds = self.leds();
+                    if let Some(records) = leds.remove(&info.path) {
+                        self.unplugged()
+                            .insert(info.unique_id(), (info.clone(), records));
+                    }
+                }
+            }
+            self.subscribers
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+        }
+    }
+
+    /// Puts a newly opened device's leds back the way they were, if it's been seen before
+    fn replay(&self, info: &DeviceInfo) {
+        let handle = match self
+            .devices()
+            .into_iter()
+            .find(|handle| handle.info.path == info.path)
+        {
+            Some(handle) => handle,
+            None => return,
+        };
+        {
+            let mut leds = self.leds();
+            let saved = match self.unplugged().remove(&info.unique_id()) {
+                Some((_, saved)) => saved,
+                None => return,
+            };
+            leds.insert(info.path.clone(), saved);
+        }
+        self.resend(&handle);
+    }
+
+    /// Sends a device everything its leds were set to, for when it's forgotten
+    fn resend(&self, handle: &DeviceHandle) {
+        let now = Instant::now();
+        let requests: Vec<_> = match self.leds().get_mut(&handle.info.path) {
+            Some(records) => (0..LEDS_PER_DEVICE)
+                .zip(records.iter_mut())
+                .map(|(led, record)| record.replay(led, now))
+                .collect(),
+            None => return,
+        };
+        let mut device = handle.lock();
+        for (background, foreground) in requests {
+            // A device that fails has most likely gone away again, and is closed on the next
+            // rescan
+            if let Some(background) = background {
+                device.request_background(background).ok();
+            }
+            if let Some(foreground) = foreground {
+                device.request_foreground(foreground).ok();
+            }
+        }
+    }
+
+    /// Rescans on a background thread whenever a device might have been plugged in or unplugged,
+    /// until the daemon is dropped. Devices are polled for if the source can't say when they
+    /// change. Returns whether the source can.
+    pub fn start_watching(self: &Arc<Self>) -> bool {
+        let mut changes = self.watcher().changes();
+        let watching = changes.is_watching();
+        let daemon = Arc::downgrade(self);
+        thread::spawn(move || loop {
+            let removed = changes.wait(watcher::POLL);
+            let daemon = match daemon.upgrade() {
+                Some(daemon) => daemon,
+                None => return,
+            };
+            for path in removed {
+                daemon.close(&path);
+            }
+            daemon.rescan();
+        });
+        watching
+    }
+
+    /// Finds the devices the selector matches, after checking for newly attached devices
+    fn select(&self, selector: &DeviceSelector) -> Result<Vec<DeviceHandle>, Report> {
+        self.rescan();
+        let handles = self.devices();
+        selector
+            .select(handles)
+            .map_err(|e| Report::failed(Outcome::NoDevices, e.to_string()))
+    }
+
+    /// Describes every device the selector matches, without sending them any requests
+    pub fn describe(&self, selector: &DeviceSelector) -> Result<Vec<DeviceInfo>, Report> {
+        Ok(self
+            .select(selector)?
+            .into_iter()
+            .map(|handle| handle.info)
+            .collect())
+    }
+
+    pub fn handle(&self, request: &DaemonRequest) -> DaemonResponse {
+        let response = self.dispatch(request);
+        self.save();
+        response
+    }
+
+    fn dispatch(&self, request: &DaemonRequest) -> DaemonResponse {
+        match &request.action {
+            Action::Heartbeat(ping) => DaemonResponse::Report(self.heartbeat(ping)),
+            Action::Claim(claim) => DaemonResponse::Report(self.claim(request, claim)),
+            Action::Release(release) => DaemonResponse::Report(self.release(request, release)),
+            Action::Claims => DaemonResponse::Claims(self.claims()),
+            Action::Snapshot => match self.snapshot(&request.selector) {
+                Ok(snapshot) => DaemonResponse::Snapshot(snapshot),
+                Err(report) => DaemonResponse::Report(report),
+            },
+            Action::Restore(snapshot) => DaemonResponse::Report(self.restore(request, snapshot)),
+            action => match action.led() {
+                Some(led) => self.change(request, led),
+                None => self.apply(request),
+            },
+        }
+    }
+
+    /// Makes a change to an led on the selected devices where the request's owner is allowed to,
+    /// queueing or refusing it on the others
+    fn change(&self, request: &DaemonRequest, led: u8) -> DaemonResponse {
+        let selected = match self.select(&request.selector) {
+            Ok(selected) => selected,
+            Err(report) => return DaemonResponse::Report(report),
+        };
+        let owner = request.owner.as_deref();
+        let mut allowed = request.selector.clone();
+        let mut held = Vec::new();
+        let mut leds = self.leds();
+        for handle in &selected {
+            let claims = match leds
+                .get_mut(&handle.info.path)
+                .and_then(|records| records.get_mut(led as usize))
+            {
+                Some(record) => record.claims_mut(),
+                None => continue,
+            };
+            let claimed = |owner| format!("led {} is claimed by '{}'", led, owner);
+            held.push(match claims.access(owner) {
+                Access::Allowed => continue,
+                Access::Queued(controller) => {
+                    claims.queue(owner.unwrap_or_default(), request.action.clone());
+                    DeviceReport::queued(handle.path(), handle.name(), claimed(controller))
+                }
+                Access::Refused(controller) => {
+                    DeviceReport::new(handle.path(), handle.name(), &Err(claimed(controller)))
+                }
+            });
+            allowed = allowed.except(&handle.info.path);
+        }
+        drop(leds);
+        if held.is_empty() {
+            return self.apply(request);
+        }
+        if held.len() < selected.len() {
+            match self.apply(&DaemonRequest {
+                selector: allowed,
+                ..request.clone()
+            }) {
+                DaemonResponse::Report(report) => held.extend(report.devices),
+                response => return response,
+            }
+        }
+        DaemonResponse::Report(Report::from_devices(held))
+    }
+
+    /// Runs the request on the selected devices, whatever claims there are
+    fn apply(&self, request: &DaemonRequest) -> DaemonResponse {
+        if let Action::Effect(led_effect) = &request.action {
+            let effect = led_effect.effect.clone();
+            return DaemonResponse::Report(self.set_effect(
+                &request.selector,
+                led_effect.led,
+                effect,
+            ));
+        }
+        if let Action::Lease(lease) = &request.action {
+            return DaemonResponse::Report(self.set_lease(&request.selector, lease));
+        }
+        let selected = match self.select(&request.selector) {
+            Ok(selected) => selected,
+            Err(report) => return DaemonResponse::Report(report),
+        };
+        if let Action::Foreground(led_color_timed) = &request.action {
+            // Stop any effect or lease first, so it can't overwrite the new color
+            let mut leds = self.leds();
+            for handle in &selected {
+                if let Some(record) = leds
+                    .entry(handle.info.path.clone())
+                    .or_default()
+                    .get_mut(led_color_timed.led as usize)
+                {
+                    record.stop_effect();
+                    record.stop_lease();
+                }
+            }
+        }
+        // Locks are always taken in the same order, so connections can't deadlock each other
+        let mut guards: Vec<_> = selected.iter().map(DeviceHandle::lock).collect();
+        let response = request.action.run(
+            guards
+                .iter_mut()
+                .map(|guard| &mut ***guard as &mut dyn Device),
+        );
+        drop(guards);
+        if let DaemonResponse::Report(report) = &response {
+            self.record(&request.action, report);
+        }
+        response
+    }
+
+    /// Remembers the colors that each device accepted
+    fn record(&self, action: &Action, report: &Report) {
+        let now = Instant::now();
+        let mut leds = self.leds();
+        for device in report.devices.iter().filter(|device| device.ok) {
+            let records = leds.entry(device.path.clone()).or_default();
+            match action {
+                Action::Background(led_color) => {
+                    if let Some(record) = records.get_mut(led_color.led as usize) {
+                        record.set_background(led_color.color);
+                        self.changed.store(true, Ordering::SeqCst);
+                    }
+                }
+                Action::Foreground(led_color_timed) => {
+                    if let Some(record) = records.get_mut(led_color_timed.led as usize) {
+                        record.set_foreground(led_color_timed.color, led_color_timed.seconds, now);
+                        self.changed.store(true, Ordering::SeqCst);
+                    }
+                }
+                Action::List
+                | Action::Effect(_)
+                | Action::Heartbeat(_)
+                | Action::Lease(_)
+                | Action::Claim(_)
+                | Action::Release(_)
+                | Action::Claims
+                | Action::Snapshot
+                | Action::Restore(_) => {}
+            }
+        }
+    }
+
+    /// What each led on the device was last set to, or `None` if there's no such device
+    pub fn led_states(&self, path: &str) -> Option<Vec<LedState>> {
+        if !self.devices().iter().any(|handle| handle.info.path == path) {
+            return None;
+        }
+        let now = Instant::now();
+        let mut leds = self.leds();
+        let records = leds.entry(path.to_string()).or_default();
+        Some(
+            (0..LEDS_PER_DEVICE)
+                .zip(records.iter())
+                .map(|(led, record)| record.to_state(led, now))
+                .collect(),
+        )
+    }
+
+    /// Starts playing an effect on an led of each selected device, or stops it when `effect` is
+    /// `None`. Starting an effect replaces the foreground, stopping one clears it.
+    fn set_effect(&self, selector: &DeviceSelector, led: u8, effect: Option<Effect>) -> Report {
+        if let Err(report) = check_led(led) {
+            return report;
+        }
+        let selected = match self.select(selector) {
+            Ok(selected) => selected,
+            Err(report) => return report,
+        };
+        if selected.is_empty() {
+            return Report::failed(Outcome::NoDevices, "No devices found".to_string());
+        }
+        let now = Instant::now();
+        let requests: Vec<LedColorTimed> = {
+            let mut leds = self.leds();
+            selected
+                .iter()
+                .map(|handle| {
+                    let record =
+                        &mut leds.entry(handle.info.path.clone()).or_default()[led as usize];
+                    match &effect {
+                        Some(effect) => {
+                            record.start_effect(effect.clone(), now);
+                            let color = record.effect_frame(now).unwrap_or_default();
+                            LedColorTimed::new(led, color, EFFECT_HOLD_SECONDS)
+                        }
+                        None => {
+                            record.set_foreground(Rgb::OFF, 0, now);
+                            LedColorTimed::new(led, Rgb::OFF, 0)
+                        }
+                    }
+                })
+                .collect()
+        };
+        let devices = selected
+            .iter()
+            .zip(requests)
+            .map(|(handle, request)| {
+                let result = handle.lock().request_foreground(request);
+                match result {
+                    Ok(()) => self.changed.store(true, Ordering::SeqCst),
+                    Err(_) => {
+                        if let Some(records) = self.leds().get_mut(&handle.info.path) {
+                            records[led as usize].stop_effect();
+                        }
+                    }
+                }
+                DeviceReport::new(handle.path(), handle.name(), &result)
+            })
+            .collect();
+        Report::from_devices(devices)
+    }
+
+    /// Shows a foreground color on an led of each selected device until the lease runs out,
+    /// sending it again whenever the device would drop it
+    fn set_lease(&self, selector: &DeviceSelector, lease: &LedLease) -> Report {
+        if let Err(report) = check_led(lease.led) {
+            return report;
+        }
+        let remaining = match lease.until.duration_since(SystemTime::now()) {
+            Ok(remaining) => remaining,
+            Err(_) => {
+                return Report::failed(
+                    Outcome::UsageError,
+                    format!(
+                        "Can't show a color until {}, which has already passed",
+                        humantime::format_rfc3339_seconds(lease.until)
+                    ),
+                )
+            }
+        };
+        let selected = match self.select(selector) {
+            Ok(selected) => selected,
+            Err(report) => return report,
+        };
+        if selected.is_empty() {
+            return Report::failed(Outcome::NoDevices, "No devices found".to_string());
+        }
+        let now = Instant::now();
+        let requests: Vec<_> = {
+            let mut leds = self.leds();
+            selected
+                .iter()
+                .map(|handle| {
+                    let record =
+                        &mut leds.entry(handle.info.path.clone()).or_default()[lease.led as usize];
+                    let previous = record.clone();
+                    record.start_lease(lease.color, now + remaining, now);
+                    (previous, record.lease_frame(lease.led, now))
+                })
+                .collect()
+        };
+        let devices = selected
+            .iter()
+            .zip(requests)
+            .map(|(handle, (previous, request))| {
+                let result = match request {
+                    Some(request) => handle.lock().request_foreground(request),
+                    None => Ok(()),
+                };
+                match result {
+                    Ok(()) => self.changed.store(true, Ordering::SeqCst),
+                    Err(_) => {
+                        if let Some(records) = self.leds().get_mut(&handle.info.path) {
+                            records[lease.led as usize] = previous;
+                        }
+                    }
+                }
+                DeviceReport::new(handle.path(), handle.name(), &result)
+            })
+            .collect();
+        Report::from_devices(devices)
+    }
+
+    /// Claims an led on each selected device for the request's owner, making any changes it
+    /// queued if it now has control
+    fn claim(&self, request: &DaemonRequest, claim: &LedClaim) -> Report {
+        let (owner, selected) = match self.select_claimed(request, claim.led) {
+            Ok(selected) => selected,
+            Err(report) => return report,
+        };
+        let now = SystemTime::now();
+        let mut reports = Vec::new();
+        let mut changes = Vec::new();
+        let mut leds = self.leds();
+        for handle in &selected {
+            let records = leds.entry(handle.info.path.clone()).or_default();
+            let claims = records[claim.led as usize].claims_mut();
+            let (access, queued) = claims.claim(owner, claim.priority, now);
+            reports.push(match access {
+                Access::Allowed => {
+                    DeviceReport::new::<String>(handle.path(), handle.name(), &Ok(()))
+                }
+                Access::Queued(controller) | Access::Refused(controller) => DeviceReport::queued(
+                    handle.path(),
+                    handle.name(),
+                    format!("led {} is claimed by '{}'", claim.led, controller),
+                ),
+            });
+            changes.push((handle.info.path.clone(), owner.to_string(), queued));
+        }
+        drop(leds);
+        self.make_queued(changes);
+        Report::from_devices(reports)
+    }
+
+    /// Drops the request owner's claim on an led of each selected device, handing it over to the
+    /// next claim
+    fn release(&self, request: &DaemonRequest, release: &LedRelease) -> Report {
+        let (owner, selected) = match self.select_claimed(request, release.led) {
+            Ok(selected) => selected,
+            Err(report) => return report,
+        };
+        let mut changes = Vec::new();
+        let mut leds = self.leds();
+        for handle in &selected {
+            let released = leds
+                .get_mut(&handle.info.path)
+                .and_then(|records| records[release.led as usize].claims_mut().release(owner));
+            if let Some((next, queued)) = released {
+                changes.push((handle.info.path.clone(), next, queued));
+            }
+        }
+        drop(leds);
+        self.make_queued(changes);
+        let reports = selected
+            .iter()
+            .map(|handle| DeviceReport::new::<String>(handle.path(), handle.name(), &Ok(())))
+            .collect();
+        Report::from_devices(reports)
+    }
+
+    /// The owner of a claim or release, and the devices it's for
+    fn select_claimed<'a>(
+        &self,
+        request: &'a DaemonRequest,
+        led: u8,
+    ) -> Result<(&'a str, Vec<DeviceHandle>), Report> {
+        let owner = request.owner.as_deref().ok_or_else(|| {
+            Report::failed(
+                Outcome::UsageError,
+                format!(
+                    "Claims need an owner, give one with --owner or ${}",
+                    ipc::OWNER_ENV
+                ),
+            )
+        })?;
+        check_led(led)?;
+        let selected = self.select(&request.selector)?;
+        if selected.is_empty() {
+            return Err(Report::failed(
+                Outcome::NoDevices,
+                "No devices found".to_string(),
+            ));
+        }
+        Ok((owner, selected))
+    }
+
+    /// Makes the changes that were queued for each owner that now controls an led
+    fn make_queued(&self, changes: Vec<(String, String, Vec<Action>)>) {
+        for (path, owner, actions) in changes {
+            for action in actions {
+                // Failures are the same as if the change had been made while the device was
+                // unplugged
+                self.dispatch(&DaemonRequest {
+                    selector: DeviceSelector::only(&path),
+                    action,
+                    owner: Some(owner.clone()),
+                });
+            }
+        }
+    }
+
+    /// The claims on every led that has any, to show who controls what
+    pub fn claims(&self) -> Vec<LedClaims> {
+        self.rescan();
+        let leds = self.leds();
+        let mut paths: Vec<&String> = leds.keys().collect();
+        paths.sort();
+        paths
+            .into_iter()
+            .flat_map(|path| {
+                (0..LEDS_PER_DEVICE)
+                    .zip(leds[path].iter())
+                    .filter(|(_, record)| !record.claims().is_empty())
+                    .map(move |(led, record)| LedClaims {
+                        path: path.clone(),
+                        led,
+                        claims: record.claims().states(),
+                    })
+            })
+            .collect()
+    }
+
+    /// What every led on the selected devices was set to
+    fn snapshot(&self, selector: &DeviceSelector) -> Result<Snapshot, Report> {
+        let selected = self.select(selector)?;
+        let now = Instant::now();
+        let wall_now = SystemTime::now();
+        let mut leds = self.leds();
+        let devices = selected
+            .iter()
+            .map(|handle| {
+                let records = leds.entry(handle.info.path.clone()).or_default();
+                DeviceSnapshot::new(&handle.info, records, now, wall_now)
+            })
+            .collect();
+        Ok(Snapshot { devices })
+    }
+
+    /// Writes the records of every device, plugged in or not, to the state file if there is one
+    /// and they've changed since it was last written
+    fn save(&self) {
+        let path = match &self.state_file {
+            Some(path) => path.lock().unwrap_or_else(PoisonError::into_inner),
+            None => return,
+        };
+        if !self.changed.swap(false, Ordering::SeqCst) {
+            return;
+        }
+        let now = Instant::now();
+        let wall_now = SystemTime::now();
+        let handles = self.devices();
+        let snapshot = {
+            let leds = self.leds();
+            let unplugged = self.unplugged();
+            let attached = handles.iter().filter_map(|handle| {
+                let records = leds.get(&handle.info.path)?;
+                Some(DeviceSnapshot::new(&handle.info, records, now, wall_now))
+            });
+            let unplugged = unplugged
+                .values()
+                .map(|(info, records)| DeviceSnapshot::new(info, records, now, wall_now));
+            Snapshot {
+                devices: attached.chain(unplugged).collect(),
+            }
+        };
+        // The records are still good in memory, so this isn't worth failing the request over
+        if let Err(e) = snapshot.write(&path) {
+            eprintln!("Unable to save state to '{}': {}", path.display(), e);
+        }
+    }
+
+    /// Puts the leds of each selected device back the way they were in the snapshot. Devices
+    /// that aren't in the snapshot are left alone.
+    fn restore(&self, request: &DaemonRequest, snapshot: &Snapshot) -> Report {
+        let selected = match self.select(&request.selector) {
+            Ok(selected) => selected,
+            Err(report) => return report,
+        };
+        let now = SystemTime::now();
+        let mut reports = Vec::new();
+        for handle in &selected {
+            let saved = match snapshot
+                .devices
+                .iter()
+                .find(|saved| saved.is_of(&handle.info))
+            {
+                Some(saved) => saved,
+                None => continue,
+            };
+            let mut device = DeviceReport::new::<String>(handle.path(), handle.name(), &Ok(()));
+            for action in saved.leds.iter().flat_map(|led| led.actions(now)) {
+                let response = self.dispatch(&DaemonRequest {
+                    selector: DeviceSelector::only(&handle.info.path),
+                    action,
+                    owner: request.owner.clone(),
+                });
+                let report = match response {
+                    DaemonResponse::Report(report) => report,
+                    _ => continue,
+                };
+                match report.devices.into_iter().next() {
+                    Some(changed) if !changed.ok => {
+                        device = changed;
+                        break;
+                    }
+                    Some(changed) if changed.queued.is_some() => device = changed,
+                    Some(_) => {}
+                    None => {
+                        let error = report.error.unwrap_or_default();
+                        device = DeviceReport::new(handle.path(), handle.name(), &Err(error));
+                        break;
+                    }
+                }
+            }
+            reports.push(device);
+        }
+        match reports.is_empty() {
+            true => Report::failed(
+                Outcome::NoDevices,
+                "None of the devices in the snapshot are plugged in".to_string(),
+            ),
+            false => Report::from_devices(reports),
+        }
+    }
+
+    /// Records a ping for a heartbeat check, showing its new status if it changed
+    pub fn heartbeat(&self, ping: &HeartbeatPing) -> Report {
+        let heartbeats = match &self.heartbeats {
+            Some(heartbeats) => heartbeats,
+            None => {
+                return Report::failed(
+                    Outcome::UsageError,
+                    "There are no heartbeat checks in the daemon's config file".to_string(),
+                )
+            }
+        };
+        match heartbeats.ping(&ping.check, ping.failed, Instant::now()) {
+            Ok(Some(change)) => self.show_heartbeat(heartbeats, &change),
+            Ok(None) => Report::unchanged(),
+            Err(e) => Report::failed(Outcome::UsageError, e),
+        }
+    }
+
+    /// The status of every heartbeat check, or `None` if there aren't any
+    pub fn heartbeat_states(&self) -> Option<Vec<HeartbeatState>> {
+        let heartbeats = self.heartbeats.as_ref()?;
+        Some(heartbeats.states_at(Instant::now()))
+    }
+
+    /// Shows a heartbeat check's status on every device it applies to
+    fn show_heartbeat(&self, heartbeats: &Heartbeats, change: &Change) -> Report {
+        let devices = self.describe(&DeviceSelector::all()).unwrap_or_default();
+        let reports: Vec<DeviceReport> = devices
+            .iter()
+            .filter(|info| change.applies_to(info))
+            .flat_map(|info| {
+                let request = DaemonRequest {
+                    selector: DeviceSelector::only(&info.path),
+                    action: change.indicator.action(change.led),
+                    owner: None,
+                };
+                match self.dispatch(&request) {
+                    DaemonResponse::Report(report) => report.devices,
+                    DaemonResponse::Devices(_)
+                    | DaemonResponse::Claims(_)
+                    | DaemonResponse::Snapshot(_) => Vec::new(),
+                }
+            })
+            .collect();
+        let report = match reports.is_empty() {
+            true => Report::failed(Outcome::NoDevices, "No devices found".to_string()),
+            false => Report::from_devices(reports),
+        };
+        heartbeats.shown(change, report.outcome == Outcome::Success, Instant::now());
+        report
+    }
+
+    /// Asks every device for its uptime, and puts back the leds of any that have restarted since
+    /// they were last asked
+    fn check_boots(&self) {
+        for handle in self.devices() {
+            let previous = match self.uptimes().get(&handle.info.path) {
+                Some(None) => continue,
+                Some(Some(previous)) => Some(*previous),
+                None => None,
+            };
+            let uptime = {
+                let mut device = handle.lock();
+                match previous {
+                    // The first time a device is checked, make sure its firmware can say
+                    None => device.request_version().and_then(|version| {
+                        match (version.major, version.minor, version.patch) >= FIRST_UPTIME_FIRMWARE
+                        {
+                            true => device.request_uptime().map(Some),
+                            false => Ok(None),
+                        }
+                    }),
+                    Some(_) => device.request_uptime().map(Some),
+                }
+            };
+            let now = Instant::now();
+            let uptime = match uptime {
+                Ok(Some(uptime)) => uptime,
+                Ok(None)
+                | Err(ClientError::ErrorReceivedFromDevice { .. })
+                | Err(ClientError::UnexpectedResponse { .. }) => {
+                    self.uptimes().insert(handle.info.path.clone(), None);
+                    continue;
+                }
+                // A device that doesn't answer is dealt with by `tick`, and asked again next time
+                Err(_) => continue,
+            };
+            self.uptimes()
+                .insert(handle.info.path.clone(), Some((now, uptime)));
+            // Uptimes are only counted in whole seconds, and the device's clock isn't exact, so
+            // it only counts as restarted if it's fallen well behind
+            let restarted = previous
+                .is_some_and(|(asked, previous)| uptime < previous + now.duration_since(asked) / 2);
+            if restarted {
+                self.resend(&handle);
+            }
+        }
+    }
+
+    /// Sends the next color of every running effect and lease that needs it, shows heartbeat
+    /// checks that have stopped getting pings, and puts back the leds of devices that have
+    /// restarted
+    pub fn tick(&self) {
+        let boot_check_due = {
+            let mut next_boot_check = self
+                .next_boot_check
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            let due = *next_boot_check <= Instant::now();
+            if due {
+                *next_boot_check = Instant::now() + BOOT_CHECK;
+            }
+            due
+        };
+        if boot_check_due {
+            self.check_boots();
+        }
+        if let Some(heartbeats) = &self.heartbeats {
+            let due = heartbeats.due(Instant::now());
+            for change in &due {
+                self.show_heartbeat(heartbeats, change);
+            }
+            self.save();
+        }
+        let now = Instant::now();
+        let frames: Vec<(DeviceHandle, Vec<LedColorTimed>)> = {
+            let mut leds = self.leds();
+            self.devices()
+                .into_iter()
+                .filter_map(|handle| {
+                    let records = leds.get_mut(&handle.info.path)?;
+                    let requests = (0..LEDS_PER_DEVICE)
+                        .zip(records.iter_mut())
+                        .filter_map(|(led, record)| {
+                            record
+                                .effect_frame(now)
+                                .map(|color| LedColorTimed::new(led, color, EFFECT_HOLD_SECONDS))
+                                .or_else(|| record.lease_frame(led, now))
+                        })
+                        .collect();
+                    Some((handle, requests))
+                })
+                .collect()
+        };
+        let mut broken = Vec::new();
+        for (handle, requests) in frames {
+            let mut device = handle.lock();
+            for request in requests {
+                // Other failures are left for the next tick. A device that can't be written to
+                // has most likely been unplugged, and is opened again on the next rescan if it's
+                // come back under the same path.
+                if let Err(ClientError::Io { .. }) = device.request_foreground(request) {
+                    broken.push(handle.info.path.clone());
+                    break;
+                }
+            }
+        }
+        for path in broken {
+            self.close(&path);
+        }
+    }
+
+    /// Ticks on a background thread until the daemon is dropped
+    pub fn start_ticking(self: &Arc<Self>) {
+        let daemon = Arc::downgrade(self);
+        thread::spawn(move || {
+            while let Some(daemon) = daemon.upgrade() {
+                daemon.tick();
+                drop(daemon);
+                thread::sleep(TICK);
+            }
+        });
+    }
+
+    /// Accepts connections until the listener fails, handling each on its own thread
+    pub fn serve(self: &Arc<Self>, listener: UnixListener) -> io::Result<()> {
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let daemon = Arc::clone(self);
+            thread::spawn(move || {
+                if let Err(e) = daemon.handle_connection(stream) {
+                    eprintln!("Connection error: {}", e);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    fn handle_connection(&self, stream: UnixStream) -> io::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = stream;
+        loop {
+            let response = match ipc::read_message::<DaemonRequest>(&mut reader) {
+                Ok(Some(request)) => self.handle(&request),
+                Ok(None) => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => DaemonResponse::Report(
+                    Report::failed(Outcome::UsageError, format!("Invalid request: {}", e)),
+                ),
+                Err(e) => return Err(e),
+            };
+            ipc::write_message(&mut writer, &response)?;
+        }
+    }
+}
+
+/// Fails with a usage error if the led doesn't exist
+fn check_led(led: u8) -> Result<(), Report> {
+    match led < LEDS_PER_DEVICE {
+        true => Ok(()),
+        false => Err(Report::failed(
+            Outcome::UsageError,
+            format!(
+                "There is no led {}, devices have leds 0 to {}",
+                led,
+                LEDS_PER_DEVICE - 1
+            ),
+        )),
+    }
+}
+
+/// Binds the daemon socket, replacing the socket file left behind by a daemon that has stopped.
+/// Fails if another daemon is still listening.
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("A daemon is already listening on '{}'", path.display()),
+            ));
+        }
+        fs::remove_file(path)?;
+    }
+    UnixListener::bind(path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::Config;
+    use crate::device::SHARED_SERIAL_NUMBER;
+    use crate::ipc::{Action, DaemonClient, LedEffect};
+    use crate::mock::{MockDevice, MockSource};
+    use crate::selector::{DeviceSelector, Selector};
+    use status_lights_messages::{LedColor, Rgb};
+
+    use std::path::PathBuf;
+
+    fn socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "status_lights_{}_{}.sock",
+            name,
+            std::process::id()
+        ))
+    }
+
+    fn background(selector: DeviceSelector) -> DaemonRequest {
+        DaemonRequest {
+            selector,
+            action: Action::Background(LedColor::new(0, Rgb::new(0, 0, 1))),
+            owner: None,
+        }
+    }
+
+    #[test]
+    fn test_handle_rescans() {
+        let source = MockSource::new(vec![MockDevice::new("/dev/ttyACM0")]);
+        let daemon = Daemon::new(Box::new(source.clone()));
+
+        let late = MockDevice::new("/dev/ttyACM1");
+        source.plug(late.clone());
+        source.unplug("/dev/ttyACM0");
+
+        match daemon.handle(&background(DeviceSelector::all())) {
+            DaemonResponse::Report(report) => {
+                assert_eq!(report.outcome, Outcome::Success);
+                assert_eq!(report.devices.len(), 1);
+                assert_eq!(report.devices[0].path, "/dev/ttyACM1");
+            }
+            response => panic!("Unexpected response {:?}", response),
+        }
+        assert_eq!(late.state().background[0], Rgb::new(0, 0, 1));
+    }
+
+    #[test]
+    fn test_handle_unmatched_selector() {
+        let daemon = Daemon::new(Box::new(MockSource::new(vec![MockDevice::new(
+            "/dev/ttyACM0",
+        )])));
+        let selector = DeviceSelector::all().include(Selector::serial("missing").unwrap());
+        assert_eq!(
+            daemon.handle(&background(selector)),
+            DaemonResponse::Report(Report::failed(
+                Outcome::NoDevices,
+                "No device matched --serial 'missing'".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_lease() {
+        let device = MockDevice::new("/dev/ttyACM0");
+        let daemon = Daemon::new(Box::new(MockSource::new(vec![device.clone()])));
+        let red = Rgb::new(1, 0, 0);
+        let lease = |until| DaemonRequest {
+            selector: DeviceSelector::all(),
+            action: Action::Lease(LedLease {
+                led: 2,
+                color: red,
+                until,
+            }),
+            owner: None,
+        };
+        let report = |response| match response {
+            DaemonResponse::Report(report) => report,
+            response => panic!("Unexpected response {:?}", response),
+        };
+
+        let hour = Duration::from_secs(3600);
+        let response = daemon.handle(&lease(SystemTime::now() + hour));
+        assert_eq!(report(response).outcome, Outcome::Success);
+        assert_eq!(device.state().foreground[2], red);
+        assert_eq!(device.state().foreground_seconds[2], 30);
+        let state = &daemon.led_states("/dev/ttyACM0").unwrap()[2];
+        assert_eq!(state.foreground, red);
+        assert!(state.foreground_remaining.unwrap() > hour - Duration::from_secs(5));
+
+        // It isn't sent again until the device would be about to drop it
+        daemon.tick();
+        assert_eq!(device.state().requests, 1);
+
+        // Setting the foreground releases it
+        let release = DaemonRequest {
+            selector: DeviceSelector::all(),
+            action: Action::Foreground(LedColorTimed::new(2, Rgb::OFF, 0)),
+            owner: None,
+        };
+        assert_eq!(report(daemon.handle(&release)).outcome, Outcome::Success);
+        let state = &daemon.led_states("/dev/ttyACM0").unwrap()[2];
+        assert_eq!(
+            (state.foreground, state.foreground_remaining),
+            (Rgb::OFF, None)
+        );
+
+        let response = daemon.handle(&lease(SystemTime::now() - hour));
+        assert_eq!(report(response).outcome, Outcome::UsageError);
+
+        device.set_unresponsive(true);
+        let response = daemon.handle(&lease(SystemTime::now() + hour));
+        assert_eq!(report(response).outcome, Outcome::DeviceError);
+        let state = &daemon.led_states("/dev/ttyACM0").unwrap()[2];
+        assert_eq!(state.foreground, Rgb::OFF);
+    }
+
+    #[test]
+    fn test_replugged() {
+        let source = MockSource::new(vec![MockDevice::new("/dev/ttyACM0").with_serial("abc")]);
+        let daemon = Daemon::new(Box::new(source.clone()));
+        let red = Rgb::new(1, 0, 0);
+        daemon.handle(&background(DeviceSelector::all()));
+        daemon.handle(&DaemonRequest {
+            selector: DeviceSelector::all(),
+            action: Action::Foreground(LedColorTimed::new(2, red, 0)),
+            owner: None,
+        });
+
+        // Plugged back in under a different path, with everything forgotten
+        source.unplug("/dev/ttyACM0");
+        daemon.rescan();
+        assert_eq!(daemon.led_states("/dev/ttyACM0"), None);
+        let replugged = MockDevice::new("/dev/ttyACM1").with_serial("abc");
+        source.plug(replugged.clone());
+        daemon.rescan();
+        assert_eq!(replugged.state().background[0], Rgb::new(0, 0, 1));
+        assert_eq!(replugged.state().foreground[2], red);
+        assert_eq!(replugged.state().requests, 2);
+        let states = daemon.led_states("/dev/ttyACM1").unwrap();
+        assert_eq!(states[2].foreground, red);
+
+        // A device that hasn't been seen before is left alone
+        let other = MockDevice::new("/dev/ttyACM2");
+        source.plug(other.clone());
+        daemon.rescan();
+        assert_eq!(other.state().requests, 0);
+    }
+
+    #[test]
+    fn test_replugged_shared_serial() {
+        let device = |path, usb_port| {
+            MockDevice::new(path)
+                .with_serial(SHARED_SERIAL_NUMBER)
+                .with_usb_port(usb_port)
+        };
+        let source = MockSource::new(vec![
+            device("/dev/ttyACM0", "1-1.2"),
+            device("/dev/ttyACM1", "1-1.3"),
+        ]);
+        let daemon = Daemon::new(Box::new(source.clone()));
+        let color = |led| DaemonRequest {
+            selector: DeviceSelector::only(&format!("/dev/ttyACM{}", led)),
+            action: Action::Background(LedColor::new(led, Rgb::new(0, 0, 1))),
+            owner: None,
+        };
+        daemon.handle(&color(0));
+        daemon.handle(&color(1));
+
+        // Both are unplugged, and come back under each other's path but in the same ports
+        source.unplug("/dev/ttyACM0");
+        source.unplug("/dev/ttyACM1");
+        daemon.rescan();
+        let first = device("/dev/ttyACM1", "1-1.2");
+        let second = device("/dev/ttyACM0", "1-1.3");
+        source.plug(first.clone());
+        source.plug(second.clone());
+        daemon.rescan();
+        assert_eq!(first.state().background[0], Rgb::new(0, 0, 1));
+        assert_eq!(first.state().background[1], Rgb::OFF);
+        assert_eq!(second.state().background[0], Rgb::OFF);
+        assert_eq!(second.state().background[1], Rgb::new(0, 0, 1));
+    }
+
+    #[test]
+    fn test_state_file() {
+        let path =
+            std::env::temp_dir().join(format!("status_lights_state_{}.json", std::process::id()));
+        let device = || MockDevice::new("/dev/ttyACM0").with_serial("abc");
+        let red = Rgb::new(1, 0, 0);
+        let daemon = Daemon::new(Box::new(MockSource::new(vec![device()])))
+            .with_state_file(path.clone())
+            .unwrap();
+        daemon.handle(&background(DeviceSelector::all()));
+        daemon.handle(&DaemonRequest {
+            selector: DeviceSelector::all(),
+            action: Action::Lease(LedLease {
+                led: 2,
+                color: red,
+                until: SystemTime::now() + Duration::from_secs(3600),
+            }),
+            owner: None,
+        });
+        drop(daemon);
+
+        // A new daemon puts the leds back, on a device that has forgotten them
+        let restarted = device();
+        let daemon = Daemon::new(Box::new(MockSource::new(vec![restarted.clone()])))
+            .with_state_file(path.clone())
+            .unwrap();
+        assert_eq!(restarted.state().background[0], Rgb::new(0, 0, 1));
+        let state = &daemon.led_states("/dev/ttyACM0").unwrap()[2];
+        assert_eq!(state.foreground, red);
+        daemon.tick();
+        assert_eq!(restarted.state().foreground[2], red);
+        drop(daemon);
+
+        fs::write(&path, "not json").unwrap();
+        let source = MockSource::new(vec![device()]);
+        let error = Daemon::new(Box::new(source)).with_state_file(path.clone());
+        assert_eq!(error.err().unwrap().kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_restarted() {
+        let device = MockDevice::new("/dev/ttyACM0");
+        device.set_uptime(Duration::from_secs(100));
+        let daemon = Daemon::new(Box::new(MockSource::new(vec![device.clone()])));
+        daemon.handle(&background(DeviceSelector::all()));
+        daemon.check_boots();
+
+        // Still running, so nothing needs putting back
+        device.set_uptime(Duration::from_secs(105));
+        daemon.check_boots();
+        assert_eq!(device.state().requests, 1);
+        assert_eq!(device.state().uptime_requests, 2);
+
+        // Restarted without being seen to go away
+        device.restart();
+        assert_eq!(device.state().background[0], Rgb::OFF);
+        daemon.check_boots();
+        assert_eq!(device.state().background[0], Rgb::new(0, 0, 1));
+        assert_eq!(device.state().requests, 2);
+    }
+
+    #[test]
+    fn test_uptime_unsupported() {
+        let device = MockDevice::new("/dev/ttyACM0");
+        device.set_version(0, 1, 0);
+        let source = MockSource::new(vec![device.clone()]);
+        let daemon = Daemon::new(Box::new(source.clone()));
+        daemon.check_boots();
+        daemon.check_boots();
+        assert_eq!(device.state().uptime_requests, 0);
+
+        // Until it's plugged back in, as it might have been flashed with newer firmware
+        source.unplug("/dev/ttyACM0");
+        daemon.rescan();
+        device.set_version(0, 2, 0);
+        source.plug(device.clone());
+        daemon.rescan();
+        daemon.check_boots();
+        assert_eq!(device.state().uptime_requests, 1);
+
+        // Firmware that answers with an error isn't asked again either
+        device.set_version(0, 1, 0);
+        daemon.check_boots();
+        daemon.check_boots();
+        assert_eq!(device.state().uptime_requests, 2);
+    }
+
+    #[test]
+    fn test_state_file_unchanged() {
+        let path = std::env::temp_dir().join(format!(
+            "status_lights_unchanged_{}.json",
+            std::process::id()
+        ));
+        let config = Config::parse(
+            r#"
+            [[heartbeat.checks]]
+            name = "backup"
+            period = "1h"
+            led = 1
+            "#,
+            Path::new("config.toml"),
+        )
+        .unwrap();
+        let heartbeats = Heartbeats::new(&config.heartbeat, Instant::now()).unwrap();
+        let source = MockSource::new(vec![MockDevice::new("/dev/ttyACM0")]);
+        let daemon = Daemon::new(Box::new(source))
+            .with_heartbeats(heartbeats)
+            .with_state_file(path.clone())
+            .unwrap();
+        let ping = || DaemonRequest {
+            selector: DeviceSelector::all(),
+            action: Action::Heartbeat(HeartbeatPing {
+                check: "backup".to_string(),
+                failed: false,
+            }),
+            owner: None,
+        };
+        let modified = || fs::metadata(&path).unwrap().modified().unwrap();
+
+        // The first ping shows the check is ok, which changes the led
+        daemon.handle(&ping());
+        let saved = modified();
+        thread::sleep(Duration::from_millis(20));
+
+        // Requests that don't change any led leave the file alone
+        daemon.handle(&ping());
+        daemon.handle(&DaemonRequest {
+            selector: DeviceSelector::all(),
+            action: Action::Claim(LedClaim {
+                led: 2,
+                priority: 0,
+            }),
+            owner: Some("backup".to_string()),
+        });
+        daemon.handle(&background(DeviceSelector::only("/dev/ttyACM9")));
+        daemon.tick();
+        assert_eq!(modified(), saved);
+
+        daemon.handle(&background(DeviceSelector::all()));
+        assert_ne!(modified(), saved);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_watching() {
+        let source = MockSource::new(vec![]);
+        let daemon = Arc::new(Daemon::new(Box::new(source.clone())));
+        let events = daemon.subscribe();
+        assert!(daemon.start_watching());
+
+        let device = MockDevice::new("/dev/ttyACM0");
+        let timeout = Duration::from_secs(5);
+        source.plug(device.clone());
+        assert_eq!(
+            events.recv_timeout(timeout),
+            Ok(DeviceEvent::Attached(device.describe()))
+        );
+        source.unplug("/dev/ttyACM0");
+        assert_eq!(
+            events.recv_timeout(timeout),
+            Ok(DeviceEvent::Detached(device.describe()))
+        );
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let device = MockDevice::new("/dev/ttyACM0").with_serial("abc");
+        let source = MockSource::new(vec![device.clone()]);
+        let daemon = Daemon::new(Box::new(source.clone()));
+        let request = |action| DaemonRequest {
+            selector: DeviceSelector::all(),
+            action,
+            owner: None,
+        };
+        let red = Rgb::new(1, 0, 0);
+        daemon.handle(&background(DeviceSelector::all()));
+        daemon.handle(&request(Action::Foreground(LedColorTimed::new(2, red, 0))));
+        let snapshot = match daemon.handle(&request(Action::Snapshot)) {
+            DaemonResponse::Snapshot(snapshot) => snapshot,
+            response => panic!("Unexpected response {:?}", response),
+        };
+        assert_eq!(snapshot.devices.len(), 1);
+        assert_eq!(snapshot.devices[0].serial.as_deref(), Some("abc"));
+
+        // Devices that aren't in the snapshot are left alone
+        daemon.handle(&request(Action::Foreground(LedColorTimed::new(
+            2,
+            Rgb::OFF,
+            0,
+        ))));
+        let other = MockDevice::new("/dev/ttyACM1");
+        source.plug(other.clone());
+        match daemon.handle(&request(Action::Restore(snapshot))) {
+            DaemonResponse::Report(report) => {
+                assert_eq!(report.outcome, Outcome::Success);
+                assert_eq!(report.devices.len(), 1);
+            }
+            response => panic!("Unexpected response {:?}", response),
+        }
+        assert_eq!(device.state().background[0], Rgb::new(0, 0, 1));
+        assert_eq!(device.state().foreground[2], red);
+        assert_eq!(other.state().requests, 0);
+    }
+
+    #[test]
+    fn test_claims() {
+        let device = MockDevice::new("/dev/ttyACM0");
+        let daemon = Daemon::new(Box::new(MockSource::new(vec![device.clone()])));
+        let request = |owner: Option<&str>, action| DaemonRequest {
+            selector: DeviceSelector::all(),
+            action,
+            owner: owner.map(str::to_string),
+        };
+        let report = |response| match response {
+            DaemonResponse::Report(report) => report,
+            response => panic!("Unexpected response {:?}", response),
+        };
+        let claim = |priority| Action::Claim(LedClaim { led: 1, priority });
+        let release = Action::Release(LedRelease { led: 1 });
+        let foreground = |color| Action::Foreground(LedColorTimed::new(1, color, 0));
+        let red = Rgb::new(1, 0, 0);
+        let blue = Rgb::new(0, 0, 1);
+
+        let response = daemon.handle(&request(None, claim(0)));
+        assert_eq!(report(response).outcome, Outcome::UsageError);
+        let response = daemon.handle(&request(Some("ci"), claim(0)));
+        assert_eq!(report(response).outcome, Outcome::Success);
+        let state = &daemon.led_states("/dev/ttyACM0").unwrap()[1];
+        assert_eq!(state.owner.as_deref(), Some("ci"));
+
+        // Without a claim of their own, anyone else is refused
+        let response = report(daemon.handle(&request(None, foreground(blue))));
+        assert_eq!(response.outcome, Outcome::DeviceError);
+        assert_eq!(
+            response.devices[0].error.as_deref(),
+            Some("led 1 is claimed by 'ci'")
+        );
+        assert_eq!(device.state().foreground[1], Rgb::OFF);
+        let response = daemon.handle(&request(Some("ci"), foreground(red)));
+        assert_eq!(report(response).outcome, Outcome::Success);
+        assert_eq!(device.state().foreground[1], red);
+
+        // A waiting claim has its change queued until it gets control
+        daemon.handle(&request(Some("backup"), claim(0)));
+        let response = report(daemon.handle(&request(Some("backup"), foreground(blue))));
+        assert_eq!(response.outcome, Outcome::Success);
+        assert!(response.devices[0].queued.is_some());
+        assert_eq!(device.state().foreground[1], red);
+        assert_eq!(daemon.claims()[0].claims[1].queued, 1);
+
+        let response = daemon.handle(&request(Some("ci"), release.clone()));
+        assert_eq!(report(response).outcome, Outcome::Success);
+        assert_eq!(device.state().foreground[1], blue);
+        let claims = daemon.claims();
+        assert_eq!(claims.len(), 1);
+        assert_eq!(claims[0].claims.len(), 1);
+        assert_eq!(claims[0].claims[0].owner, "backup");
+
+        daemon.handle(&request(Some("backup"), release));
+        assert!(daemon.claims().is_empty());
+        let response = daemon.handle(&request(None, foreground(Rgb::OFF)));
+        assert_eq!(report(response).outcome, Outcome::Success);
+    }
+
+    #[test]
+    fn test_slow_device() {
+        let other = MockDevice::new("/dev/ttyACM1");
+        let source = MockSource::new(vec![MockDevice::new("/dev/ttyACM0"), other.clone()]);
+        let daemon = Arc::new(Daemon::new(Box::new(source)));
+
+        // Holding a device is the same as a request to it that's taking a long time
+        let handle = daemon
+            .devices()
+            .into_iter()
+            .find(|handle| handle.info.path == "/dev/ttyACM0")
+            .unwrap();
+        let slow = handle.lock();
+        let effects = Arc::clone(&daemon);
+        let effect = thread::spawn(move || {
+            effects.handle(&DaemonRequest {
+                selector: DeviceSelector::all(),
+                action: Action::Effect(LedEffect {
+                    led: 0,
+                    effect: Some(Effect::Blink {
+                        color: Rgb::new(1, 0, 0),
+                        period: Duration::from_secs(1),
+                    }),
+                }),
+                owner: None,
+            })
+        });
+        thread::sleep(Duration::from_millis(50));
+
+        let (sender, receiver) = mpsc::channel();
+        let others = Arc::clone(&daemon);
+        thread::spawn(move || {
+            sender
+                .send(others.handle(&background(DeviceSelector::only("/dev/ttyACM1"))))
+                .ok()
+        });
+        let response = receiver.recv_timeout(Duration::from_secs(5));
+        assert!(matches!(response, Ok(DaemonResponse::Report(r)) if r.outcome == Outcome::Success));
+        assert_eq!(other.state().background[0], Rgb::new(0, 0, 1));
+
+        drop(slow);
+        let response = effect.join().unwrap();
+        assert!(matches!(response, DaemonResponse::Report(r) if r.outcome == Outcome::Success));
+    }
+
+    #[test]
+    fn test_serve() {
+        let device = MockDevice::new("/dev/ttyACM0");
+        let daemon = Arc::new(Daemon::new(Box::new(MockSource::new(vec![device.clone()]))));
+        let path = socket_path("serve");
+        let listener = bind(&path).unwrap();
+        thread::spawn(move || daemon.serve(listener));
+
+        assert_eq!(bind(&path).unwrap_err().kind(), io::ErrorKind::AddrInUse);
+
+        let mut client = DaemonClient::connect(&path).unwrap();
+        let response = client.send(&background(DeviceSelector::all())).unwrap();
+        assert!(matches!(response, DaemonResponse::Report(r) if r.outcome == Outcome::Success));
+        let response = client
+            .send(&DaemonRequest {
+                selector: DeviceSelector::all(),
+                action: Action::List,
+                owner: None,
+            })
+            .unwrap();
+        match response {
+            DaemonResponse::Devices(devices) => {
+                assert_eq!(devices[0].firmware_version.as_deref(), Some("v0.2.0"))
+            }
+            response => panic!("Unexpected response {:?}", response),
+        }
+        assert_eq!(device.state().requests, 1);
+        fs::remove_file(&path).unwrap();
+    }
+}
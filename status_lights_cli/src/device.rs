@@ -0,0 +1,65 @@
+//! The requests every status lights device can answer. `Client` talks to a real USB device, but
+//! anything built on top of this trait (the daemon, the HTTP API) can be tested against a
+//! `MockDevice` instead.
+
+use status_lights_messages::{LedColor, LedColorTimed, VersionNumber};
+
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
+
+use crate::output::DeviceInfo;
+use crate::selector::Selectable;
+use crate::watcher::DeviceChange;
+use crate::ClientError;
+
+/// The number of leds on every device, all of which are currently Neo Trinkeys
+pub const LEDS_PER_DEVICE: u8 = 4;
+
+/// The serial number every device reported before the firmware used the chip's own, so it can't
+/// tell devices apart
+pub const SHARED_SERIAL_NUMBER: &str = "Gisleburt Neo Trinkey Status Lights";
+
+/// The first firmware version that can say how long it's been running
+pub const FIRST_UPTIME_FIRMWARE: (u8, u8, u8) = (0, 2, 0);
+
+pub trait Device: Selectable + Send {
+    fn request_version(&mut self) -> Result<VersionNumber, ClientError>;
+    fn request_background(&mut self, led_color: LedColor) -> Result<(), ClientError>;
+    fn request_foreground(&mut self, led_color_timed: LedColorTimed) -> Result<(), ClientError>;
+    /// How long the device has been running, which starts again from 0 whenever it restarts.
+    /// Firmware older than the uptime request answers with an error.
+    fn request_uptime(&mut self) -> Result<Duration, ClientError>;
+    /// What is known about the device without sending it a request
+    fn describe(&self) -> DeviceInfo;
+}
+
+/// Somewhere devices can be found and opened
+pub trait DeviceSource: Send + Sync {
+    /// The paths of every device currently attached
+    fn attached(&self) -> Vec<String>;
+    fn open(&self, path: &str) -> Option<Box<dyn Device>>;
+    /// Says whenever a device is plugged in or unplugged, if the source can tell. Otherwise the
+    /// source is polled.
+    fn watch(&self) -> Option<Receiver<DeviceChange>> {
+        None
+    }
+}
+
+/// Real status lights devices attached over USB
+pub struct SerialSource;
+
+impl DeviceSource for SerialSource {
+    fn attached(&self) -> Vec<String> {
+        crate::Client::available_paths().unwrap_or_default()
+    }
+
+    fn open(&self, path: &str) -> Option<Box<dyn Device>> {
+        let client = crate::Client::open(path).ok()?;
+        Some(Box::new(client))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn watch(&self) -> Option<Receiver<DeviceChange>> {
+        crate::udev::watch()
+    }
+}
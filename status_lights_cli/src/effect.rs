@@ -0,0 +1,231 @@
+//! Animations the daemon plays on an led. The devices only hold a color, so the daemon works out
+//! what color an effect should be showing and sends it as a short lived foreground. If the daemon
+//! stops, the led falls back to its background soon after.
+
+use serde::{Deserialize, Serialize};
+use status_lights_messages::Rgb;
+
+use std::time::Duration;
+
+use crate::args;
+
+/// The names of every effect, as used in the `effect` field
+pub const EFFECTS: &[&str] = &["blink", "pulse", "chase", "rainbow"];
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "effect", rename_all = "snake_case")]
+pub enum Effect {
+    /// Shows the color for the first half of each period and the background for the second
+    Blink {
+        #[serde(deserialize_with = "args::deserialize_color")]
+        color: Rgb,
+        #[serde(with = "humantime_serde", default = "default_period")]
+        period: Duration,
+    },
+    /// Fades the color in and out over each period
+    Pulse {
+        #[serde(deserialize_with = "args::deserialize_color")]
+        color: Rgb,
+        #[serde(with = "humantime_serde", default = "default_period")]
+        period: Duration,
+    },
+    /// Lights up `offset` of the way through each period and fades out over `width` of it. Leds
+    /// given evenly spaced offsets light up one after another.
+    Chase {
+        #[serde(deserialize_with = "args::deserialize_color")]
+        color: Rgb,
+        #[serde(with = "humantime_serde", default = "default_period")]
+        period: Duration,
+        #[serde(default)]
+        offset: f32,
+        #[serde(default = "default_width")]
+        width: f32,
+    },
+    /// Cycles through every hue once per period
+    Rainbow {
+        #[serde(with = "humantime_serde", default = "default_rainbow_period")]
+        period: Duration,
+        #[serde(default = "default_brightness")]
+        brightness: f32,
+    },
+}
+
+fn default_period() -> Duration {
+    Duration::from_secs(1)
+}
+
+fn default_width() -> f32 {
+    0.25
+}
+
+fn default_rainbow_period() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_brightness() -> f32 {
+    1.0
+}
+
+impl Effect {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Effect::Blink { .. } => "blink",
+            Effect::Pulse { .. } => "pulse",
+            Effect::Chase { .. } => "chase",
+            Effect::Rainbow { .. } => "rainbow",
+        }
+    }
+
+    /// Creates the named effect with its default period, or `None` if there is no such effect
+    pub fn from_name(name: &str, color: Rgb) -> Option<Effect> {
+        match name {
+            "blink" => Some(Effect::Blink {
+                color,
+                period: default_period(),
+            }),
+            "pulse" => Some(Effect::Pulse {
+                color,
+                period: default_period(),
+            }),
+            "chase" => Some(Effect::Chase {
+                color,
+                period: default_period(),
+                offset: 0.0,
+                width: default_width(),
+            }),
+            "rainbow" => Some(Effect::Rainbow {
+                period: default_rainbow_period(),
+                brightness: default_brightness(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// The color to show once the effect has been running for `elapsed`. Off means the led should
+    /// show its background.
+    pub fn color_at(&self, elapsed: Duration) -> Rgb {
+        match self {
+            Effect::Blink { color, period } => {
+                if phase(elapsed, *period) < 0.5 {
+                    *color
+                } else {
+                    Rgb::OFF
+                }
+            }
+            Effect::Pulse { color, period } => {
+                let phase = phase(elapsed, *period);
+                let brightness = 1.0 - (2.0 * phase - 1.0).abs();
+                color.scale(brightness)
+            }
+            Effect::Chase {
+                color,
+                period,
+                offset,
+                width,
+            } => {
+                let since = (phase(elapsed, *period) - offset).rem_euclid(1.0);
+                let brightness = 1.0 - since / width.max(f32::EPSILON);
+                color.scale(brightness.max(0.0))
+            }
+            Effect::Rainbow { period, brightness } => {
+                Rgb::from_hsv(phase(elapsed, *period) * 360.0, 1.0, 1.0).scale(*brightness)
+            }
+        }
+    }
+}
+
+/// How far through the current period we are, from 0.0 up to (but not including) 1.0
+fn phase(elapsed: Duration, period: Duration) -> f32 {
+    if period.as_nanos() == 0 {
+        return 0.0;
+    }
+    (elapsed.as_nanos() % period.as_nanos()) as f32 / period.as_nanos() as f32
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const RED: Rgb = Rgb::new(255, 0, 0);
+
+    #[test]
+    fn test_blink() {
+        let effect = Effect::Blink {
+            color: RED,
+            period: Duration::from_secs(2),
+        };
+        assert_eq!(effect.color_at(Duration::from_millis(0)), RED);
+        assert_eq!(effect.color_at(Duration::from_millis(999)), RED);
+        assert_eq!(effect.color_at(Duration::from_millis(1000)), Rgb::OFF);
+        assert_eq!(effect.color_at(Duration::from_millis(2500)), RED);
+    }
+
+    #[test]
+    fn test_pulse() {
+        let effect = Effect::Pulse {
+            color: RED,
+            period: Duration::from_secs(2),
+        };
+        assert_eq!(effect.color_at(Duration::from_secs(0)), Rgb::OFF);
+        assert_eq!(effect.color_at(Duration::from_secs(1)), RED);
+        let fading = effect.color_at(Duration::from_millis(1500));
+        assert!(fading.red > 0 && fading.red < 255);
+    }
+
+    #[test]
+    fn test_chase() {
+        let effect = |offset| Effect::Chase {
+            color: RED,
+            period: Duration::from_secs(4),
+            offset,
+            width: 0.25,
+        };
+        assert_eq!(effect(0.0).color_at(Duration::from_secs(0)), RED);
+        assert_eq!(effect(0.0).color_at(Duration::from_secs(1)), Rgb::OFF);
+        assert_eq!(effect(0.5).color_at(Duration::from_secs(1)), Rgb::OFF);
+        assert_eq!(effect(0.5).color_at(Duration::from_secs(2)), RED);
+        let fading = effect(0.5).color_at(Duration::from_millis(2500));
+        assert!(fading.red > 0 && fading.red < 255);
+        // The offset wraps around, so the last led fades out as the first lights up
+        assert_eq!(effect(0.75).color_at(Duration::from_secs(4)), Rgb::OFF);
+        assert_eq!(effect(0.75).color_at(Duration::from_secs(3)), RED);
+    }
+
+    #[test]
+    fn test_rainbow() {
+        let effect = Effect::Rainbow {
+            period: Duration::from_secs(3),
+            brightness: 1.0,
+        };
+        assert_eq!(effect.color_at(Duration::from_secs(0)), RED);
+        assert_eq!(effect.color_at(Duration::from_secs(1)), Rgb::new(0, 255, 0));
+        assert_eq!(effect.color_at(Duration::from_secs(2)), Rgb::new(0, 0, 255));
+    }
+
+    #[test]
+    fn test_zero_period() {
+        let effect = Effect::Blink {
+            color: RED,
+            period: Duration::from_secs(0),
+        };
+        assert_eq!(effect.color_at(Duration::from_secs(5)), RED);
+    }
+
+    #[test]
+    fn test_json() {
+        let effect: Effect =
+            serde_json::from_str(r#"{"effect":"pulse","color":"red@50%","period":"2s"}"#).unwrap();
+        assert_eq!(
+            effect,
+            Effect::Pulse {
+                color: RED.scale(0.5),
+                period: Duration::from_secs(2)
+            }
+        );
+        assert_eq!(
+            serde_json::to_string(&Effect::from_name("blink", RED).unwrap()).unwrap(),
+            r##"{"effect":"blink","color":"#ff0000","period":"1s"}"##
+        );
+        assert!(serde_json::from_str::<Effect>(r#"{"effect":"sparkle"}"#).is_err());
+    }
+}
@@ -0,0 +1,323 @@
+//! Turns numbers into colors, by blending between colors at set values.
+//!
+//! A gradient is either one of the `PRESETS` by name, stops written as `value=color` separated by
+//! commas, or in a config file, a list of stops:
+//!
+//! ```toml
+//! gradient = "traffic-light"
+//! gradient = "0%=green, 70%=#ffbf00, 90%=red"
+//! gradient = [
+//!     { at = 0, color = "green@10%" },
+//!     { at = 70, color = "orange@10%" },
+//!     { at = 90, color = "red@10%" },
+//! ]
+//! ```
+//!
+//! Values are compared with the stops as they are, so a `%` after a stop is only there to read
+//! well and percentages should be given from 0 to 100.
+//!
+//! Colors are blended in the Oklab color space rather than by mixing red, green and blue, so the
+//! colors in between look evenly spaced and keep their brightness. As the leds respond linearly,
+//! their values are treated as linear light.
+
+use serde::Deserialize;
+use status_lights_messages::Rgb;
+
+use std::convert::TryFrom;
+use std::str::FromStr;
+
+use crate::args::{self, ArgError};
+
+/// Gradients that can be given by name
+pub const PRESETS: [(&str, &str); 4] = [
+    ("traffic-light", "0=green, 70=#ffbf00, 90=red"),
+    ("reverse-traffic-light", "0=red, 10=#ffbf00, 30=green"),
+    ("cool-warm", "0=blue, 50=purple, 100=red"),
+    ("brightness", "0=#010101, 100=white"),
+];
+
+/// A color at a value
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Stop {
+    pub at: f64,
+    #[serde(deserialize_with = "args::deserialize_color")]
+    pub color: Rgb,
+}
+
+impl FromStr for Stop {
+    type Err = String;
+
+    /// Parses `value=color`, eg `70%=orange@10%`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (at, color) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected value=color, got '{}'", s.trim()))?;
+        let at = at.trim();
+        let at = at
+            .strip_suffix('%')
+            .unwrap_or(at)
+            .trim()
+            .parse::<f64>()
+            .map_err(|_| format!("'{}' isn't a number", at))?;
+        let color = args::parse_color(color.trim()).map_err(|e| e.to_string())?;
+        Ok(Stop { at, color })
+    }
+}
+
+/// Colors at increasing values. Values below the first stop get its color, and values above the
+/// last get the last's.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(try_from = "GradientConfig")]
+pub struct Gradient {
+    stops: Vec<Stop>,
+}
+
+/// How a gradient can be written in a config file
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum GradientConfig {
+    Stops(Vec<Stop>),
+    Text(String),
+}
+
+impl Gradient {
+    pub fn new(mut stops: Vec<Stop>) -> Result<Gradient, String> {
+        if stops.is_empty() {
+            return Err("A gradient needs at least one stop".to_string());
+        }
+        if stops.iter().any(|stop| !stop.at.is_finite()) {
+            return Err("Gradient stops need to be at a number".to_string());
+        }
+        stops.sort_by(|a, b| a.at.total_cmp(&b.at));
+        Ok(Gradient { stops })
+    }
+
+    /// One of the `PRESETS`
+    pub fn preset(name: &str) -> Option<Gradient> {
+        PRESETS
+            .iter()
+            .find(|(preset, _)| preset.eq_ignore_ascii_case(name))
+            .map(|(_, stops)| stops.parse().expect("presets are valid"))
+    }
+
+    pub fn stops(&self) -> &[Stop] {
+        &self.stops
+    }
+
+    /// The color for a value, blended between the stops either side of it
+    pub fn color_at(&self, value: f64) -> Rgb {
+        let after = self.stops.iter().position(|stop| stop.at > value);
+        let (from, to) = match after {
+            Some(0) => return self.stops[0].color,
+            Some(after) => (self.stops[after - 1], self.stops[after]),
+            None => return self.stops[self.stops.len() - 1].color,
+        };
+        if value == from.at {
+            return from.color;
+        }
+        let amount = (value - from.at) / (to.at - from.at);
+        let (from, to) = (Oklab::from(from.color), Oklab::from(to.color));
+        let blend = |from: f64, to: f64| from + (to - from) * amount;
+        Rgb::from(Oklab {
+            lightness: blend(from.lightness, to.lightness),
+            a: blend(from.a, to.a),
+            b: blend(from.b, to.b),
+        })
+    }
+}
+
+impl FromStr for Gradient {
+    type Err = ArgError;
+
+    /// Parses a preset's name, or stops separated by commas
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(preset) = Gradient::preset(s.trim()) {
+            return Ok(preset);
+        }
+        let invalid = |reason: String| ArgError::InvalidGradient(s.to_string(), reason);
+        let stops = split_stops(s)
+            .into_iter()
+            .map(str::parse)
+            .collect::<Result<Vec<Stop>, _>>()
+            .map_err(invalid)?;
+        Gradient::new(stops).map_err(invalid)
+    }
+}
+
+impl TryFrom<GradientConfig> for Gradient {
+    type Error = String;
+
+    fn try_from(config: GradientConfig) -> Result<Self, Self::Error> {
+        match config {
+            GradientConfig::Stops(stops) => Gradient::new(stops),
+            GradientConfig::Text(text) => text.parse().map_err(|e: ArgError| e.to_string()),
+        }
+    }
+}
+
+/// Splits on commas that aren't inside brackets, so colors like `hsv(30, 100%, 50%)` stay whole
+fn split_stops(s: &str) -> Vec<&str> {
+    let mut stops = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                stops.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    stops.push(&s[start..]);
+    stops
+}
+
+/// A color in the Oklab color space, where equal distances look like equal differences
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Oklab {
+    lightness: f64,
+    a: f64,
+    b: f64,
+}
+
+impl From<Rgb> for Oklab {
+    fn from(color: Rgb) -> Self {
+        let [r, g, b] = [color.red, color.green, color.blue].map(|c| f64::from(c) / 255.0);
+        let l = (0.4122214708 * r + 0.5363325363 * g + 0.0514459929 * b).cbrt();
+        let m = (0.2119034982 * r + 0.6806995451 * g + 0.1073969566 * b).cbrt();
+        let s = (0.0883024619 * r + 0.2817188376 * g + 0.6299787005 * b).cbrt();
+        Oklab {
+            lightness: 0.2104542553 * l + 0.7936177850 * m - 0.0040720468 * s,
+            a: 1.9779984951 * l - 2.4285922050 * m + 0.4505937099 * s,
+            b: 0.0259040371 * l + 0.7827717662 * m - 0.8086757660 * s,
+        }
+    }
+}
+
+impl From<Oklab> for Rgb {
+    fn from(color: Oklab) -> Self {
+        let Oklab { lightness, a, b } = color;
+        let l = (lightness + 0.3963377774 * a + 0.2158037573 * b).powi(3);
+        let m = (lightness - 0.1055613458 * a - 0.0638541728 * b).powi(3);
+        let s = (lightness - 0.0894841775 * a - 1.2914855480 * b).powi(3);
+        let channel = |value: f64| (value.clamp(0.0, 1.0) * 255.0).round() as u8;
+        Rgb::new(
+            channel(4.0767416621 * l - 3.3077115913 * m + 0.2309699292 * s),
+            channel(-1.2684380046 * l + 2.6097574011 * m - 0.3413193965 * s),
+            channel(-0.0041960863 * l - 0.7034186147 * m + 1.7076147010 * s),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const GREEN: Rgb = Rgb::new(0, 200, 0);
+    const RED: Rgb = Rgb::new(200, 0, 0);
+
+    #[test]
+    fn test_color_at() {
+        let gradient = Gradient::new(vec![
+            Stop {
+                at: 100.0,
+                color: RED,
+            },
+            Stop {
+                at: 0.0,
+                color: GREEN,
+            },
+        ])
+        .unwrap();
+        assert_eq!(gradient.color_at(-5.0), GREEN);
+        assert_eq!(gradient.color_at(0.0), GREEN);
+        assert_eq!(gradient.color_at(25.0), Rgb::new(70, 134, 0));
+        assert_eq!(gradient.color_at(100.0), RED);
+        assert_eq!(gradient.color_at(1000.0), RED);
+
+        let single = Gradient::new(vec![Stop {
+            at: 1.0,
+            color: RED,
+        }])
+        .unwrap();
+        assert_eq!(single.color_at(0.0), RED);
+        assert_eq!(single.color_at(2.0), RED);
+    }
+
+    #[test]
+    fn test_oklab() {
+        for color in [
+            Rgb::new(255, 255, 255),
+            Rgb::new(255, 0, 0),
+            Rgb::new(0, 128, 0),
+            Rgb::new(12, 34, 200),
+            Rgb::new(1, 1, 1),
+        ] {
+            assert_eq!(Rgb::from(Oklab::from(color)), color);
+        }
+        // Halfway in lightness is far less than half the light
+        let grey = Gradient::preset("brightness").unwrap().color_at(50.0);
+        assert!(grey.red < 64 && grey.red == grey.green && grey.green == grey.blue);
+    }
+
+    #[test]
+    fn test_parse() {
+        let gradient: Gradient = "70% = orange@10%, 0%=hsv(120, 100%, 50%),90=#ff0000"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            gradient.stops(),
+            [
+                Stop {
+                    at: 0.0,
+                    color: Rgb::from_hsv(120.0, 1.0, 0.5),
+                },
+                Stop {
+                    at: 70.0,
+                    color: Rgb::new(255, 165, 0).scale(0.1),
+                },
+                Stop {
+                    at: 90.0,
+                    color: Rgb::new(255, 0, 0),
+                },
+            ]
+        );
+        let traffic_light: Gradient = "Traffic-Light".parse().unwrap();
+        assert_eq!(traffic_light.color_at(95.0), Rgb::new(255, 0, 0));
+        for (name, _) in PRESETS {
+            assert!(Gradient::preset(name).is_some());
+        }
+
+        let invalid = |s: &str| match s.parse::<Gradient>() {
+            Err(ArgError::InvalidGradient(_, reason)) => reason,
+            result => panic!("Expected an invalid gradient, got {:?}", result),
+        };
+        assert_eq!(invalid("rainbow"), "expected value=color, got 'rainbow'");
+        assert_eq!(invalid("high=red"), "'high' isn't a number");
+        assert!(invalid("0=blurple").starts_with("Invalid color 'blurple'"));
+    }
+
+    #[test]
+    fn test_deserialize() {
+        #[derive(Deserialize)]
+        struct Config {
+            gradient: Gradient,
+        }
+        let config: Config = toml::from_str(
+            r#"gradient = [{ at = 0, color = "green" }, { at = 0.5, color = "red@50%" }]"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.gradient.stops()[1].color,
+            Rgb::new(255, 0, 0).scale(0.5)
+        );
+        let config: Config = toml::from_str(r#"gradient = "traffic-light""#).unwrap();
+        assert_eq!(Some(config.gradient), Gradient::preset("traffic-light"));
+        assert!(toml::from_str::<Config>("gradient = []").is_err());
+        assert!(toml::from_str::<Config>(r#"gradient = "rainbow""#).is_err());
+    }
+}
@@ -0,0 +1,419 @@
+//! A dead man's switch for jobs such as cron jobs. Each job pings its heartbeat check through the
+//! daemon when it runs, and if the pings stop for longer than the check's period and grace, its
+//! led shows that the job has failed. Jobs can also report that they failed straight away.
+//!
+//! ```toml
+//! [[heartbeat.checks]]
+//! name = "backup"
+//! period = "1d"
+//! grace = "30m"
+//! led = 2
+//! ```
+//!
+//! Checks start out waiting for their first ping, and are late if it hasn't arrived within the
+//! period and grace of the daemon starting.
+
+use glob::Pattern;
+use serde::{Deserialize, Serialize};
+use status_lights_messages::Rgb;
+
+use std::collections::HashSet;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::config::ConfigError;
+use crate::device::LEDS_PER_DEVICE;
+use crate::monitor::Indicator;
+use crate::output::DeviceInfo;
+
+/// How long to wait before trying to show a status again after it failed to show
+const RETRY: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeartbeatConfig {
+    pub checks: Vec<HeartbeatCheck>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HeartbeatCheck {
+    /// What jobs ping
+    pub name: String,
+    /// How often the job pings
+    #[serde(with = "humantime_serde")]
+    pub period: Duration,
+    /// How late a ping can be before the check fails
+    #[serde(default = "default_grace", with = "humantime_serde")]
+    pub grace: Duration,
+    /// A glob pattern matched against each device's id, path, serial number and USB port. The
+    /// status is shown on every device if this isn't set.
+    pub device: Option<String>,
+    pub led: u8,
+    #[serde(default = "default_ok")]
+    pub ok: Indicator,
+    /// Shown when the pings stop, or a job reports that it failed
+    #[serde(default = "default_failed")]
+    pub failed: Indicator,
+}
+
+fn default_grace() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn default_ok() -> Indicator {
+    Indicator::Color(Rgb::new(0, 128, 0).scale(0.1))
+}
+
+fn default_failed() -> Indicator {
+    Indicator::Color(Rgb::new(255, 0, 0).scale(0.1))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HeartbeatStatus {
+    /// There hasn't been a ping since the daemon started
+    Waiting,
+    Ok,
+    /// The pings have stopped
+    Late,
+    /// The job reported that it failed
+    Failed,
+}
+
+/// A check's status, as reported by the API
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct HeartbeatState {
+    pub name: String,
+    pub status: HeartbeatStatus,
+    /// How long ago the last ping was
+    #[serde(with = "humantime_serde", skip_serializing_if = "Option::is_none")]
+    pub last_ping: Option<Duration>,
+    /// How long until the check is late without another ping
+    #[serde(with = "humantime_serde", skip_serializing_if = "Option::is_none")]
+    pub due_in: Option<Duration>,
+}
+
+/// What an led should show now that a check's status has changed
+#[derive(Clone, Debug, PartialEq)]
+pub struct Change {
+    /// The check's index, for reporting back whether it was shown
+    pub check: usize,
+    /// The devices it applies to, every device if `None`
+    pub device: Option<Pattern>,
+    pub led: u8,
+    pub indicator: Indicator,
+}
+
+impl Change {
+    pub fn applies_to(&self, info: &DeviceInfo) -> bool {
+        match &self.device {
+            Some(pattern) => info.matches_pattern(pattern),
+            None => true,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    status: HeartbeatStatus,
+    last_ping: Option<Instant>,
+    deadline: Instant,
+    /// When to show the status, if it hasn't been shown yet
+    show_at: Option<Instant>,
+}
+
+/// Keeps track of when each check was last pinged
+pub struct Heartbeats {
+    checks: Vec<(HeartbeatCheck, Option<Pattern>)>,
+    states: Mutex<Vec<State>>,
+}
+
+impl Heartbeats {
+    pub fn new(config: &HeartbeatConfig, now: Instant) -> Result<Heartbeats, ConfigError> {
+        let mut names = HashSet::new();
+        let checks = config
+            .checks
+            .iter()
+            .map(|check| {
+                let invalid = |reason: String| {
+                    ConfigError::Invalid(format!("Heartbeat check '{}': {}", check.name, reason))
+                };
+                if !names.insert(&check.name) {
+                    return Err(invalid("there's another check with this name".to_string()));
+                }
+                if check.period.is_zero() {
+                    return Err(invalid("the period needs to be longer than 0s".to_string()));
+                }
+                if check.led >= LEDS_PER_DEVICE {
+                    return Err(invalid(format!(
+                        "led {} doesn't exist, devices have leds 0 to {}",
+                        check.led,
+                        LEDS_PER_DEVICE - 1
+                    )));
+                }
+                let device = check
+                    .device
+                    .as_deref()
+                    .map(Pattern::new)
+                    .transpose()
+                    .map_err(|e| invalid(format!("invalid device pattern: {}", e)))?;
+                Ok((check.clone(), device))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let states = checks
+            .iter()
+            .map(|(check, _)| State {
+                status: HeartbeatStatus::Waiting,
+                last_ping: None,
+                deadline: now + check.period + check.grace,
+                show_at: None,
+            })
+            .collect();
+        Ok(Heartbeats {
+            checks,
+            states: Mutex::new(states),
+        })
+    }
+
+    fn states(&self) -> MutexGuard<'_, Vec<State>> {
+        self.states.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Records a ping for the named check, returning what to show if its status changed
+    pub fn ping(&self, name: &str, failed: bool, now: Instant) -> Result<Option<Change>, String> {
+        let index = self
+            .checks
+            .iter()
+            .position(|(check, _)| check.name == name)
+            .ok_or_else(|| format!("There's no heartbeat check named '{}'", name))?;
+        let (check, _) = &self.checks[index];
+        let mut states = self.states();
+        let state = &mut states[index];
+        state.last_ping = Some(now);
+        state.deadline = now + check.period + check.grace;
+        let status = match failed {
+            true => HeartbeatStatus::Failed,
+            false => HeartbeatStatus::Ok,
+        };
+        if status == state.status {
+            return Ok(None);
+        }
+        state.status = status;
+        state.show_at = Some(now);
+        Ok(Some(self.change(index, status)))
+    }
+
+    /// Marks checks whose pings have stopped as late, returning every change that still needs to
+    /// be shown
+    pub fn due(&self, now: Instant) -> Vec<Change> {
+        let mut states = self.states();
+        for state in states.iter_mut() {
+            let expecting_ping =
+                matches!(state.status, HeartbeatStatus::Waiting | HeartbeatStatus::Ok);
+            if expecting_ping && now >= state.deadline {
+                state.status = HeartbeatStatus::Late;
+                state.show_at = Some(now);
+            }
+        }
+        states
+            .iter()
+            .enumerate()
+            .filter(|(_, state)| state.show_at.is_some_and(|at| now >= at))
+            .map(|(index, state)| self.change(index, state.status))
+            .collect()
+    }
+
+    /// Records whether a change was shown, so one that failed is tried again later
+    pub fn shown(&self, change: &Change, ok: bool, now: Instant) {
+        let mut states = self.states();
+        let state = &mut states[change.check];
+        if self.change(change.check, state.status) != *change {
+            // The status changed again while this one was being shown
+            return;
+        }
+        state.show_at = match ok {
+            true => None,
+            false => Some(now + RETRY),
+        };
+    }
+
+    pub fn states_at(&self, now: Instant) -> Vec<HeartbeatState> {
+        let states = self.states();
+        self.checks
+            .iter()
+            .zip(states.iter())
+            .map(|((check, _), state)| HeartbeatState {
+                name: check.name.clone(),
+                status: state.status,
+                last_ping: state.last_ping.map(|at| round(now - at)),
+                due_in: match state.status {
+                    HeartbeatStatus::Waiting | HeartbeatStatus::Ok => {
+                        Some(round(state.deadline.saturating_duration_since(now)))
+                    }
+                    HeartbeatStatus::Late | HeartbeatStatus::Failed => None,
+                },
+            })
+            .collect()
+    }
+
+    fn change(&self, index: usize, status: HeartbeatStatus) -> Change {
+        let (check, device) = &self.checks[index];
+        let indicator = match status {
+            HeartbeatStatus::Ok => &check.ok,
+            // A waiting check isn't shown, so this is never used
+            HeartbeatStatus::Waiting | HeartbeatStatus::Late | HeartbeatStatus::Failed => {
+                &check.failed
+            }
+        };
+        Change {
+            check: index,
+            device: device.clone(),
+            led: check.led,
+            indicator: indicator.clone(),
+        }
+    }
+}
+
+/// Rounds to whole seconds, which is all that's worth reporting
+fn round(duration: Duration) -> Duration {
+    Duration::from_secs(duration.as_secs_f64().round() as u64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::Config;
+
+    use std::path::Path;
+
+    fn heartbeats(now: Instant) -> Heartbeats {
+        let config = Config::parse(
+            r#"
+            [[heartbeat.checks]]
+            name = "backup"
+            period = "1h"
+            grace = "5m"
+            device = "ttyACM0"
+            led = 2
+
+            [[heartbeat.checks]]
+            name = "certs"
+            period = "1d"
+            led = 3
+            ok = "blue"
+            failed = { effect = "blink", color = "red" }
+            "#,
+            Path::new("config.toml"),
+        )
+        .unwrap();
+        Heartbeats::new(&config.heartbeat, now).unwrap()
+    }
+
+    fn statuses(heartbeats: &Heartbeats, now: Instant) -> Vec<HeartbeatStatus> {
+        heartbeats
+            .states_at(now)
+            .into_iter()
+            .map(|state| state.status)
+            .collect()
+    }
+
+    #[test]
+    fn test_pings() {
+        let start = Instant::now();
+        let minutes = |minutes: u64| start + Duration::from_secs(minutes * 60);
+        let heartbeats = heartbeats(start);
+        assert_eq!(heartbeats.due(minutes(64)), []);
+
+        let change = heartbeats
+            .ping("backup", false, minutes(30))
+            .unwrap()
+            .unwrap();
+        assert_eq!(change.led, 2);
+        assert_eq!(change.indicator, default_ok());
+        assert!(change.applies_to(&DeviceInfo {
+            path: "/dev/ttyACM0".to_string(),
+            ..DeviceInfo::default()
+        }));
+        heartbeats.shown(&change, true, minutes(30));
+        // Another ping doesn't change anything
+        assert_eq!(heartbeats.ping("backup", false, minutes(31)), Ok(None));
+        assert!(heartbeats.ping("restore", false, minutes(31)).is_err());
+
+        // Nothing changes until a ping is due, and certs is still waiting for its first
+        assert_eq!(heartbeats.due(minutes(66)), []);
+        assert_eq!(
+            statuses(&heartbeats, minutes(66)),
+            [HeartbeatStatus::Ok, HeartbeatStatus::Waiting]
+        );
+
+        // backup's last ping was at 31 minutes, so it's late after an hour and five minutes more
+        assert_eq!(heartbeats.due(minutes(95)), []);
+        let due = heartbeats.due(minutes(96));
+        assert_eq!((due.len(), due[0].led), (1, 2));
+        assert_eq!(due[0].indicator, default_failed());
+        heartbeats.shown(&due[0], true, minutes(96));
+        assert_eq!(heartbeats.due(minutes(97)), []);
+        let state = &heartbeats.states_at(minutes(97))[0];
+        assert_eq!(state.status, HeartbeatStatus::Late);
+        assert_eq!(state.last_ping, Some(Duration::from_secs(66 * 60)));
+
+        let change = heartbeats
+            .ping("certs", true, minutes(100))
+            .unwrap()
+            .unwrap();
+        assert!(matches!(change.indicator, Indicator::Effect(_)));
+        assert_eq!(
+            statuses(&heartbeats, minutes(100)),
+            [HeartbeatStatus::Late, HeartbeatStatus::Failed]
+        );
+    }
+
+    #[test]
+    fn test_retry() {
+        let start = Instant::now();
+        let heartbeats = heartbeats(start);
+        let change = heartbeats.ping("certs", false, start).unwrap().unwrap();
+        heartbeats.shown(&change, false, start);
+        assert_eq!(heartbeats.due(start + Duration::from_secs(1)), []);
+        assert_eq!(heartbeats.due(start + RETRY), [change]);
+    }
+
+    #[test]
+    fn test_invalid() {
+        let invalid = |checks: &str| {
+            let config = Config::parse(checks, Path::new("config.toml")).unwrap();
+            Heartbeats::new(&config.heartbeat, Instant::now())
+                .err()
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(
+            invalid(
+                r#"
+                [[heartbeat.checks]]
+                name = "backup"
+                period = "1h"
+                led = 4
+                "#
+            ),
+            "Invalid config: Heartbeat check 'backup': led 4 doesn't exist, devices have leds 0 to 3"
+        );
+        assert_eq!(
+            invalid(
+                r#"
+                [[heartbeat.checks]]
+                name = "backup"
+                period = "1h"
+                led = 0
+
+                [[heartbeat.checks]]
+                name = "backup"
+                period = "1d"
+                led = 1
+                "#
+            ),
+            "Invalid config: Heartbeat check 'backup': there's another check with this name"
+        );
+    }
+}
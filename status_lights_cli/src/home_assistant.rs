@@ -0,0 +1,390 @@
+//! Home Assistant MQTT discovery, so that every led shows up in Home Assistant as a light without
+//! any configuration there.
+//!
+//! For each led the MQTT bridge publishes a retained config to
+//! `homeassistant/light/status_lights_<unique id>/led<led>/config`. The unique id comes from the
+//! device's USB serial number, so the lights keep their names and history when a device is
+//! plugged in somewhere else. The lights use Home Assistant's JSON schema, with commands arriving
+//! on `<prefix>/<id>/<led>/ha/set` and state published to `<prefix>/<id>/<led>/ha/state`.
+//!
+//! A light being on means the led has a foreground color or an effect, turning it off goes back
+//! to the background. The firmware doesn't report anything about its touch pads, so they don't
+//! appear in Home Assistant.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use status_lights_messages::Rgb;
+
+use crate::effect::{Effect, EFFECTS};
+use crate::output::DeviceInfo;
+use crate::state::LedState;
+
+/// The effect Home Assistant sends to stop an effect
+const NO_EFFECT: &str = "none";
+
+/// The color and brightness Home Assistant last asked for. Home Assistant keeps these apart, while
+/// the led only has the color they make together.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HaColor {
+    pub color: Rgb,
+    pub brightness: u8,
+}
+
+impl HaColor {
+    const WHITE: HaColor = HaColor {
+        color: Rgb::new(255, 255, 255),
+        brightness: 255,
+    };
+
+    fn shown(&self) -> Rgb {
+        self.color.scale(f32::from(self.brightness) / 255.0)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+struct HaRgb {
+    r: u8,
+    g: u8,
+    b: u8,
+}
+
+impl From<Rgb> for HaRgb {
+    fn from(rgb: Rgb) -> Self {
+        HaRgb {
+            r: rgb.red,
+            g: rgb.green,
+            b: rgb.blue,
+        }
+    }
+}
+
+impl From<HaRgb> for Rgb {
+    fn from(rgb: HaRgb) -> Self {
+        Rgb::new(rgb.r, rgb.g, rgb.b)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+enum OnOff {
+    On,
+    Off,
+}
+
+/// A light in Home Assistant's JSON schema, as both commands and state are sent. Anything else
+/// Home Assistant sends, such as a transition, is ignored.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct HaLight {
+    state: OnOff,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    color_mode: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    color: Option<HaRgb>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    brightness: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    effect: Option<String>,
+}
+
+/// What a command asks the led to do
+#[derive(Clone, Debug, PartialEq)]
+pub enum Change {
+    /// Clear the foreground, showing the background
+    Off,
+    Foreground(Rgb),
+    Effect(Effect),
+}
+
+/// The Home Assistant config for an led
+pub fn discovery(
+    discovery_prefix: &str,
+    prefix: &str,
+    info: &DeviceInfo,
+    led: u8,
+) -> (String, Value) {
+    let unique_id = info.unique_id();
+    let topic = |suffix: &str| format!("{}/{}/{}/{}", prefix, info.id(), led, suffix);
+    let mut effects = vec![NO_EFFECT];
+    effects.extend(EFFECTS);
+    let config = json!({
+        "name": format!("LED {}", led),
+        "unique_id": format!("status_lights_{}_led{}", unique_id, led),
+        "schema": "json",
+        "command_topic": topic("ha/set"),
+        "state_topic": topic("ha/state"),
+        "availability": [
+            {"topic": format!("{}/status", prefix)},
+            {"topic": format!("{}/{}/status", prefix, info.id())},
+        ],
+        "availability_mode": "all",
+        "supported_color_modes": ["rgb"],
+        "brightness": true,
+        "effect": true,
+        "effect_list": effects,
+        "device": {
+            "identifiers": [format!("status_lights_{}", unique_id)],
+            "name": format!("Status Lights {}", unique_id),
+            "manufacturer": info.manufacturer,
+            "model": info.product.as_ref().or(info.name.as_ref()),
+            "serial_number": info.serial,
+            "sw_version": info.firmware_version,
+        },
+    });
+    let topic = format!(
+        "{}/light/status_lights_{}/led{}/config",
+        discovery_prefix, unique_id, led
+    );
+    (topic, config)
+}
+
+/// The color an led is showing, or showing in pulses
+fn shown_color(state: &LedState) -> Rgb {
+    match &state.effect {
+        Some(Effect::Blink { color, .. })
+        | Some(Effect::Pulse { color, .. })
+        | Some(Effect::Chase { color, .. }) => *color,
+        Some(Effect::Rainbow { .. }) | None => state.foreground,
+    }
+}
+
+/// The led's state for Home Assistant. The color and brightness Home Assistant last asked for are
+/// reported as long as the led is still showing them.
+pub fn light_state(state: &LedState, last: Option<HaColor>) -> HaLight {
+    let shown = shown_color(state);
+    let on = state.effect.is_some() || !shown.is_off();
+    if !on {
+        return HaLight {
+            state: OnOff::Off,
+            color_mode: None,
+            color: None,
+            brightness: None,
+            effect: None,
+        };
+    }
+    let color = match (&state.effect, last) {
+        (Some(Effect::Rainbow { brightness, .. }), last) => HaColor {
+            brightness: (brightness * 255.0).round() as u8,
+            ..last.unwrap_or(HaColor::WHITE)
+        },
+        (_, Some(last)) if last.shown() == shown => last,
+        _ => HaColor {
+            color: shown,
+            brightness: 255,
+        },
+    };
+    HaLight {
+        state: OnOff::On,
+        color_mode: Some("rgb".to_string()),
+        color: Some(color.color.into()),
+        brightness: Some(color.brightness),
+        effect: Some(
+            state
+                .effect
+                .as_ref()
+                .map_or(NO_EFFECT, Effect::name)
+                .to_string(),
+        ),
+    }
+}
+
+/// Works out what a command from Home Assistant should change, and the color and brightness to
+/// remember for it. Anything the command leaves out stays as it is.
+pub fn apply(
+    command: &HaLight,
+    state: &LedState,
+    last: Option<HaColor>,
+) -> Result<(Change, HaColor), String> {
+    // A light that's off comes back on as it was before
+    let current = light_state(state, last);
+    let mut color = match (current.color, current.brightness) {
+        (Some(rgb), Some(brightness)) => HaColor {
+            color: rgb.into(),
+            brightness,
+        },
+        _ => last.unwrap_or(HaColor::WHITE),
+    };
+    if command.state == OnOff::Off {
+        return Ok((Change::Off, color));
+    }
+    if let Some(rgb) = command.color {
+        color.color = rgb.into();
+    }
+    if let Some(brightness) = command.brightness {
+        color.brightness = brightness;
+    }
+    if color.brightness == 0 || color.color.is_off() {
+        return Ok((Change::Off, color));
+    }
+    let effect = match &command.effect {
+        Some(name) => name.as_str(),
+        None => state.effect.as_ref().map_or(NO_EFFECT, Effect::name),
+    };
+    if effect == NO_EFFECT {
+        return Ok((Change::Foreground(color.shown()), color));
+    }
+    let effect = match Effect::from_name(effect, color.shown()) {
+        Some(Effect::Rainbow { period, .. }) => Effect::Rainbow {
+            period,
+            brightness: f32::from(color.brightness) / 255.0,
+        },
+        Some(effect) => effect,
+        None => return Err(format!("Unknown effect '{}'", effect)),
+    };
+    Ok((Change::Effect(effect), color))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::time::Duration;
+
+    use crate::device::{Device, SHARED_SERIAL_NUMBER};
+    use crate::mock::MockDevice;
+
+    const RED: Rgb = Rgb::new(255, 0, 0);
+
+    fn command(json: &str) -> HaLight {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn led(foreground: Rgb, effect: Option<Effect>) -> LedState {
+        LedState {
+            foreground,
+            effect,
+            ..LedState::default()
+        }
+    }
+
+    #[test]
+    fn test_discovery() {
+        let info = DeviceInfo {
+            path: "/dev/ttyACM0".to_string(),
+            serial: Some("ABC123".to_string()),
+            product: Some("Status Lights".to_string()),
+            ..DeviceInfo::default()
+        };
+        let (topic, config) = discovery("homeassistant", "status_lights", &info, 2);
+        assert_eq!(
+            topic,
+            "homeassistant/light/status_lights_ABC123/led2/config"
+        );
+        assert_eq!(config["unique_id"], "status_lights_ABC123_led2");
+        assert_eq!(config["command_topic"], "status_lights/ttyACM0/2/ha/set");
+        assert_eq!(
+            config["availability"][1]["topic"],
+            "status_lights/ttyACM0/status"
+        );
+        assert_eq!(
+            config["effect_list"],
+            json!(["none", "blink", "pulse", "chase", "rainbow"])
+        );
+        assert_eq!(config["device"]["identifiers"][0], "status_lights_ABC123");
+        assert_eq!(config["device"]["model"], "Status Lights");
+    }
+
+    #[test]
+    fn test_discovery_shared_serial() {
+        let devices = ["1-1.2", "1-1.3"].iter().enumerate().map(|(i, usb_port)| {
+            MockDevice::new(&format!("/dev/ttyACM{}", i))
+                .with_serial(SHARED_SERIAL_NUMBER)
+                .with_usb_port(usb_port)
+        });
+        let topics: Vec<String> = devices
+            .map(|device| discovery("homeassistant", "status_lights", &device.describe(), 0).0)
+            .collect();
+        assert_eq!(
+            topics,
+            vec![
+                "homeassistant/light/status_lights_port-1-1_2/led0/config",
+                "homeassistant/light/status_lights_port-1-1_3/led0/config",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_light_state() {
+        let off = light_state(&led(Rgb::OFF, None), None);
+        assert_eq!(serde_json::to_value(off).unwrap(), json!({"state": "OFF"}));
+
+        let on = light_state(&led(RED, None), None);
+        assert_eq!(
+            serde_json::to_value(on).unwrap(),
+            json!({
+                "state": "ON",
+                "color_mode": "rgb",
+                "color": {"r": 255, "g": 0, "b": 0},
+                "brightness": 255,
+                "effect": "none"
+            })
+        );
+
+        let dim = HaColor {
+            color: RED,
+            brightness: 128,
+        };
+        let state = light_state(&led(dim.shown(), None), Some(dim));
+        assert_eq!(state.brightness, Some(128));
+        assert_eq!(state.color, Some(RED.into()));
+        // Once something else changes the led, the remembered color no longer applies
+        let state = light_state(&led(Rgb::new(0, 0, 255), None), Some(dim));
+        assert_eq!(state.brightness, Some(255));
+
+        let rainbow = Effect::Rainbow {
+            period: Duration::from_secs(10),
+            brightness: 0.5,
+        };
+        let state = light_state(&led(Rgb::OFF, Some(rainbow)), None);
+        assert_eq!(state.effect.as_deref(), Some("rainbow"));
+        assert_eq!(state.brightness, Some(128));
+    }
+
+    #[test]
+    fn test_apply() {
+        let off = led(Rgb::OFF, None);
+        let (change, color) = apply(&command(r#"{"state": "ON"}"#), &off, None).unwrap();
+        assert_eq!(change, Change::Foreground(Rgb::new(255, 255, 255)));
+        assert_eq!(color, HaColor::WHITE);
+
+        let (change, color) = apply(
+            &command(r#"{"state": "ON", "color": {"r": 255, "g": 0, "b": 0}, "brightness": 64}"#),
+            &off,
+            None,
+        )
+        .unwrap();
+        assert_eq!(change, Change::Foreground(RED.scale(64.0 / 255.0)));
+        assert_eq!(color.brightness, 64);
+
+        // Changing the brightness keeps the color, and the effect that's running
+        let pulsing = led(Rgb::OFF, Effect::from_name("pulse", color.shown()));
+        let (change, _) = apply(
+            &command(r#"{"state": "ON", "brightness": 255}"#),
+            &pulsing,
+            Some(color),
+        )
+        .unwrap();
+        assert_eq!(
+            change,
+            Change::Effect(Effect::from_name("pulse", RED).unwrap())
+        );
+
+        let (change, _) = apply(
+            &command(r#"{"state": "ON", "effect": "none"}"#),
+            &pulsing,
+            Some(color),
+        )
+        .unwrap();
+        assert_eq!(change, Change::Foreground(color.shown()));
+
+        let (change, _) = apply(&command(r#"{"state": "OFF"}"#), &pulsing, Some(color)).unwrap();
+        assert_eq!(change, Change::Off);
+        let (change, _) = apply(&command(r#"{"state": "ON"}"#), &off, Some(color)).unwrap();
+        assert_eq!(change, Change::Foreground(color.shown()));
+        assert!(apply(
+            &command(r#"{"state": "ON", "effect": "strobe"}"#),
+            &off,
+            None
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,334 @@
+//! A small blocking HTTP/1.1 server, enough to serve JSON to other machines on the network. Each
+//! connection gets its own thread and carries a single request.
+
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use serde::Serialize;
+use thiserror::Error;
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// The most a request's line and headers can take up
+const MAX_HEAD: usize = 16 * 1024;
+/// The largest request body that will be read
+const MAX_BODY: usize = 1024 * 1024;
+const MAX_HEADERS: usize = 64;
+/// How long to wait for a slow client before giving up on it
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Error, Debug)]
+pub enum HttpError {
+    #[error("Malformed request: {0}")]
+    Malformed(String),
+    #[error("Request is too large")]
+    TooLarge,
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl HttpError {
+    fn status(&self) -> u16 {
+        match self {
+            HttpError::Malformed(_) => 400,
+            HttpError::TooLarge => 413,
+            HttpError::Io(_) => 500,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Request {
+    pub method: String,
+    /// The path without the query string
+    pub path: String,
+    pub query: Option<String>,
+    /// Header names are lower case
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn new(method: &str, path: &str) -> Request {
+        let (path, query) = match path.split_once('?') {
+            Some((path, query)) => (path, Some(query.to_string())),
+            None => (path, None),
+        };
+        Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            query,
+            ..Request::default()
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Request {
+        self.headers
+            .push((name.to_ascii_lowercase(), value.to_string()));
+        self
+    }
+
+    pub fn with_body(mut self, body: &str) -> Request {
+        self.body = body.as_bytes().to_vec();
+        self
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Reads a request from the stream, returning `None` if it closed before sending anything
+    pub fn read_from(reader: &mut impl BufRead) -> Result<Option<Request>, HttpError> {
+        let mut head = Vec::new();
+        loop {
+            let read = reader
+                .by_ref()
+                .take((MAX_HEAD - head.len()) as u64)
+                .read_until(b'\n', &mut head)?;
+            if read == 0 && head.is_empty() {
+                return Ok(None);
+            }
+            if head.ends_with(b"\r\n\r\n") || head.ends_with(b"\n\n") {
+                break;
+            }
+            if read == 0 {
+                return Err(match head.len() {
+                    MAX_HEAD => HttpError::TooLarge,
+                    _ => HttpError::Malformed("Connection closed mid request".to_string()),
+                });
+            }
+        }
+
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut parsed = httparse::Request::new(&mut headers);
+        match parsed.parse(&head) {
+            Ok(httparse::Status::Complete(_)) => {}
+            Ok(httparse::Status::Partial) => {
+                return Err(HttpError::Malformed("Incomplete headers".to_string()))
+            }
+            Err(e) => return Err(HttpError::Malformed(e.to_string())),
+        }
+        let mut request = Request::new(
+            parsed.method.unwrap_or_default(),
+            parsed.path.unwrap_or_default(),
+        );
+        for header in parsed.headers.iter() {
+            let value = String::from_utf8_lossy(header.value).trim().to_string();
+            request = request.with_header(header.name, &value);
+        }
+
+        if request.header("transfer-encoding").is_some() {
+            return Err(HttpError::Malformed(
+                "Chunked request bodies aren't supported".to_string(),
+            ));
+        }
+        let length = match request.header("content-length") {
+            Some(length) => length.parse::<usize>().map_err(|_| {
+                HttpError::Malformed(format!("Invalid Content-Length '{}'", length))
+            })?,
+            None => 0,
+        };
+        if length > MAX_BODY {
+            return Err(HttpError::TooLarge);
+        }
+        request.body = vec![0; length];
+        reader.read_exact(&mut request.body)?;
+        Ok(Some(request))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &str, body: Vec<u8>) -> Response {
+        Response {
+            status,
+            headers: vec![("Content-Type".to_string(), content_type.to_string())],
+            body,
+        }
+    }
+
+    pub fn json<T: Serialize + ?Sized>(status: u16, body: &T) -> Response {
+        let mut body = serde_json::to_vec(body).expect("responses can always be serialized");
+        body.push(b'\n');
+        Response::new(status, "application/json", body)
+    }
+
+    /// A JSON response of the form `{"error": "..."}`
+    pub fn error(status: u16, message: &str) -> Response {
+        Response::json(status, &serde_json::json!({ "error": message }))
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Parses the body as JSON, mostly useful in tests
+    pub fn json_body(&self) -> serde_json::Value {
+        serde_json::from_slice(&self.body).unwrap_or(serde_json::Value::Null)
+    }
+
+    pub fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status));
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str(&format!(
+            "Content-Length: {}\r\nConnection: close\r\n\r\n",
+            self.body.len()
+        ));
+        writer.write_all(head.as_bytes())?;
+        writer.write_all(&self.body)?;
+        writer.flush()
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+/// Anything that can answer requests
+pub trait Handler: Send + Sync + 'static {
+    fn handle(&self, request: &Request) -> Response;
+}
+
+impl<F> Handler for F
+where
+    F: Fn(&Request) -> Response + Send + Sync + 'static,
+{
+    fn handle(&self, request: &Request) -> Response {
+        self(request)
+    }
+}
+
+/// Accepts connections until the listener fails, handling each on its own thread. Connections
+/// are wrapped in TLS when there's a TLS config.
+pub fn serve(
+    listener: TcpListener,
+    tls: Option<Arc<ServerConfig>>,
+    handler: Arc<dyn Handler>,
+) -> io::Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let tls = tls.clone();
+        let handler = Arc::clone(&handler);
+        thread::spawn(move || {
+            if let Err(e) = handle_connection(stream, tls, handler.as_ref()) {
+                eprintln!("HTTP connection error: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+fn handle_connection(
+    stream: TcpStream,
+    tls: Option<Arc<ServerConfig>>,
+    handler: &dyn Handler,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    match tls {
+        Some(config) => {
+            let connection = ServerConnection::new(config)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let mut stream = BufReader::new(StreamOwned::new(connection, stream));
+            respond(&mut stream, handler)?;
+            let stream = stream.get_mut();
+            stream.conn.send_close_notify();
+            stream.flush()
+        }
+        None => respond(&mut BufReader::new(stream), handler),
+    }
+}
+
+/// Reads a single request and writes the response
+fn respond<S: Read + Write>(stream: &mut BufReader<S>, handler: &dyn Handler) -> io::Result<()> {
+    let response = match Request::read_from(stream) {
+        Ok(Some(request)) => handler.handle(&request),
+        Ok(None) => return Ok(()),
+        // The connection is unusable, so there's no one to respond to
+        Err(HttpError::Io(e)) => return Err(e),
+        Err(e) => Response::error(e.status(), &e.to_string()),
+    };
+    response.write_to(stream.get_mut())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_read_request() {
+        let raw = "PUT /devices/ttyACM0?pretty HTTP/1.1\r\nHost: pi\r\nContent-Length: 17\r\n\r\n{\"color\":\"green\"}";
+        let request = Request::read_from(&mut raw.as_bytes()).unwrap().unwrap();
+        assert_eq!(request.method, "PUT");
+        assert_eq!(request.path, "/devices/ttyACM0");
+        assert_eq!(request.query.as_deref(), Some("pretty"));
+        assert_eq!(request.header("HOST"), Some("pi"));
+        assert_eq!(request.body, b"{\"color\":\"green\"}");
+
+        assert_eq!(Request::read_from(&mut "".as_bytes()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_read_bad_request() {
+        let raw = "NOT HTTP\r\n\r\n";
+        assert!(matches!(
+            Request::read_from(&mut raw.as_bytes()),
+            Err(HttpError::Malformed(_))
+        ));
+        let raw = "GET / HTTP/1.1\r\nContent-Length: 99999999\r\n\r\n";
+        assert!(matches!(
+            Request::read_from(&mut raw.as_bytes()),
+            Err(HttpError::TooLarge)
+        ));
+        let raw = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_HEAD));
+        assert!(matches!(
+            Request::read_from(&mut raw.as_bytes()),
+            Err(HttpError::TooLarge)
+        ));
+    }
+
+    #[test]
+    fn test_serve() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let handler = |request: &Request| Response::json(200, &request.path);
+        thread::spawn(move || serve(listener, None, Arc::new(handler)));
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream
+            .write_all(b"GET /devices HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: application/json\r\n"));
+        assert!(response.ends_with("\r\n\r\n\"/devices\"\n"));
+    }
+}
@@ -0,0 +1,387 @@
+//! The protocol spoken between the cli and the daemon over a Unix domain socket. Each side sends
+//! one JSON document per line and every request gets exactly one response, eg
+//!
+//! ```text
+//! {"selector":{"include":[{"path":"/dev/ttyACM0"}]},"action":{"action":"background","led":0,"color":"#010000"}}
+//! {"report":{"outcome":"success","exit_code":0,"devices":[{"path":"/dev/ttyACM0","name":"...","ok":true}]}}
+//! ```
+//!
+//! Actions are run the same way whether they come from the daemon or the cli is talking to
+//! devices directly.
+
+use serde::{Deserialize, Serialize};
+use status_lights_messages::{LedColor, LedColorTimed, Rgb};
+
+use std::env;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::args::{self, ArgError, MAX_DEVICE_DURATION};
+use crate::claims::LedClaims;
+use crate::device::Device;
+use crate::effect::Effect;
+use crate::output::DeviceInfo;
+use crate::report::{DeviceReport, Outcome, Report};
+use crate::selector::DeviceSelector;
+use crate::state::Snapshot;
+
+/// Overrides where the daemon socket is
+pub const SOCKET_ENV: &str = "STATUS_LIGHTS_SOCKET";
+
+/// Who requests are from, if `--owner` isn't given
+pub const OWNER_ENV: &str = "STATUS_LIGHTS_OWNER";
+
+/// `$STATUS_LIGHTS_OWNER`, if it's set
+pub fn default_owner() -> Option<String> {
+    env::var(OWNER_ENV).ok().filter(|owner| !owner.is_empty())
+}
+
+/// `$STATUS_LIGHTS_SOCKET`, or `status_lights.sock` in `$XDG_RUNTIME_DIR` (falling back to `/tmp`)
+pub fn default_socket_path() -> PathBuf {
+    if let Some(path) = env::var_os(SOCKET_ENV) {
+        return PathBuf::from(path);
+    }
+    env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(env::temp_dir)
+        .join("status_lights.sock")
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Action {
+    List,
+    Background(LedColor),
+    Foreground(LedColorTimed),
+    /// Starts an effect, or stops it if there isn't one. Only the daemon can play effects.
+    Effect(LedEffect),
+    /// Pings a heartbeat check, which only the daemon keeps track of
+    Heartbeat(HeartbeatPing),
+    /// Shows a foreground color for longer than the device can hold it. Only the daemon can
+    /// hold leases.
+    Lease(LedLease),
+    /// Claims an led for the request's owner, see `claims`
+    Claim(LedClaim),
+    /// Drops the request owner's claim on an led
+    Release(LedRelease),
+    /// Lists the claims on every led
+    Claims,
+    /// Takes a snapshot of what every led was set to
+    Snapshot,
+    /// Puts the leds back the way they were in a snapshot
+    Restore(Snapshot),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LedEffect {
+    pub led: u8,
+    pub effect: Option<Effect>,
+}
+
+/// A foreground color that the daemon keeps sending until a time. Setting the led's foreground
+/// again releases it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LedLease {
+    pub led: u8,
+    pub color: Rgb,
+    #[serde(with = "humantime_serde")]
+    pub until: SystemTime,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LedClaim {
+    pub led: u8,
+    /// Higher priorities take control from lower ones
+    #[serde(default)]
+    pub priority: i32,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LedRelease {
+    pub led: u8,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct HeartbeatPing {
+    pub check: String,
+    /// Reports that the job failed, rather than that it ran
+    #[serde(default)]
+    pub failed: bool,
+}
+
+impl Action {
+    /// Shows a foreground color for a time, or indefinitely if there isn't one. Times longer than
+    /// the device can hold a color for become a lease.
+    pub fn foreground_for(
+        led: u8,
+        color: Rgb,
+        duration: Option<Duration>,
+    ) -> Result<Action, ArgError> {
+        let duration = match duration {
+            Some(duration) => duration,
+            None => return Ok(Action::Foreground(LedColorTimed::new(led, color, 0))),
+        };
+        if let Ok(seconds) = args::to_device_seconds(duration) {
+            return Ok(Action::Foreground(LedColorTimed::new(led, color, seconds)));
+        }
+        let until = SystemTime::now()
+            .checked_add(duration)
+            .ok_or(ArgError::DurationOutOfRange(duration))?;
+        Ok(Action::Lease(LedLease { led, color, until }))
+    }
+
+    /// The led the action changes, if it changes one
+    pub fn led(&self) -> Option<u8> {
+        match self {
+            Action::Background(led_color) => Some(led_color.led),
+            Action::Foreground(led_color_timed) => Some(led_color_timed.led),
+            Action::Effect(led_effect) => Some(led_effect.led),
+            Action::Lease(lease) => Some(lease.led),
+            Action::List
+            | Action::Heartbeat(_)
+            | Action::Claim(_)
+            | Action::Release(_)
+            | Action::Claims
+            | Action::Snapshot
+            | Action::Restore(_) => None,
+        }
+    }
+
+    /// Runs the action against each of the devices in turn
+    pub fn run<'a>(&self, devices: impl IntoIterator<Item = &'a mut dyn Device>) -> DaemonResponse {
+        let devices = devices.into_iter();
+        match self {
+            Action::List => DaemonResponse::Devices(devices.map(DeviceInfo::from_device).collect()),
+            Action::Background(led_color) => {
+                Self::change(devices, |device| device.request_background(*led_color))
+            }
+            Action::Foreground(led_color_timed) => Self::change(devices, |device| {
+                device.request_foreground(*led_color_timed)
+            }),
+            Action::Effect(_) => DaemonResponse::Report(Report::failed(
+                Outcome::UsageError,
+                "Effects are played by the daemon, start it with `status_lights daemon`"
+                    .to_string(),
+            )),
+            Action::Heartbeat(_) => DaemonResponse::Report(Report::failed(
+                Outcome::UsageError,
+                "Heartbeats are kept track of by the daemon, start it with `status_lights daemon`"
+                    .to_string(),
+            )),
+            Action::Lease(_) => DaemonResponse::Report(Report::failed(
+                Outcome::UsageError,
+                format!(
+                    "The device can only hold a color for {}, start the daemon with \
+                     `status_lights daemon` to show it for longer",
+                    humantime::format_duration(MAX_DEVICE_DURATION)
+                ),
+            )),
+            Action::Claim(_) | Action::Release(_) | Action::Claims => {
+                DaemonResponse::Report(Report::failed(
+                    Outcome::UsageError,
+                    "Claims are kept by the daemon, start it with `status_lights daemon`"
+                        .to_string(),
+                ))
+            }
+            Action::Snapshot | Action::Restore(_) => DaemonResponse::Report(Report::failed(
+                Outcome::UsageError,
+                "What the leds were set to is kept by the daemon, start it with \
+                 `status_lights daemon`"
+                    .to_string(),
+            )),
+        }
+    }
+
+    fn change<'a>(
+        devices: impl Iterator<Item = &'a mut dyn Device>,
+        mut request: impl FnMut(&mut dyn Device) -> Result<(), crate::ClientError>,
+    ) -> DaemonResponse {
+        let devices: Vec<DeviceReport> = devices
+            .map(|device| {
+                let result = request(device);
+                DeviceReport::new(device.path(), device.name(), &result)
+            })
+            .collect();
+        if devices.is_empty() {
+            return DaemonResponse::Report(Report::failed(
+                Outcome::NoDevices,
+                "No devices found".to_string(),
+            ));
+        }
+        DaemonResponse::Report(Report::from_devices(devices))
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DaemonRequest {
+    #[serde(default)]
+    pub selector: DeviceSelector,
+    pub action: Action,
+    /// Who the request is from, for leds that have been claimed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DaemonResponse {
+    Devices(Vec<DeviceInfo>),
+    Report(Report),
+    Claims(Vec<LedClaims>),
+    Snapshot(Snapshot),
+}
+
+/// The cli's side of the connection to the daemon
+pub struct DaemonClient {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+impl DaemonClient {
+    pub fn connect(path: &Path) -> io::Result<DaemonClient> {
+        let writer = UnixStream::connect(path)?;
+        let reader = BufReader::new(writer.try_clone()?);
+        Ok(DaemonClient { reader, writer })
+    }
+
+    pub fn send(&mut self, request: &DaemonRequest) -> io::Result<DaemonResponse> {
+        write_message(&mut self.writer, request)?;
+        read_message(&mut self.reader)?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "The daemon closed the connection without responding",
+            )
+        })
+    }
+}
+
+/// Writes a message as a single line of JSON
+pub fn write_message<T: Serialize>(writer: &mut impl Write, message: &T) -> io::Result<()> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    writer.write_all(&line)?;
+    writer.flush()
+}
+
+/// Reads a single line of JSON, returning `None` if the other end hung up
+pub fn read_message<T: for<'de> Deserialize<'de>>(
+    reader: &mut impl BufRead,
+) -> io::Result<Option<T>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_str(&line)?))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::MockDevice;
+    use status_lights_messages::Rgb;
+
+    #[test]
+    fn test_request_json() {
+        let request = DaemonRequest {
+            selector: DeviceSelector::all(),
+            action: Action::Foreground(LedColorTimed::new(1, Rgb::new(0, 1, 0), 120)),
+            owner: None,
+        };
+        let json = serde_json::to_string(&request).unwrap();
+        assert_eq!(
+            json,
+            r##"{"selector":{"include":[],"exclude":[]},"action":{"action":"foreground","led":1,"color":"#000100","seconds":120}}"##
+        );
+        let parsed: DaemonRequest =
+            serde_json::from_str(r#"{"action":{"action":"background","led":1,"color":"green"}}"#)
+                .unwrap();
+        assert_eq!(
+            parsed.action,
+            Action::Background(LedColor::new(1, Rgb::new(0, 128, 0)))
+        );
+    }
+
+    #[test]
+    fn test_run_action() {
+        let mut ok = MockDevice::new("/dev/ttyACM0");
+        let mut broken = MockDevice::new("/dev/ttyACM1");
+        broken.set_unresponsive(true);
+        let action = Action::Background(LedColor::new(2, Rgb::new(1, 0, 0)));
+
+        let response = action.run(vec![&mut ok as &mut dyn Device, &mut broken]);
+
+        match response {
+            DaemonResponse::Report(report) => {
+                assert_eq!(report.outcome, Outcome::PartialFailure);
+                assert_eq!(
+                    report.devices[1].error.as_deref(),
+                    Some("Timed out waiting for device at '/dev/ttyACM1'")
+                );
+            }
+            _ => panic!("Expected a report"),
+        }
+        assert_eq!(ok.state().background[2], Rgb::new(1, 0, 0));
+    }
+
+    #[test]
+    fn test_effects_need_the_daemon() {
+        let mut device = MockDevice::new("/dev/ttyACM0");
+        let action = Action::Effect(LedEffect {
+            led: 0,
+            effect: Effect::from_name("blink", Rgb::new(1, 0, 0)),
+        });
+        match action.run(vec![&mut device as &mut dyn Device]) {
+            DaemonResponse::Report(report) => assert_eq!(report.outcome, Outcome::UsageError),
+            _ => panic!("Expected a report"),
+        }
+    }
+
+    #[test]
+    fn test_run_action_without_devices() {
+        let action = Action::Background(LedColor::new(0, Rgb::new(1, 0, 0)));
+        assert_eq!(
+            action.run(Vec::new()),
+            DaemonResponse::Report(Report::failed(
+                Outcome::NoDevices,
+                "No devices found".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_foreground_for() {
+        let red = Rgb::new(1, 0, 0);
+        assert_eq!(
+            Action::foreground_for(1, red, None).unwrap(),
+            Action::Foreground(LedColorTimed::new(1, red, 0))
+        );
+        assert_eq!(
+            Action::foreground_for(1, red, Some(Duration::from_secs(255))).unwrap(),
+            Action::Foreground(LedColorTimed::new(1, red, 255))
+        );
+        let before = SystemTime::now();
+        match Action::foreground_for(1, red, Some(Duration::from_secs(3600))).unwrap() {
+            Action::Lease(lease) => {
+                assert_eq!((lease.led, lease.color), (1, red));
+                let until = lease.until.duration_since(before).unwrap();
+                assert!(until >= Duration::from_secs(3600) && until < Duration::from_secs(3601));
+            }
+            action => panic!("Unexpected action {:?}", action),
+        }
+        let mut device = MockDevice::new("/dev/ttyACM0");
+        let too_long = Duration::from_secs(u64::MAX);
+        assert_eq!(
+            Action::foreground_for(1, red, Some(too_long)),
+            Err(ArgError::DurationOutOfRange(too_long))
+        );
+        match Action::foreground_for(1, red, Some(Duration::from_secs(3600)))
+            .unwrap()
+            .run([&mut device as &mut dyn Device])
+        {
+            DaemonResponse::Report(report) => assert_eq!(report.outcome, Outcome::UsageError),
+            response => panic!("Unexpected response {:?}", response),
+        }
+    }
+}
@@ -0,0 +1,772 @@
+//! Checks the health of a Kubernetes cluster, such as k3s, for the monitor's `kubernetes` checks.
+//!
+//! The cluster is found the same way `kubectl` finds it: from the check's `kubeconfig`,
+//! `$KUBECONFIG` or `~/.kube/config`, falling back to k3s's `/etc/rancher/k3s/k3s.yaml` and then
+//! to the pod's service account when running inside the cluster. Clusters are reached with a
+//! bearer token, a token file, basic auth or a client certificate. `exec` credential plugins
+//! aren't supported.
+//!
+//! A node that isn't `Ready` makes the check `CRITICAL`. Deployments without all their replicas
+//! ready, and pods that aren't ready (other than ones that have finished), in any of the check's
+//! `namespaces` make it `WARNING`. Not being allowed to read the cluster is `UNKNOWN`, and not
+//! being able to reach it is `CRITICAL`.
+//!
+//! ```toml
+//! [[monitor.checks]]
+//! name = "cluster"
+//! type = "kubernetes"
+//! namespaces = ["default", "monitoring"]
+//! led = 0
+//! ```
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{ClientConfig, RootCertStore};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::monitor::{CheckResult, Status};
+
+/// Where the service account's credentials are when running in a pod
+const SERVICE_ACCOUNT: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+/// Where k3s writes its kubeconfig
+const K3S_KUBECONFIG: &str = "/etc/rancher/k3s/k3s.yaml";
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KubernetesCheck {
+    /// Found the same way as `kubectl` if it isn't set
+    pub kubeconfig: Option<PathBuf>,
+    /// Defaults to the kubeconfig's current context
+    pub context: Option<String>,
+    /// Whether every node needs to be `Ready`
+    #[serde(default = "default_nodes")]
+    pub nodes: bool,
+    /// Namespaces whose deployments and pods all need to be ready
+    #[serde(default)]
+    pub namespaces: Vec<String>,
+}
+
+fn default_nodes() -> bool {
+    true
+}
+
+impl KubernetesCheck {
+    /// Makes a relative kubeconfig path relative to `dir`
+    pub fn resolve_paths(&mut self, dir: &Path) {
+        self.kubeconfig = self.kubeconfig.as_ref().map(|path| dir.join(path));
+    }
+
+    pub fn run(&self, timeout: Duration) -> CheckResult {
+        let cluster = match Cluster::load(self.kubeconfig.as_deref(), self.context.as_deref()) {
+            Ok(cluster) => cluster,
+            Err(e) => return CheckResult::new(Status::Unknown, e),
+        };
+        match self.problems(&cluster.client(timeout)) {
+            Ok((ready, problems)) if problems.is_empty() => {
+                CheckResult::new(Status::Ok, format!("{} ready", ready.join(", ")))
+            }
+            Ok((_, problems)) => {
+                let status = match problems.iter().any(|problem| problem.starts_with("node ")) {
+                    true => Status::Critical,
+                    false => Status::Warning,
+                };
+                CheckResult::new(status, format!("Not ready: {}", problems.join(", ")))
+            }
+            Err(e) => e,
+        }
+    }
+
+    /// Counts of what's ready, and a description of everything that isn't
+    fn problems(&self, client: &Client) -> Result<(Vec<String>, Vec<String>), CheckResult> {
+        let mut ready = Vec::new();
+        let mut problems = Vec::new();
+        if self.nodes {
+            let nodes: List<Node> = client.get("/api/v1/nodes")?;
+            ready.push(count(&nodes.items, "node"));
+            for node in &nodes.items {
+                if !is_ready(&node.status.conditions) {
+                    problems.push(format!("node {}", node.metadata.name));
+                }
+            }
+        }
+        let mut deployments = Vec::new();
+        let mut pods = Vec::new();
+        for namespace in &self.namespaces {
+            let path = format!("/apis/apps/v1/namespaces/{}/deployments", namespace);
+            let list: List<Deployment> = client.get(&path)?;
+            for deployment in &list.items {
+                let wanted = deployment.spec.replicas.unwrap_or(1);
+                let ready = deployment.status.ready_replicas.unwrap_or(0);
+                if ready < wanted {
+                    problems.push(format!(
+                        "deployment {}/{} ({}/{})",
+                        namespace, deployment.metadata.name, ready, wanted
+                    ));
+                }
+            }
+            deployments.extend(list.items);
+            let path = format!("/api/v1/namespaces/{}/pods", namespace);
+            let list: List<Pod> = client.get(&path)?;
+            for pod in &list.items {
+                let finished = pod.status.phase.as_deref() == Some("Succeeded");
+                if !finished && !is_ready(&pod.status.conditions) {
+                    problems.push(format!("pod {}/{}", namespace, pod.metadata.name));
+                }
+            }
+            pods.extend(list.items);
+        }
+        if !self.namespaces.is_empty() {
+            ready.push(count(&deployments, "deployment"));
+            ready.push(count(&pods, "pod"));
+        }
+        Ok((ready, problems))
+    }
+}
+
+fn count<T>(items: &[T], kind: &str) -> String {
+    match items.len() {
+        1 => format!("1 {}", kind),
+        n => format!("{} {}s", n, kind),
+    }
+}
+
+fn is_ready(conditions: &[Condition]) -> bool {
+    conditions
+        .iter()
+        .any(|condition| condition.kind == "Ready" && condition.status == "True")
+}
+
+/// Just enough of the API's objects to tell whether they're ready
+#[derive(Deserialize)]
+struct List<T> {
+    items: Vec<T>,
+}
+
+#[derive(Deserialize)]
+struct Metadata {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct Condition {
+    #[serde(rename = "type")]
+    kind: String,
+    status: String,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct ObjectStatus {
+    conditions: Vec<Condition>,
+    phase: Option<String>,
+    #[serde(rename = "readyReplicas")]
+    ready_replicas: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct Node {
+    metadata: Metadata,
+    #[serde(default)]
+    status: ObjectStatus,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct DeploymentSpec {
+    replicas: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct Deployment {
+    metadata: Metadata,
+    #[serde(default)]
+    spec: DeploymentSpec,
+    #[serde(default)]
+    status: ObjectStatus,
+}
+
+#[derive(Deserialize)]
+struct Pod {
+    metadata: Metadata,
+    #[serde(default)]
+    status: ObjectStatus,
+}
+
+/// The parts of a kubeconfig that are used
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Kubeconfig {
+    #[serde(default)]
+    clusters: Vec<NamedCluster>,
+    #[serde(default)]
+    users: Vec<NamedUser>,
+    #[serde(default)]
+    contexts: Vec<NamedContext>,
+    current_context: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct NamedCluster {
+    name: String,
+    cluster: KubeconfigCluster,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct KubeconfigCluster {
+    server: String,
+    certificate_authority: Option<PathBuf>,
+    certificate_authority_data: Option<String>,
+    #[serde(default)]
+    insecure_skip_tls_verify: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct NamedUser {
+    name: String,
+    user: KubeconfigUser,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct KubeconfigUser {
+    token: Option<String>,
+    #[serde(rename = "tokenFile")]
+    token_file: Option<PathBuf>,
+    username: Option<String>,
+    password: Option<String>,
+    client_certificate: Option<PathBuf>,
+    client_certificate_data: Option<String>,
+    client_key: Option<PathBuf>,
+    client_key_data: Option<String>,
+    exec: Option<serde_yaml::Value>,
+    auth_provider: Option<serde_yaml::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct NamedContext {
+    name: String,
+    context: KubeconfigContext,
+}
+
+#[derive(Debug, Deserialize)]
+struct KubeconfigContext {
+    cluster: String,
+    user: Option<String>,
+}
+
+/// How requests prove who they're from, other than with a client certificate
+#[derive(Clone, Debug, PartialEq)]
+enum Credentials {
+    None,
+    Token(String),
+    /// Read for every check, as service account tokens are rotated
+    TokenFile(PathBuf),
+    Basic(String),
+}
+
+/// A cluster and how to talk to it
+struct Cluster {
+    server: String,
+    tls: Option<Arc<ClientConfig>>,
+    credentials: Credentials,
+}
+
+impl Cluster {
+    /// Loads the cluster from a kubeconfig, or finds it the same way as `kubectl`
+    fn load(kubeconfig: Option<&Path>, context: Option<&str>) -> Result<Cluster, String> {
+        if let Some(path) = kubeconfig {
+            return Cluster::from_kubeconfig(path, context);
+        }
+        let home = env::var_os("HOME").map(|home| PathBuf::from(home).join(".kube/config"));
+        let kubeconfig = env::var_os("KUBECONFIG")
+            // Only the first of several kubeconfigs is read
+            .and_then(|paths| env::split_paths(&paths).next())
+            .or_else(|| home.filter(|path| path.exists()))
+            .or_else(|| Some(PathBuf::from(K3S_KUBECONFIG)).filter(|path| path.exists()));
+        match kubeconfig {
+            Some(path) => Cluster::from_kubeconfig(&path, context),
+            None => Cluster::in_cluster(),
+        }
+    }
+
+    fn in_cluster() -> Result<Cluster, String> {
+        let (host, port) = match (
+            env::var("KUBERNETES_SERVICE_HOST"),
+            env::var("KUBERNETES_SERVICE_PORT"),
+        ) {
+            (Ok(host), Ok(port)) => (host, port),
+            _ => return Err("No kubeconfig found, and not running in a cluster".to_string()),
+        };
+        let host = match host.contains(':') {
+            true => format!("[{}]", host),
+            false => host,
+        };
+        let dir = Path::new(SERVICE_ACCOUNT);
+        let ca = read(&dir.join("ca.crt"))?;
+        Ok(Cluster {
+            server: format!("https://{}:{}", host, port),
+            tls: Some(tls_config(Some(&ca), None)?),
+            credentials: Credentials::TokenFile(dir.join("token")),
+        })
+    }
+
+    fn from_kubeconfig(path: &Path, context: Option<&str>) -> Result<Cluster, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read '{}': {}", path.display(), e))?;
+        let kubeconfig: Kubeconfig = serde_yaml::from_str(&contents)
+            .map_err(|e| format!("Invalid kubeconfig '{}': {}", path.display(), e))?;
+        // Paths in a kubeconfig are relative to it
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        Cluster::from_parsed(kubeconfig, context, dir)
+    }
+
+    fn from_parsed(
+        kubeconfig: Kubeconfig,
+        context: Option<&str>,
+        dir: &Path,
+    ) -> Result<Cluster, String> {
+        let context_name = context
+            .or(kubeconfig.current_context.as_deref())
+            .ok_or("The kubeconfig has no current context")?;
+        let context = kubeconfig
+            .contexts
+            .iter()
+            .find(|context| context.name == context_name)
+            .map(|context| &context.context)
+            .ok_or_else(|| format!("The kubeconfig has no context '{}'", context_name))?;
+        let cluster = kubeconfig
+            .clusters
+            .iter()
+            .find(|cluster| cluster.name == context.cluster)
+            .map(|cluster| &cluster.cluster)
+            .ok_or_else(|| format!("The kubeconfig has no cluster '{}'", context.cluster))?;
+        let default_user = KubeconfigUser::default();
+        let user = match &context.user {
+            Some(name) => kubeconfig
+                .users
+                .iter()
+                .find(|user| &user.name == name)
+                .map(|user| &user.user)
+                .ok_or_else(|| format!("The kubeconfig has no user '{}'", name))?,
+            None => &default_user,
+        };
+        if user.exec.is_some() || user.auth_provider.is_some() {
+            return Err("Credential plugins in kubeconfigs aren't supported".to_string());
+        }
+        if cluster.insecure_skip_tls_verify {
+            return Err("Clusters with insecure-skip-tls-verify aren't supported".to_string());
+        }
+
+        let ca = data_or_file(
+            &cluster.certificate_authority_data,
+            &cluster.certificate_authority,
+            dir,
+        )?;
+        let cert = data_or_file(&user.client_certificate_data, &user.client_certificate, dir)?;
+        let key = data_or_file(&user.client_key_data, &user.client_key, dir)?;
+        let client_auth = match (cert, key) {
+            (Some(cert), Some(key)) => Some((cert, key)),
+            (None, None) => None,
+            _ => return Err("The kubeconfig's user needs both a certificate and a key".to_string()),
+        };
+        let tls = match (&ca, &client_auth) {
+            (None, None) => None,
+            (ca, client_auth) => Some(tls_config(ca.as_deref(), client_auth.as_ref())?),
+        };
+        let credentials = match (&user.token, &user.token_file, &user.username) {
+            (Some(token), _, _) => Credentials::Token(token.clone()),
+            (None, Some(file), _) => Credentials::TokenFile(dir.join(file)),
+            (None, None, Some(username)) => Credentials::Basic(STANDARD.encode(format!(
+                "{}:{}",
+                username,
+                user.password.as_deref().unwrap_or_default()
+            ))),
+            (None, None, None) => Credentials::None,
+        };
+        Ok(Cluster {
+            server: cluster.server.trim_end_matches('/').to_string(),
+            tls,
+            credentials,
+        })
+    }
+
+    fn client(&self, timeout: Duration) -> Client<'_> {
+        let mut agent = ureq::AgentBuilder::new().timeout(timeout);
+        if let Some(tls) = &self.tls {
+            agent = agent.tls_config(Arc::clone(tls));
+        }
+        Client {
+            cluster: self,
+            agent: agent.build(),
+        }
+    }
+}
+
+/// Makes requests to a cluster
+struct Client<'a> {
+    cluster: &'a Cluster,
+    agent: ureq::Agent,
+}
+
+impl Client<'_> {
+    fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T, CheckResult> {
+        let mut request = self.agent.get(&format!("{}{}", self.cluster.server, path));
+        let authorization = match &self.cluster.credentials {
+            Credentials::None => None,
+            Credentials::Token(token) => Some(format!("Bearer {}", token)),
+            Credentials::TokenFile(path) => {
+                let token = read(path).map_err(|e| CheckResult::new(Status::Unknown, e))?;
+                Some(format!("Bearer {}", String::from_utf8_lossy(&token).trim()))
+            }
+            Credentials::Basic(encoded) => Some(format!("Basic {}", encoded)),
+        };
+        if let Some(authorization) = authorization {
+            request = request.set("Authorization", &authorization);
+        }
+        let response = match request.call() {
+            Ok(response) => response,
+            Err(ureq::Error::Status(status @ (401 | 403), _)) => {
+                return Err(CheckResult::new(
+                    Status::Unknown,
+                    format!("Not allowed to read {} ({})", path, status),
+                ))
+            }
+            Err(e) => return Err(CheckResult::new(Status::Critical, e.to_string())),
+        };
+        response.into_json().map_err(|e| {
+            CheckResult::new(
+                Status::Unknown,
+                format!("Unexpected response from {}: {}", path, e),
+            )
+        })
+    }
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, String> {
+    fs::read(path).map_err(|e| format!("Unable to read '{}': {}", path.display(), e))
+}
+
+/// Kubeconfigs have either base64 encoded data or a path for certificates and keys
+fn data_or_file(
+    data: &Option<String>,
+    file: &Option<PathBuf>,
+    dir: &Path,
+) -> Result<Option<Vec<u8>>, String> {
+    match (data, file) {
+        (Some(data), _) => STANDARD
+            .decode(data.trim())
+            .map(Some)
+            .map_err(|e| format!("Invalid base64 in the kubeconfig: {}", e)),
+        (None, Some(file)) => read(&dir.join(file)).map(Some),
+        (None, None) => Ok(None),
+    }
+}
+
+/// TLS trusting the cluster's CA, if it has one, and presenting the client certificate, if there
+/// is one
+fn tls_config(
+    ca: Option<&[u8]>,
+    client_auth: Option<&(Vec<u8>, Vec<u8>)>,
+) -> Result<Arc<ClientConfig>, String> {
+    let ca = ca.ok_or("Client certificates need the cluster's certificate authority")?;
+    let mut roots = RootCertStore::empty();
+    for cert in pem_certs(ca)? {
+        roots
+            .add(cert)
+            .map_err(|e| format!("Invalid certificate authority: {}", e))?;
+    }
+    let builder =
+        ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| e.to_string())?
+            .with_root_certificates(roots);
+    let config = match client_auth {
+        Some((cert, key)) => {
+            let key: PrivateKeyDer<'static> = rustls_pemfile::private_key(&mut &key[..])
+                .map_err(|e| format!("Invalid client key: {}", e))?
+                .ok_or("No private key in the client key")?;
+            builder
+                .with_client_auth_cert(pem_certs(cert)?, key)
+                .map_err(|e| format!("Invalid client certificate: {}", e))?
+        }
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
+
+fn pem_certs(pem: &[u8]) -> Result<Vec<CertificateDer<'static>>, String> {
+    let certs = rustls_pemfile::certs(&mut &pem[..])
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Invalid certificate: {}", e))?;
+    match certs.is_empty() {
+        true => Err("No certificates found in the kubeconfig".to_string()),
+        false => Ok(certs),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::http::{self, Response};
+    use crate::tls::TlsConfig;
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+    use serde_json::json;
+
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn parse(kubeconfig: &str, context: Option<&str>) -> Result<Cluster, String> {
+        let kubeconfig = serde_yaml::from_str(kubeconfig).unwrap();
+        Cluster::from_parsed(kubeconfig, context, Path::new("/home/pi/.kube"))
+    }
+
+    fn kubeconfig(server: &str, user: &str) -> String {
+        format!(
+            "apiVersion: v1\n\
+             kind: Config\n\
+             clusters:\n\
+             - name: home\n  cluster:\n    server: {}\n\
+             users:\n\
+             - name: admin\n  user:\n    {}\n\
+             contexts:\n\
+             - name: home\n  context:\n    cluster: home\n    user: admin\n\
+             current-context: home\n",
+            server, user
+        )
+    }
+
+    fn check(path: PathBuf, namespaces: &[&str]) -> KubernetesCheck {
+        KubernetesCheck {
+            kubeconfig: Some(path),
+            context: None,
+            nodes: true,
+            namespaces: namespaces.iter().map(|ns| ns.to_string()).collect(),
+        }
+    }
+
+    fn write(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "status_lights_kubernetes_{}_{}",
+            name,
+            std::process::id()
+        ));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_kubeconfig() {
+        let cluster = parse(&kubeconfig("http://pi-1:6443/", "token: abc"), None).unwrap();
+        assert_eq!(cluster.server, "http://pi-1:6443");
+        assert_eq!(cluster.credentials, Credentials::Token("abc".to_string()));
+        assert!(cluster.tls.is_none());
+
+        let cluster = parse(&kubeconfig("http://pi-1:6443", "tokenFile: token"), None).unwrap();
+        assert_eq!(
+            cluster.credentials,
+            Credentials::TokenFile(PathBuf::from("/home/pi/.kube/token"))
+        );
+        let basic = "username: admin\n    password: secret";
+        let cluster = parse(&kubeconfig("http://pi-1:6443", basic), None).unwrap();
+        assert_eq!(
+            cluster.credentials,
+            Credentials::Basic("YWRtaW46c2VjcmV0".to_string())
+        );
+
+        let error = |kubeconfig: &str, context| parse(kubeconfig, context).err().unwrap();
+        assert_eq!(
+            error(&kubeconfig("http://pi-1:6443", "token: abc"), Some("work")),
+            "The kubeconfig has no context 'work'"
+        );
+        assert_eq!(
+            error(
+                &kubeconfig("http://pi-1:6443", "exec:\n      command: aws"),
+                None
+            ),
+            "Credential plugins in kubeconfigs aren't supported"
+        );
+        assert!(error(
+            &kubeconfig("http://pi-1:6443", "client-certificate-data: AAAA"),
+            None
+        )
+        .contains("both a certificate and a key"));
+        assert!(error(
+            &kubeconfig("http://pi-1:6443", "token: abc")
+                .replace("certificate-authority", "")
+                .replace("server: ", "certificate-authority-data: '!'\n    server: "),
+            None
+        )
+        .starts_with("Invalid base64"));
+    }
+
+    #[test]
+    fn test_cluster() {
+        let node_ready = Arc::new(AtomicBool::new(true));
+        let web_ready = Arc::new(AtomicBool::new(true));
+        let (node, web) = (Arc::clone(&node_ready), Arc::clone(&web_ready));
+        let handler = move |request: &http::Request| {
+            if request.header("authorization") != Some("Bearer abc") {
+                return Response::json(401, &json!({"kind": "Status", "code": 401}));
+            }
+            let ready = |ready: bool| match ready {
+                true => json!({"conditions": [{"type": "Ready", "status": "True"}]}),
+                false => json!({"conditions": [{"type": "Ready", "status": "False"}]}),
+            };
+            let web_ready = web.load(Ordering::SeqCst);
+            let items = match request.path.as_str() {
+                "/api/v1/nodes" => json!([
+                    {"metadata": {"name": "pi-1"}, "status": ready(true)},
+                    {"metadata": {"name": "pi-2"}, "status": ready(node.load(Ordering::SeqCst))},
+                ]),
+                "/apis/apps/v1/namespaces/default/deployments" => json!([{
+                    "metadata": {"name": "web"},
+                    "spec": {"replicas": 2},
+                    "status": {"readyReplicas": if web_ready { 2 } else { 1 }},
+                }]),
+                "/api/v1/namespaces/default/pods" => json!([
+                    {"metadata": {"name": "web-1"}, "status": ready(true)},
+                    {"metadata": {"name": "web-2"}, "status": ready(web_ready)},
+                    {"metadata": {"name": "migrate"}, "status": {"phase": "Succeeded"}},
+                ]),
+                _ => return Response::json(404, &json!({"kind": "Status", "code": 404})),
+            };
+            Response::json(200, &json!({ "items": items }))
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = format!("http://{}", listener.local_addr().unwrap());
+        thread::spawn(move || http::serve(listener, None, Arc::new(handler)));
+
+        let path = write("config", &kubeconfig(&server, "token: abc"));
+        let run = |namespaces: &[&str]| {
+            let result = check(path.clone(), namespaces).run(TIMEOUT);
+            (result.status, result.summary)
+        };
+        assert_eq!(run(&[]), (Status::Ok, "2 nodes ready".to_string()));
+        assert_eq!(
+            run(&["default"]),
+            (
+                Status::Ok,
+                "2 nodes, 1 deployment, 3 pods ready".to_string()
+            )
+        );
+
+        web_ready.store(false, Ordering::SeqCst);
+        assert_eq!(
+            run(&["default"]),
+            (
+                Status::Warning,
+                "Not ready: deployment default/web (1/2), pod default/web-2".to_string()
+            )
+        );
+        node_ready.store(false, Ordering::SeqCst);
+        assert_eq!(
+            run(&["default"]),
+            (
+                Status::Critical,
+                "Not ready: node pi-2, deployment default/web (1/2), pod default/web-2".to_string()
+            )
+        );
+
+        fs::write(&path, kubeconfig(&server, "token: wrong")).unwrap();
+        assert_eq!(
+            run(&[]),
+            (
+                Status::Unknown,
+                "Not allowed to read /api/v1/nodes (401)".to_string()
+            )
+        );
+        fs::write(&path, kubeconfig(&server, "token: abc")).unwrap();
+        let result = check(path.clone(), &["missing"]).run(TIMEOUT);
+        assert_eq!(result.status, Status::Critical);
+        fs::remove_file(&path).unwrap();
+
+        let result = check(PathBuf::from("/nonexistent/kubeconfig"), &[]).run(TIMEOUT);
+        assert_eq!(result.status, Status::Unknown);
+    }
+
+    /// k3s's kubeconfig has the CA, client certificate and key inline
+    #[test]
+    fn test_client_certificate() {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        let signed = |name: &str| {
+            let key = KeyPair::generate().unwrap();
+            let cert = CertificateParams::new(vec![name.to_string()])
+                .unwrap()
+                .signed_by(&key, &ca, &ca_key)
+                .unwrap();
+            (cert, key)
+        };
+        let (server_cert, server_key) = signed("127.0.0.1");
+        let (client_cert, client_key) = signed("system:admin");
+
+        let tls = TlsConfig {
+            cert: write("server_cert", &server_cert.pem()),
+            key: write("server_key", &server_key.serialize_pem()),
+            client_ca: Some(write("ca", &ca.pem())),
+        };
+        let server_config = tls.server_config().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handler = |_: &http::Request| {
+            let node = json!({
+                "metadata": {"name": "pi-1"},
+                "status": {"conditions": [{"type": "Ready", "status": "True"}]},
+            });
+            Response::json(200, &json!({ "items": [node] }))
+        };
+        thread::spawn(move || http::serve(listener, Some(server_config), Arc::new(handler)));
+
+        let encode = |pem: String| STANDARD.encode(pem);
+        let kubeconfig = format!(
+            "clusters:\n\
+             - name: default\n  cluster:\n    server: https://127.0.0.1:{}\n    \
+             certificate-authority-data: {}\n\
+             users:\n\
+             - name: default\n  user:\n    client-certificate-data: {}\n    \
+             client-key-data: {}\n\
+             contexts:\n\
+             - name: default\n  context:\n    cluster: default\n    user: default\n\
+             current-context: default\n",
+            port,
+            encode(ca.pem()),
+            encode(client_cert.pem()),
+            encode(client_key.serialize_pem()),
+        );
+        let path = write("k3s", &kubeconfig);
+        let result = check(path.clone(), &[]).run(TIMEOUT);
+        assert_eq!(result.status, Status::Ok, "{}", result.summary);
+        assert_eq!(result.summary, "1 node ready");
+
+        // Without the client certificate the server won't accept the connection
+        let without_cert = kubeconfig
+            .lines()
+            .filter(|line| !line.contains("client-"))
+            .collect::<Vec<_>>()
+            .join("\n")
+            .replace("user:\n    \n", "user: {}\n");
+        fs::write(&path, without_cert).unwrap();
+        assert_eq!(
+            check(path.clone(), &[]).run(TIMEOUT).status,
+            Status::Critical
+        );
+
+        for path in [path, tls.cert, tls.key, tls.client_ca.unwrap()] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+}
@@ -1,3 +1,35 @@
+pub mod alertmanager;
+pub mod api;
+pub mod args;
+pub mod auth;
+pub mod claims;
+pub mod config;
+pub mod daemon;
+pub mod device;
+pub mod effect;
+pub mod gradient;
+pub mod heartbeat;
+pub mod home_assistant;
+pub mod http;
+pub mod ipc;
+pub mod kubernetes;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+pub mod monitor;
+pub mod mqtt;
+pub mod nagios;
+pub mod output;
+pub mod progress;
+pub mod prometheus;
+pub mod report;
+pub mod selector;
 mod serial;
+pub mod state;
+pub mod sysmon;
+pub mod tls;
+#[cfg(target_os = "linux")]
+mod udev;
+pub mod watcher;
+pub mod wrapper;
 
 pub use serial::{Client, ClientError};
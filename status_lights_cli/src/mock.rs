@@ -0,0 +1,246 @@
+//! Stand ins for real devices, so that anything built on `Device` can be tested without a USB
+//! device plugged in.
+
+use status_lights_messages::{
+    ErrorResponse, LedColor, LedColorTimed, ResponseError, Rgb, VersionNumber,
+};
+
+use std::collections::BTreeMap;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::device::{Device, DeviceSource, FIRST_UPTIME_FIRMWARE};
+use crate::output::DeviceInfo;
+use crate::selector::Selectable;
+use crate::watcher::DeviceChange;
+use crate::ClientError;
+
+/// The number of leds on a mock device, matching the Neo Trinkey
+pub const MOCK_LEDS: usize = crate::device::LEDS_PER_DEVICE as usize;
+
+/// What a mock device has been told
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MockState {
+    pub background: [Rgb; MOCK_LEDS],
+    pub foreground: [Rgb; MOCK_LEDS],
+    pub foreground_seconds: [u8; MOCK_LEDS],
+    /// The number of requests to change an led the device has answered
+    pub requests: usize,
+    /// When set, every request times out
+    pub unresponsive: bool,
+    /// What the device answers when asked for its uptime
+    pub uptime: Duration,
+    /// The number of times the device has been asked for its uptime, which the daemon does on its
+    /// own
+    pub uptime_requests: usize,
+    /// The firmware version the device reports, if it isn't the current one
+    pub version: Option<(u8, u8, u8)>,
+}
+
+/// A device that keeps its state in memory. Clones share the same state, so a test can keep one
+/// to inspect while another is used by the code under test.
+#[derive(Clone, Debug)]
+pub struct MockDevice {
+    path: String,
+    serial: Option<String>,
+    usb_port: Option<String>,
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockDevice {
+    pub fn new(path: &str) -> MockDevice {
+        MockDevice {
+            path: path.to_string(),
+            serial: None,
+            usb_port: None,
+            state: Arc::default(),
+        }
+    }
+
+    pub fn with_serial(mut self, serial: &str) -> MockDevice {
+        self.serial = Some(serial.to_string());
+        self
+    }
+
+    pub fn with_usb_port(mut self, usb_port: &str) -> MockDevice {
+        self.usb_port = Some(usb_port.to_string());
+        self
+    }
+
+    pub fn state(&self) -> MockState {
+        self.state.lock().unwrap().clone()
+    }
+
+    pub fn set_unresponsive(&self, unresponsive: bool) {
+        self.state.lock().unwrap().unresponsive = unresponsive;
+    }
+
+    /// Reports a firmware version other than the current one, as if the device had been flashed
+    pub fn set_version(&self, major: u8, minor: u8, patch: u8) {
+        self.state.lock().unwrap().version = Some((major, minor, patch));
+    }
+
+    fn version(&self) -> (u8, u8, u8) {
+        self.state.lock().unwrap().version.unwrap_or((0, 2, 0))
+    }
+
+    pub fn set_uptime(&self, uptime: Duration) {
+        self.state.lock().unwrap().uptime = uptime;
+    }
+
+    /// Forgets every led, like a real device does when it restarts
+    pub fn restart(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.background = Default::default();
+        state.foreground = Default::default();
+        state.foreground_seconds = Default::default();
+        state.uptime = Duration::from_secs(0);
+    }
+
+    fn request<T>(
+        &mut self,
+        id: u8,
+        led: u8,
+        f: impl FnOnce(&mut MockState, usize) -> T,
+    ) -> Result<T, ClientError> {
+        let mut state = self.state.lock().unwrap();
+        if state.unresponsive {
+            return Err(ClientError::Timeout {
+                path: self.path.clone(),
+            });
+        }
+        if led as usize >= MOCK_LEDS {
+            return Err(ClientError::ErrorReceivedFromDevice {
+                path: self.path.clone(),
+                error: ResponseError::ErrorResponse(ErrorResponse::MalformedRequestForId(id)),
+            });
+        }
+        state.requests += 1;
+        Ok(f(&mut state, led as usize))
+    }
+}
+
+impl Selectable for MockDevice {
+    fn path(&self) -> &str {
+        &self.path
+    }
+
+    fn name(&self) -> &str {
+        "Mock Status Lights"
+    }
+
+    fn serial(&self) -> Option<&str> {
+        self.serial.as_deref()
+    }
+
+    fn usb_port(&self) -> Option<String> {
+        self.usb_port.clone()
+    }
+}
+
+impl Device for MockDevice {
+    fn request_version(&mut self) -> Result<VersionNumber, ClientError> {
+        let (major, minor, patch) = self.version();
+        if self.state.lock().unwrap().unresponsive {
+            return Err(ClientError::Timeout {
+                path: self.path.clone(),
+            });
+        }
+        Ok(VersionNumber::new(major, minor, patch))
+    }
+
+    fn request_background(&mut self, led_color: LedColor) -> Result<(), ClientError> {
+        self.request(2, led_color.led, |state, led| {
+            state.background[led] = led_color.color
+        })
+    }
+
+    fn request_foreground(&mut self, led_color_timed: LedColorTimed) -> Result<(), ClientError> {
+        self.request(3, led_color_timed.led, |state, led| {
+            state.foreground[led] = led_color_timed.color;
+            state.foreground_seconds[led] = led_color_timed.seconds;
+        })
+    }
+
+    fn request_uptime(&mut self) -> Result<Duration, ClientError> {
+        let old_firmware = self.version() < FIRST_UPTIME_FIRMWARE;
+        let mut state = self.state.lock().unwrap();
+        if state.unresponsive {
+            return Err(ClientError::Timeout {
+                path: self.path.clone(),
+            });
+        }
+        state.uptime_requests += 1;
+        if old_firmware {
+            return Err(ClientError::ErrorReceivedFromDevice {
+                path: self.path.clone(),
+                error: ResponseError::ErrorResponse(ErrorResponse::UnknownRequestId(4)),
+            });
+        }
+        Ok(state.uptime)
+    }
+
+    fn describe(&self) -> DeviceInfo {
+        DeviceInfo {
+            path: self.path.clone(),
+            name: Some(self.name().to_string()),
+            serial: self.serial.clone(),
+            usb_port: self.usb_port.clone(),
+            ..DeviceInfo::default()
+        }
+    }
+}
+
+/// A set of mock devices that can be plugged in and unplugged, which says when they are
+#[derive(Clone, Debug, Default)]
+pub struct MockSource {
+    devices: Arc<Mutex<BTreeMap<String, MockDevice>>>,
+    watchers: Arc<Mutex<Vec<Sender<DeviceChange>>>>,
+}
+
+impl MockSource {
+    pub fn new(devices: Vec<MockDevice>) -> MockSource {
+        let source = MockSource::default();
+        devices.into_iter().for_each(|device| source.plug(device));
+        source
+    }
+
+    pub fn plug(&self, device: MockDevice) {
+        let change = DeviceChange::Added(device.path.clone());
+        self.devices
+            .lock()
+            .unwrap()
+            .insert(device.path.clone(), device);
+        self.notify(change);
+    }
+
+    pub fn unplug(&self, path: &str) {
+        self.devices.lock().unwrap().remove(path);
+        self.notify(DeviceChange::Removed(path.to_string()));
+    }
+
+    fn notify(&self, change: DeviceChange) {
+        self.watchers
+            .lock()
+            .unwrap()
+            .retain(|watcher| watcher.send(change.clone()).is_ok());
+    }
+}
+
+impl DeviceSource for MockSource {
+    fn attached(&self) -> Vec<String> {
+        self.devices.lock().unwrap().keys().cloned().collect()
+    }
+
+    fn open(&self, path: &str) -> Option<Box<dyn Device>> {
+        let device = self.devices.lock().unwrap().get(path)?.clone();
+        Some(Box::new(device))
+    }
+
+    fn watch(&self) -> Option<Receiver<DeviceChange>> {
+        let (sender, receiver) = mpsc::channel();
+        self.watchers.lock().unwrap().push(sender);
+        Some(receiver)
+    }
+}
@@ -0,0 +1,960 @@
+//! Runs health checks and shows their results on the leds, replacing cron jobs that ping
+//! something and set the foreground.
+//!
+//! Checks are listed in the config file, each with the led (and optionally the devices, using
+//! the same glob patterns as tokens) its result is shown on:
+//!
+//! ```toml
+//! [[monitor.checks]]
+//! name = "internet"
+//! type = "ping"
+//! host = "1.1.1.1"
+//! led = 0
+//!
+//! [[monitor.checks]]
+//! name = "website"
+//! type = "http"
+//! url = "https://example.com/health"
+//! body = "ok"
+//! interval = "5m"
+//! led = 1
+//! ```
+//!
+//! Every check comes out with one of the same statuses as a Nagios plugin, and each status has
+//! its own color or effect. Built in checks are either `OK` or `CRITICAL`, while `nagios` checks
+//! run monitoring plugins, see `nagios`. `prometheus` checks can pick their own color from a
+//! gradient, see `prometheus`. Checks sharing an led show the worst status of any of
+//! them, using the first of those checks listed. Every result is sent to the devices again, so a
+//! device that's plugged back in catches up with the next check.
+
+use glob::Pattern;
+use serde::Deserialize;
+use status_lights_messages::{LedColorTimed, Rgb};
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Read;
+use std::net::{IpAddr, TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::args;
+use crate::config::ConfigError;
+use crate::device::LEDS_PER_DEVICE;
+use crate::effect::Effect;
+use crate::ipc::{Action, DaemonRequest, DaemonResponse, LedEffect};
+use crate::kubernetes::KubernetesCheck;
+use crate::nagios::{self, PerfData};
+use crate::prometheus::PrometheusCheck;
+use crate::report::{Outcome, Report};
+use crate::selector::DeviceSelector;
+use crate::sysmon::SystemCheck;
+
+/// How often to see whether a command has finished
+const COMMAND_POLL: Duration = Duration::from_millis(20);
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MonitorConfig {
+    pub checks: Vec<CheckConfig>,
+}
+
+impl MonitorConfig {
+    /// Makes paths in checks relative to `dir`, the directory the config file is in
+    pub fn resolve_paths(&mut self, dir: &Path) {
+        for config in &mut self.checks {
+            config.check.resolve_paths(dir);
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct CheckConfig {
+    /// Used when logging the check's results
+    pub name: String,
+    #[serde(flatten)]
+    pub check: Check,
+    /// How long to wait between checks
+    #[serde(default = "default_interval", with = "humantime_serde")]
+    pub interval: Duration,
+    /// How long a check can take before it's `CRITICAL`
+    #[serde(default = "default_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+    /// A glob pattern matched against each device's id, path, serial number and USB port. The
+    /// result is shown on every device if this isn't set.
+    pub device: Option<String>,
+    pub led: u8,
+    #[serde(default = "default_ok")]
+    pub ok: Indicator,
+    #[serde(default = "default_warning")]
+    pub warning: Indicator,
+    #[serde(default = "default_critical")]
+    pub critical: Indicator,
+    #[serde(default = "default_unknown")]
+    pub unknown: Indicator,
+}
+
+impl CheckConfig {
+    fn indicator(&self, status: Status) -> &Indicator {
+        match status {
+            Status::Ok => &self.ok,
+            Status::Warning => &self.warning,
+            Status::Critical => &self.critical,
+            Status::Unknown => &self.unknown,
+        }
+    }
+}
+
+fn default_interval() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn default_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_ok() -> Indicator {
+    Indicator::Color(Rgb::new(0, 128, 0).scale(0.1))
+}
+
+fn default_warning() -> Indicator {
+    Indicator::Color(Rgb::new(255, 165, 0).scale(0.1))
+}
+
+fn default_critical() -> Indicator {
+    Indicator::Color(Rgb::new(255, 0, 0).scale(0.1))
+}
+
+fn default_unknown() -> Indicator {
+    Indicator::Color(Rgb::new(128, 0, 128).scale(0.1))
+}
+
+/// What an led shows for a status, either a color or an effect such as
+/// `{ effect = "blink", color = "red" }`
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum Indicator {
+    Color(#[serde(deserialize_with = "args::deserialize_color")] Rgb),
+    Effect(Effect),
+}
+
+impl Indicator {
+    pub fn action(&self, led: u8) -> Action {
+        match self {
+            Indicator::Color(color) => Action::Foreground(LedColorTimed::new(led, *color, 0)),
+            Indicator::Effect(effect) => Action::Effect(LedEffect {
+                led,
+                effect: Some(effect.clone()),
+            }),
+        }
+    }
+}
+
+/// How a check came out, using the same statuses as Nagios plugins. They're ordered from best to
+/// worst.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Status {
+    Ok,
+    Unknown,
+    Warning,
+    Critical,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Status::Ok => "OK",
+            Status::Unknown => "UNKNOWN",
+            Status::Warning => "WARNING",
+            Status::Critical => "CRITICAL",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Limits for a value, such as `{ warning = 80, critical = 95 }`. They're upper limits unless
+/// `critical` is below `warning`, in which case they're lower limits.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Thresholds {
+    pub warning: Option<f64>,
+    pub critical: Option<f64>,
+}
+
+impl Thresholds {
+    pub fn new(warning: Option<f64>, critical: Option<f64>) -> Thresholds {
+        Thresholds { warning, critical }
+    }
+
+    fn lower_is_worse(&self) -> bool {
+        matches!((self.warning, self.critical), (Some(warning), Some(critical)) if critical < warning)
+    }
+
+    /// Orders values from best to worst
+    pub fn compare(&self, a: f64, b: f64) -> Ordering {
+        match self.lower_is_worse() {
+            true => b.total_cmp(&a),
+            false => a.total_cmp(&b),
+        }
+    }
+
+    /// `CRITICAL` past the critical limit, `WARNING` past the warning limit, otherwise `OK`
+    pub fn status(&self, value: f64) -> Status {
+        let beyond = |threshold: Option<f64>| {
+            threshold.is_some_and(|threshold| self.compare(value, threshold) == Ordering::Greater)
+        };
+        match (beyond(self.critical), beyond(self.warning)) {
+            (true, _) => Status::Critical,
+            (false, true) => Status::Warning,
+            (false, false) => Status::Ok,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CheckResult {
+    pub status: Status,
+    /// What the check said about its status, empty for built in checks that passed
+    pub summary: String,
+    pub perfdata: Vec<PerfData>,
+    /// A color the check picked for its result, shown instead of the status's
+    pub color: Option<Rgb>,
+}
+
+impl CheckResult {
+    pub fn new(status: Status, summary: String) -> CheckResult {
+        CheckResult {
+            status,
+            summary,
+            perfdata: Vec::new(),
+            color: None,
+        }
+    }
+}
+
+impl From<Result<(), String>> for CheckResult {
+    fn from(result: Result<(), String>) -> Self {
+        match result {
+            Ok(()) => CheckResult::new(Status::Ok, String::new()),
+            Err(reason) => CheckResult::new(Status::Critical, reason),
+        }
+    }
+}
+
+/// What to check, picked by the check's `type`
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Check {
+    /// Sends a single ICMP echo request using the system's `ping`, which is allowed to send them
+    /// without running as root
+    Ping { host: String },
+    /// Opens a TCP connection to `host:port`
+    Tcp { address: String },
+    /// Makes a GET request, which passes with a status below 400 or the `status` given, and with
+    /// a body containing `body` if it's set
+    Http {
+        url: String,
+        status: Option<u16>,
+        body: Option<String>,
+    },
+    /// Resolves a name, which passes if it resolves to anything or to `address` if it's set
+    Dns {
+        name: String,
+        address: Option<IpAddr>,
+    },
+    /// Runs a command with `sh -c`, which passes if it exits with 0
+    Command { command: String },
+    /// Runs a Nagios plugin with `sh -c`, taking the status from its exit code
+    Nagios { command: String },
+    /// Checks the nodes, and optionally the workloads, of a Kubernetes cluster
+    Kubernetes(KubernetesCheck),
+    /// Compares the value of a PromQL query to thresholds
+    Prometheus(PrometheusCheck),
+    /// Compares one of this machine's metrics, such as its load or temperature, to thresholds
+    System(SystemCheck),
+}
+
+impl Check {
+    pub fn run(&self, timeout: Duration) -> CheckResult {
+        match self {
+            Check::Nagios { command } => nagios::run(command, timeout),
+            Check::Kubernetes(kubernetes) => kubernetes.run(timeout),
+            Check::Prometheus(prometheus) => prometheus.run(timeout),
+            Check::System(system) => system.run(),
+            check => check.run_built_in(timeout).into(),
+        }
+    }
+
+    /// Makes paths in the check relative to `dir`
+    pub fn resolve_paths(&mut self, dir: &Path) {
+        if let Check::Kubernetes(kubernetes) = self {
+            kubernetes.resolve_paths(dir);
+        }
+    }
+
+    /// Runs a built in check, giving the reason if it fails
+    fn run_built_in(&self, timeout: Duration) -> Result<(), String> {
+        match self {
+            Check::Ping { host } => {
+                // ping's timeout is in whole seconds
+                let seconds = (timeout.as_secs_f64().ceil() as u64).max(1);
+                let output = run_command(
+                    Command::new("ping")
+                        .args(["-c", "1", "-W", &seconds.to_string()])
+                        .arg(host),
+                    timeout,
+                )?;
+                match output.status.success() {
+                    true => Ok(()),
+                    false => Err(output.last_line()),
+                }
+            }
+            Check::Tcp { address } => {
+                let addresses = address
+                    .to_socket_addrs()
+                    .map_err(|e| format!("Unable to resolve '{}': {}", address, e))?;
+                let mut error = format!("'{}' didn't resolve to any addresses", address);
+                for socket_address in addresses {
+                    match TcpStream::connect_timeout(&socket_address, timeout) {
+                        Ok(_) => return Ok(()),
+                        Err(e) => error = format!("Unable to connect to '{}': {}", address, e),
+                    }
+                }
+                Err(error)
+            }
+            Check::Http { url, status, body } => {
+                let agent = ureq::AgentBuilder::new().timeout(timeout).build();
+                let response = match agent.get(url).call() {
+                    Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+                    Err(e) => return Err(e.to_string()),
+                };
+                let code = response.status();
+                match status {
+                    Some(status) if code != *status => {
+                        return Err(format!("Expected status {} but got {}", status, code))
+                    }
+                    None if code >= 400 => return Err(format!("Got status {}", code)),
+                    _ => {}
+                }
+                if let Some(body) = body {
+                    let text = response
+                        .into_string()
+                        .map_err(|e| format!("Unable to read the response: {}", e))?;
+                    if !text.contains(body.as_str()) {
+                        return Err(format!("The response doesn't contain '{}'", body));
+                    }
+                }
+                Ok(())
+            }
+            Check::Dns { name, address } => {
+                // The system resolver can't be given a timeout, so it's left running if it takes
+                // too long
+                let (sender, receiver) = mpsc::channel();
+                let lookup = name.clone();
+                thread::spawn(move || {
+                    let addresses = (lookup.as_str(), 0)
+                        .to_socket_addrs()
+                        .map(|addresses| addresses.map(|a| a.ip()).collect::<Vec<_>>());
+                    let _ = sender.send(addresses);
+                });
+                let addresses = receiver
+                    .recv_timeout(timeout)
+                    .map_err(|_| format!("Timed out resolving '{}'", name))?
+                    .map_err(|e| format!("Unable to resolve '{}': {}", name, e))?;
+                match address {
+                    Some(address) if !addresses.contains(address) => Err(format!(
+                        "'{}' resolved to {:?} instead of {}",
+                        name, addresses, address
+                    )),
+                    None if addresses.is_empty() => {
+                        Err(format!("'{}' didn't resolve to any addresses", name))
+                    }
+                    _ => Ok(()),
+                }
+            }
+            Check::Command { command } => {
+                let output = run_command(Command::new("sh").arg("-c").arg(command), timeout)?;
+                match output.status.success() {
+                    true => Ok(()),
+                    false => Err(format!("{}: {}", output.status, output.last_line())),
+                }
+            }
+            Check::Nagios { .. }
+            | Check::Kubernetes(_)
+            | Check::Prometheus(_)
+            | Check::System(_) => {
+                unreachable!("these have statuses other than OK and CRITICAL")
+            }
+        }
+    }
+}
+
+/// What a command printed, and how it exited
+pub struct CommandOutput {
+    pub status: ExitStatus,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl CommandOutput {
+    /// The last thing the command printed, which is usually the most useful part of an error
+    pub fn last_line(&self) -> String {
+        [&self.stderr, &self.stdout]
+            .iter()
+            .flat_map(|output| output.lines().rev())
+            .map(str::trim)
+            .find(|line| !line.is_empty())
+            .unwrap_or("No output")
+            .to_string()
+    }
+}
+
+/// Runs a command, killing it if it takes longer than the timeout
+pub fn run_command(command: &mut Command, timeout: Duration) -> Result<CommandOutput, String> {
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Unable to run {:?}: {}", command.get_program(), e))?;
+    // Read in the background so the command can't fill the pipes and stop before it finishes
+    let stdout = read_to_end(child.stdout.take());
+    let stderr = read_to_end(child.stderr.take());
+    let started = Instant::now();
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) if started.elapsed() < timeout => thread::sleep(COMMAND_POLL),
+            Ok(None) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!(
+                    "Timed out after {}",
+                    humantime::format_duration(timeout)
+                ));
+            }
+            Err(e) => return Err(format!("Unable to wait for {:?}: {}", command, e)),
+        }
+    };
+    let output = |reader: JoinHandle<Vec<u8>>| {
+        String::from_utf8_lossy(&reader.join().unwrap_or_default()).into_owned()
+    };
+    Ok(CommandOutput {
+        status,
+        stdout: output(stdout),
+        stderr: output(stderr),
+    })
+}
+
+fn read_to_end(pipe: Option<impl Read + Send + 'static>) -> JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut output = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut output);
+        }
+        output
+    })
+}
+
+/// The device pattern and led that checks show their results on. Checks with the same target
+/// share it.
+type Target = (Option<String>, u8);
+
+struct MonitorCheck {
+    config: CheckConfig,
+    device: Option<Pattern>,
+    /// The last result, `None` until the check has run
+    result: Option<CheckResult>,
+}
+
+impl MonitorCheck {
+    fn target(&self) -> Target {
+        (self.config.device.clone(), self.config.led)
+    }
+}
+
+/// Runs the checks and shows their results
+pub struct Monitor {
+    checks: Vec<MonitorCheck>,
+    /// The last error showing each target, so it's only logged when it changes
+    errors: HashMap<Target, Option<String>>,
+}
+
+impl Monitor {
+    pub fn new(config: &MonitorConfig) -> Result<Monitor, ConfigError> {
+        if config.checks.is_empty() {
+            return Err(ConfigError::Invalid(
+                "There are no checks, add some to [[monitor.checks]]".to_string(),
+            ));
+        }
+        let checks = config
+            .checks
+            .iter()
+            .map(|check| {
+                let invalid = |problem: String| {
+                    ConfigError::Invalid(format!("Check '{}' {}", check.name, problem))
+                };
+                if check.led >= LEDS_PER_DEVICE {
+                    return Err(invalid(format!(
+                        "uses led {}, devices have leds 0 to {}",
+                        check.led,
+                        LEDS_PER_DEVICE - 1
+                    )));
+                }
+                if check.interval.as_millis() == 0 {
+                    return Err(invalid("needs an interval".to_string()));
+                }
+                let device = check
+                    .device
+                    .as_deref()
+                    .map(Pattern::new)
+                    .transpose()
+                    .map_err(|e| invalid(format!("has an invalid device pattern: {}", e)))?;
+                Ok(MonitorCheck {
+                    config: check.clone(),
+                    device,
+                    result: None,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Monitor {
+            checks,
+            errors: HashMap::new(),
+        })
+    }
+
+    /// Runs every check on its own thread, showing each result as it comes in. Only returns if
+    /// every check stops, which they don't.
+    pub fn run(
+        &mut self,
+        mut apply: impl FnMut(&DaemonRequest) -> DaemonResponse,
+        mut log: impl FnMut(String),
+    ) {
+        let (sender, receiver) = mpsc::channel();
+        for (index, check) in self.checks.iter().enumerate() {
+            let sender = sender.clone();
+            let config = check.config.clone();
+            thread::spawn(move || loop {
+                let result = config.check.run(config.timeout);
+                if sender.send((index, result)).is_err() {
+                    return;
+                }
+                thread::sleep(config.interval);
+            });
+        }
+        drop(sender);
+        for (index, result) in receiver {
+            self.record(index, result, &mut log);
+            self.show(index, &mut apply, &mut log);
+        }
+    }
+
+    /// Keeps a check's result, logging it if its status or summary has changed
+    fn record(&mut self, index: usize, result: CheckResult, log: &mut impl FnMut(String)) {
+        let check = &mut self.checks[index];
+        let changed = match &check.result {
+            Some(last) => last.status != result.status || last.summary != result.summary,
+            None => true,
+        };
+        if changed {
+            match result.summary.as_str() {
+                "" => log(format!("{}: {}", check.config.name, result.status)),
+                summary => log(format!(
+                    "{}: {}, {}",
+                    check.config.name, result.status, summary
+                )),
+            }
+        }
+        check.result = Some(result);
+    }
+
+    /// What a target shows, `None` until one of its checks has run
+    fn indicator(&self, target: &Target) -> Option<Indicator> {
+        let mut worst: Option<(Status, Indicator)> = None;
+        for check in self.checks.iter().filter(|check| &check.target() == target) {
+            if let Some(result) = &check.result {
+                if worst
+                    .as_ref()
+                    .is_none_or(|(status, _)| result.status > *status)
+                {
+                    let indicator = match result.color {
+                        Some(color) => Indicator::Color(color),
+                        None => check.config.indicator(result.status).clone(),
+                    };
+                    worst = Some((result.status, indicator));
+                }
+            }
+        }
+        worst.map(|(_, indicator)| indicator)
+    }
+
+    /// Shows the worst status for the check's target on the devices it applies to
+    fn show(
+        &mut self,
+        index: usize,
+        apply: &mut impl FnMut(&DaemonRequest) -> DaemonResponse,
+        log: &mut impl FnMut(String),
+    ) {
+        let check = &self.checks[index];
+        let target = check.target();
+        let action = match self.indicator(&target) {
+            Some(indicator) => indicator.action(check.config.led),
+            None => return,
+        };
+        let report = match &check.device {
+            None => report(apply(&DaemonRequest {
+                selector: DeviceSelector::all(),
+                action,
+                owner: None,
+            })),
+            Some(pattern) => match apply(&DaemonRequest {
+                selector: DeviceSelector::all(),
+                action: Action::List,
+                owner: None,
+            }) {
+                DaemonResponse::Devices(devices) => {
+                    let reports = devices
+                        .iter()
+                        .filter(|info| info.matches_pattern(pattern))
+                        .flat_map(|info| {
+                            report(apply(&DaemonRequest {
+                                selector: DeviceSelector::only(&info.path),
+                                action: action.clone(),
+                                owner: None,
+                            }))
+                            .devices
+                        })
+                        .collect::<Vec<_>>();
+                    match reports.is_empty() {
+                        true => Report::failed(
+                            Outcome::NoDevices,
+                            format!("No device matches '{}'", pattern),
+                        ),
+                        false => Report::from_devices(reports),
+                    }
+                }
+                DaemonResponse::Report(report) => report,
+                DaemonResponse::Claims(_) | DaemonResponse::Snapshot(_) => Report::failed(
+                    Outcome::DeviceError,
+                    "The daemon didn't respond with a device list".to_string(),
+                ),
+            },
+        };
+        let error = match report.outcome {
+            Outcome::Success => None,
+            _ => Some(describe_failure(&report)),
+        };
+        if error.is_some() && self.errors.get(&target) != Some(&error) {
+            log(format!(
+                "Unable to show {}: {}",
+                check.config.name,
+                error.as_deref().unwrap_or_default()
+            ));
+        }
+        self.errors.insert(target, error);
+    }
+}
+
+/// The first thing that went wrong
+pub(crate) fn describe_failure(report: &Report) -> String {
+    report
+        .error
+        .clone()
+        .or_else(|| {
+            let failed = report.devices.iter().find(|device| !device.ok);
+            failed.map(|device| device.summary())
+        })
+        .unwrap_or_else(|| "Unable to set the led".to_string())
+}
+
+pub(crate) fn report(response: DaemonResponse) -> Report {
+    match response {
+        DaemonResponse::Report(report) => report,
+        DaemonResponse::Devices(_) | DaemonResponse::Claims(_) | DaemonResponse::Snapshot(_) => {
+            Report::failed(
+                Outcome::DeviceError,
+                "The daemon didn't respond with a report".to_string(),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::Config;
+    use crate::daemon::Daemon;
+    use crate::mock::{MockDevice, MockSource};
+
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::path::{Path, PathBuf};
+
+    const RED: Rgb = Rgb::new(255, 0, 0);
+    const GREEN: Rgb = Rgb::new(0, 128, 0);
+
+    fn monitor_config(contents: &str) -> MonitorConfig {
+        Config::parse(contents, Path::new("config.toml"))
+            .unwrap()
+            .monitor
+    }
+
+    /// Serves a single HTTP response on a random port
+    fn serve_once(response: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request);
+            stream.write_all(response.as_bytes()).unwrap();
+        });
+        format!("http://{}/health", address)
+    }
+
+    #[test]
+    fn test_parse() {
+        let config = monitor_config(
+            r#"
+            [[monitor.checks]]
+            name = "internet"
+            type = "ping"
+            host = "1.1.1.1"
+            led = 0
+
+            [[monitor.checks]]
+            name = "website"
+            type = "http"
+            url = "https://example.com"
+            status = 204
+            interval = "5m"
+            timeout = "2s"
+            device = "ttyACM*"
+            led = 1
+            ok = "blue"
+            critical = "orange"
+            warning = { effect = "blink", color = "orange" }
+
+            [[monitor.checks]]
+            name = "cluster"
+            type = "kubernetes"
+            kubeconfig = "k3s.yaml"
+            namespaces = ["default"]
+            led = 2
+
+            [[monitor.checks]]
+            name = "load"
+            type = "prometheus"
+            url = "http://localhost:9090"
+            query = "max(node_load1)"
+            thresholds = { warning = 2, critical = 4 }
+            warning = "yellow"
+            gradient = [{ at = 0, color = "green" }, { at = 4, color = "red" }]
+            led = 3
+            "#,
+        );
+        assert_eq!(config.checks[0].interval, Duration::from_secs(60));
+        assert_eq!(config.checks[0].ok, Indicator::Color(GREEN.scale(0.1)));
+        assert_eq!(
+            config.checks[1].check,
+            Check::Http {
+                url: "https://example.com".to_string(),
+                status: Some(204),
+                body: None,
+            }
+        );
+        assert_eq!(config.checks[1].interval, Duration::from_secs(300));
+        assert_eq!(config.checks[1].ok, Indicator::Color(Rgb::new(0, 0, 255)));
+        assert_eq!(
+            config.checks[1].warning,
+            Indicator::Effect(Effect::from_name("blink", Rgb::new(255, 165, 0)).unwrap())
+        );
+        assert_eq!(
+            config.checks[2].check,
+            Check::Kubernetes(KubernetesCheck {
+                kubeconfig: Some(PathBuf::from("k3s.yaml")),
+                context: None,
+                nodes: true,
+                namespaces: vec!["default".to_string()],
+            })
+        );
+        match &config.checks[3].check {
+            Check::Prometheus(prometheus) => {
+                let gradient = prometheus.gradient.as_ref().unwrap();
+                assert_eq!(gradient.color_at(4.0), RED);
+                assert_eq!(prometheus.thresholds.status(3.0), Status::Warning);
+            }
+            check => panic!("Expected a prometheus check, got {:?}", check),
+        }
+
+        let unknown = Config::parse(
+            "[[monitor.checks]]\nname = \"a\"\ntype = \"ping\"\nhost = \"b\"\nport = 1\nled = 0",
+            Path::new("config.toml"),
+        );
+        assert!(unknown.is_err());
+        let invalid = |check: &str| {
+            Monitor::new(&monitor_config(&format!(
+                "[[monitor.checks]]\nname = \"a\"\ntype = \"command\"\ncommand = \"true\"\n{}",
+                check
+            )))
+            .is_err()
+        };
+        assert!(!invalid("led = 0"));
+        assert!(invalid("led = 4"));
+        assert!(invalid("led = 0\ndevice = \"[\""));
+        assert!(invalid("led = 0\ninterval = \"0s\""));
+        assert!(Monitor::new(&MonitorConfig::default()).is_err());
+    }
+
+    #[test]
+    fn test_checks() {
+        let timeout = Duration::from_secs(5);
+        let command = |command: &str| Check::Command {
+            command: command.to_string(),
+        };
+        assert_eq!(command("true").run_built_in(timeout), Ok(()));
+        assert_eq!(
+            command("echo 'disk full' >&2; exit 2").run_built_in(timeout),
+            Err("exit status: 2: disk full".to_string())
+        );
+        assert_eq!(
+            command("sleep 5").run_built_in(Duration::from_millis(100)),
+            Err("Timed out after 100ms".to_string())
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let tcp = Check::Tcp { address };
+        assert_eq!(tcp.run_built_in(timeout), Ok(()));
+        drop(listener);
+        assert!(tcp.run_built_in(timeout).is_err());
+
+        let dns = Check::Dns {
+            name: "localhost".to_string(),
+            address: None,
+        };
+        assert_eq!(dns.run_built_in(timeout), Ok(()));
+
+        let http = |url: String, status: Option<u16>, body: Option<&str>| Check::Http {
+            url,
+            status,
+            body: body.map(str::to_string),
+        };
+        let ok = "HTTP/1.1 200 OK\r\nContent-Length: 9\r\nConnection: close\r\n\r\nstatus:ok";
+        assert_eq!(
+            http(serve_once(ok), None, Some("ok")).run_built_in(timeout),
+            Ok(())
+        );
+        assert_eq!(
+            http(serve_once(ok), None, Some("healthy")).run_built_in(timeout),
+            Err("The response doesn't contain 'healthy'".to_string())
+        );
+        assert_eq!(
+            http(serve_once(ok), Some(204), None).run_built_in(timeout),
+            Err("Expected status 204 but got 200".to_string())
+        );
+        let down =
+            "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+        assert_eq!(
+            http(serve_once(down), None, None).run_built_in(timeout),
+            Err("Got status 503".to_string())
+        );
+        assert_eq!(
+            http(serve_once(down), Some(503), None).run_built_in(timeout),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_show_results() {
+        let devices = vec![
+            MockDevice::new("/dev/ttyACM0"),
+            MockDevice::new("/dev/ttyACM1"),
+        ];
+        let daemon = Daemon::new(Box::new(MockSource::new(devices.clone())));
+        let mut monitor = Monitor::new(&monitor_config(
+            r#"
+            [[monitor.checks]]
+            name = "internet"
+            type = "command"
+            command = "true"
+            led = 0
+            critical = "red"
+
+            [[monitor.checks]]
+            name = "dns"
+            type = "nagios"
+            command = "check_dns -H example.com"
+            led = 0
+            ok = "green"
+            warning = { effect = "blink", color = "orange" }
+
+            [[monitor.checks]]
+            name = "nas"
+            type = "command"
+            command = "true"
+            device = "ttyACM1"
+            led = 2
+            "#,
+        ))
+        .unwrap();
+        let mut logged = Vec::new();
+        let mut result = |index: usize, status: Status, summary: &str| {
+            let mut log = |line| logged.push(line);
+            let result = CheckResult::new(status, summary.to_string());
+            monitor.record(index, result, &mut log);
+            monitor.show(index, &mut |request| daemon.handle(request), &mut log);
+        };
+        let effect = |path: &str, led: usize| daemon.led_states(path).unwrap()[led].effect.clone();
+
+        result(1, Status::Ok, "");
+        assert_eq!(devices[0].state().foreground[0], GREEN);
+        result(0, Status::Ok, "");
+        assert_eq!(devices[0].state().foreground[0], GREEN.scale(0.1));
+        // The worst status wins
+        result(
+            1,
+            Status::Warning,
+            "DNS WARNING - 1.2 seconds response time",
+        );
+        assert_eq!(effect("/dev/ttyACM1", 0).unwrap().name(), "blink");
+        result(0, Status::Critical, "No route to host");
+        assert_eq!(devices[1].state().foreground[0], RED);
+        assert_eq!(effect("/dev/ttyACM1", 0), None);
+        result(0, Status::Ok, "");
+        result(1, Status::Ok, "");
+        assert_eq!(devices[1].state().foreground[0], GREEN.scale(0.1));
+
+        result(2, Status::Critical, "Timed out after 10s");
+        assert_eq!(devices[1].state().foreground[2], RED.scale(0.1));
+        assert_eq!(devices[0].state().foreground[2], Rgb::OFF);
+        // Results only get logged when they change
+        result(2, Status::Critical, "Timed out after 10s");
+        assert_eq!(
+            logged,
+            vec![
+                "dns: OK",
+                "internet: OK",
+                "dns: WARNING, DNS WARNING - 1.2 seconds response time",
+                "internet: CRITICAL, No route to host",
+                "internet: OK",
+                "dns: OK",
+                "nas: CRITICAL, Timed out after 10s",
+            ]
+        );
+
+        // A color picked by the check is shown instead of the status's
+        let blue = Rgb::new(0, 0, 50);
+        let picked = CheckResult {
+            color: Some(blue),
+            ..CheckResult::new(Status::Ok, "1.5".to_string())
+        };
+        monitor.record(2, picked, &mut |_| {});
+        monitor.show(2, &mut |request| daemon.handle(request), &mut |_| {});
+        assert_eq!(devices[1].state().foreground[2], blue);
+    }
+}
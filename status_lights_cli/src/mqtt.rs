@@ -0,0 +1,739 @@
+//! Bridges the daemon to an MQTT broker, so lights can be set by anything that speaks MQTT and
+//! their state shows up wherever it's needed.
+//!
+//! Devices are identified by the last part of their path, the same as the HTTP API. With the
+//! default prefix of `status_lights`:
+//!
+//! | Topic                            | Payload                                          | Retained |
+//! |----------------------------------|--------------------------------------------------|----------|
+//! | `status_lights/status`           | `online`, or `offline` (the bridge's last will)  | yes      |
+//! | `status_lights/<id>/status`      | `online`, or `offline` once it's unplugged       | yes      |
+//! | `status_lights/<id>/info`        | The device, as `list --format json` shows it     | yes      |
+//! | `status_lights/<id>/<led>/state` | The led, as the HTTP API shows it                | yes      |
+//! | `status_lights/<id>/<led>/set`   | A change, sent by other clients                  | no       |
+//! | `status_lights/<id>/<led>/error` | Why a change failed                              | no       |
+//!
+//! A change is either a color on its own, which is shown as the foreground until it is changed
+//! again, or a JSON object such as
+//! `{"background": "green@5%", "foreground": "red", "for": "2m"}` or
+//! `{"effect": {"effect": "pulse", "color": "blue"}}`.
+//!
+//! With `home_assistant` turned on, every led also shows up in Home Assistant as a light, see
+//! `home_assistant`.
+
+use rumqttc::{Client, Event, LastWill, MqttOptions, Packet, QoS};
+use serde::{Deserialize, Serialize};
+use status_lights_messages::{LedColor, LedColorTimed, Rgb};
+use thiserror::Error;
+
+use std::collections::HashMap;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::args::{self, ArgError};
+use crate::daemon::Daemon;
+use crate::device::LEDS_PER_DEVICE;
+use crate::effect::Effect;
+use crate::home_assistant::{self, Change, HaColor, HaLight};
+use crate::ipc::{Action, DaemonRequest, DaemonResponse, LedEffect};
+use crate::output::DeviceInfo;
+use crate::report::{Outcome, Report};
+use crate::selector::DeviceSelector;
+use crate::state::LedState;
+
+#[cfg(test)]
+mod test_broker;
+
+/// How long to wait before reconnecting after the first failure, this doubles with each failure
+/// in a row up to `MAX_RECONNECT_DELAY`
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// Settings for the MQTT bridge
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    /// Defaults to the prefix. Brokers disconnect a client when another connects with the same
+    /// id, so this needs to be unique when running more than one bridge.
+    pub client_id: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// The start of every topic
+    pub prefix: String,
+    #[serde(with = "humantime_serde")]
+    pub keep_alive: Duration,
+    /// How often to check for devices being plugged in or unplugged, and foregrounds running out
+    #[serde(with = "humantime_serde")]
+    pub device_poll: Duration,
+    /// Publish Home Assistant discovery configs, so every led appears there as a light
+    pub home_assistant: bool,
+    /// Where Home Assistant looks for discovery configs
+    pub discovery_prefix: String,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        MqttConfig {
+            host: "localhost".to_string(),
+            port: 1883,
+            client_id: None,
+            username: None,
+            password: None,
+            prefix: "status_lights".to_string(),
+            keep_alive: Duration::from_secs(30),
+            device_poll: Duration::from_secs(5),
+            home_assistant: false,
+            discovery_prefix: "homeassistant".to_string(),
+        }
+    }
+}
+
+impl MqttConfig {
+    fn options(&self) -> MqttOptions {
+        let client_id = self.client_id.as_ref().unwrap_or(&self.prefix);
+        let mut options = MqttOptions::new(client_id, &self.host, self.port);
+        options
+            .set_keep_alive(self.keep_alive)
+            .set_clean_session(true)
+            .set_last_will(LastWill::new(
+                format!("{}/status", self.prefix),
+                "offline",
+                QoS::AtLeastOnce,
+                true,
+            ));
+        if let Some(username) = &self.username {
+            options.set_credentials(username, self.password.as_deref().unwrap_or_default());
+        }
+        options
+    }
+}
+
+#[derive(Error, Debug, PartialEq)]
+enum SetError {
+    #[error("Expected a topic like '<prefix>/<device>/<led>/set'")]
+    InvalidTopic,
+    #[error("No device with id '{0}'")]
+    NoSuchDevice(String),
+    #[error("There is no led '{}', devices have leds 0 to {}", .0, LEDS_PER_DEVICE - 1)]
+    NoSuchLed(String),
+    #[error("Invalid payload: {0}")]
+    InvalidPayload(String),
+    #[error("The device failed")]
+    Device(Report),
+}
+
+impl From<ArgError> for SetError {
+    fn from(error: ArgError) -> Self {
+        SetError::InvalidPayload(error.to_string())
+    }
+}
+
+/// A change to an led, as sent in a JSON payload
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SetCommand {
+    background: Option<String>,
+    foreground: Option<String>,
+    /// How long to show the foreground for, indefinitely if not set
+    #[serde(default, rename = "for", with = "humantime_serde")]
+    duration: Option<Duration>,
+    effect: Option<Effect>,
+}
+
+impl SetCommand {
+    fn parse(payload: &[u8]) -> Result<SetCommand, SetError> {
+        let payload = std::str::from_utf8(payload)
+            .map_err(|e| SetError::InvalidPayload(e.to_string()))?
+            .trim();
+        if !payload.starts_with('{') {
+            return Ok(SetCommand {
+                foreground: Some(payload.to_string()),
+                ..SetCommand::default()
+            });
+        }
+        let command: SetCommand =
+            serde_json::from_str(payload).map_err(|e| SetError::InvalidPayload(e.to_string()))?;
+        if command.foreground.is_some() && command.effect.is_some() {
+            return Err(SetError::InvalidPayload(
+                "An led can't be given a foreground and an effect at the same time".to_string(),
+            ));
+        }
+        if command.duration.is_some() && command.foreground.is_none() {
+            return Err(SetError::InvalidPayload(
+                "'for' only applies to a foreground".to_string(),
+            ));
+        }
+        Ok(command)
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorPayload {
+    error: String,
+}
+
+/// What the event loop passes on to the bridge
+enum Message {
+    Connected,
+    Set { topic: String, payload: Vec<u8> },
+}
+
+/// Connects to the broker and bridges it to the daemon on background threads. The connection is
+/// retried for as long as the daemon is running.
+pub fn start(daemon: Arc<Daemon>, config: MqttConfig) {
+    let (client, mut connection) = Client::new(config.options(), 100);
+    let (sender, receiver) = mpsc::channel();
+    let bridge = Bridge {
+        daemon,
+        client,
+        config,
+        published: HashMap::new(),
+        ha_colors: HashMap::new(),
+    };
+    thread::spawn(move || bridge.serve(receiver));
+    thread::spawn(move || {
+        let mut delay = RECONNECT_DELAY;
+        for event in connection.iter() {
+            let message = match event {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    delay = RECONNECT_DELAY;
+                    Message::Connected
+                }
+                Ok(Event::Incoming(Packet::Publish(publish))) => Message::Set {
+                    topic: publish.topic,
+                    payload: publish.payload.to_vec(),
+                },
+                Ok(_) => continue,
+                Err(e) => {
+                    eprintln!(
+                        "MQTT connection failed, retrying in {}: {}",
+                        humantime::format_duration(delay),
+                        e
+                    );
+                    thread::sleep(delay);
+                    delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+                    continue;
+                }
+            };
+            // The bridge only stops if the daemon has
+            if sender.send(message).is_err() {
+                return;
+            }
+        }
+    });
+}
+
+/// What was last published for a device
+struct Published {
+    info: DeviceInfo,
+    leds: Vec<LedState>,
+}
+
+struct Bridge {
+    daemon: Arc<Daemon>,
+    client: Client,
+    config: MqttConfig,
+    /// By device id
+    published: HashMap<String, Published>,
+    /// What Home Assistant last asked each led to show, by device id and led
+    ha_colors: HashMap<(String, u8), HaColor>,
+}
+
+impl Bridge {
+    fn serve(mut self, receiver: Receiver<Message>) {
+        loop {
+            match receiver.recv_timeout(self.config.device_poll) {
+                Ok(Message::Connected) => {
+                    self.subscribe();
+                    self.publish(&format!("{}/status", self.config.prefix), "online", true);
+                    // The broker may have lost anything published while disconnected
+                    self.published.clear();
+                }
+                Ok(Message::Set { topic, payload }) => {
+                    if let Err(e) = self.set(&topic, &payload) {
+                        self.publish_error(&topic, e);
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+            self.sync();
+        }
+    }
+
+    fn topic(&self, id: &str, suffix: &str) -> String {
+        format!("{}/{}/{}", self.config.prefix, id, suffix)
+    }
+
+    fn subscribe(&self) {
+        let mut filters = vec![format!("{}/+/+/set", self.config.prefix)];
+        if self.config.home_assistant {
+            filters.push(format!("{}/+/+/ha/set", self.config.prefix));
+        }
+        for filter in filters {
+            // Only fails if the event loop has stopped, in which case so will the bridge
+            self.client.try_subscribe(filter, QoS::AtLeastOnce).ok();
+        }
+    }
+
+    /// Publishes without blocking. Anything that can't be queued is published again on the
+    /// next connection.
+    fn publish(&self, topic: &str, payload: impl Into<Vec<u8>>, retain: bool) {
+        self.client
+            .try_publish(topic, QoS::AtLeastOnce, retain, payload)
+            .ok();
+    }
+
+    fn publish_json<T: Serialize>(&self, topic: &str, value: &T, retain: bool) {
+        let payload = serde_json::to_vec(value).expect("messages are always valid JSON");
+        self.publish(topic, payload, retain);
+    }
+
+    fn publish_error(&self, set_topic: &str, error: SetError) {
+        let led_topic = set_topic
+            .strip_suffix("/ha/set")
+            .or_else(|| set_topic.strip_suffix("/set"));
+        let topic = match led_topic {
+            Some(led_topic) => format!("{}/error", led_topic),
+            None => format!("{}/error", self.config.prefix),
+        };
+        match error {
+            SetError::Device(report) => self.publish_json(&topic, &report, false),
+            error => self.publish_json(
+                &topic,
+                &ErrorPayload {
+                    error: error.to_string(),
+                },
+                false,
+            ),
+        }
+    }
+
+    /// Publishes any device that has been plugged in or unplugged, and any led that has changed
+    fn sync(&mut self) {
+        let devices = self
+            .daemon
+            .describe(&DeviceSelector::all())
+            .unwrap_or_default();
+        let gone: Vec<String> = self
+            .published
+            .keys()
+            .filter(|id| !devices.iter().any(|info| info.id() == id.as_str()))
+            .cloned()
+            .collect();
+        for id in gone {
+            self.publish(&self.topic(&id, "status"), "offline", true);
+            self.published.remove(&id);
+            self.ha_colors.retain(|(ha_id, _), _| ha_id != &id);
+        }
+        for info in devices {
+            let leds = self.daemon.led_states(&info.path).unwrap_or_default();
+            let id = info.id().to_string();
+            let previous = self.published.remove(&id);
+            if previous.as_ref().map(|p| &p.info) != Some(&info) {
+                self.publish(&self.topic(&id, "status"), "online", true);
+                self.publish_json(&self.topic(&id, "info"), &info, true);
+                if self.config.home_assistant {
+                    for led in 0..LEDS_PER_DEVICE {
+                        let (topic, config) = home_assistant::discovery(
+                            &self.config.discovery_prefix,
+                            &self.config.prefix,
+                            &info,
+                            led,
+                        );
+                        self.publish_json(&topic, &config, true);
+                    }
+                }
+            }
+            for state in &leds {
+                let unchanged = previous
+                    .as_ref()
+                    .and_then(|p| p.leds.get(state.led as usize))
+                    .is_some_and(|old| same_state(old, state));
+                if !unchanged {
+                    let topic = self.topic(&id, &format!("{}/state", state.led));
+                    self.publish_json(&topic, state, true);
+                    self.publish_ha_state(&id, state);
+                }
+            }
+            self.published.insert(id, Published { info, leds });
+        }
+    }
+
+    fn publish_ha_state(&self, id: &str, state: &LedState) {
+        if self.config.home_assistant {
+            let last = self.ha_colors.get(&(id.to_string(), state.led)).copied();
+            let topic = self.topic(id, &format!("{}/ha/state", state.led));
+            self.publish_json(&topic, &home_assistant::light_state(state, last), true);
+        }
+    }
+
+    fn set(&mut self, topic: &str, payload: &[u8]) -> Result<(), SetError> {
+        let (id, led, from_home_assistant) = self.parse_topic(topic)?;
+        let info = self
+            .daemon
+            .describe(&DeviceSelector::all())
+            .unwrap_or_default()
+            .into_iter()
+            .find(|info| info.id() == id)
+            .ok_or_else(|| SetError::NoSuchDevice(id.to_string()))?;
+        let selector = DeviceSelector::only(&info.path);
+        if from_home_assistant {
+            return self.set_from_home_assistant(&info, led, payload);
+        }
+        let command = SetCommand::parse(payload)?;
+        if let Some(background) = &command.background {
+            let led_color = LedColor::new(led, args::parse_color(background)?);
+            self.run(&selector, Action::Background(led_color))?;
+        }
+        if let Some(foreground) = &command.foreground {
+            let color = args::parse_color(foreground)?;
+            self.run(
+                &selector,
+                Action::foreground_for(led, color, command.duration)?,
+            )?;
+        }
+        if let Some(effect) = command.effect {
+            let effect = Some(effect);
+            self.run(&selector, Action::Effect(LedEffect { led, effect }))?;
+        }
+        Ok(())
+    }
+
+    fn set_from_home_assistant(
+        &mut self,
+        info: &DeviceInfo,
+        led: u8,
+        payload: &[u8],
+    ) -> Result<(), SetError> {
+        let command: HaLight =
+            serde_json::from_slice(payload).map_err(|e| SetError::InvalidPayload(e.to_string()))?;
+        let state = self
+            .daemon
+            .led_states(&info.path)
+            .and_then(|mut leds| leds.drain(..).nth(led as usize))
+            .ok_or_else(|| SetError::NoSuchDevice(info.id().to_string()))?;
+        let key = (info.id().to_string(), led);
+        let (change, color) =
+            home_assistant::apply(&command, &state, self.ha_colors.get(&key).copied())
+                .map_err(SetError::InvalidPayload)?;
+        let selector = DeviceSelector::only(&info.path);
+        match change {
+            Change::Off => {
+                let led_color = LedColorTimed::new(led, Rgb::OFF, 0);
+                self.run(&selector, Action::Foreground(led_color))?;
+            }
+            Change::Foreground(rgb) => {
+                let led_color = LedColorTimed::new(led, rgb, 0);
+                self.run(&selector, Action::Foreground(led_color))?;
+            }
+            Change::Effect(effect) => {
+                let effect = Some(effect);
+                self.run(&selector, Action::Effect(LedEffect { led, effect }))?;
+            }
+        }
+        self.ha_colors.insert(key, color);
+        // Home Assistant waits to hear the new state, even if the led ends up looking the same
+        if let Some(state) = self
+            .daemon
+            .led_states(&info.path)
+            .and_then(|mut leds| leds.drain(..).nth(led as usize))
+        {
+            self.publish_ha_state(info.id(), &state);
+        }
+        Ok(())
+    }
+
+    /// Splits `<prefix>/<id>/<led>/set` (or `<prefix>/<id>/<led>/ha/set`) into the device id,
+    /// led and whether it came from Home Assistant
+    fn parse_topic<'a>(&self, topic: &'a str) -> Result<(&'a str, u8, bool), SetError> {
+        let segments: Vec<&str> = topic
+            .strip_prefix(&self.config.prefix)
+            .and_then(|rest| rest.strip_prefix('/'))
+            .ok_or(SetError::InvalidTopic)?
+            .split('/')
+            .collect();
+        let (id, led, from_home_assistant) = match segments.as_slice() {
+            [id, led, "set"] => (id, led, false),
+            [id, led, "ha", "set"] if self.config.home_assistant => (id, led, true),
+            _ => return Err(SetError::InvalidTopic),
+        };
+        let led = led
+            .parse::<u8>()
+            .ok()
+            .filter(|led| *led < LEDS_PER_DEVICE)
+            .ok_or_else(|| SetError::NoSuchLed(led.to_string()))?;
+        Ok((id, led, from_home_assistant))
+    }
+
+    fn run(&self, selector: &DeviceSelector, action: Action) -> Result<(), SetError> {
+        let request = DaemonRequest {
+            selector: selector.clone(),
+            action,
+            owner: None,
+        };
+        match self.daemon.handle(&request) {
+            DaemonResponse::Report(report) => check(report),
+            DaemonResponse::Devices(_)
+            | DaemonResponse::Claims(_)
+            | DaemonResponse::Snapshot(_) => {
+                unreachable!("only list, claims and snapshot don't respond with a report")
+            }
+        }
+    }
+}
+
+fn check(report: Report) -> Result<(), SetError> {
+    match report.outcome {
+        Outcome::Success => Ok(()),
+        _ => Err(SetError::Device(report)),
+    }
+}
+
+/// Whether two states show the same thing, ignoring the time left on the foreground which
+/// changes constantly
+fn same_state(a: &LedState, b: &LedState) -> bool {
+    LedState {
+        foreground_remaining: None,
+        ..a.clone()
+    } == LedState {
+        foreground_remaining: None,
+        ..b.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::{MockDevice, MockSource};
+    use status_lights_messages::Rgb;
+    use test_broker::TestBroker;
+
+    fn start_bridge(source: MockSource) -> TestBroker {
+        start_bridge_with(source, |_| {})
+    }
+
+    fn start_bridge_with(
+        source: MockSource,
+        configure: impl FnOnce(&mut MqttConfig),
+    ) -> TestBroker {
+        let broker = TestBroker::start();
+        let mut config = MqttConfig {
+            host: "127.0.0.1".to_string(),
+            port: broker.port(),
+            client_id: Some(format!("test-{}", broker.port())),
+            device_poll: Duration::from_millis(50),
+            ..MqttConfig::default()
+        };
+        configure(&mut config);
+        start(Arc::new(Daemon::new(Box::new(source))), config);
+        broker
+    }
+
+    fn state(payload: &str) -> LedState {
+        serde_json::from_str(payload).unwrap()
+    }
+
+    #[test]
+    fn test_parse_set_command() {
+        assert_eq!(
+            SetCommand::parse(b" red@10%\n").unwrap(),
+            SetCommand {
+                foreground: Some("red@10%".to_string()),
+                ..SetCommand::default()
+            }
+        );
+        let command = SetCommand::parse(br#"{"foreground": "red", "for": "2m"}"#).unwrap();
+        assert_eq!(command.duration, Some(Duration::from_secs(120)));
+        let command = SetCommand::parse(br#"{"effect": {"effect": "rainbow"}}"#).unwrap();
+        assert_eq!(command.effect.unwrap().name(), "rainbow");
+
+        assert!(SetCommand::parse(br#"{"colour": "red"}"#).is_err());
+        assert!(SetCommand::parse(br#"{"background": "red", "for": "2m"}"#).is_err());
+        assert!(
+            SetCommand::parse(br#"{"foreground": "red", "effect": {"effect": "rainbow"}}"#)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_publishes_state() {
+        let broker = start_bridge(MockSource::new(vec![
+            MockDevice::new("/dev/ttyACM0").with_serial("abc")
+        ]));
+        broker
+            .wait_for("status_lights/status", |payload| payload == "online")
+            .unwrap();
+        broker
+            .wait_for("status_lights/ttyACM0/status", |payload| {
+                payload == "online"
+            })
+            .unwrap();
+        let info = broker
+            .wait_for("status_lights/ttyACM0/info", |_| true)
+            .unwrap();
+        let info: DeviceInfo = serde_json::from_str(&info).unwrap();
+        assert_eq!(info.serial.as_deref(), Some("abc"));
+        let led = broker
+            .wait_for("status_lights/ttyACM0/3/state", |_| true)
+            .unwrap();
+        assert_eq!(state(&led).foreground, Rgb::OFF);
+    }
+
+    #[test]
+    fn test_set() {
+        let device = MockDevice::new("/dev/ttyACM0");
+        let broker = start_bridge(MockSource::new(vec![device.clone()]));
+        broker
+            .wait_for("status_lights/ttyACM0/1/state", |_| true)
+            .unwrap();
+
+        broker.publish("status_lights/ttyACM0/1/set", "blue");
+        broker
+            .wait_for("status_lights/ttyACM0/1/state", |payload| {
+                state(payload).foreground == Rgb::new(0, 0, 255)
+            })
+            .unwrap();
+        assert_eq!(device.state().foreground[1], Rgb::new(0, 0, 255));
+        assert_eq!(device.state().foreground_seconds[1], 0);
+
+        broker.publish(
+            "status_lights/ttyACM0/2/set",
+            r#"{"background": "green", "foreground": "red", "for": "1m"}"#,
+        );
+        let led = broker
+            .wait_for("status_lights/ttyACM0/2/state", |payload| {
+                state(payload).foreground == Rgb::new(255, 0, 0)
+            })
+            .unwrap();
+        assert_eq!(state(&led).background, Rgb::new(0, 128, 0));
+        assert_eq!(device.state().foreground_seconds[2], 60);
+
+        broker.publish("status_lights/ttyACM0/0/set", "blurple");
+        let error = broker
+            .wait_for("status_lights/ttyACM0/0/error", |_| true)
+            .unwrap();
+        assert!(error.contains("blurple"));
+        broker.publish("status_lights/ttyACM9/0/set", "red");
+        let error = broker
+            .wait_for("status_lights/ttyACM9/0/error", |_| true)
+            .unwrap();
+        assert_eq!(error, r#"{"error":"No device with id 'ttyACM9'"}"#);
+    }
+
+    #[test]
+    fn test_devices_come_and_go() {
+        let source = MockSource::new(vec![MockDevice::new("/dev/ttyACM0")]);
+        let broker = start_bridge(source.clone());
+        broker
+            .wait_for("status_lights/ttyACM0/status", |payload| {
+                payload == "online"
+            })
+            .unwrap();
+
+        source.plug(MockDevice::new("/dev/ttyACM1"));
+        broker
+            .wait_for("status_lights/ttyACM1/status", |payload| {
+                payload == "online"
+            })
+            .unwrap();
+        source.unplug("/dev/ttyACM0");
+        broker
+            .wait_for("status_lights/ttyACM0/status", |payload| {
+                payload == "offline"
+            })
+            .unwrap();
+        assert_eq!(
+            broker.retained("status_lights/ttyACM1/status").as_deref(),
+            Some("online")
+        );
+    }
+
+    #[test]
+    fn test_reconnect() {
+        let device = MockDevice::new("/dev/ttyACM0");
+        let broker = start_bridge(MockSource::new(vec![device.clone()]));
+        broker
+            .wait_for("status_lights/status", |payload| payload == "online")
+            .unwrap();
+
+        broker.drop_connections();
+        // The broker publishes the bridge's last will, then the bridge reconnects
+        broker
+            .wait_for("status_lights/status", |payload| payload == "offline")
+            .unwrap();
+        broker
+            .wait_for_retained("status_lights/status", |payload| payload == "online")
+            .unwrap();
+
+        broker.publish("status_lights/ttyACM0/0/set", "red");
+        broker
+            .wait_for("status_lights/ttyACM0/0/state", |payload| {
+                state(payload).foreground == Rgb::new(255, 0, 0)
+            })
+            .unwrap();
+        assert_eq!(device.state().foreground[0], Rgb::new(255, 0, 0));
+    }
+
+    #[test]
+    fn test_home_assistant() {
+        let device = MockDevice::new("/dev/ttyACM0").with_serial("ABC123");
+        let broker = start_bridge_with(MockSource::new(vec![device.clone()]), |config| {
+            config.home_assistant = true;
+        });
+        let config = broker
+            .wait_for(
+                "homeassistant/light/status_lights_ABC123/led3/config",
+                |_| true,
+            )
+            .unwrap();
+        let config: serde_json::Value = serde_json::from_str(&config).unwrap();
+        assert_eq!(config["command_topic"], "status_lights/ttyACM0/3/ha/set");
+        broker
+            .wait_for("status_lights/ttyACM0/3/ha/state", |payload| {
+                payload == r#"{"state":"OFF"}"#
+            })
+            .unwrap();
+
+        broker.publish(
+            "status_lights/ttyACM0/3/ha/set",
+            r#"{"state": "ON", "color": {"r": 0, "g": 0, "b": 255}, "brightness": 128}"#,
+        );
+        let light = broker
+            .wait_for("status_lights/ttyACM0/3/ha/state", |payload| {
+                payload.contains(r#""state":"ON""#)
+            })
+            .unwrap();
+        let light: serde_json::Value = serde_json::from_str(&light).unwrap();
+        assert_eq!(
+            light["color"],
+            serde_json::json!({"r": 0, "g": 0, "b": 255})
+        );
+        assert_eq!(light["brightness"], 128);
+        assert_eq!(
+            device.state().foreground[3],
+            Rgb::new(0, 0, 255).scale(128.0 / 255.0)
+        );
+
+        broker.publish(
+            "status_lights/ttyACM0/3/ha/set",
+            r#"{"state": "ON", "effect": "pulse"}"#,
+        );
+        broker
+            .wait_for("status_lights/ttyACM0/3/state", |payload| {
+                state(payload).effect.is_some()
+            })
+            .unwrap();
+        broker.publish("status_lights/ttyACM0/3/ha/set", r#"{"state": "OFF"}"#);
+        broker
+            .wait_for("status_lights/ttyACM0/3/state", |payload| {
+                let led = state(payload);
+                led.effect.is_none() && led.foreground == Rgb::OFF
+            })
+            .unwrap();
+    }
+}
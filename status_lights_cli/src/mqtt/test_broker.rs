@@ -0,0 +1,231 @@
+//! A minimal MQTT 3.1.1 broker for tests. It supports just enough for the bridge: retained
+//! messages, last wills, wildcard subscriptions and QoS 0 and 1 (everything is delivered at QoS
+//! 0). Connections can be dropped to test reconnecting.
+
+use bytes::BytesMut;
+use rumqttc::{
+    mqttbytes, ConnAck, ConnectReturnCode, LastWill, Packet, PingResp, PubAck, Publish, QoS,
+    SubAck, SubscribeReasonCode,
+};
+
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const MAX_PACKET: usize = 1024 * 1024;
+
+struct Subscriber {
+    id: usize,
+    stream: TcpStream,
+    filters: Vec<String>,
+}
+
+#[derive(Default)]
+struct State {
+    next_id: usize,
+    subscribers: Vec<Subscriber>,
+    retained: BTreeMap<String, Vec<u8>>,
+    /// Every message published, in order
+    published: Vec<(String, Vec<u8>)>,
+}
+
+impl State {
+    fn publish(&mut self, topic: &str, payload: &[u8], retain: bool) {
+        if retain {
+            match payload.is_empty() {
+                true => self.retained.remove(topic),
+                false => self.retained.insert(topic.to_string(), payload.to_vec()),
+            };
+        }
+        self.published.push((topic.to_string(), payload.to_vec()));
+        let mut buffer = BytesMut::new();
+        Publish::new(topic, QoS::AtMostOnce, payload)
+            .write(&mut buffer)
+            .unwrap();
+        for subscriber in &mut self.subscribers {
+            if subscriber
+                .filters
+                .iter()
+                .any(|filter| mqttbytes::matches(topic, filter))
+            {
+                subscriber.stream.write_all(&buffer).ok();
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct TestBroker {
+    address: SocketAddr,
+    state: Arc<Mutex<State>>,
+}
+
+impl TestBroker {
+    pub fn start() -> TestBroker {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let broker = TestBroker {
+            address: listener.local_addr().unwrap(),
+            state: Arc::default(),
+        };
+        let accepting = broker.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let broker = accepting.clone();
+                thread::spawn(move || broker.handle_connection(stream));
+            }
+        });
+        broker
+    }
+
+    pub fn port(&self) -> u16 {
+        self.address.port()
+    }
+
+    /// Publishes a message as if it came from another client
+    pub fn publish(&self, topic: &str, payload: &str) {
+        self.state
+            .lock()
+            .unwrap()
+            .publish(topic, payload.as_bytes(), false);
+    }
+
+    pub fn retained(&self, topic: &str) -> Option<String> {
+        let state = self.state.lock().unwrap();
+        let payload = state.retained.get(topic)?;
+        Some(String::from_utf8_lossy(payload).to_string())
+    }
+
+    /// Waits for a message to be published on the topic that satisfies the check
+    pub fn wait_for(&self, topic: &str, check: impl Fn(&str) -> bool) -> Option<String> {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut seen = 0;
+        while Instant::now() < deadline {
+            let state = self.state.lock().unwrap();
+            for (published, payload) in &state.published[seen..] {
+                let payload = String::from_utf8_lossy(payload);
+                if published == topic && check(&payload) {
+                    return Some(payload.to_string());
+                }
+            }
+            seen = state.published.len();
+            drop(state);
+            thread::sleep(Duration::from_millis(10));
+        }
+        None
+    }
+
+    /// Waits for the retained message on the topic to satisfy the check
+    pub fn wait_for_retained(&self, topic: &str, check: impl Fn(&str) -> bool) -> Option<String> {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while Instant::now() < deadline {
+            match self.retained(topic) {
+                Some(payload) if check(&payload) => return Some(payload),
+                _ => thread::sleep(Duration::from_millis(10)),
+            }
+        }
+        None
+    }
+
+    /// Drops every connection without a disconnect, as if the network went away
+    pub fn drop_connections(&self) {
+        let mut state = self.state.lock().unwrap();
+        for subscriber in state.subscribers.drain(..) {
+            subscriber.stream.shutdown(Shutdown::Both).ok();
+        }
+    }
+
+    fn handle_connection(&self, mut stream: TcpStream) {
+        let id = {
+            let mut state = self.state.lock().unwrap();
+            state.next_id += 1;
+            let id = state.next_id;
+            state.subscribers.push(Subscriber {
+                id,
+                stream: stream.try_clone().unwrap(),
+                filters: Vec::new(),
+            });
+            id
+        };
+        let mut will = None;
+        let clean = self.serve(&mut stream, id, &mut will).unwrap_or(false);
+        let mut state = self.state.lock().unwrap();
+        state.subscribers.retain(|subscriber| subscriber.id != id);
+        if let (false, Some(will)) = (clean, will) {
+            let will: LastWill = will;
+            state.publish(&will.topic, &will.message, will.retain);
+        }
+    }
+
+    /// Handles packets until the connection closes, returning whether it closed cleanly
+    fn serve(
+        &self,
+        stream: &mut TcpStream,
+        id: usize,
+        will: &mut Option<LastWill>,
+    ) -> io::Result<bool> {
+        let mut buffer = BytesMut::new();
+        loop {
+            let packet = match mqttbytes::v4::read(&mut buffer, MAX_PACKET) {
+                Ok(packet) => packet,
+                Err(mqttbytes::Error::InsufficientBytes(_)) => {
+                    let mut chunk = [0; 4096];
+                    let read = stream.read(&mut chunk)?;
+                    if read == 0 {
+                        return Ok(false);
+                    }
+                    buffer.extend_from_slice(&chunk[..read]);
+                    continue;
+                }
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+            };
+            let mut response = BytesMut::new();
+            let mut state = self.state.lock().unwrap();
+            match packet {
+                Packet::Connect(connect) => {
+                    *will = connect.last_will;
+                    ConnAck::new(ConnectReturnCode::Success, false)
+                        .write(&mut response)
+                        .unwrap();
+                }
+                Packet::Subscribe(subscribe) => {
+                    let codes = subscribe
+                        .filters
+                        .iter()
+                        .map(|_| SubscribeReasonCode::Success(QoS::AtMostOnce))
+                        .collect();
+                    SubAck::new(subscribe.pkid, codes)
+                        .write(&mut response)
+                        .unwrap();
+                    let filters: Vec<String> =
+                        subscribe.filters.into_iter().map(|f| f.path).collect();
+                    for (topic, payload) in &state.retained {
+                        if filters.iter().any(|f| mqttbytes::matches(topic, f)) {
+                            Publish::new(topic, QoS::AtMostOnce, payload.clone())
+                                .write(&mut response)
+                                .unwrap();
+                        }
+                    }
+                    if let Some(subscriber) = state.subscribers.iter_mut().find(|s| s.id == id) {
+                        subscriber.filters.extend(filters);
+                    }
+                }
+                Packet::Publish(publish) => {
+                    if publish.qos != QoS::AtMostOnce {
+                        PubAck::new(publish.pkid).write(&mut response).unwrap();
+                    }
+                    state.publish(&publish.topic, &publish.payload, publish.retain);
+                }
+                Packet::PingReq => {
+                    PingResp.write(&mut response).unwrap();
+                }
+                Packet::Disconnect => return Ok(true),
+                _ => {}
+            }
+            drop(state);
+            stream.write_all(&response)?;
+        }
+    }
+}
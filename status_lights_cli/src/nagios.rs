@@ -0,0 +1,253 @@
+//! Runs Nagios plugins, such as the `check_*` commands from Monitoring Plugins, as monitor checks.
+//!
+//! A plugin's exit code is its status: 0 is `OK`, 1 `WARNING`, 2 `CRITICAL` and anything else
+//! `UNKNOWN`. The first line it prints is a summary, optionally followed by `|` and performance
+//! data. Any lines after that are a longer description, and performance data can carry on after
+//! another `|` in them:
+//!
+//! ```text
+//! DISK OK - free space: / 3326 MB (56%); | /=2643MB;5948;5958;0;5968
+//! / 15272 MB (77%);
+//! /boot 68 MB (69%); | /boot=68MB;88;93;0;98
+//! ```
+
+use std::process::Command;
+use std::time::Duration;
+
+use crate::monitor::{self, CheckResult, Status};
+
+/// A single value from a plugin's performance data, eg `'free space'=3326MB;500;100;0;5968`
+#[derive(Clone, Debug, PartialEq)]
+pub struct PerfData {
+    pub label: String,
+    /// `None` if the plugin couldn't work the value out, which it shows as `U`
+    pub value: Option<f64>,
+    /// eg `%`, `s`, `MB` or `c` for a counter, empty if there isn't one
+    pub unit: String,
+    /// The warning and critical thresholds are ranges, such as `10:20` or `@~:5`
+    pub warning: Option<String>,
+    pub critical: Option<String>,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+}
+
+/// Runs a plugin with `sh -c`. A plugin that takes longer than the timeout is `CRITICAL`, the same
+/// as Nagios treats it.
+pub fn run(command: &str, timeout: Duration) -> CheckResult {
+    let output = match monitor::run_command(Command::new("sh").arg("-c").arg(command), timeout) {
+        Ok(output) => output,
+        Err(e) => return CheckResult::new(Status::Critical, e),
+    };
+    let mut result = parse_output(&output.stdout);
+    result.status = status(output.status.code());
+    if result.summary.is_empty() {
+        result.summary = output.last_line();
+    }
+    result
+}
+
+/// The status an exit code means, `None` being killed by a signal
+pub fn status(code: Option<i32>) -> Status {
+    match code {
+        Some(0) => Status::Ok,
+        Some(1) => Status::Warning,
+        Some(2) => Status::Critical,
+        _ => Status::Unknown,
+    }
+}
+
+/// Splits a plugin's output into its summary and performance data. The status is left as
+/// `UNKNOWN`, as it comes from the exit code.
+pub fn parse_output(output: &str) -> CheckResult {
+    let (first, rest) = output.split_once('\n').unwrap_or((output, ""));
+    let (summary, mut perfdata) = match first.split_once('|') {
+        Some((summary, perfdata)) => (summary, parse_perfdata(perfdata)),
+        None => (first, Vec::new()),
+    };
+    if let Some((_, more)) = rest.split_once('|') {
+        perfdata.extend(parse_perfdata(more));
+    }
+    CheckResult {
+        status: Status::Unknown,
+        summary: summary.trim().to_string(),
+        perfdata,
+        color: None,
+    }
+}
+
+/// Parses space separated performance data, skipping anything that isn't `label=value`
+pub fn parse_perfdata(perfdata: &str) -> Vec<PerfData> {
+    let mut parsed = Vec::new();
+    let mut rest = perfdata.trim_start();
+    while !rest.is_empty() {
+        let (label, after) = match rest.strip_prefix('\'') {
+            Some(quoted) => quoted_label(quoted),
+            None => {
+                let end = rest.find(['=', ' ']).unwrap_or(rest.len());
+                (rest[..end].to_string(), &rest[end..])
+            }
+        };
+        let (value, after) = match after.strip_prefix('=') {
+            Some(after) => after.split_at(after.find(char::is_whitespace).unwrap_or(after.len())),
+            // Not performance data, skip to the next space
+            None => ("", after.trim_start_matches(|c: char| !c.is_whitespace())),
+        };
+        if !label.is_empty() && !value.is_empty() {
+            parsed.push(parse_value(label, value));
+        }
+        rest = after.trim_start();
+    }
+    parsed
+}
+
+/// Reads a label up to its closing quote, where `''` is a quote in the label
+fn quoted_label(quoted: &str) -> (String, &str) {
+    let mut label = String::new();
+    let mut chars = quoted.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match (c, chars.peek()) {
+            ('\'', Some((_, '\''))) => {
+                label.push('\'');
+                chars.next();
+            }
+            ('\'', _) => return (label, &quoted[i + 1..]),
+            (c, _) => label.push(c),
+        }
+    }
+    (label, "")
+}
+
+/// Parses `value[unit];[warning];[critical];[min];[max]`
+fn parse_value(label: String, value: &str) -> PerfData {
+    let mut fields = value.split(';');
+    let first = fields.next().unwrap_or_default();
+    let number_end = first
+        .find(|c: char| !(c.is_ascii_digit() || matches!(c, '.' | ',' | '-' | '+')))
+        .unwrap_or(first.len());
+    let (number, unit) = match first {
+        "U" => ("", ""),
+        _ => first.split_at(number_end),
+    };
+    let mut field = || {
+        fields
+            .next()
+            .map(str::trim)
+            .filter(|field| !field.is_empty())
+            .map(str::to_string)
+    };
+    let warning = field();
+    let critical = field();
+    let number_field = |field: Option<String>| field.and_then(|field| parse_number(&field));
+    let min = number_field(field());
+    let max = number_field(field());
+    PerfData {
+        label,
+        value: parse_number(number),
+        unit: unit.to_string(),
+        warning,
+        critical,
+        min,
+        max,
+    }
+}
+
+/// Some plugins use a comma as the decimal point
+fn parse_number(number: &str) -> Option<f64> {
+    number.replace(',', ".").parse().ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn perfdata(label: &str, value: Option<f64>, unit: &str) -> PerfData {
+        PerfData {
+            label: label.to_string(),
+            value,
+            unit: unit.to_string(),
+            warning: None,
+            critical: None,
+            min: None,
+            max: None,
+        }
+    }
+
+    #[test]
+    fn test_parse_output() {
+        let result = parse_output(
+            "DISK OK - free space: / 3326 MB (56%); | /=2643MB;5948;5958;0;5968\n\
+             / 15272 MB (77%);\n\
+             /boot 68 MB (69%); | /boot=68MB;88;93;0;98\n",
+        );
+        assert_eq!(result.summary, "DISK OK - free space: / 3326 MB (56%);");
+        assert_eq!(
+            result.perfdata,
+            vec![
+                PerfData {
+                    label: "/".to_string(),
+                    value: Some(2643.0),
+                    unit: "MB".to_string(),
+                    warning: Some("5948".to_string()),
+                    critical: Some("5958".to_string()),
+                    min: Some(0.0),
+                    max: Some(5968.0),
+                },
+                PerfData {
+                    label: "/boot".to_string(),
+                    value: Some(68.0),
+                    unit: "MB".to_string(),
+                    warning: Some("88".to_string()),
+                    critical: Some("93".to_string()),
+                    min: Some(0.0),
+                    max: Some(98.0),
+                },
+            ]
+        );
+
+        let result = parse_output("PING OK - Packet loss = 0%");
+        assert_eq!(result.summary, "PING OK - Packet loss = 0%");
+        assert_eq!(result.perfdata, vec![]);
+    }
+
+    #[test]
+    fn test_parse_perfdata() {
+        assert_eq!(
+            parse_perfdata(
+                "'free space'=12,5% 'it''s'=U load1=0.42;; nonsense time=-1s;~:5;@10:20"
+            ),
+            vec![
+                perfdata("free space", Some(12.5), "%"),
+                perfdata("it's", None, ""),
+                perfdata("load1", Some(0.42), ""),
+                PerfData {
+                    warning: Some("~:5".to_string()),
+                    critical: Some("@10:20".to_string()),
+                    ..perfdata("time", Some(-1.0), "s")
+                },
+            ]
+        );
+        assert_eq!(parse_perfdata("  "), vec![]);
+    }
+
+    #[test]
+    fn test_run() {
+        let timeout = Duration::from_secs(5);
+        let result = run(
+            "echo 'LOAD WARNING - load 4.2 | load1=4.2;4;8'; exit 1",
+            timeout,
+        );
+        assert_eq!(result.status, Status::Warning);
+        assert_eq!(result.summary, "LOAD WARNING - load 4.2");
+        assert_eq!(result.perfdata[0].value, Some(4.2));
+
+        assert_eq!(run("echo 'OK'", timeout).status, Status::Ok);
+        assert_eq!(run("exit 2", timeout).status, Status::Critical);
+        assert_eq!(run("exit 3", timeout).status, Status::Unknown);
+        let result = run("echo 'check_nothing: not found' >&2; exit 127", timeout);
+        assert_eq!(result.status, Status::Unknown);
+        assert_eq!(result.summary, "check_nothing: not found");
+        let result = run("sleep 5", Duration::from_millis(100));
+        assert_eq!(result.status, Status::Critical);
+        assert_eq!(result.summary, "Timed out after 100ms");
+    }
+}
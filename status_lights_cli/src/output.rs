@@ -0,0 +1,303 @@
+//! Formats for commands that print information about devices rather than change them, so that
+//! their output can be read by other tools as well as people.
+
+use serde::{Deserialize, Serialize};
+use serialport::{SerialPortInfo, SerialPortType};
+
+use std::str::FromStr;
+
+use crate::device::{Device, SHARED_SERIAL_NUMBER};
+use crate::selector::Selectable;
+
+/// The formats that can be passed to `--format`
+pub const FORMATS: &[&str] = &["text", "json", "csv", "table"];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Format {
+    /// The original free form output, different for each command
+    Text,
+    Json,
+    Csv,
+    Table,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            "table" => Ok(Format::Table),
+            _ => Err(format!(
+                "Unknown format '{}', expected one of {:?}",
+                s, FORMATS
+            )),
+        }
+    }
+}
+
+/// Everything we know about a serial device
+#[derive(Clone, Default, PartialEq, Debug, Serialize, Deserialize)]
+pub struct DeviceInfo {
+    pub path: String,
+    pub name: Option<String>,
+    pub serial: Option<String>,
+    pub usb_port: Option<String>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub vid: Option<String>,
+    pub pid: Option<String>,
+    pub firmware_version: Option<String>,
+    pub error: Option<String>,
+}
+
+const COLUMNS: [&str; 10] = [
+    "path",
+    "name",
+    "serial",
+    "usb_port",
+    "manufacturer",
+    "product",
+    "vid",
+    "pid",
+    "firmware_version",
+    "error",
+];
+
+impl DeviceInfo {
+    /// Describes any serial port, whether or not it is a status lights device
+    pub fn from_port(port: &SerialPortInfo) -> DeviceInfo {
+        let mut info = DeviceInfo {
+            path: port.port_name.clone(),
+            ..DeviceInfo::default()
+        };
+        if let SerialPortType::UsbPort(usb) = &port.port_type {
+            info.serial = usb.serial_number.clone();
+            info.usb_port = usb.location.as_ref().map(ToString::to_string);
+            info.manufacturer = usb.manufacturer.clone();
+            info.product = usb.product.clone();
+            info.vid = Some(format!("{:04x}", usb.vid));
+            info.pid = Some(format!("{:04x}", usb.pid));
+        }
+        info
+    }
+
+    /// Describes a status lights device, asking it for its firmware version. If the device
+    /// doesn't answer, the error is recorded instead.
+    pub fn from_device(device: &mut dyn Device) -> DeviceInfo {
+        let mut info = device.describe();
+        match device.request_version() {
+            Ok(version) => {
+                info.firmware_version = Some(format!(
+                    "v{}.{}.{}",
+                    version.major, version.minor, version.patch
+                ))
+            }
+            Err(e) => info.error = Some(e.to_string()),
+        }
+        info
+    }
+
+    /// A short name for the device that can be used in urls, the last part of its path
+    pub fn id(&self) -> &str {
+        self.path.rsplit('/').next().unwrap_or_default()
+    }
+
+    /// Whether the pattern matches the device's id, path, serial number or USB port
+    pub fn matches_pattern(&self, pattern: &glob::Pattern) -> bool {
+        let usb_port = self.usb_port.as_deref();
+        [Some(self.id()), Some(self.path()), self.serial(), usb_port]
+            .iter()
+            .flatten()
+            .any(|value| pattern.matches(value))
+    }
+
+    /// The USB serial number, unless it's the one older firmware gave every device
+    pub fn unique_serial(&self) -> Option<&str> {
+        self.serial
+            .as_deref()
+            .filter(|serial| *serial != SHARED_SERIAL_NUMBER)
+    }
+
+    /// An id that stays the same when the device moves to a different path, for things like
+    /// Home Assistant that remember devices. This is the USB serial number, or failing that the
+    /// USB port it's plugged into, with anything other than letters, numbers, `-` and `_`
+    /// replaced.
+    pub fn unique_id(&self) -> String {
+        let id = match (self.unique_serial(), &self.usb_port) {
+            (Some(serial), _) => serial.to_string(),
+            (None, Some(usb_port)) => format!("port-{}", usb_port),
+            (None, None) => self.id().to_string(),
+        };
+        id.chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => c,
+                _ => '_',
+            })
+            .collect()
+    }
+
+    fn row(&self) -> [&str; 10] {
+        fn field(value: &Option<String>) -> &str {
+            value.as_deref().unwrap_or_default()
+        }
+        [
+            &self.path,
+            field(&self.name),
+            field(&self.serial),
+            field(&self.usb_port),
+            field(&self.manufacturer),
+            field(&self.product),
+            field(&self.vid),
+            field(&self.pid),
+            field(&self.firmware_version),
+            field(&self.error),
+        ]
+    }
+}
+
+impl Selectable for DeviceInfo {
+    fn path(&self) -> &str {
+        &self.path
+    }
+
+    fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or_default()
+    }
+
+    fn serial(&self) -> Option<&str> {
+        self.serial.as_deref()
+    }
+
+    fn usb_port(&self) -> Option<String> {
+        self.usb_port.clone()
+    }
+}
+
+/// Renders devices as json, csv or a table. Text output is left to each command.
+pub fn render(format: Format, devices: &[DeviceInfo]) -> Option<String> {
+    match format {
+        Format::Text => None,
+        Format::Json => {
+            Some(serde_json::to_string(devices).expect("device info is always serializable"))
+        }
+        Format::Csv => Some(to_csv(devices)),
+        Format::Table => Some(to_table(devices)),
+    }
+}
+
+fn to_csv(devices: &[DeviceInfo]) -> String {
+    let escape = |field: &str| {
+        if field.contains([',', '"', '\n']) {
+            format!("\"{}\"", field.replace('"', "\"\""))
+        } else {
+            field.to_string()
+        }
+    };
+    let mut csv = COLUMNS.join(",");
+    for device in devices {
+        csv.push('\n');
+        let row: Vec<String> = device.row().iter().map(|field| escape(field)).collect();
+        csv.push_str(&row.join(","));
+    }
+    csv
+}
+
+fn to_table(devices: &[DeviceInfo]) -> String {
+    let rows: Vec<[&str; 10]> = std::iter::once(COLUMNS)
+        .chain(devices.iter().map(DeviceInfo::row))
+        .collect();
+    let widths: Vec<usize> = (0..COLUMNS.len())
+        .map(|column| {
+            rows.iter()
+                .map(|row| row[column].chars().count())
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+    rows.iter()
+        .map(|row| {
+            row.iter()
+                .zip(&widths)
+                .map(|(field, width)| format!("{:width$}", field, width = width))
+                .collect::<Vec<_>>()
+                .join("  ")
+                .trim_end()
+                .to_string()
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn devices() -> Vec<DeviceInfo> {
+        vec![
+            DeviceInfo {
+                path: "/dev/ttyACM0".to_string(),
+                name: Some("Trinkey".to_string()),
+                usb_port: Some("1-1.2".to_string()),
+                vid: Some("0000".to_string()),
+                pid: Some("0000".to_string()),
+                firmware_version: Some("v0.1.0".to_string()),
+                ..DeviceInfo::default()
+            },
+            DeviceInfo {
+                path: "/dev/ttyACM1".to_string(),
+                name: Some("Trinkey".to_string()),
+                error: Some("Timed out, \"again\"".to_string()),
+                ..DeviceInfo::default()
+            },
+        ]
+    }
+
+    #[test]
+    fn test_ids() {
+        let mut devices = devices();
+        assert_eq!(devices[0].id(), "ttyACM0");
+        assert_eq!(devices[0].unique_id(), "port-1-1_2");
+        assert_eq!(devices[1].unique_id(), "ttyACM1");
+        devices[0].serial = Some(SHARED_SERIAL_NUMBER.to_string());
+        assert_eq!(devices[0].unique_id(), "port-1-1_2");
+        devices[0].serial = Some("E4:2F".to_string());
+        assert_eq!(devices[0].unique_id(), "E4_2F");
+    }
+
+    #[test]
+    fn test_format_from_str() {
+        assert_eq!("csv".parse(), Ok(Format::Csv));
+        assert!("xml".parse::<Format>().is_err());
+    }
+
+    #[test]
+    fn test_render_json() {
+        assert_eq!(
+            render(Format::Json, &devices()[1..]).unwrap(),
+            r#"[{"path":"/dev/ttyACM1","name":"Trinkey","serial":null,"usb_port":null,"manufacturer":null,"product":null,"vid":null,"pid":null,"firmware_version":null,"error":"Timed out, \"again\""}]"#
+        );
+    }
+
+    #[test]
+    fn test_render_csv() {
+        assert_eq!(
+            render(Format::Csv, &devices()).unwrap(),
+            "path,name,serial,usb_port,manufacturer,product,vid,pid,firmware_version,error\n\
+             /dev/ttyACM0,Trinkey,,1-1.2,,,0000,0000,v0.1.0,\n\
+             /dev/ttyACM1,Trinkey,,,,,,,,\"Timed out, \"\"again\"\"\""
+        );
+    }
+
+    #[test]
+    fn test_render_table() {
+        assert_eq!(
+            render(Format::Table, &devices()).unwrap(),
+            "path          name     serial  usb_port  manufacturer  product  vid   pid   firmware_version  error\n\
+             /dev/ttyACM0  Trinkey          1-1.2                            0000  0000  v0.1.0\n\
+             /dev/ttyACM1  Trinkey                                                                         Timed out, \"again\""
+        );
+    }
+}
@@ -0,0 +1,379 @@
+//! Shows how far through a job is by filling the leds from the first to the last. Each led is an
+//! equal step, and the step that's partly done is lit at part brightness. The bar can be on each
+//! selected device, or span all of them in a set order so that more leds give finer steps.
+//!
+//! When there's no way to tell how far through a job is, the bar shows a light chasing along it
+//! instead, which needs the daemon to play.
+
+use status_lights_messages::{LedColorTimed, Rgb};
+
+use std::cmp::Ordering;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::device::LEDS_PER_DEVICE;
+use crate::effect::Effect;
+use crate::ipc::{Action, DaemonRequest, DaemonResponse, LedEffect};
+use crate::output::DeviceInfo;
+use crate::report::{DeviceReport, Outcome, Report};
+use crate::selector::DeviceSelector;
+
+/// How long the busy light spends on each led
+const BUSY_STEP: Duration = Duration::from_millis(500);
+
+/// The names of every order, as used by `--order`
+pub const ORDERS: &[&str] = &["path", "usb-port", "serial", "name"];
+
+/// The order devices are put in when a bar spans them
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Order {
+    Path,
+    UsbPort,
+    Serial,
+    Name,
+}
+
+impl FromStr for Order {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "path" => Ok(Order::Path),
+            "usb-port" => Ok(Order::UsbPort),
+            "serial" => Ok(Order::Serial),
+            "name" => Ok(Order::Name),
+            _ => Err(format!(
+                "Unknown order '{}', expected one of {:?}",
+                s, ORDERS
+            )),
+        }
+    }
+}
+
+impl Order {
+    /// Sorts the devices, putting those without the value being sorted on last. Numbers within
+    /// values are compared as numbers, so `1-1.2` comes before `1-1.10`.
+    pub fn sort(&self, devices: &mut [DeviceInfo]) {
+        devices.sort_by(|a, b| {
+            let (a_key, b_key) = (self.key(a), self.key(b));
+            let by_key = match (a_key, b_key) {
+                (Some(a), Some(b)) => natural_cmp(a, b),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => Ordering::Equal,
+            };
+            by_key.then_with(|| natural_cmp(&a.path, &b.path))
+        });
+    }
+
+    fn key<'a>(&self, device: &'a DeviceInfo) -> Option<&'a str> {
+        match self {
+            Order::Path => Some(&device.path),
+            Order::UsbPort => device.usb_port.as_deref(),
+            Order::Serial => device.serial.as_deref(),
+            Order::Name => device.name.as_deref(),
+        }
+    }
+}
+
+/// Compares runs of digits by their value and everything else as text
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    #[derive(PartialEq, Eq, PartialOrd, Ord)]
+    enum Chunk<'a> {
+        Number(u128),
+        Text(&'a str),
+    }
+    fn chunks(s: &str) -> Vec<Chunk<'_>> {
+        let mut chunks = Vec::new();
+        let mut rest = s;
+        while let Some(first) = rest.chars().next() {
+            let digits = first.is_ascii_digit();
+            let end = rest
+                .find(|c: char| c.is_ascii_digit() != digits)
+                .unwrap_or(rest.len());
+            let (chunk, remaining) = rest.split_at(end);
+            chunks.push(match chunk.parse() {
+                Ok(number) if digits => Chunk::Number(number),
+                _ => Chunk::Text(chunk),
+            });
+            rest = remaining;
+        }
+        chunks
+    }
+    chunks(a).cmp(&chunks(b)).then_with(|| a.cmp(b))
+}
+
+/// What the bar shows
+#[derive(Clone, Debug, PartialEq)]
+pub enum Bar {
+    /// Fills `percent` of the leds with `color`, showing `empty` on the rest. An empty color that's
+    /// off shows the led's background. Shows for `seconds`, or indefinitely if it's 0.
+    Fill {
+        percent: f64,
+        color: Rgb,
+        empty: Rgb,
+        seconds: u8,
+    },
+    /// A light that chases along the leds, going all the way along once per `period`. If there's
+    /// no period, it spends half a second on each led.
+    Busy {
+        color: Rgb,
+        period: Option<Duration>,
+    },
+}
+
+impl Bar {
+    /// The color of each of `leds` leds for a filled bar, from the first to the last
+    pub fn fill(percent: f64, leds: usize, color: Rgb, empty: Rgb) -> Vec<Rgb> {
+        (0..leds)
+            .map(|index| fill_color(percent, index, leds, color, empty))
+            .collect()
+    }
+
+    /// The action that shows the part of the bar at `index` of `leds`, on led `led` of a device
+    pub fn action(&self, index: usize, leds: usize, led: u8) -> Action {
+        match self {
+            Bar::Fill {
+                percent,
+                color,
+                empty,
+                seconds,
+            } => Action::Foreground(LedColorTimed::new(
+                led,
+                fill_color(*percent, index, leds, *color, *empty),
+                *seconds,
+            )),
+            Bar::Busy { color, period } => Action::Effect(LedEffect {
+                led,
+                effect: Some(Effect::Chase {
+                    color: *color,
+                    period: period.unwrap_or(BUSY_STEP * leds as u32),
+                    offset: index as f32 / leds as f32,
+                    width: (2.0 / leds as f32).min(1.0),
+                }),
+            }),
+        }
+    }
+
+    /// Shows the bar on each of the selected devices, using `apply` to run each request
+    pub fn show(
+        &self,
+        selector: &DeviceSelector,
+        mut apply: impl FnMut(&DaemonRequest) -> DaemonResponse,
+    ) -> Report {
+        let leds = LEDS_PER_DEVICE as usize;
+        let responses = (0..LEDS_PER_DEVICE)
+            .map(|led| {
+                apply(&DaemonRequest {
+                    selector: selector.clone(),
+                    action: self.action(led as usize, leds, led),
+                    owner: None,
+                })
+            })
+            .collect();
+        merge(responses)
+    }
+
+    /// Shows one bar across all of the selected devices, one after another in `order`
+    pub fn show_across(
+        &self,
+        selector: &DeviceSelector,
+        order: Order,
+        mut apply: impl FnMut(&DaemonRequest) -> DaemonResponse,
+    ) -> Report {
+        let mut devices = match apply(&DaemonRequest {
+            selector: selector.clone(),
+            action: Action::List,
+            owner: None,
+        }) {
+            DaemonResponse::Devices(devices) if devices.is_empty() => {
+                return Report::failed(Outcome::NoDevices, "No devices found".to_string())
+            }
+            DaemonResponse::Devices(devices) => devices,
+            DaemonResponse::Report(report) => return report,
+            DaemonResponse::Claims(_) | DaemonResponse::Snapshot(_) => {
+                return Report::failed(
+                    Outcome::DeviceError,
+                    "The daemon didn't respond with a device list".to_string(),
+                )
+            }
+        };
+        order.sort(&mut devices);
+        let leds = devices.len() * LEDS_PER_DEVICE as usize;
+        let mut responses = Vec::new();
+        for (position, device) in devices.iter().enumerate() {
+            for led in 0..LEDS_PER_DEVICE {
+                let index = position * LEDS_PER_DEVICE as usize + led as usize;
+                responses.push(apply(&DaemonRequest {
+                    selector: DeviceSelector::only(&device.path),
+                    action: self.action(index, leds, led),
+                    owner: None,
+                }));
+            }
+        }
+        merge(responses)
+    }
+}
+
+/// The color of the led at `index` of `leds`, lit in part if it's the step that's partly done
+fn fill_color(percent: f64, index: usize, leds: usize, color: Rgb, empty: Rgb) -> Rgb {
+    let lit = (percent / 100.0).clamp(0.0, 1.0) * leds as f64;
+    let amount = (lit - index as f64).clamp(0.0, 1.0);
+    match amount > 0.0 {
+        true => color.scale(amount as f32),
+        false => empty,
+    }
+}
+
+/// Combines the report for each led into one for each device, which failed if any of its leds
+/// did. A request that failed before reaching any device fails the whole bar.
+fn merge(responses: Vec<DaemonResponse>) -> Report {
+    let mut devices: Vec<DeviceReport> = Vec::new();
+    for response in responses {
+        let report = match response {
+            DaemonResponse::Report(report) => report,
+            DaemonResponse::Devices(_)
+            | DaemonResponse::Claims(_)
+            | DaemonResponse::Snapshot(_) => {
+                return Report::failed(
+                    Outcome::DeviceError,
+                    "The daemon didn't respond with a report".to_string(),
+                )
+            }
+        };
+        if report.error.is_some() {
+            return report;
+        }
+        for device in report.devices {
+            match devices.iter_mut().find(|known| known.path == device.path) {
+                Some(known) if known.ok => *known = device,
+                Some(_) => {}
+                None => devices.push(device),
+            }
+        }
+    }
+    Report::from_devices(devices)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::daemon::Daemon;
+    use crate::mock::{MockDevice, MockSource};
+
+    const GREEN: Rgb = Rgb::new(0, 255, 0);
+    const BLUE: Rgb = Rgb::new(0, 0, 255);
+
+    fn fill(percent: f64) -> Bar {
+        Bar::Fill {
+            percent,
+            color: GREEN,
+            empty: Rgb::OFF,
+            seconds: 0,
+        }
+    }
+
+    #[test]
+    fn test_fill() {
+        assert_eq!(Bar::fill(0.0, 4, GREEN, BLUE), [BLUE; 4]);
+        assert_eq!(Bar::fill(50.0, 4, GREEN, BLUE), [GREEN, GREEN, BLUE, BLUE]);
+        assert_eq!(Bar::fill(100.0, 4, GREEN, BLUE), [GREEN; 4]);
+        assert_eq!(Bar::fill(150.0, 2, GREEN, BLUE), [GREEN; 2]);
+
+        // 60% of 4 leds is 2.4, so the third is lit at 40% brightness
+        let partial = Bar::fill(60.0, 4, GREEN, Rgb::OFF);
+        assert_eq!(partial[..2], [GREEN, GREEN]);
+        assert_eq!(partial[2], GREEN.scale(0.4));
+        assert_eq!(partial[3], Rgb::OFF);
+        // Any progress lights the led, however dimly
+        assert_eq!(Bar::fill(0.001, 2, GREEN, BLUE), [Rgb::new(0, 1, 0), BLUE]);
+    }
+
+    #[test]
+    fn test_busy() {
+        let busy = Bar::Busy {
+            color: GREEN,
+            period: None,
+        };
+        let offsets = (0..4)
+            .map(|led| match busy.action(led as usize, 4, led) {
+                Action::Effect(LedEffect {
+                    effect: Some(Effect::Chase { offset, period, .. }),
+                    ..
+                }) => {
+                    assert_eq!(period, Duration::from_secs(2));
+                    offset
+                }
+                action => panic!("Expected a chase, got {:?}", action),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(offsets, [0.0, 0.25, 0.5, 0.75]);
+    }
+
+    #[test]
+    fn test_order() {
+        let device = |path: &str, usb_port: Option<&str>| DeviceInfo {
+            path: path.to_string(),
+            usb_port: usb_port.map(str::to_string),
+            ..DeviceInfo::default()
+        };
+        let mut devices = vec![
+            device("/dev/ttyACM10", Some("1-1.2")),
+            device("/dev/ttyACM2", None),
+            device("/dev/ttyACM1", Some("1-1.10")),
+        ];
+        let paths = |devices: &[DeviceInfo]| {
+            devices
+                .iter()
+                .map(|device| device.path.clone())
+                .collect::<Vec<_>>()
+        };
+        Order::Path.sort(&mut devices);
+        assert_eq!(
+            paths(&devices),
+            ["/dev/ttyACM1", "/dev/ttyACM2", "/dev/ttyACM10"]
+        );
+        Order::UsbPort.sort(&mut devices);
+        assert_eq!(
+            paths(&devices),
+            ["/dev/ttyACM10", "/dev/ttyACM1", "/dev/ttyACM2"]
+        );
+        assert!("usb_port".parse::<Order>().is_err());
+    }
+
+    #[test]
+    fn test_show() {
+        let first = MockDevice::new("/dev/ttyACM0").with_usb_port("1-2");
+        let second = MockDevice::new("/dev/ttyACM1").with_usb_port("1-1");
+        let daemon = Daemon::new(Box::new(MockSource::new(vec![
+            first.clone(),
+            second.clone(),
+        ])));
+        let all = DeviceSelector::all();
+
+        let report = fill(50.0).show(&all, |request| daemon.handle(request));
+        assert_eq!(report.outcome, Outcome::Success);
+        assert_eq!(report.devices.len(), 2);
+        for device in [&first, &second] {
+            assert_eq!(
+                device.state().foreground,
+                [GREEN, GREEN, Rgb::OFF, Rgb::OFF]
+            );
+        }
+
+        // Spanning the devices makes one bar of 8 leds, starting on the lowest usb port
+        let report = fill(75.0).show_across(&all, Order::UsbPort, |request| daemon.handle(request));
+        assert_eq!(report.outcome, Outcome::Success);
+        assert_eq!(second.state().foreground, [GREEN; 4]);
+        assert_eq!(first.state().foreground, [GREEN, GREEN, Rgb::OFF, Rgb::OFF]);
+
+        first.set_unresponsive(true);
+        let report = fill(100.0).show_across(&all, Order::Path, |request| daemon.handle(request));
+        assert_eq!(report.outcome, Outcome::PartialFailure);
+        assert_eq!(report.devices.len(), 2);
+
+        let none = DeviceSelector::only("/dev/ttyACM9");
+        let report = fill(100.0).show_across(&none, Order::Path, |request| daemon.handle(request));
+        assert_eq!(report.outcome, Outcome::NoDevices);
+    }
+}
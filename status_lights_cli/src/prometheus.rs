@@ -0,0 +1,300 @@
+//! Evaluates PromQL queries against Prometheus's HTTP API for the monitor's `prometheus` checks.
+//!
+//! A query's value is compared to the check's `warning` and `critical` thresholds, which are
+//! upper limits unless `critical` is below `warning`, in which case they're lower limits. With a
+//! `gradient`, the led shows the value's color from it instead of the status's. A query that
+//! returns several series is judged by the worst of them.
+//!
+//! ```toml
+//! [[monitor.checks]]
+//! name = "cpu"
+//! type = "prometheus"
+//! url = "http://prometheus.local:9090"
+//! query = 'max(100 - avg by (instance) (rate(node_cpu_seconds_total{mode="idle"}[5m])) * 100)'
+//! thresholds = { warning = 70, critical = 90 }
+//! led = 0
+//! ```
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::gradient::Gradient;
+use crate::monitor::{CheckResult, Status, Thresholds};
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PrometheusCheck {
+    /// Where Prometheus is, eg `http://localhost:9090`
+    pub url: String,
+    pub query: String,
+    #[serde(default)]
+    pub thresholds: Thresholds,
+    /// Colors to show for the value, instead of the status's
+    pub gradient: Option<Gradient>,
+}
+
+/// The body of a query response. Only the fields that are used are read.
+#[derive(Deserialize)]
+struct QueryResponse {
+    status: String,
+    data: Option<QueryData>,
+    error: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct QueryData {
+    #[serde(rename = "resultType")]
+    result_type: String,
+    result: Value,
+}
+
+#[derive(Deserialize)]
+struct Series {
+    #[serde(default)]
+    metric: BTreeMap<String, String>,
+    value: Sample,
+}
+
+/// A timestamp and the value, which Prometheus sends as a string
+type Sample = (f64, String);
+
+impl PrometheusCheck {
+    pub fn run(&self, timeout: Duration) -> CheckResult {
+        let samples = match self.query(timeout) {
+            Ok(samples) => samples,
+            Err(e) => return CheckResult::new(Status::Unknown, e),
+        };
+        let parsed = samples
+            .into_iter()
+            .map(|(labels, value)| {
+                let number = value.parse::<f64>().ok().filter(|n| !n.is_nan());
+                (labels, value, number)
+            })
+            .collect::<Vec<_>>();
+        let worst = parsed
+            .iter()
+            .filter_map(|(labels, value, number)| number.map(|number| (labels, value, number)))
+            .max_by(|a, b| self.thresholds.compare(a.2, b.2));
+        let (labels, value, number) = match (worst, parsed.first()) {
+            (Some(worst), _) => worst,
+            (None, Some((_, value, _))) => {
+                return CheckResult::new(Status::Unknown, format!("The query returned {}", value))
+            }
+            (None, None) => {
+                return CheckResult::new(Status::Unknown, "The query returned nothing".to_string())
+            }
+        };
+        let summary = match labels.is_empty() {
+            true => value.clone(),
+            false => format!("{} {}", value, labels),
+        };
+        CheckResult {
+            color: self
+                .gradient
+                .as_ref()
+                .map(|gradient| gradient.color_at(number)),
+            ..CheckResult::new(self.thresholds.status(number), summary)
+        }
+    }
+
+    /// Runs the query, returning the value of each series along with its labels
+    fn query(&self, timeout: Duration) -> Result<Vec<(String, String)>, String> {
+        let url = format!("{}/api/v1/query", self.url.trim_end_matches('/'));
+        let response = ureq::AgentBuilder::new()
+            .timeout(timeout)
+            .build()
+            .get(&url)
+            .query("query", &self.query)
+            .call();
+        let response: QueryResponse = match response {
+            Ok(response) => response.into_json(),
+            // Prometheus explains bad queries in the body of the error
+            Err(ureq::Error::Status(_, response)) => response.into_json(),
+            Err(e) => return Err(e.to_string()),
+        }
+        .map_err(|e| format!("Unexpected response from Prometheus: {}", e))?;
+        let data = match (response.status.as_str(), response.data) {
+            ("success", Some(data)) => data,
+            _ => {
+                return Err(response
+                    .error
+                    .unwrap_or_else(|| "The query failed".to_string()))
+            }
+        };
+        let unexpected = |e: serde_json::Error| format!("Unexpected result from Prometheus: {}", e);
+        match data.result_type.as_str() {
+            "vector" => Ok(serde_json::from_value::<Vec<Series>>(data.result)
+                .map_err(unexpected)?
+                .into_iter()
+                .map(|series| (describe(&series.metric), series.value.1))
+                .collect()),
+            "scalar" => {
+                let sample: Sample = serde_json::from_value(data.result).map_err(unexpected)?;
+                Ok(vec![(String::new(), sample.1)])
+            }
+            // Range vectors and strings can't be turned into a color
+            _ => Err("The query needs to return an instant vector or scalar".to_string()),
+        }
+    }
+}
+
+/// Labels in the same form as PromQL, leaving out the metric name
+fn describe(metric: &BTreeMap<String, String>) -> String {
+    let labels = metric
+        .iter()
+        .filter(|(name, _)| name.as_str() != "__name__")
+        .map(|(name, value)| format!("{}=\"{}\"", name, value))
+        .collect::<Vec<_>>();
+    match labels.is_empty() {
+        true => String::new(),
+        false => format!("{{{}}}", labels.join(", ")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::gradient::Stop;
+    use crate::http::{self, Response};
+    use serde_json::json;
+    use status_lights_messages::Rgb;
+
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::thread;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Stands in for Prometheus, answering a few queries
+    fn prometheus() -> String {
+        let handler = |request: &http::Request| {
+            if request.path != "/api/v1/query" {
+                return Response::json(404, &json!({"status": "error", "error": "not found"}));
+            }
+            let vector = |values: &[(&str, &str)]| {
+                let result = values
+                    .iter()
+                    .map(|(instance, value)| {
+                        json!({
+                            "metric": {"__name__": "node_load1", "instance": instance},
+                            "value": [1700000000.123, value],
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                json!({"status": "success", "data": {"resultType": "vector", "result": result}})
+            };
+            let body = match request.query.as_deref().unwrap_or_default() {
+                "query=node_load1" => vector(&[("pi-1", "0.5"), ("pi-2", "2.5"), ("pi-3", "1")]),
+                "query=up" => vector(&[]),
+                "query=nan" => vector(&[("pi-1", "NaN")]),
+                "query=scalar%281%29" => json!({
+                    "status": "success",
+                    "data": {"resultType": "scalar", "result": [1700000000.123, "1"]},
+                }),
+                "query=rate%28" => {
+                    return Response::json(
+                        400,
+                        &json!({
+                            "status": "error",
+                            "errorType": "bad_data",
+                            "error": "parse error: unclosed left parenthesis",
+                        }),
+                    )
+                }
+                _ => return Response::json(500, &json!({"status": "error"})),
+            };
+            Response::json(200, &body)
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        thread::spawn(move || http::serve(listener, None, Arc::new(handler)));
+        url
+    }
+
+    fn check(url: &str, query: &str, thresholds: Option<(f64, f64)>) -> PrometheusCheck {
+        PrometheusCheck {
+            url: url.to_string(),
+            query: query.to_string(),
+            thresholds: Thresholds::new(
+                thresholds.map(|(warning, _)| warning),
+                thresholds.map(|(_, critical)| critical),
+            ),
+            gradient: None,
+        }
+    }
+
+    fn run(check: &PrometheusCheck) -> (Status, String) {
+        let result = check.run(TIMEOUT);
+        (result.status, result.summary)
+    }
+
+    #[test]
+    fn test_thresholds() {
+        let url = prometheus();
+        let summary = "2.5 {instance=\"pi-2\"}".to_string();
+        let load = |thresholds| run(&check(&url, "node_load1", thresholds));
+        assert_eq!(load(None), (Status::Ok, summary.clone()));
+        assert_eq!(load(Some((3.0, 4.0))), (Status::Ok, summary.clone()));
+        assert_eq!(load(Some((2.0, 4.0))), (Status::Warning, summary.clone()));
+        assert_eq!(load(Some((1.0, 2.0))), (Status::Critical, summary));
+        // A critical threshold below the warning one makes them lower limits
+        assert_eq!(
+            load(Some((0.6, 0.2))),
+            (Status::Warning, "0.5 {instance=\"pi-1\"}".to_string())
+        );
+
+        let scalar = check(&url, "scalar(1)", Some((0.5, 2.0)));
+        assert_eq!(run(&scalar), (Status::Warning, "1".to_string()));
+    }
+
+    #[test]
+    fn test_failures() {
+        let url = prometheus();
+        assert_eq!(
+            run(&check(&url, "up", None)),
+            (Status::Unknown, "The query returned nothing".to_string())
+        );
+        assert_eq!(
+            run(&check(&url, "nan", None)),
+            (Status::Unknown, "The query returned NaN".to_string())
+        );
+        assert_eq!(
+            run(&check(&url, "rate(", None)),
+            (
+                Status::Unknown,
+                "parse error: unclosed left parenthesis".to_string()
+            )
+        );
+        assert_eq!(
+            run(&check("http://127.0.0.1:1", "up", None)).0,
+            Status::Unknown
+        );
+    }
+
+    #[test]
+    fn test_gradient() {
+        let url = prometheus();
+        let gradient = Gradient::new(vec![
+            Stop {
+                at: 0.0,
+                color: Rgb::new(0, 100, 0),
+            },
+            Stop {
+                at: 5.0,
+                color: Rgb::new(100, 0, 0),
+            },
+        ])
+        .unwrap();
+        let result = PrometheusCheck {
+            gradient: Some(gradient),
+            ..check(&url, "node_load1", None)
+        }
+        .run(TIMEOUT);
+        assert_eq!(result.status, Status::Ok);
+        assert_eq!(result.color, Some(Rgb::new(63, 39, 0)));
+        assert_eq!(check(&url, "node_load1", None).run(TIMEOUT).color, None);
+    }
+}
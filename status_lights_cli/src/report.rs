@@ -0,0 +1,196 @@
+//! The outcome of a cli command, used to pick the exit code and to tell the user (or a wrapper
+//! script) what happened to each device.
+//!
+//! | Exit code | Outcome                                                   |
+//! |-----------|-----------------------------------------------------------|
+//! | 0         | Every device was updated                                  |
+//! | 1         | Every device failed, or devices couldn't be enumerated    |
+//! | 2         | The command line arguments were invalid                   |
+//! | 3         | Some devices were updated but at least one failed         |
+//! | 4         | No matching devices were found                            |
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Success,
+    DeviceError,
+    UsageError,
+    PartialFailure,
+    NoDevices,
+}
+
+impl Outcome {
+    /// The outcome of running a command against `total` devices, `failures` of which failed
+    pub fn from_failures(total: usize, failures: usize) -> Outcome {
+        if total == 0 {
+            Outcome::NoDevices
+        } else if failures == 0 {
+            Outcome::Success
+        } else if failures == total {
+            Outcome::DeviceError
+        } else {
+            Outcome::PartialFailure
+        }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Outcome::Success => 0,
+            Outcome::DeviceError => 1,
+            Outcome::UsageError => 2,
+            Outcome::PartialFailure => 3,
+            Outcome::NoDevices => 4,
+        }
+    }
+
+    pub fn exit(&self) -> ! {
+        std::process::exit(self.exit_code())
+    }
+}
+
+/// What happened to a single device
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct DeviceReport {
+    pub path: String,
+    pub name: String,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Why the change is waiting to be made, rather than made straight away
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub queued: Option<String>,
+}
+
+impl DeviceReport {
+    pub fn new<E: ToString>(path: &str, name: &str, result: &Result<(), E>) -> DeviceReport {
+        DeviceReport {
+            path: path.to_string(),
+            name: name.to_string(),
+            ok: result.is_ok(),
+            error: result.as_ref().err().map(ToString::to_string),
+            queued: None,
+        }
+    }
+
+    /// A change that will be made later, which counts as a success
+    pub fn queued(path: &str, name: &str, reason: String) -> DeviceReport {
+        DeviceReport {
+            queued: Some(reason),
+            ..DeviceReport::new::<String>(path, name, &Ok(()))
+        }
+    }
+
+    /// A single human readable line describing what happened
+    pub fn summary(&self) -> String {
+        match (&self.error, &self.queued) {
+            (None, None) => format!("Changed device '{}' at '{}'", self.name, self.path),
+            (None, Some(reason)) => format!(
+                "Queued change for device '{}' at '{}': {}",
+                self.name, self.path, reason
+            ),
+            (Some(error), _) => format!(
+                "Failed to change device '{}' at '{}': {}",
+                self.name, self.path, error
+            ),
+        }
+    }
+}
+
+/// What happened to every device a command was run against
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Report {
+    pub outcome: Outcome,
+    pub exit_code: i32,
+    pub devices: Vec<DeviceReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Report {
+    pub fn from_devices(devices: Vec<DeviceReport>) -> Report {
+        let failures = devices.iter().filter(|device| !device.ok).count();
+        Report::new(
+            Outcome::from_failures(devices.len(), failures),
+            devices,
+            None,
+        )
+    }
+
+    /// A report for a command that succeeded without needing to change any devices
+    pub fn unchanged() -> Report {
+        Report::new(Outcome::Success, Vec::new(), None)
+    }
+
+    /// A report for a command that failed before any device could be changed
+    pub fn failed(outcome: Outcome, error: String) -> Report {
+        Report::new(outcome, Vec::new(), Some(error))
+    }
+
+    fn new(outcome: Outcome, devices: Vec<DeviceReport>, error: Option<String>) -> Report {
+        Report {
+            outcome,
+            exit_code: outcome.exit_code(),
+            devices,
+            error,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("reports are always serializable")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn device(path: &str, result: Result<(), &str>) -> DeviceReport {
+        DeviceReport::new(path, "Trinkey", &result)
+    }
+
+    #[test]
+    fn test_outcome_from_devices() {
+        let ok = || device("/dev/ttyACM0", Ok(()));
+        let failed = || device("/dev/ttyACM1", Err("Timed out"));
+
+        assert_eq!(Report::from_devices(vec![]).outcome, Outcome::NoDevices);
+        assert_eq!(
+            Report::from_devices(vec![ok(), ok()]).outcome,
+            Outcome::Success
+        );
+        assert_eq!(
+            Report::from_devices(vec![ok(), failed()]).outcome,
+            Outcome::PartialFailure
+        );
+        assert_eq!(
+            Report::from_devices(vec![failed(), failed()]).outcome,
+            Outcome::DeviceError
+        );
+    }
+
+    #[test]
+    fn test_summary() {
+        assert_eq!(
+            device("/dev/ttyACM0", Ok(())).summary(),
+            "Changed device 'Trinkey' at '/dev/ttyACM0'"
+        );
+        assert_eq!(
+            device("/dev/ttyACM1", Err("Timed out")).summary(),
+            "Failed to change device 'Trinkey' at '/dev/ttyACM1': Timed out"
+        );
+    }
+
+    #[test]
+    fn test_to_json() {
+        let report = Report::from_devices(vec![
+            device("/dev/ttyACM0", Ok(())),
+            device("/dev/ttyACM1", Err("Timed out")),
+        ]);
+        assert_eq!(
+            report.to_json(),
+            r#"{"outcome":"partial_failure","exit_code":3,"devices":[{"path":"/dev/ttyACM0","name":"Trinkey","ok":true},{"path":"/dev/ttyACM1","name":"Trinkey","ok":false,"error":"Timed out"}]}"#
+        );
+    }
+}
@@ -0,0 +1,331 @@
+//! Choosing which devices a command should run against.
+//!
+//! A `DeviceSelector` is made of any number of include and exclude selectors. A device is
+//! selected if it matches any of the include selectors (or there aren't any) and none of the
+//! exclude selectors. Every selector value is a glob pattern, so `/dev/ttyACM*` works as well as
+//! an exact path.
+//!
+//! A device's name is its serial number, so there's no separate name selector. Devices running
+//! older firmware all report the same serial number, and can only be told apart by their path or
+//! USB port.
+
+use glob::Pattern;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use std::convert::TryFrom;
+use std::fmt;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum SelectorError {
+    #[error("Invalid pattern '{0}': {1}")]
+    InvalidPattern(String, String),
+    #[error("No device matched {0}")]
+    NoMatch(String),
+}
+
+/// The things a device can be selected by
+pub trait Selectable {
+    fn path(&self) -> &str;
+    fn name(&self) -> &str;
+    fn serial(&self) -> Option<&str>;
+    /// The physical USB port in the same form as Linux sysfs, eg `1-1.2`
+    fn usb_port(&self) -> Option<String>;
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(into = "SelectorDef", try_from = "SelectorDef")]
+pub enum Selector {
+    Path(Pattern),
+    Serial(Pattern),
+    UsbPort(Pattern),
+    /// Matches the path, serial, name or USB port
+    Any(Pattern),
+}
+
+/// How selectors are serialized, eg `{"usb_port": "1-1.2"}`
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum SelectorDef {
+    Path(String),
+    #[serde(alias = "name")]
+    Serial(String),
+    UsbPort(String),
+    Any(String),
+}
+
+impl From<Selector> for SelectorDef {
+    fn from(selector: Selector) -> Self {
+        match selector {
+            Selector::Path(p) => SelectorDef::Path(p.as_str().to_string()),
+            Selector::Serial(p) => SelectorDef::Serial(p.as_str().to_string()),
+            Selector::UsbPort(p) => SelectorDef::UsbPort(p.as_str().to_string()),
+            Selector::Any(p) => SelectorDef::Any(p.as_str().to_string()),
+        }
+    }
+}
+
+impl TryFrom<SelectorDef> for Selector {
+    type Error = SelectorError;
+
+    fn try_from(def: SelectorDef) -> Result<Self, Self::Error> {
+        match def {
+            SelectorDef::Path(p) => Selector::path(&p),
+            SelectorDef::Serial(p) => Selector::serial(&p),
+            SelectorDef::UsbPort(p) => Selector::usb_port(&p),
+            SelectorDef::Any(p) => Selector::any(&p),
+        }
+    }
+}
+
+fn pattern(pattern: &str) -> Result<Pattern, SelectorError> {
+    Pattern::new(pattern)
+        .map_err(|e| SelectorError::InvalidPattern(pattern.to_string(), e.msg.to_string()))
+}
+
+impl Selector {
+    pub fn path(path: &str) -> Result<Selector, SelectorError> {
+        Ok(Selector::Path(pattern(path)?))
+    }
+
+    pub fn serial(serial: &str) -> Result<Selector, SelectorError> {
+        Ok(Selector::Serial(pattern(serial)?))
+    }
+
+    pub fn usb_port(usb_port: &str) -> Result<Selector, SelectorError> {
+        Ok(Selector::UsbPort(pattern(usb_port)?))
+    }
+
+    pub fn any(value: &str) -> Result<Selector, SelectorError> {
+        Ok(Selector::Any(pattern(value)?))
+    }
+
+    pub fn matches<D: Selectable + ?Sized>(&self, device: &D) -> bool {
+        let usb_port = || device.usb_port();
+        match self {
+            Selector::Path(p) => p.matches(device.path()),
+            Selector::Serial(p) => matches!(device.serial(), Some(s) if p.matches(s)),
+            Selector::UsbPort(p) => matches!(usb_port(), Some(port) if p.matches(&port)),
+            Selector::Any(p) => {
+                p.matches(device.path())
+                    || p.matches(device.name())
+                    || matches!(device.serial(), Some(s) if p.matches(s))
+                    || matches!(usb_port(), Some(port) if p.matches(&port))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Selector::Path(p) => write!(f, "--device '{}'", p),
+            Selector::Serial(p) => write!(f, "--serial '{}'", p),
+            Selector::UsbPort(p) => write!(f, "--usb-port '{}'", p),
+            Selector::Any(p) => write!(f, "'{}'", p),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeviceSelector {
+    include: Vec<Selector>,
+    exclude: Vec<Selector>,
+}
+
+impl DeviceSelector {
+    /// A selector that selects every device
+    pub fn all() -> DeviceSelector {
+        DeviceSelector::default()
+    }
+
+    /// A selector that selects only the device at exactly this path
+    pub fn only(path: &str) -> DeviceSelector {
+        let path = Selector::path(&Pattern::escape(path))
+            .expect("escaped paths are always valid patterns");
+        DeviceSelector::all().include(path)
+    }
+
+    /// Leaves out the device at exactly this path
+    pub fn except(self, path: &str) -> DeviceSelector {
+        let path = Selector::path(&Pattern::escape(path))
+            .expect("escaped paths are always valid patterns");
+        self.exclude(path)
+    }
+
+    pub fn include(mut self, selector: Selector) -> DeviceSelector {
+        self.include.push(selector);
+        self
+    }
+
+    pub fn exclude(mut self, selector: Selector) -> DeviceSelector {
+        self.exclude.push(selector);
+        self
+    }
+
+    pub fn matches<D: Selectable + ?Sized>(&self, device: &D) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|s| s.matches(device)))
+            && !self.exclude.iter().any(|s| s.matches(device))
+    }
+
+    /// Filters the devices down to those selected. Fails if any include selector doesn't match
+    /// a single device, as that's almost certainly a typo or an unplugged device.
+    pub fn select<D: Selectable>(&self, devices: Vec<D>) -> Result<Vec<D>, SelectorError> {
+        if let Some(unmatched) = self
+            .include
+            .iter()
+            .find(|selector| !devices.iter().any(|device| selector.matches(device)))
+        {
+            return Err(SelectorError::NoMatch(unmatched.to_string()));
+        }
+        Ok(devices
+            .into_iter()
+            .filter(|device| self.matches(device))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct Device {
+        path: &'static str,
+        serial: &'static str,
+        usb_port: &'static str,
+    }
+
+    impl Selectable for Device {
+        fn path(&self) -> &str {
+            self.path
+        }
+
+        fn name(&self) -> &str {
+            "Trinkey"
+        }
+
+        fn serial(&self) -> Option<&str> {
+            Some(self.serial)
+        }
+
+        fn usb_port(&self) -> Option<String> {
+            Some(self.usb_port.to_string())
+        }
+    }
+
+    fn devices() -> Vec<Device> {
+        vec![
+            Device {
+                path: "/dev/ttyACM0",
+                serial: "A1",
+                usb_port: "1-1.2",
+            },
+            Device {
+                path: "/dev/ttyACM1",
+                serial: "B2",
+                usb_port: "1-1.3",
+            },
+            Device {
+                path: "/dev/ttyUSB0",
+                serial: "C3",
+                usb_port: "2-1",
+            },
+        ]
+    }
+
+    fn paths(devices: Vec<Device>) -> Vec<&'static str> {
+        devices.iter().map(|d| d.path).collect()
+    }
+
+    #[test]
+    fn test_select_all() {
+        let selected = DeviceSelector::all().select(devices()).unwrap();
+        assert_eq!(selected.len(), 3);
+    }
+
+    #[test]
+    fn test_select_multiple() {
+        let selector = DeviceSelector::all()
+            .include(Selector::serial("A1").unwrap())
+            .include(Selector::usb_port("2-*").unwrap());
+        assert_eq!(
+            paths(selector.select(devices()).unwrap()),
+            vec!["/dev/ttyACM0", "/dev/ttyUSB0"]
+        );
+    }
+
+    #[test]
+    fn test_select_glob_with_exclude() {
+        let selector = DeviceSelector::all()
+            .include(Selector::path("/dev/ttyACM*").unwrap())
+            .exclude(Selector::any("B2").unwrap());
+        assert_eq!(
+            paths(selector.select(devices()).unwrap()),
+            vec!["/dev/ttyACM0"]
+        );
+    }
+
+    #[test]
+    fn test_select_only() {
+        let selector = DeviceSelector::only("/dev/ttyACM1");
+        assert_eq!(
+            paths(selector.select(devices()).unwrap()),
+            vec!["/dev/ttyACM1"]
+        );
+        let bracketed = Device {
+            path: "/dev/tty[0]",
+            serial: "D4",
+            usb_port: "3-1",
+        };
+        assert!(DeviceSelector::only("/dev/tty[0]").matches(&bracketed));
+        assert!(!DeviceSelector::only("/dev/ttyACM?").matches(&devices()[0]));
+    }
+
+    #[test]
+    fn test_select_unmatched() {
+        let selector = DeviceSelector::all()
+            .include(Selector::path("/dev/ttyACM0").unwrap())
+            .include(Selector::serial("Kitchen").unwrap());
+        assert_eq!(
+            selector.select(devices()),
+            Err(SelectorError::NoMatch("--serial 'Kitchen'".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_serialize() {
+        let selector = DeviceSelector::all()
+            .include(Selector::usb_port("1-1.*").unwrap())
+            .exclude(Selector::any("B2").unwrap());
+        let json = serde_json::to_string(&selector).unwrap();
+        assert_eq!(
+            json,
+            r#"{"include":[{"usb_port":"1-1.*"}],"exclude":[{"any":"B2"}]}"#
+        );
+        let selector: DeviceSelector = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            paths(selector.select(devices()).unwrap()),
+            vec!["/dev/ttyACM0"]
+        );
+    }
+
+    #[test]
+    fn test_deserialize_name() {
+        let selector: DeviceSelector =
+            serde_json::from_str(r#"{"include":[{"name":"B2"}]}"#).unwrap();
+        assert_eq!(
+            paths(selector.select(devices()).unwrap()),
+            vec!["/dev/ttyACM1"]
+        );
+    }
+
+    #[test]
+    fn test_invalid_pattern() {
+        assert!(matches!(
+            Selector::path("/dev/[tty"),
+            Err(SelectorError::InvalidPattern(..))
+        ));
+    }
+}
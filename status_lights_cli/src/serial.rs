@@ -1,42 +1,69 @@
-use serialport::{SerialPort, SerialPortInfo, SerialPortType, UsbPortInfo};
+use serialport::{ClearBuffer, SerialPort, SerialPortInfo, SerialPortType, UsbPortInfo};
 use status_lights_messages::{
     LedColor, LedColorTimed, Request, Response, ResponseError, VersionNumber, DEVICE_MANUFACTURER,
     DEVICE_PRODUCT,
 };
 use thiserror::Error;
 
+use crate::device::Device;
+use crate::output::DeviceInfo;
+use crate::selector::Selectable;
+
 use std::convert::TryFrom;
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
 use std::time::Duration;
 
 const USB_TIMEOUT: Duration = Duration::from_secs(5);
 
-type ClientResult<T> = Result<T, ClientError>; // ToDo: Use a real error
+type ClientResult<T> = Result<T, ClientError>;
 
 #[derive(Error, Debug)]
 pub enum ClientError {
-    #[error("Error Received from device")]
-    ErrorReceivedFromDevice(ResponseError),
-    #[error("No Response from device: {0}")]
-    NoResponseReceived(String),
-    #[error("Unable to write to device: {0}")]
-    DeviceWriteError(String),
-    #[error("Unable to read from device: {0}")]
-    DeviceReadError(String),
-    // ToDo: Make better use of SerialPorts error type.
-    #[error("Serial device error")]
-    GeneralSerialError,
+    #[error("Error received from device at '{path}': {error:?}")]
+    ErrorReceivedFromDevice { path: String, error: ResponseError },
+    #[error("Unexpected response from device at '{path}', expected {expected} but got {bytes:?}")]
+    UnexpectedResponse {
+        path: String,
+        expected: &'static str,
+        bytes: [u8; 8],
+    },
+    #[error("Timed out waiting for device at '{path}'")]
+    Timeout { path: String },
+    #[error("Unable to communicate with device at '{path}': {source}")]
+    Io {
+        path: String,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Serial device error at '{path}': {source}")]
+    Serial {
+        path: String,
+        #[source]
+        source: serialport::Error,
+    },
+    #[error("Unable to list serial devices: {0}")]
+    ListDevices(#[source] serialport::Error),
 }
 
-impl From<ResponseError> for ClientError {
-    fn from(error: ResponseError) -> Self {
-        Self::ErrorReceivedFromDevice(error)
+impl ClientError {
+    fn from_io(path: &str, error: io::Error) -> Self {
+        if error.kind() == io::ErrorKind::TimedOut {
+            Self::Timeout {
+                path: path.to_string(),
+            }
+        } else {
+            Self::Io {
+                path: path.to_string(),
+                source: error,
+            }
+        }
     }
-}
 
-impl From<serialport::Error> for ClientError {
-    fn from(_error: serialport::Error) -> Self {
-        Self::GeneralSerialError
+    fn from_serial(path: &str, error: serialport::Error) -> Self {
+        Self::Serial {
+            path: path.to_string(),
+            source: error,
+        }
     }
 }
 
@@ -44,15 +71,17 @@ impl From<serialport::Error> for ClientError {
 pub struct AvailableDevice {
     path: String,
     name: String,
+    port: SerialPortInfo,
 }
 
 impl TryFrom<AvailableDevice> for Client {
-    type Error = serialport::Error;
+    type Error = ClientError;
 
     fn try_from(device: AvailableDevice) -> Result<Self, Self::Error> {
         let serial = serialport::new(&device.path, 9600)
             .timeout(USB_TIMEOUT)
-            .open()?;
+            .open()
+            .map_err(|e| ClientError::from_serial(&device.path, e))?;
         Ok(Client { serial, device })
     }
 }
@@ -68,6 +97,8 @@ fn is_known_device(port_info: &UsbPortInfo) -> bool {
 }
 
 impl Client {
+    /// Opens every device attached right now. `watcher::DeviceWatcher` keeps up with devices as
+    /// they're plugged in and unplugged instead.
     pub fn collect_clients() -> ClientResult<Vec<Client>> {
         Ok(Self::collect_available_devices()?
             .into_iter()
@@ -75,6 +106,29 @@ impl Client {
             .collect())
     }
 
+    /// The paths of every attached status lights device, without opening any of them
+    pub fn available_paths() -> ClientResult<Vec<String>> {
+        Ok(Self::collect_available_devices()?
+            .into_iter()
+            .map(|device| device.path)
+            .collect())
+    }
+
+    /// Opens the status lights device at the given path
+    pub fn open(path: &str) -> ClientResult<Client> {
+        let device = Self::collect_available_devices()?
+            .into_iter()
+            .find(|device| device.path == path)
+            .ok_or_else(|| {
+                let error = serialport::Error::new(
+                    serialport::ErrorKind::NoDevice,
+                    "No status lights device here",
+                );
+                ClientError::from_serial(path, error)
+            })?;
+        Client::try_from(device)
+    }
+
     pub fn get_path(&self) -> &String {
         &self.device.path
     }
@@ -83,19 +137,39 @@ impl Client {
         self.device.name.as_str()
     }
 
+    pub fn get_port_info(&self) -> &SerialPortInfo {
+        &self.device.port
+    }
+
+    fn get_usb_info(&self) -> Option<&UsbPortInfo> {
+        match &self.device.port.port_type {
+            SerialPortType::UsbPort(usb) => Some(usb),
+            _ => None,
+        }
+    }
+
+    pub fn get_serial(&self) -> Option<&str> {
+        self.get_usb_info()?.serial_number.as_deref()
+    }
+
+    /// The physical USB port the device is plugged into, eg `1-1.2`. Not every platform reports this.
+    pub fn get_usb_port(&self) -> Option<String> {
+        Some(self.get_usb_info()?.location.as_ref()?.to_string())
+    }
+
     pub fn list_all_usb_devices() -> ClientResult<Vec<SerialPortInfo>> {
-        Ok(serialport::available_ports()?)
+        serialport::available_ports().map_err(ClientError::ListDevices)
     }
 
     fn collect_available_devices() -> ClientResult<Vec<AvailableDevice>> {
-        let available_devices = serialport::available_ports()?
+        let available_devices = Self::list_all_usb_devices()?
             .into_iter()
-            .map(|port| (port.port_name, port.port_type))
-            .filter_map(|(path, port_type)| {
-                if let SerialPortType::UsbPort(port_info) = port_type {
-                    if is_known_device(&port_info) {
-                        if let Some(name) = port_info.serial_number {
-                            return Some(AvailableDevice { path, name });
+            .filter_map(|port| {
+                if let SerialPortType::UsbPort(port_info) = &port.port_type {
+                    if is_known_device(port_info) {
+                        if let Some(name) = port_info.serial_number.clone() {
+                            let path = port.port_name.clone();
+                            return Some(AvailableDevice { path, name, port });
                         }
                     }
                 }
@@ -106,15 +180,37 @@ impl Client {
     }
 
     fn send(&mut self, request: &Request) -> ClientResult<Response> {
+        let path = &self.device.path;
+        // Drop anything left over from an earlier request that timed out so responses stay in step
+        self.serial
+            .clear(ClearBuffer::Input)
+            .map_err(|e| ClientError::from_serial(path, e))?;
         self.serial
             .write_all(&request.to_bytes())
-            .map_err(|_| ClientError::DeviceWriteError(self.device.path.clone()))?;
+            .map_err(|e| ClientError::from_io(path, e))?;
         let mut buf = [0; 8];
         self.serial
             .read_exact(&mut buf)
-            .map_err(|_| ClientError::DeviceWriteError(self.device.path.clone()))?;
-        let message = Response::try_from(buf)?;
-        Ok(message)
+            .map_err(|e| ClientError::from_io(path, e))?;
+        Response::try_from(buf).map_err(|error| match error {
+            ResponseError::UnknownResponse(bytes) => ClientError::UnexpectedResponse {
+                path: path.clone(),
+                expected: "a response",
+                bytes,
+            },
+            error => ClientError::ErrorReceivedFromDevice {
+                path: path.clone(),
+                error,
+            },
+        })
+    }
+
+    fn unexpected_response(&self, expected: &'static str, response: Response) -> ClientError {
+        ClientError::UnexpectedResponse {
+            path: self.device.path.clone(),
+            expected,
+            bytes: response.to_bytes(),
+        }
     }
 
     pub fn request_version(&mut self) -> ClientResult<VersionNumber> {
@@ -123,7 +219,7 @@ impl Client {
         if let Response::Version(version_number) = message {
             Ok(version_number)
         } else {
-            panic!("Message not a version response: {:?}", message)
+            Err(self.unexpected_response("a version response", message))
         }
     }
 
@@ -133,7 +229,7 @@ impl Client {
         if message == Response::Background {
             Ok(())
         } else {
-            panic!("Message not a version response: {:?}", message)
+            Err(self.unexpected_response("a background response", message))
         }
     }
 
@@ -143,7 +239,309 @@ impl Client {
         if message == Response::Foreground {
             Ok(())
         } else {
-            panic!("Message not a version response: {:?}", message)
+            Err(self.unexpected_response("a foreground response", message))
+        }
+    }
+
+    pub fn request_uptime(&mut self) -> ClientResult<Duration> {
+        let request = Request::Uptime;
+        let message = self.send(&request)?;
+        if let Response::Uptime(seconds) = message {
+            Ok(Duration::from_secs(seconds.into()))
+        } else {
+            Err(self.unexpected_response("an uptime response", message))
         }
     }
 }
+
+impl Selectable for Client {
+    fn path(&self) -> &str {
+        self.get_path()
+    }
+
+    fn name(&self) -> &str {
+        self.get_name()
+    }
+
+    fn serial(&self) -> Option<&str> {
+        self.get_serial()
+    }
+
+    fn usb_port(&self) -> Option<String> {
+        self.get_usb_port()
+    }
+}
+
+impl Device for Client {
+    fn request_version(&mut self) -> ClientResult<VersionNumber> {
+        Client::request_version(self)
+    }
+
+    fn request_background(&mut self, led_color: LedColor) -> ClientResult<()> {
+        Client::request_background(self, led_color)
+    }
+
+    fn request_foreground(&mut self, led_color_timed: LedColorTimed) -> ClientResult<()> {
+        Client::request_foreground(self, led_color_timed)
+    }
+
+    fn request_uptime(&mut self) -> ClientResult<Duration> {
+        Client::request_uptime(self)
+    }
+
+    fn describe(&self) -> DeviceInfo {
+        let mut info = DeviceInfo::from_port(self.get_port_info());
+        info.name = Some(self.get_name().to_string());
+        info
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serialport::{DataBits, FlowControl, Parity, StopBits};
+    use status_lights_messages::ErrorResponse;
+
+    /// A serial port that answers every write with the next reply, or times out once there are
+    /// none left
+    struct FakePort {
+        replies: Vec<[u8; 8]>,
+        input: Vec<u8>,
+        fail_clear: bool,
+    }
+
+    impl Read for FakePort {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.input.is_empty() {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            let count = buf.len().min(self.input.len());
+            buf[..count].copy_from_slice(&self.input[..count]);
+            self.input.drain(..count);
+            Ok(count)
+        }
+    }
+
+    impl Write for FakePort {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if !self.replies.is_empty() {
+                let reply = self.replies.remove(0);
+                self.input.extend_from_slice(&reply);
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SerialPort for FakePort {
+        fn name(&self) -> Option<String> {
+            None
+        }
+        fn baud_rate(&self) -> serialport::Result<u32> {
+            Ok(9600)
+        }
+        fn data_bits(&self) -> serialport::Result<DataBits> {
+            Ok(DataBits::Eight)
+        }
+        fn flow_control(&self) -> serialport::Result<FlowControl> {
+            Ok(FlowControl::None)
+        }
+        fn parity(&self) -> serialport::Result<Parity> {
+            Ok(Parity::None)
+        }
+        fn stop_bits(&self) -> serialport::Result<StopBits> {
+            Ok(StopBits::One)
+        }
+        fn timeout(&self) -> Duration {
+            USB_TIMEOUT
+        }
+        fn set_baud_rate(&mut self, _: u32) -> serialport::Result<()> {
+            Ok(())
+        }
+        fn set_data_bits(&mut self, _: DataBits) -> serialport::Result<()> {
+            Ok(())
+        }
+        fn set_flow_control(&mut self, _: FlowControl) -> serialport::Result<()> {
+            Ok(())
+        }
+        fn set_parity(&mut self, _: Parity) -> serialport::Result<()> {
+            Ok(())
+        }
+        fn set_stop_bits(&mut self, _: StopBits) -> serialport::Result<()> {
+            Ok(())
+        }
+        fn set_timeout(&mut self, _: Duration) -> serialport::Result<()> {
+            Ok(())
+        }
+        fn write_request_to_send(&mut self, _: bool) -> serialport::Result<()> {
+            Ok(())
+        }
+        fn write_data_terminal_ready(&mut self, _: bool) -> serialport::Result<()> {
+            Ok(())
+        }
+        fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+            Ok(true)
+        }
+        fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+            Ok(true)
+        }
+        fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+            Ok(false)
+        }
+        fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+            Ok(false)
+        }
+        fn bytes_to_read(&self) -> serialport::Result<u32> {
+            Ok(self.input.len() as u32)
+        }
+        fn bytes_to_write(&self) -> serialport::Result<u32> {
+            Ok(0)
+        }
+        fn clear(&self, _: ClearBuffer) -> serialport::Result<()> {
+            if self.fail_clear {
+                Err(serialport::Error::new(
+                    serialport::ErrorKind::NoDevice,
+                    "unplugged",
+                ))
+            } else {
+                Ok(())
+            }
+        }
+        fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+            Err(serialport::Error::new(
+                serialport::ErrorKind::Unknown,
+                "FakePort can't be cloned",
+            ))
+        }
+        fn set_break(&self) -> serialport::Result<()> {
+            Ok(())
+        }
+        fn clear_break(&self) -> serialport::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn client(replies: Vec<[u8; 8]>) -> Client {
+        let port = FakePort {
+            replies,
+            input: Vec::new(),
+            fail_clear: false,
+        };
+        client_with_port(port)
+    }
+
+    fn client_with_port(port: FakePort) -> Client {
+        let path = "/dev/ttyACM0".to_string();
+        Client {
+            serial: Box::new(port),
+            device: AvailableDevice {
+                name: "Trinkey".to_string(),
+                port: SerialPortInfo {
+                    port_name: path.clone(),
+                    port_type: SerialPortType::Unknown,
+                },
+                path,
+            },
+        }
+    }
+
+    #[test]
+    fn test_expected_responses() {
+        let version = Response::Version(VersionNumber::new(0, 1, 0)).to_bytes();
+        let mut client = client(vec![
+            version,
+            Response::Background.to_bytes(),
+            Response::Foreground.to_bytes(),
+        ]);
+        assert_eq!(
+            client.request_version().unwrap(),
+            VersionNumber::new(0, 1, 0)
+        );
+        client
+            .request_background(LedColor::new(0, Default::default()))
+            .unwrap();
+        client
+            .request_foreground(LedColorTimed::new(0, Default::default(), 5))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_unexpected_response() {
+        let version = Response::Version(VersionNumber::new(0, 1, 0)).to_bytes();
+        let mut client = client(vec![version]);
+        let error = client
+            .request_background(LedColor::new(0, Default::default()))
+            .unwrap_err();
+        match error {
+            ClientError::UnexpectedResponse {
+                path,
+                expected,
+                bytes,
+            } => {
+                assert_eq!(path, "/dev/ttyACM0");
+                assert_eq!(expected, "a background response");
+                assert_eq!(bytes, version);
+            }
+            other => panic!("expected an unexpected response error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_unknown_response_id() {
+        let unknown = [99, 0, 0, 0, 0, 0, 0, 0];
+        let mut client = client(vec![unknown]);
+        match client.request_version().unwrap_err() {
+            ClientError::UnexpectedResponse {
+                path,
+                expected,
+                bytes,
+            } => {
+                assert_eq!(path, "/dev/ttyACM0");
+                assert_eq!(expected, "a response");
+                assert_eq!(bytes, unknown);
+            }
+            other => panic!("expected an unexpected response error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_error_from_device() {
+        let error: Response = ErrorResponse::MalformedRequestForId(2).into();
+        let mut client = client(vec![error.to_bytes()]);
+        assert!(matches!(
+            client.request_background(LedColor::new(9, Default::default())),
+            Err(ClientError::ErrorReceivedFromDevice {
+                path,
+                error: ResponseError::ErrorResponse(ErrorResponse::MalformedRequestForId(2)),
+            }) if path == "/dev/ttyACM0"
+        ));
+    }
+
+    #[test]
+    fn test_timeout() {
+        let mut client = client(vec![]);
+        assert!(matches!(
+            client.request_version(),
+            Err(ClientError::Timeout { path }) if path == "/dev/ttyACM0"
+        ));
+    }
+
+    #[test]
+    fn test_serial_error_has_path() {
+        let mut client = client_with_port(FakePort {
+            replies: vec![],
+            input: Vec::new(),
+            fail_clear: true,
+        });
+        let error = client.request_version().unwrap_err();
+        assert!(matches!(&error, ClientError::Serial { path, .. } if path == "/dev/ttyACM0"));
+        assert_eq!(
+            error.to_string(),
+            "Serial device error at '/dev/ttyACM0': unplugged"
+        );
+    }
+}
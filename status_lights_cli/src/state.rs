@@ -0,0 +1,611 @@
+//! What the daemon has told each led to show. The devices can't be asked what they are showing,
+//! so this is the only record of it, and it only knows about changes made through the daemon.
+//!
+//! Devices forget everything when they're unplugged or restart, so the daemon keeps the records
+//! of a device that goes away and replays them when it comes back or is found to have restarted
+//! (see `daemon`). The daemon also writes its records to a state file whenever they change, and
+//! reads them back when it starts, so that they survive the daemon or the host restarting.
+//! Snapshots of the records can be saved to a file with `state save` and put back with
+//! `state restore`.
+
+use serde::{Deserialize, Serialize};
+use status_lights_messages::{LedColor, LedColorTimed, Rgb};
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::claims::Claims;
+use crate::device::LEDS_PER_DEVICE;
+use crate::effect::Effect;
+use crate::ipc::{Action, LedEffect, LedLease};
+use crate::output::DeviceInfo;
+
+/// How long each color sent for an effect is held by the device. If the daemon stops, the led
+/// goes back to its background after this long.
+pub const EFFECT_HOLD_SECONDS: u8 = 2;
+
+/// How often an effect's color is sent again even if it hasn't changed, so the device doesn't
+/// drop it
+const EFFECT_REFRESH: Duration = Duration::from_secs(1);
+
+/// How long each color sent for a lease is held by the device. If the daemon stops, the led goes
+/// back to its background after at most this long.
+pub const LEASE_HOLD_SECONDS: u8 = 30;
+
+/// How often a lease's color is sent again, well before the device drops it
+const LEASE_REFRESH: Duration = Duration::from_secs(10);
+
+/// Overrides where the daemon keeps its state file
+pub const STATE_ENV: &str = "STATUS_LIGHTS_STATE";
+
+/// `$STATUS_LIGHTS_STATE`, or `status_lights/state.json` in `$XDG_STATE_HOME` (falling back to
+/// `~/.local/state`)
+pub fn default_state_path() -> Option<PathBuf> {
+    if let Some(path) = env::var_os(STATE_ENV) {
+        return Some(PathBuf::from(path));
+    }
+    env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state")))
+        .map(|dir| dir.join("status_lights").join("state.json"))
+}
+
+/// An led as the daemon last left it
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct LedState {
+    pub led: u8,
+    pub background: Rgb,
+    /// Off when the led is showing its background
+    pub foreground: Rgb,
+    /// How long until the foreground goes back to the background, if it was set for a limited
+    /// time
+    #[serde(
+        default,
+        with = "humantime_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub foreground_remaining: Option<Duration>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub effect: Option<Effect>,
+    /// Who has claimed the led, if anyone
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+}
+
+/// What an led was set to, as saved by `state save`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LedSnapshot {
+    pub led: u8,
+    pub background: Rgb,
+    pub foreground: Rgb,
+    /// When the foreground goes back to the background, if it was set for a limited time
+    #[serde(
+        default,
+        with = "humantime_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub until: Option<SystemTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub effect: Option<Effect>,
+}
+
+impl LedSnapshot {
+    /// The changes that put the led back the way it was. A foreground that has run out since is
+    /// cleared.
+    pub fn actions(&self, now: SystemTime) -> Vec<Action> {
+        let foreground = match (&self.effect, self.until) {
+            (Some(effect), _) => Action::Effect(LedEffect {
+                led: self.led,
+                effect: Some(effect.clone()),
+            }),
+            (None, None) => Action::Foreground(LedColorTimed::new(self.led, self.foreground, 0)),
+            (None, Some(until)) => match until.duration_since(now) {
+                Ok(remaining) if remaining > Duration::from_secs(0) => {
+                    Action::foreground_for(self.led, self.foreground, Some(remaining)).unwrap_or(
+                        Action::Lease(LedLease {
+                            led: self.led,
+                            color: self.foreground,
+                            until,
+                        }),
+                    )
+                }
+                _ => Action::Foreground(LedColorTimed::new(self.led, Rgb::OFF, 0)),
+            },
+        };
+        vec![
+            Action::Background(LedColor::new(self.led, self.background)),
+            foreground,
+        ]
+    }
+}
+
+/// What every led on a device was set to
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DeviceSnapshot {
+    pub path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Devices are matched by serial number or USB port when restoring, as their path can change
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serial: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usb_port: Option<String>,
+    pub leds: Vec<LedSnapshot>,
+}
+
+impl DeviceSnapshot {
+    pub(crate) fn new(
+        info: &DeviceInfo,
+        records: &DeviceLeds,
+        now: Instant,
+        wall_now: SystemTime,
+    ) -> DeviceSnapshot {
+        DeviceSnapshot {
+            path: info.path.clone(),
+            name: info.name.clone(),
+            serial: info.serial.clone(),
+            usb_port: info.usb_port.clone(),
+            leds: (0..LEDS_PER_DEVICE)
+                .zip(records.iter())
+                .map(|(led, record)| record.snapshot(led, now, wall_now))
+                .collect(),
+        }
+    }
+
+    /// The device the snapshot was taken of, as far as the snapshot knows
+    pub fn info(&self) -> DeviceInfo {
+        DeviceInfo {
+            path: self.path.clone(),
+            name: self.name.clone(),
+            serial: self.serial.clone(),
+            usb_port: self.usb_port.clone(),
+            ..DeviceInfo::default()
+        }
+    }
+
+    /// Whether the snapshot was taken of the device
+    pub fn is_of(&self, info: &DeviceInfo) -> bool {
+        self.info().unique_id() == info.unique_id()
+    }
+
+    /// Records that put the leds back the way they were. Foregrounds that run out later are kept
+    /// up by the daemon until then.
+    pub(crate) fn records(&self, now: Instant, wall_now: SystemTime) -> DeviceLeds {
+        let mut records = DeviceLeds::default();
+        for saved in &self.leds {
+            if let Some(record) = records.get_mut(saved.led as usize) {
+                *record = LedRecord::from_snapshot(saved, now, wall_now);
+            }
+        }
+        records
+    }
+}
+
+/// The file written by `state save`, and the daemon's state file
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub devices: Vec<DeviceSnapshot>,
+}
+
+impl Snapshot {
+    pub fn read(path: &Path) -> io::Result<Snapshot> {
+        let json = fs::read_to_string(path)?;
+        serde_json::from_str(&json).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Writes the snapshot to a temporary file first and then moves it into place, so the file
+    /// is never left half written
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let json = serde_json::to_string_pretty(self).expect("snapshots are always serializable");
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        fs::write(&temporary, json + "\n")?;
+        fs::rename(&temporary, path)
+    }
+}
+
+#[derive(Clone, Debug)]
+struct RunningEffect {
+    effect: Effect,
+    started: Instant,
+    /// The last color sent to the device, and when
+    sent: Option<(Rgb, Instant)>,
+}
+
+/// A foreground the daemon keeps sending until it runs out
+#[derive(Clone, Debug)]
+struct Lease {
+    /// When the color was last sent to the device
+    sent: Option<Instant>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct LedRecord {
+    background: Rgb,
+    foreground: Rgb,
+    foreground_until: Option<Instant>,
+    effect: Option<RunningEffect>,
+    lease: Option<Lease>,
+    claims: Claims,
+}
+
+/// The records for every led on a device
+pub(crate) type DeviceLeds = [LedRecord; LEDS_PER_DEVICE as usize];
+
+impl LedRecord {
+    /// The record for an led as it was saved, either by `state save` or in the state file
+    fn from_snapshot(saved: &LedSnapshot, now: Instant, wall_now: SystemTime) -> LedRecord {
+        let mut record = LedRecord::default();
+        record.set_background(saved.background);
+        match (&saved.effect, saved.until) {
+            (Some(effect), _) => record.start_effect(effect.clone(), now),
+            (None, None) => record.set_foreground(saved.foreground, 0, now),
+            (None, Some(until)) => {
+                if let Ok(remaining) = until.duration_since(wall_now) {
+                    if let Some(until) = now.checked_add(remaining) {
+                        record.start_lease(saved.foreground, until, now);
+                    }
+                }
+            }
+        }
+        record
+    }
+
+    pub fn set_background(&mut self, color: Rgb) {
+        self.background = color;
+    }
+
+    /// Records a new foreground, which replaces any effect. Zero seconds means indefinitely.
+    pub fn set_foreground(&mut self, color: Rgb, seconds: u8, now: Instant) {
+        self.effect = None;
+        self.lease = None;
+        self.foreground = color;
+        self.foreground_until = match seconds {
+            0 => None,
+            seconds => Some(now + Duration::from_secs(seconds.into())),
+        };
+    }
+
+    pub fn start_effect(&mut self, effect: Effect, now: Instant) {
+        self.set_foreground(Rgb::OFF, 0, now);
+        self.effect = Some(RunningEffect {
+            effect,
+            started: now,
+            sent: None,
+        });
+    }
+
+    /// Stops the effect, returning whether there was one
+    pub fn stop_effect(&mut self) -> bool {
+        self.effect.take().is_some()
+    }
+
+    /// Records a foreground that the daemon keeps sending until the lease runs out, which replaces
+    /// any effect
+    pub fn start_lease(&mut self, color: Rgb, until: Instant, now: Instant) {
+        self.set_foreground(color, 0, now);
+        self.foreground_until = Some(until);
+        self.lease = Some(Lease { sent: None });
+    }
+
+    /// Stops keeping the leased foreground up, returning whether there was one
+    pub fn stop_lease(&mut self) -> bool {
+        self.lease.take().is_some()
+    }
+
+    /// The request to send for the lease, if it needs refreshing. Each request is held for no
+    /// longer than the lease has left, so the device goes back to the background on time.
+    pub fn lease_frame(&mut self, led: u8, now: Instant) -> Option<LedColorTimed> {
+        let lease = self.lease.as_mut()?;
+        let remaining = self.foreground_until?.saturating_duration_since(now);
+        if remaining == Duration::from_secs(0) {
+            self.lease = None;
+            return None;
+        }
+        if matches!(lease.sent, Some(at) if now - at < LEASE_REFRESH) {
+            return None;
+        }
+        lease.sent = Some(now);
+        let seconds = remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0);
+        let seconds = seconds.min(LEASE_HOLD_SECONDS.into()) as u8;
+        Some(LedColorTimed::new(led, self.foreground, seconds))
+    }
+
+    /// The color to send for the running effect, if it has changed or needs refreshing
+    pub fn effect_frame(&mut self, now: Instant) -> Option<Rgb> {
+        let running = self.effect.as_mut()?;
+        let color = running.effect.color_at(now - running.started);
+        match running.sent {
+            Some((sent, at)) if sent == color && now - at < EFFECT_REFRESH => None,
+            _ => {
+                running.sent = Some((color, now));
+                Some(color)
+            }
+        }
+    }
+
+    pub fn claims(&self) -> &Claims {
+        &self.claims
+    }
+
+    pub fn claims_mut(&mut self) -> &mut Claims {
+        &mut self.claims
+    }
+
+    /// The requests that put the led back the way it was on a device that has been plugged back
+    /// in. Effects and leases are sent again by the next tick instead.
+    pub fn replay(&mut self, led: u8, now: Instant) -> (Option<LedColor>, Option<LedColorTimed>) {
+        if let Some(running) = &mut self.effect {
+            running.sent = None;
+        }
+        if let Some(lease) = &mut self.lease {
+            lease.sent = None;
+        }
+        let background =
+            Some(LedColor::new(led, self.background)).filter(|_| !self.background.is_off());
+        let remaining = self
+            .foreground_until
+            .map(|until| until.saturating_duration_since(now));
+        let foreground = match remaining {
+            _ if self.effect.is_some() || self.lease.is_some() || self.foreground.is_off() => None,
+            None => Some(0),
+            Some(remaining) if remaining == Duration::from_secs(0) => None,
+            Some(remaining) => {
+                let seconds = remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0);
+                Some(seconds.min(u8::MAX.into()) as u8)
+            }
+        };
+        let foreground =
+            foreground.map(|seconds| LedColorTimed::new(led, self.foreground, seconds));
+        (background, foreground)
+    }
+
+    /// The record as it can be saved, with times that still mean something once the daemon has
+    /// stopped
+    pub fn snapshot(&self, led: u8, now: Instant, wall_now: SystemTime) -> LedSnapshot {
+        let state = self.to_state(led, now);
+        LedSnapshot {
+            led,
+            background: state.background,
+            foreground: state.foreground,
+            until: state
+                .foreground_remaining
+                .filter(|_| self.effect.is_none())
+                .map(|remaining| wall_now + remaining),
+            effect: state.effect,
+        }
+    }
+
+    pub fn to_state(&self, led: u8, now: Instant) -> LedState {
+        let remaining = self
+            .foreground_until
+            .map(|until| until.saturating_duration_since(now));
+        let expired = matches!(remaining, Some(remaining) if remaining == Duration::from_secs(0));
+        LedState {
+            led,
+            background: self.background,
+            foreground: if expired { Rgb::OFF } else { self.foreground },
+            // Rounded up to whole seconds, which is all the device works in
+            foreground_remaining: remaining.filter(|_| !expired).map(|remaining| {
+                Duration::from_secs(remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0))
+            }),
+            effect: self.effect.as_ref().map(|running| running.effect.clone()),
+            owner: self.claims.owner().map(str::to_string),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::device::SHARED_SERIAL_NUMBER;
+
+    const RED: Rgb = Rgb::new(255, 0, 0);
+
+    #[test]
+    fn test_foreground_expires() {
+        let now = Instant::now();
+        let mut record = LedRecord::default();
+        record.set_background(Rgb::new(0, 1, 0));
+        record.set_foreground(RED, 90, now);
+
+        let state = record.to_state(2, now + Duration::from_millis(500));
+        assert_eq!(state.led, 2);
+        assert_eq!(state.foreground, RED);
+        assert_eq!(state.foreground_remaining, Some(Duration::from_secs(90)));
+
+        let state = record.to_state(2, now + Duration::from_secs(91));
+        assert_eq!(state.foreground, Rgb::OFF);
+        assert_eq!(state.foreground_remaining, None);
+        assert_eq!(state.background, Rgb::new(0, 1, 0));
+    }
+
+    #[test]
+    fn test_effect_frames() {
+        let now = Instant::now();
+        let mut record = LedRecord::default();
+        record.start_effect(
+            Effect::Blink {
+                color: RED,
+                period: Duration::from_secs(4),
+            },
+            now,
+        );
+
+        assert_eq!(record.effect_frame(now), Some(RED));
+        assert_eq!(record.effect_frame(now + Duration::from_millis(500)), None);
+        assert_eq!(
+            record.effect_frame(now + Duration::from_millis(1000)),
+            Some(RED)
+        );
+        assert_eq!(
+            record.effect_frame(now + Duration::from_millis(2000)),
+            Some(Rgb::OFF)
+        );
+
+        record.set_foreground(RED, 0, now);
+        assert_eq!(record.effect_frame(now), None);
+        assert_eq!(record.to_state(0, now).effect, None);
+    }
+
+    #[test]
+    fn test_lease_frames() {
+        let now = Instant::now();
+        let at = |seconds: f64| now + Duration::from_secs_f64(seconds);
+        let mut record = LedRecord::default();
+        record.start_lease(RED, at(3600.0), now);
+
+        let held = |seconds| Some(LedColorTimed::new(1, RED, seconds));
+        assert_eq!(record.lease_frame(1, now), held(LEASE_HOLD_SECONDS));
+        assert_eq!(record.lease_frame(1, at(5.0)), None);
+        assert_eq!(record.lease_frame(1, at(10.0)), held(LEASE_HOLD_SECONDS));
+        let state = record.to_state(1, at(10.0));
+        assert_eq!(state.foreground, RED);
+        assert_eq!(state.foreground_remaining, Some(Duration::from_secs(3590)));
+
+        // The last request runs out with the lease
+        assert_eq!(record.lease_frame(1, at(3590.5)), held(10));
+        assert_eq!(record.lease_frame(1, at(3600.0)), None);
+        assert_eq!(record.to_state(1, at(3600.0)).foreground, Rgb::OFF);
+        assert!(!record.stop_lease());
+
+        record.start_lease(RED, at(3600.0), now);
+        record.set_foreground(Rgb::OFF, 0, now);
+        assert_eq!(record.lease_frame(1, now), None);
+    }
+
+    #[test]
+    fn test_replay() {
+        let now = Instant::now();
+        let mut record = LedRecord::default();
+        assert_eq!(record.replay(3, now), (None, None));
+
+        record.set_background(Rgb::new(0, 1, 0));
+        record.set_foreground(RED, 90, now);
+        assert_eq!(
+            record.replay(3, now + Duration::from_millis(30_500)),
+            (
+                Some(LedColor::new(3, Rgb::new(0, 1, 0))),
+                Some(LedColorTimed::new(3, RED, 60))
+            )
+        );
+        assert_eq!(record.replay(3, now + Duration::from_secs(90)).1, None);
+
+        // Leases are sent again by the next tick
+        record.start_lease(RED, now + Duration::from_secs(3600), now);
+        assert!(record.lease_frame(3, now).is_some());
+        assert_eq!(record.replay(3, now).1, None);
+        assert!(record.lease_frame(3, now).is_some());
+    }
+
+    #[test]
+    fn test_snapshot_is_of() {
+        let info = |path: &str, serial: &str, usb_port: &str| DeviceInfo {
+            path: path.to_string(),
+            serial: Some(serial.to_string()),
+            usb_port: Some(usb_port.to_string()),
+            ..DeviceInfo::default()
+        };
+        let snapshot = |info: &DeviceInfo| DeviceSnapshot {
+            path: info.path.clone(),
+            name: None,
+            serial: info.serial.clone(),
+            usb_port: info.usb_port.clone(),
+            leds: Vec::new(),
+        };
+
+        // A serial number of its own follows the device wherever it's plugged in
+        let saved = snapshot(&info("/dev/ttyACM0", "A1", "1-1.2"));
+        assert!(saved.is_of(&info("/dev/ttyACM3", "A1", "2-1")));
+        assert!(!saved.is_of(&info("/dev/ttyACM0", "B2", "1-1.2")));
+
+        // Devices with the serial number older firmware gave them all are told apart by port
+        let saved = snapshot(&info("/dev/ttyACM0", SHARED_SERIAL_NUMBER, "1-1.2"));
+        assert!(saved.is_of(&info("/dev/ttyACM1", SHARED_SERIAL_NUMBER, "1-1.2")));
+        assert!(!saved.is_of(&info("/dev/ttyACM0", SHARED_SERIAL_NUMBER, "1-1.3")));
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let now = Instant::now();
+        let wall_now = SystemTime::now();
+        let mut record = LedRecord::default();
+        record.set_background(Rgb::new(0, 1, 0));
+        record.set_foreground(RED, 90, now);
+        let snapshot = record.snapshot(1, now, wall_now);
+        assert_eq!(snapshot.until, Some(wall_now + Duration::from_secs(90)));
+        assert_eq!(
+            snapshot.actions(wall_now + Duration::from_secs(30)),
+            vec![
+                Action::Background(LedColor::new(1, Rgb::new(0, 1, 0))),
+                Action::Foreground(LedColorTimed::new(1, RED, 60)),
+            ]
+        );
+        // A foreground that has run out since is cleared
+        assert_eq!(
+            snapshot.actions(wall_now + Duration::from_secs(90))[1],
+            Action::Foreground(LedColorTimed::new(1, Rgb::OFF, 0))
+        );
+
+        let blink = Effect::Blink {
+            color: RED,
+            period: Duration::from_secs(1),
+        };
+        record.start_effect(blink.clone(), now);
+        let snapshot = record.snapshot(1, now, wall_now);
+        assert_eq!(snapshot.until, None);
+        assert_eq!(
+            snapshot.actions(wall_now)[1],
+            Action::Effect(LedEffect {
+                led: 1,
+                effect: Some(blink),
+            })
+        );
+    }
+
+    #[test]
+    fn test_records_from_snapshot() {
+        let now = Instant::now();
+        let wall_now = SystemTime::now();
+        let mut records = DeviceLeds::default();
+        records[0].set_background(Rgb::new(0, 1, 0));
+        records[1].set_foreground(RED, 0, now);
+        records[2].start_lease(RED, now + Duration::from_secs(3600), now);
+        let info = DeviceInfo {
+            path: "/dev/ttyACM0".to_string(),
+            ..DeviceInfo::default()
+        };
+        let saved = DeviceSnapshot::new(&info, &records, now, wall_now);
+
+        // Read back a minute later, after the daemon has restarted
+        let later = now + Duration::from_secs(60);
+        let records = saved.records(later, wall_now + Duration::from_secs(60));
+        let states: Vec<LedState> = (0..LEDS_PER_DEVICE)
+            .zip(records.iter())
+            .map(|(led, record)| record.to_state(led, later))
+            .collect();
+        assert_eq!(states[0].background, Rgb::new(0, 1, 0));
+        assert_eq!(states[1].foreground, RED);
+        assert_eq!(states[1].foreground_remaining, None);
+        assert_eq!(states[2].foreground, RED);
+        assert_eq!(
+            states[2].foreground_remaining,
+            Some(Duration::from_secs(3540))
+        );
+        assert_eq!(
+            states[3],
+            LedState {
+                led: 3,
+                ..LedState::default()
+            }
+        );
+
+        // The lease is kept up by the daemon
+        let mut records = records;
+        assert!(records[2].lease_frame(2, later).is_some());
+    }
+}
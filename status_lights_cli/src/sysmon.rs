@@ -0,0 +1,542 @@
+//! Reads this machine's own health from `/proc` and `/sys`, for `status_lights sysmon` and the
+//! monitor's `system` checks, so each Pi can show how it's doing without any other monitoring.
+//!
+//! Each check reads one metric and compares it to `warning` and `critical` thresholds, the same
+//! as `prometheus` checks, optionally picking the led's color from a `gradient`:
+//!
+//! ```toml
+//! [[sysmon.checks]]
+//! name = "data"
+//! type = "system"
+//! metric = "disk"
+//! mount = "/mnt/data"
+//! thresholds = { warning = 80, critical = 95 }
+//! led = 2
+//! ```
+//!
+//! `throttled` reads the Raspberry Pi firmware's throttling flags, and doesn't use thresholds: it's
+//! `CRITICAL` while the Pi is under-voltage or throttled, and `WARNING` if it has been since it
+//! booted.
+
+use serde::Deserialize;
+
+use std::ffi::CString;
+use std::fs;
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use crate::gradient::Gradient;
+use crate::monitor::{CheckConfig, CheckResult, MonitorConfig, Status, Thresholds};
+
+/// What `status_lights sysmon` runs when the config file doesn't have any `[[sysmon.checks]]`
+pub const DEFAULT_CHECKS: &str = r#"
+[[checks]]
+name = "load"
+type = "system"
+metric = "load"
+thresholds = { warning = 1, critical = 2 }
+interval = "10s"
+led = 0
+
+[[checks]]
+name = "memory"
+type = "system"
+metric = "memory"
+thresholds = { warning = 80, critical = 95 }
+interval = "10s"
+led = 1
+
+[[checks]]
+name = "disk"
+type = "system"
+metric = "disk"
+thresholds = { warning = 80, critical = 95 }
+interval = "1m"
+led = 2
+
+[[checks]]
+name = "temperature"
+type = "system"
+metric = "temperature"
+thresholds = { warning = 70, critical = 80 }
+interval = "10s"
+led = 3
+
+[[checks]]
+name = "throttled"
+type = "system"
+metric = "throttled"
+interval = "10s"
+led = 3
+"#;
+
+/// Where the Raspberry Pi firmware's throttling flags are
+const THROTTLED: &str = "sys/devices/platform/soc/soc:firmware/get_throttled";
+
+/// The checks `status_lights sysmon` runs
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SysmonConfig {
+    pub checks: Vec<CheckConfig>,
+}
+
+impl Default for SysmonConfig {
+    fn default() -> Self {
+        // Parsed without `#[serde(default)]`, which would come back here
+        #[derive(Deserialize)]
+        struct Defaults {
+            checks: Vec<CheckConfig>,
+        }
+        let defaults: Defaults =
+            toml::from_str(DEFAULT_CHECKS).expect("the default checks are valid");
+        SysmonConfig {
+            checks: defaults.checks,
+        }
+    }
+}
+
+impl SysmonConfig {
+    /// Makes paths in checks relative to `dir`, the directory the config file is in
+    pub fn resolve_paths(&mut self, dir: &Path) {
+        for config in &mut self.checks {
+            config.check.resolve_paths(dir);
+        }
+    }
+
+    pub fn monitor_config(&self) -> MonitorConfig {
+        MonitorConfig {
+            checks: self.checks.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Metric {
+    /// The 1 minute load average per CPU, so 1 is every CPU being busy
+    Load,
+    /// The percentage of memory in use, not counting what can be freed
+    Memory,
+    /// The percentage of the last 10 seconds that some processes were waiting for memory, which
+    /// needs the kernel's pressure stall information
+    MemoryPressure,
+    /// The percentage of `mount` in use, the same as `df` shows
+    Disk,
+    /// The hottest thermal zone (or `zone`) in °C
+    Temperature,
+    /// The Raspberry Pi firmware's under-voltage and throttling flags
+    Throttled,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SystemCheck {
+    pub metric: Metric,
+    /// The filesystem `disk` checks, `/` by default
+    pub mount: Option<PathBuf>,
+    /// The type of the thermal zone `temperature` checks, eg `cpu-thermal`, the hottest by
+    /// default
+    pub zone: Option<String>,
+    #[serde(default)]
+    pub thresholds: Thresholds,
+    /// Colors to show for the value, instead of the status's
+    pub gradient: Option<Gradient>,
+}
+
+impl SystemCheck {
+    pub fn run(&self) -> CheckResult {
+        self.run_in(Path::new("/"))
+    }
+
+    /// Runs the check with `/proc` and `/sys` under `root`
+    fn run_in(&self, root: &Path) -> CheckResult {
+        if self.metric == Metric::Throttled {
+            return match read(&root.join(THROTTLED)).and_then(|flags| parse_throttled(&flags)) {
+                Ok(result) => result,
+                Err(e) => CheckResult::new(Status::Unknown, e),
+            };
+        }
+        match self.measure(root) {
+            Ok((value, summary)) => CheckResult {
+                color: self
+                    .gradient
+                    .as_ref()
+                    .map(|gradient| gradient.color_at(value)),
+                ..CheckResult::new(self.thresholds.status(value), summary)
+            },
+            Err(e) => CheckResult::new(Status::Unknown, e),
+        }
+    }
+
+    /// The metric's value, and a description of it
+    fn measure(&self, root: &Path) -> Result<(f64, String), String> {
+        match self.metric {
+            Metric::Load => {
+                let loadavg = read(&root.join("proc/loadavg"))?;
+                let load = loadavg
+                    .split_whitespace()
+                    .next()
+                    .and_then(|load| load.parse::<f64>().ok())
+                    .ok_or_else(|| format!("Unexpected load average '{}'", loadavg.trim()))?;
+                let cpus = count_cpus(&read(&root.join("sys/devices/system/cpu/online"))?);
+                let per_cpu = load / cpus.max(1) as f64;
+                Ok((
+                    per_cpu,
+                    format!("{:.2} per CPU ({} on {})", per_cpu, load, cpus),
+                ))
+            }
+            Metric::Memory => {
+                let meminfo = read(&root.join("proc/meminfo"))?;
+                let field = |name: &str| {
+                    meminfo
+                        .lines()
+                        .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+                        .and_then(|value| value.trim().trim_end_matches(" kB").parse::<f64>().ok())
+                        .ok_or_else(|| format!("No {} in /proc/meminfo", name))
+                };
+                let total = field("MemTotal")?;
+                let used = 100.0 * (total - field("MemAvailable")?) / total;
+                Ok((used, format!("{:.0}% used", used)))
+            }
+            Metric::MemoryPressure => {
+                let pressure = read(&root.join("proc/pressure/memory"))?;
+                let stalled = pressure
+                    .lines()
+                    .find_map(|line| line.strip_prefix("some "))
+                    .and_then(|line| {
+                        line.split_whitespace()
+                            .find_map(|field| field.strip_prefix("avg10="))
+                    })
+                    .and_then(|avg| avg.parse::<f64>().ok())
+                    .ok_or("Unexpected contents in /proc/pressure/memory")?;
+                Ok((stalled, format!("{:.1}% stalled", stalled)))
+            }
+            Metric::Disk => {
+                let mount = self.mount.as_deref().unwrap_or_else(|| Path::new("/"));
+                let used = disk_usage(mount)?;
+                Ok((used, format!("{} {:.0}% used", mount.display(), used)))
+            }
+            Metric::Temperature => {
+                let (zone, celsius) = hottest_zone(root, self.zone.as_deref())?;
+                Ok((celsius, format!("{} {:.1}°C", zone, celsius)))
+            }
+            Metric::Throttled => unreachable!("throttling doesn't have a value"),
+        }
+    }
+}
+
+fn read(path: &Path) -> Result<String, String> {
+    fs::read_to_string(path).map_err(|e| format!("Unable to read '{}': {}", path.display(), e))
+}
+
+/// Counts the CPUs in a list like `0-3,6`
+fn count_cpus(online: &str) -> usize {
+    online
+        .trim()
+        .split(',')
+        .filter_map(|range| match range.split_once('-') {
+            Some((first, last)) => {
+                Some(last.parse::<usize>().ok()? + 1 - first.parse::<usize>().ok()?)
+            }
+            None => range.parse::<usize>().ok().map(|_| 1),
+        })
+        .sum()
+}
+
+/// The percentage of a filesystem in use, counting the space reserved for root as free the same
+/// way `df` does
+fn disk_usage(mount: &Path) -> Result<f64, String> {
+    let error = |e: std::io::Error| format!("Unable to read '{}': {}", mount.display(), e);
+    let path = CString::new(mount.as_os_str().as_bytes())
+        .map_err(|_| format!("Invalid path '{}'", mount.display()))?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    // Safety: the path is a valid C string, and statvfs fills in stat when it succeeds
+    let stat = unsafe {
+        if libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return Err(error(std::io::Error::last_os_error()));
+        }
+        stat.assume_init()
+    };
+    let used = (stat.f_blocks - stat.f_bfree) as f64;
+    let available = stat.f_bavail as f64;
+    match used + available {
+        total if total > 0.0 => Ok(100.0 * used / total),
+        _ => Ok(0.0),
+    }
+}
+
+/// The type and temperature of the hottest thermal zone, or of the one named `zone`
+fn hottest_zone(root: &Path, zone: Option<&str>) -> Result<(String, f64), String> {
+    let dir = root.join("sys/class/thermal");
+    let entries =
+        fs::read_dir(&dir).map_err(|e| format!("Unable to read '{}': {}", dir.display(), e))?;
+    let mut hottest: Option<(String, f64)> = None;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let is_zone = entry
+            .file_name()
+            .to_string_lossy()
+            .starts_with("thermal_zone");
+        let kind = match read(&path.join("type")) {
+            Ok(kind) if is_zone => kind.trim().to_string(),
+            _ => continue,
+        };
+        if zone.is_some_and(|zone| zone != kind) {
+            continue;
+        }
+        // Zones that are turned off can't be read
+        let celsius = match read(&path.join("temp")).map(|temp| temp.trim().parse::<f64>()) {
+            Ok(Ok(millidegrees)) => millidegrees / 1000.0,
+            _ => continue,
+        };
+        if hottest
+            .as_ref()
+            .is_none_or(|(_, hottest)| celsius > *hottest)
+        {
+            hottest = Some((kind, celsius));
+        }
+    }
+    hottest.ok_or_else(|| match zone {
+        Some(zone) => format!("No thermal zone '{}'", zone),
+        None => "No thermal zones".to_string(),
+    })
+}
+
+/// The flags the firmware sets, which are in the low bits while they're happening and in the high
+/// bits once they've happened since boot
+const THROTTLED_FLAGS: [&str; 4] = [
+    "under-voltage",
+    "frequency capped",
+    "throttled",
+    "soft temperature limit",
+];
+
+/// Turns the firmware's flags, eg `0x50005`, into a status
+fn parse_throttled(flags: &str) -> Result<CheckResult, String> {
+    let flags = flags.trim();
+    let bits = u32::from_str_radix(flags.trim_start_matches("0x"), 16)
+        .map_err(|_| format!("Unexpected throttling flags '{}'", flags))?;
+    let names = |shift: u32| {
+        THROTTLED_FLAGS
+            .iter()
+            .enumerate()
+            .filter(|(bit, _)| bits & (1 << (bit + shift as usize)) != 0)
+            .map(|(_, name)| *name)
+            .collect::<Vec<_>>()
+    };
+    let (now, since_boot) = (names(0), names(16));
+    Ok(match (now.is_empty(), since_boot.is_empty()) {
+        (false, _) => CheckResult::new(Status::Critical, format!("Now {}", now.join(", "))),
+        (true, false) => CheckResult::new(
+            Status::Warning,
+            format!("Has been {} since booting", since_boot.join(", ")),
+        ),
+        (true, true) => CheckResult::new(Status::Ok, "Not throttled".to_string()),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::Config;
+    use crate::gradient::Stop;
+    use crate::monitor::{Check, Monitor};
+    use status_lights_messages::Rgb;
+
+    /// Makes a directory with the files a check reads
+    fn root(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let root = std::env::temp_dir().join(format!(
+            "status_lights_sysmon_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&root);
+        for (path, contents) in files {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        root
+    }
+
+    fn check(metric: Metric, warning: f64, critical: f64) -> SystemCheck {
+        SystemCheck {
+            metric,
+            mount: None,
+            zone: None,
+            thresholds: Thresholds::new(Some(warning), Some(critical)),
+            gradient: None,
+        }
+    }
+
+    fn run(check: &SystemCheck, root: &Path) -> (Status, String) {
+        let result = check.run_in(root);
+        (result.status, result.summary)
+    }
+
+    #[test]
+    fn test_metrics() {
+        let root = root(
+            "metrics",
+            &[
+                ("proc/loadavg", "3.00 1.50 0.75 2/345 6789\n"),
+                ("sys/devices/system/cpu/online", "0-3\n"),
+                (
+                    "proc/meminfo",
+                    "MemTotal:        4000000 kB\nMemFree:          500000 kB\n\
+                     MemAvailable:    1000000 kB\n",
+                ),
+                (
+                    "proc/pressure/memory",
+                    "some avg10=1.50 avg60=0.80 avg300=0.20 total=123\n\
+                     full avg10=0.00 avg60=0.00 avg300=0.00 total=0\n",
+                ),
+                ("sys/class/thermal/thermal_zone0/type", "cpu-thermal\n"),
+                ("sys/class/thermal/thermal_zone0/temp", "71540\n"),
+                ("sys/class/thermal/thermal_zone1/type", "gpu-thermal\n"),
+                ("sys/class/thermal/thermal_zone1/temp", "65000\n"),
+                ("sys/class/thermal/cooling_device0/type", "fan\n"),
+            ],
+        );
+        assert_eq!(
+            run(&check(Metric::Load, 0.7, 1.0), &root),
+            (Status::Warning, "0.75 per CPU (3 on 4)".to_string())
+        );
+        assert_eq!(
+            run(&check(Metric::Memory, 80.0, 95.0), &root),
+            (Status::Ok, "75% used".to_string())
+        );
+        assert_eq!(
+            run(&check(Metric::MemoryPressure, 1.0, 10.0), &root),
+            (Status::Warning, "1.5% stalled".to_string())
+        );
+        assert_eq!(
+            run(&check(Metric::Temperature, 60.0, 70.0), &root),
+            (Status::Critical, "cpu-thermal 71.5°C".to_string())
+        );
+        let gpu = SystemCheck {
+            zone: Some("gpu-thermal".to_string()),
+            ..check(Metric::Temperature, 60.0, 70.0)
+        };
+        assert_eq!(
+            run(&gpu, &root),
+            (Status::Warning, "gpu-thermal 65.0°C".to_string())
+        );
+        let missing = SystemCheck {
+            zone: Some("pmic".to_string()),
+            ..gpu
+        };
+        assert_eq!(
+            run(&missing, &root),
+            (Status::Unknown, "No thermal zone 'pmic'".to_string())
+        );
+        // Without pressure stall information
+        fs::remove_file(root.join("proc/pressure/memory")).unwrap();
+        assert_eq!(
+            run(&check(Metric::MemoryPressure, 1.0, 10.0), &root).0,
+            Status::Unknown
+        );
+
+        let gradient = SystemCheck {
+            gradient: Some(
+                Gradient::new(vec![
+                    Stop {
+                        at: 0.0,
+                        color: Rgb::new(0, 100, 0),
+                    },
+                    Stop {
+                        at: 100.0,
+                        color: Rgb::new(100, 0, 0),
+                    },
+                ])
+                .unwrap(),
+            ),
+            ..check(Metric::Memory, 80.0, 95.0)
+        };
+        assert_eq!(gradient.run_in(&root).color, Some(Rgb::new(85, 17, 0)));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_disk() {
+        let (status, summary) = run(&check(Metric::Disk, 101.0, 102.0), Path::new("/"));
+        assert_eq!(status, Status::Ok);
+        assert!(summary.starts_with("/ ") && summary.ends_with("% used"));
+        let missing = SystemCheck {
+            mount: Some(PathBuf::from("/nonexistent")),
+            ..check(Metric::Disk, 80.0, 95.0)
+        };
+        assert_eq!(run(&missing, Path::new("/")).0, Status::Unknown);
+    }
+
+    #[test]
+    fn test_throttled() {
+        let throttled = |flags: &str| {
+            let result = parse_throttled(flags).unwrap();
+            (result.status, result.summary)
+        };
+        assert_eq!(
+            throttled("0x0\n"),
+            (Status::Ok, "Not throttled".to_string())
+        );
+        assert_eq!(
+            throttled("0x50000"),
+            (
+                Status::Warning,
+                "Has been under-voltage, throttled since booting".to_string()
+            )
+        );
+        assert_eq!(
+            throttled("0x50005"),
+            (Status::Critical, "Now under-voltage, throttled".to_string())
+        );
+        assert!(parse_throttled("nonsense").is_err());
+
+        let root = root("throttled", &[(THROTTLED, "0x20002\n")]);
+        let check = SystemCheck {
+            thresholds: Thresholds::default(),
+            ..check(Metric::Throttled, 0.0, 0.0)
+        };
+        assert_eq!(
+            run(&check, &root),
+            (Status::Critical, "Now frequency capped".to_string())
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_count_cpus() {
+        assert_eq!(count_cpus("0-3\n"), 4);
+        assert_eq!(count_cpus("0-3,6,8-9"), 7);
+        assert_eq!(count_cpus("0"), 1);
+    }
+
+    #[test]
+    fn test_default_checks() {
+        let config = SysmonConfig::default();
+        assert_eq!(config.checks.len(), 5);
+        assert!(config
+            .checks
+            .iter()
+            .all(|check| matches!(check.check, Check::System(_))));
+        assert!(Monitor::new(&config.monitor_config()).is_ok());
+
+        // Any checks in the config file replace the defaults
+        let config = Config::parse(
+            r#"
+            [[sysmon.checks]]
+            name = "data"
+            type = "system"
+            metric = "disk"
+            mount = "/mnt/data"
+            thresholds = { warning = 80, critical = 95 }
+            led = 2
+            "#,
+            Path::new("config.toml"),
+        )
+        .unwrap();
+        assert_eq!(config.sysmon.checks.len(), 1);
+        assert_eq!(Config::default().sysmon, SysmonConfig::default());
+    }
+}
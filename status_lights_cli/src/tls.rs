@@ -0,0 +1,205 @@
+//! TLS for the HTTP API. With a `client_ca`, clients must also present a certificate signed by it
+//! (mutual TLS) before they can send a request.
+
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
+use serde::Deserialize;
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::config::ConfigError;
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// The server's certificate chain, PEM encoded
+    pub cert: PathBuf,
+    /// The server's private key, PEM encoded
+    pub key: PathBuf,
+    /// When set, only clients with a certificate signed by this CA can connect
+    pub client_ca: Option<PathBuf>,
+}
+
+impl TlsConfig {
+    /// Makes relative paths relative to `dir`
+    pub fn resolve_paths(&mut self, dir: &Path) {
+        self.cert = dir.join(&self.cert);
+        self.key = dir.join(&self.key);
+        self.client_ca = self.client_ca.as_ref().map(|ca| dir.join(ca));
+    }
+
+    pub fn server_config(&self) -> Result<Arc<ServerConfig>, ConfigError> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| ConfigError::Tls(e.to_string()))?;
+        let builder = match &self.client_ca {
+            Some(client_ca) => {
+                let mut roots = RootCertStore::empty();
+                for cert in read_certs(client_ca)? {
+                    roots.add(cert).map_err(|e| tls_error(client_ca, e))?;
+                }
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                        .build()
+                        .map_err(|e| tls_error(client_ca, e))?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let config = builder
+            .with_single_cert(read_certs(&self.cert)?, read_key(&self.key)?)
+            .map_err(|e| ConfigError::Tls(e.to_string()))?;
+        Ok(Arc::new(config))
+    }
+}
+
+fn tls_error(path: &Path, error: impl ToString) -> ConfigError {
+    ConfigError::Tls(format!("'{}': {}", path.display(), error.to_string()))
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, ConfigError> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|source| ConfigError::Read {
+            path: path.to_path_buf(),
+            source,
+        })
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, ConfigError> {
+    let certs = rustls_pemfile::certs(&mut open(path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| tls_error(path, e))?;
+    if certs.is_empty() {
+        return Err(tls_error(path, "no certificates found"));
+    }
+    Ok(certs)
+}
+
+fn read_key(path: &Path) -> Result<PrivateKeyDer<'static>, ConfigError> {
+    rustls_pemfile::private_key(&mut open(path)?)
+        .map_err(|e| tls_error(path, e))?
+        .ok_or_else(|| tls_error(path, "no private key found"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::http::{self, Response};
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+    use rustls::pki_types::PrivatePkcs8KeyDer;
+    use rustls::{ClientConfig, ClientConnection, StreamOwned};
+
+    use std::convert::TryInto;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::{fs, thread};
+
+    struct Pki {
+        ca: rcgen::Certificate,
+        server: (rcgen::Certificate, KeyPair),
+        client: (rcgen::Certificate, KeyPair),
+    }
+
+    fn pki() -> Pki {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        let signed = |names: Vec<String>| {
+            let key = KeyPair::generate().unwrap();
+            let cert = CertificateParams::new(names)
+                .unwrap()
+                .signed_by(&key, &ca, &ca_key)
+                .unwrap();
+            (cert, key)
+        };
+        Pki {
+            server: signed(vec!["localhost".to_string()]),
+            client: signed(vec!["client".to_string()]),
+            ca,
+        }
+    }
+
+    fn write(name: &str, contents: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("status_lights_{}_{}.pem", name, std::process::id()));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    /// Makes a request over TLS, returning what was read back
+    fn get(address: std::net::SocketAddr, pki: &Pki, client_cert: bool) -> std::io::Result<String> {
+        let mut roots = RootCertStore::empty();
+        roots.add(pki.ca.der().clone()).unwrap();
+        let builder =
+            ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots);
+        let config = if client_cert {
+            let (cert, key) = &pki.client;
+            let key = PrivatePkcs8KeyDer::from(key.serialize_der());
+            builder
+                .with_client_auth_cert(vec![cert.der().clone()], key.into())
+                .unwrap()
+        } else {
+            builder.with_no_client_auth()
+        };
+        let connection =
+            ClientConnection::new(Arc::new(config), "localhost".try_into().unwrap()).unwrap();
+        let mut stream = StreamOwned::new(connection, TcpStream::connect(address)?);
+        stream.write_all(b"GET /devices HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        Ok(response)
+    }
+
+    #[test]
+    fn test_mutual_tls() {
+        let pki = pki();
+        let config = TlsConfig {
+            cert: write("server_cert", &pki.server.0.pem()),
+            key: write("server_key", &pki.server.1.serialize_pem()),
+            client_ca: Some(write("ca", &pki.ca.pem())),
+        };
+        let server_config = config.server_config().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let handler = |_: &http::Request| Response::json(200, "hello");
+        thread::spawn(move || http::serve(listener, Some(server_config), Arc::new(handler)));
+
+        let response = get(address, &pki, true).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\"hello\"\n"));
+
+        let rejected = get(address, &pki, false);
+        assert!(!matches!(rejected, Ok(response) if response.contains("hello")));
+
+        for path in [
+            &config.cert,
+            &config.key,
+            config.client_ca.as_ref().unwrap(),
+        ] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_missing_files() {
+        let config = TlsConfig {
+            cert: PathBuf::from("/nonexistent/cert.pem"),
+            key: PathBuf::from("/nonexistent/key.pem"),
+            client_ca: None,
+        };
+        assert!(matches!(
+            config.server_config(),
+            Err(ConfigError::Read { .. })
+        ));
+    }
+}
@@ -0,0 +1,74 @@
+//! Hears from udev when serial devices are plugged in or unplugged, so the daemon can open them
+//! straight away instead of waiting for its next poll.
+
+use libudev::{Context, EventType, Monitor, MonitorSocket};
+
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+use crate::watcher::DeviceChange;
+
+/// Listens on a background thread, passing on every serial device that is added or removed.
+/// Returns `None` if udev can't be listened to, for example inside a container. The thread stops
+/// at the first change after the receiver is dropped.
+pub fn watch() -> Option<Receiver<DeviceChange>> {
+    let (sender, receiver) = mpsc::channel();
+    let (started, starting) = mpsc::channel();
+    thread::spawn(move || {
+        // The udev context can't be sent between threads, so everything happens on this one
+        let context = match Context::new() {
+            Ok(context) => context,
+            Err(_) => return,
+        };
+        match listen(&context) {
+            Ok(socket) => {
+                started.send(()).ok();
+                forward(socket, sender).ok();
+            }
+            Err(_) => drop(started),
+        }
+    });
+    starting.recv().ok()?;
+    Some(receiver)
+}
+
+fn listen(context: &Context) -> libudev::Result<MonitorSocket> {
+    let mut monitor = Monitor::new(context)?;
+    monitor.match_subsystem("tty")?;
+    monitor.listen()
+}
+
+/// Waits for events until the receiver goes away, or the socket fails
+fn forward(mut socket: MonitorSocket, sender: Sender<DeviceChange>) -> io::Result<()> {
+    loop {
+        let mut poll = libc::pollfd {
+            fd: socket.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // Safety: poll only writes to the one pollfd it's given
+        if unsafe { libc::poll(&mut poll, 1, -1) } < 0 {
+            let error = io::Error::last_os_error();
+            match error.kind() {
+                io::ErrorKind::Interrupted => continue,
+                _ => return Err(error),
+            }
+        }
+        while let Some(event) = socket.receive_event() {
+            let path = match event.devnode() {
+                Some(path) => path.to_string_lossy().into_owned(),
+                None => continue,
+            };
+            let change = match event.event_type() {
+                EventType::Add => DeviceChange::Added(path),
+                EventType::Remove => DeviceChange::Removed(path),
+                EventType::Change | EventType::Unknown => continue,
+            };
+            if sender.send(change).is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
@@ -0,0 +1,288 @@
+//! Keeps every attached device open, opening devices as they're plugged in and closing them once
+//! they're unplugged. On Linux udev says when a serial device comes or goes, so it's picked up
+//! straight away. Everywhere else, or if udev isn't running, devices are looked for every couple
+//! of seconds.
+
+use std::collections::VecDeque;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+use crate::device::{Device, DeviceSource};
+use crate::output::DeviceInfo;
+use crate::selector::Selectable;
+
+/// How often to look for devices when the source can't say when they change. Sources that can
+/// are still checked this often in case they miss something.
+pub const POLL: Duration = Duration::from_secs(2);
+
+/// What a source says has happened to the device at a path
+#[derive(Clone, Debug, PartialEq)]
+pub enum DeviceChange {
+    Added(String),
+    Removed(String),
+}
+
+/// A device being plugged in or unplugged
+#[derive(Clone, Debug, PartialEq)]
+pub enum DeviceEvent {
+    Attached(DeviceInfo),
+    Detached(DeviceInfo),
+}
+
+/// An open device that can be shared between threads. It's closed once the watcher and every
+/// clone have let go of it.
+#[derive(Clone)]
+pub struct DeviceHandle {
+    pub info: DeviceInfo,
+    device: Arc<Mutex<Box<dyn Device>>>,
+}
+
+impl DeviceHandle {
+    pub fn new(device: Box<dyn Device>) -> DeviceHandle {
+        DeviceHandle {
+            info: device.describe(),
+            device: Arc::new(Mutex::new(device)),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, Box<dyn Device>> {
+        self.device.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Selectable for DeviceHandle {
+    fn path(&self) -> &str {
+        self.info.path()
+    }
+
+    fn name(&self) -> &str {
+        self.info.name()
+    }
+
+    fn serial(&self) -> Option<&str> {
+        self.info.serial()
+    }
+
+    fn usb_port(&self) -> Option<String> {
+        self.info.usb_port()
+    }
+}
+
+/// The devices attached to a source, opened as they're found
+pub struct DeviceWatcher {
+    source: Box<dyn DeviceSource>,
+    devices: Vec<DeviceHandle>,
+}
+
+impl DeviceWatcher {
+    /// Nothing is opened until the first scan
+    pub fn new(source: Box<dyn DeviceSource>) -> DeviceWatcher {
+        DeviceWatcher {
+            source,
+            devices: Vec::new(),
+        }
+    }
+
+    /// Every device that was attached as of the last scan
+    pub fn devices(&self) -> &[DeviceHandle] {
+        &self.devices
+    }
+
+    /// Opens any newly attached devices and closes any that have gone away
+    pub fn scan(&mut self) -> Vec<DeviceEvent> {
+        let attached = self.source.attached();
+        let mut events = Vec::new();
+        self.devices
+            .retain(|handle| match attached.contains(&handle.info.path) {
+                true => true,
+                false => {
+                    events.push(DeviceEvent::Detached(handle.info.clone()));
+                    false
+                }
+            });
+        for path in &attached {
+            if self.devices.iter().any(|handle| &handle.info.path == path) {
+                continue;
+            }
+            if let Some(device) = self.source.open(path) {
+                let handle = DeviceHandle::new(device);
+                events.push(DeviceEvent::Attached(handle.info.clone()));
+                self.devices.push(handle);
+            }
+        }
+        events
+    }
+
+    /// Closes the device at the path if it's open, so that the next scan opens it again if it's
+    /// still attached. Used for devices that have stopped working, or that may have been
+    /// unplugged and plugged back in since the last scan.
+    pub fn close(&mut self, path: &str) -> Option<DeviceEvent> {
+        let index = self
+            .devices
+            .iter()
+            .position(|handle| handle.info.path == path)?;
+        Some(DeviceEvent::Detached(self.devices.remove(index).info))
+    }
+
+    /// Something to wait on until a device might have been plugged in or unplugged
+    pub fn changes(&self) -> Changes {
+        Changes {
+            receiver: self.source.watch(),
+        }
+    }
+
+    /// Every device as it's plugged in or unplugged, starting with those already attached
+    pub fn events(self) -> Events {
+        let changes = self.changes();
+        Events {
+            watcher: self,
+            changes,
+            pending: VecDeque::new(),
+        }
+    }
+}
+
+/// Tells when devices might have changed, from the source if it can say, or else every `POLL`
+pub struct Changes {
+    receiver: Option<Receiver<DeviceChange>>,
+}
+
+impl Changes {
+    /// Whether the source says when devices change, rather than them being polled for
+    pub fn is_watching(&self) -> bool {
+        self.receiver.is_some()
+    }
+
+    /// Waits until a device might have been plugged in or unplugged, or for the timeout. Returns
+    /// the paths of any devices the source says have been removed, which should be closed in
+    /// case they've been plugged back in under the same path since.
+    pub fn wait(&mut self, timeout: Duration) -> Vec<String> {
+        let receiver = match &self.receiver {
+            Some(receiver) => receiver,
+            None => {
+                thread::sleep(timeout);
+                return Vec::new();
+            }
+        };
+        match receiver.recv_timeout(timeout) {
+            Ok(change) => std::iter::once(change)
+                .chain(receiver.try_iter())
+                .filter_map(|change| match change {
+                    DeviceChange::Added(_) => None,
+                    DeviceChange::Removed(path) => Some(path),
+                })
+                .collect(),
+            Err(RecvTimeoutError::Timeout) => Vec::new(),
+            Err(RecvTimeoutError::Disconnected) => {
+                // The source has stopped watching, so fall back to polling
+                self.receiver = None;
+                Vec::new()
+            }
+        }
+    }
+}
+
+/// A blocking stream of devices being plugged in and unplugged, from `DeviceWatcher::events`
+pub struct Events {
+    watcher: DeviceWatcher,
+    changes: Changes,
+    pending: VecDeque<DeviceEvent>,
+}
+
+impl Events {
+    /// Every device that is currently attached, opened and ready to use
+    pub fn devices(&self) -> &[DeviceHandle] {
+        self.watcher.devices()
+    }
+}
+
+impl Iterator for Events {
+    type Item = DeviceEvent;
+
+    fn next(&mut self) -> Option<DeviceEvent> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Some(event);
+            }
+            self.pending.extend(self.watcher.scan());
+            if self.pending.is_empty() {
+                for path in self.changes.wait(POLL) {
+                    self.pending.extend(self.watcher.close(&path));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::{MockDevice, MockSource};
+
+    #[test]
+    fn test_scan() {
+        let source = MockSource::new(vec![MockDevice::new("/dev/ttyACM0")]);
+        let mut watcher = DeviceWatcher::new(Box::new(source.clone()));
+        assert!(watcher.devices().is_empty());
+
+        let info = |path| MockDevice::new(path).describe();
+        assert_eq!(
+            watcher.scan(),
+            vec![DeviceEvent::Attached(info("/dev/ttyACM0"))]
+        );
+        assert_eq!(watcher.scan(), vec![]);
+
+        source.unplug("/dev/ttyACM0");
+        source.plug(MockDevice::new("/dev/ttyACM1"));
+        assert_eq!(
+            watcher.scan(),
+            vec![
+                DeviceEvent::Detached(info("/dev/ttyACM0")),
+                DeviceEvent::Attached(info("/dev/ttyACM1")),
+            ]
+        );
+        assert_eq!(watcher.devices().len(), 1);
+
+        assert_eq!(
+            watcher.close("/dev/ttyACM1"),
+            Some(DeviceEvent::Detached(info("/dev/ttyACM1")))
+        );
+        assert_eq!(watcher.close("/dev/ttyACM1"), None);
+        assert_eq!(
+            watcher.scan(),
+            vec![DeviceEvent::Attached(info("/dev/ttyACM1"))]
+        );
+    }
+
+    #[test]
+    fn test_events() {
+        let source = MockSource::new(vec![MockDevice::new("/dev/ttyACM0")]);
+        let mut events = DeviceWatcher::new(Box::new(source.clone())).events();
+        let info = |path| MockDevice::new(path).describe();
+
+        assert_eq!(
+            events.next(),
+            Some(DeviceEvent::Attached(info("/dev/ttyACM0")))
+        );
+        source.plug(MockDevice::new("/dev/ttyACM1"));
+        assert_eq!(
+            events.next(),
+            Some(DeviceEvent::Attached(info("/dev/ttyACM1")))
+        );
+        assert_eq!(events.devices().len(), 2);
+
+        // Unplugged and plugged back in before anything looked
+        source.unplug("/dev/ttyACM0");
+        source.plug(MockDevice::new("/dev/ttyACM0"));
+        assert_eq!(
+            events.next(),
+            Some(DeviceEvent::Detached(info("/dev/ttyACM0")))
+        );
+        assert_eq!(
+            events.next(),
+            Some(DeviceEvent::Attached(info("/dev/ttyACM0")))
+        );
+    }
+}
@@ -0,0 +1,347 @@
+//! Runs a command while showing that it's busy on an led, then whether it succeeded.
+//!
+//! The busy color is shown for a short time and kept topped up, so if the wrapper is killed the
+//! led goes back to its background soon after. Termination, hangup and user signals sent to the
+//! wrapper are passed on to the command. Interrupts and quits from the terminal reach the command
+//! directly as it's in the same process group, so the wrapper ignores them and waits for the
+//! command to finish, the same as `system()` does.
+
+use libc::c_int;
+use status_lights_messages::{LedColorTimed, Rgb};
+
+use std::io;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Command, ExitStatus};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::effect::Effect;
+use crate::ipc::{Action, DaemonRequest, DaemonResponse, LedEffect};
+use crate::monitor::{describe_failure, report};
+use crate::report::Outcome;
+use crate::selector::DeviceSelector;
+
+/// How long the busy color is shown for each time it's sent
+const BUSY_SECONDS: u8 = 15;
+
+/// How often the busy color is sent again, well before it runs out
+const BUSY_REFRESH: Duration = Duration::from_secs(5);
+
+/// How often to check whether the command has finished
+const POLL: Duration = Duration::from_millis(50);
+
+/// Signals that are passed on to the command
+const FORWARDED: [c_int; 4] = [libc::SIGTERM, libc::SIGHUP, libc::SIGUSR1, libc::SIGUSR2];
+
+/// Signals from the terminal, which the command gets as well
+const IGNORED: [c_int; 2] = [libc::SIGINT, libc::SIGQUIT];
+
+/// Signals received while a command is running, one bit for each signal number
+static PENDING: AtomicU64 = AtomicU64::new(0);
+
+extern "C" fn record_signal(signal: c_int) {
+    PENDING.fetch_or(1 << signal, Ordering::SeqCst);
+}
+
+/// What to show while a command runs, and once it's finished
+#[derive(Clone, Debug)]
+pub struct Wrapper {
+    pub selector: DeviceSelector,
+    pub led: u8,
+    pub busy: Rgb,
+    /// Played instead of the busy color if the daemon is running
+    pub effect: Option<Effect>,
+    pub success: Rgb,
+    pub failure: Rgb,
+    /// How long to show the success or failure color for, 0 shows it until it's changed
+    pub hold: u8,
+}
+
+impl Wrapper {
+    /// Runs the command until it finishes, returning the exit code to exit with. Like a shell,
+    /// this is the command's own exit code, 128 plus the signal that killed it, 127 if it couldn't
+    /// be found or 126 if it couldn't be run.
+    pub fn run(
+        &self,
+        command: &mut Command,
+        mut apply: impl FnMut(&DaemonRequest) -> DaemonResponse,
+        mut log: impl FnMut(String),
+    ) -> i32 {
+        let mut last_error = None;
+        let mut show = |action: Action, log: &mut dyn FnMut(String)| {
+            self.show(action, &mut apply, log, &mut last_error)
+        };
+
+        let mut playing = false;
+        let status = wait(command, |first| {
+            if let (Some(effect), true) = (&self.effect, first) {
+                playing = show(
+                    Action::Effect(LedEffect {
+                        led: self.led,
+                        effect: Some(effect.clone()),
+                    }),
+                    &mut log,
+                );
+            }
+            if !playing {
+                let busy = LedColorTimed::new(self.led, self.busy, BUSY_SECONDS);
+                show(Action::Foreground(busy), &mut log);
+            }
+        });
+        let (color, code) = match &status {
+            Ok(status) => match status.success() {
+                true => (self.success, 0),
+                false => (self.failure, exit_code(*status)),
+            },
+            Err(e) => {
+                log(format!("Unable to run {:?}: {}", command.get_program(), e));
+                match e.kind() {
+                    io::ErrorKind::NotFound => (self.failure, 127),
+                    _ => (self.failure, 126),
+                }
+            }
+        };
+        let done = LedColorTimed::new(self.led, color, self.hold);
+        show(Action::Foreground(done), &mut log);
+        code
+    }
+
+    /// Runs the action on the selected devices, logging failures unless they're the same as the
+    /// last time. Returns whether it succeeded.
+    fn show(
+        &self,
+        action: Action,
+        apply: &mut impl FnMut(&DaemonRequest) -> DaemonResponse,
+        log: &mut dyn FnMut(String),
+        last_error: &mut Option<String>,
+    ) -> bool {
+        let report = report(apply(&DaemonRequest {
+            selector: self.selector.clone(),
+            action,
+            owner: None,
+        }));
+        let error = match report.outcome {
+            Outcome::Success => None,
+            _ => Some(describe_failure(&report)),
+        };
+        if error.is_some() && error != *last_error {
+            log(format!(
+                "Unable to show the command's status: {}",
+                error.as_deref().unwrap_or_default()
+            ));
+        }
+        *last_error = error;
+        last_error.is_none()
+    }
+}
+
+/// Runs the command, passing on signals and calling `busy` once it's started and then every
+/// `BUSY_REFRESH` until it finishes. `busy` is told whether it's the first call.
+fn wait(command: &mut Command, mut busy: impl FnMut(bool)) -> io::Result<ExitStatus> {
+    let handlers = Handlers::install();
+    let mut child = command.spawn()?;
+    busy(true);
+    let mut next_refresh = Instant::now() + BUSY_REFRESH;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        for signal in handlers.take_forwarded() {
+            // Safety: kill only sends a signal, the child hasn't been waited on so its pid is
+            // still its own
+            unsafe { libc::kill(child.id() as libc::pid_t, signal) };
+        }
+        if Instant::now() >= next_refresh {
+            busy(false);
+            next_refresh += BUSY_REFRESH;
+        }
+        thread::sleep(POLL);
+    }
+}
+
+/// The exit code a shell would give for the status
+pub fn exit_code(status: ExitStatus) -> i32 {
+    status
+        .code()
+        .or_else(|| status.signal().map(|signal| 128 + signal))
+        .unwrap_or(1)
+}
+
+/// Records signals while a command runs, putting back the previous handlers when dropped.
+/// Handled signals go back to their defaults in the command once it starts, so it isn't affected.
+struct Handlers {
+    previous: Vec<(c_int, libc::sighandler_t)>,
+}
+
+impl Handlers {
+    fn install() -> Handlers {
+        PENDING.store(0, Ordering::SeqCst);
+        let handler = record_signal as extern "C" fn(c_int) as libc::sighandler_t;
+        let previous = FORWARDED
+            .iter()
+            .chain(&IGNORED)
+            // Safety: the handler only touches an atomic, which is safe in a signal handler
+            .map(|&signal| (signal, unsafe { libc::signal(signal, handler) }))
+            .collect();
+        Handlers { previous }
+    }
+
+    /// The signals received since last time that should be passed on
+    fn take_forwarded(&self) -> Vec<c_int> {
+        let pending = PENDING.swap(0, Ordering::SeqCst);
+        FORWARDED
+            .iter()
+            .copied()
+            .filter(|signal| pending & (1 << signal) != 0)
+            .collect()
+    }
+}
+
+impl Drop for Handlers {
+    fn drop(&mut self) {
+        for (signal, previous) in &self.previous {
+            // Safety: puts back what was there before
+            unsafe { libc::signal(*signal, *previous) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::daemon::Daemon;
+    use crate::mock::{MockDevice, MockSource};
+
+    use std::sync::Mutex;
+
+    /// Signal handlers are shared by the whole process, so only one test can run a command at once
+    static RUNNING: Mutex<()> = Mutex::new(());
+
+    const BLUE: Rgb = Rgb::new(0, 0, 255);
+    const GREEN: Rgb = Rgb::new(0, 255, 0);
+    const RED: Rgb = Rgb::new(255, 0, 0);
+
+    fn wrapper() -> Wrapper {
+        Wrapper {
+            selector: DeviceSelector::all(),
+            led: 1,
+            busy: BLUE,
+            effect: None,
+            success: GREEN,
+            failure: RED,
+            hold: 60,
+        }
+    }
+
+    fn shell(script: &str) -> Command {
+        let mut command = Command::new("sh");
+        command.args(["-c", script]);
+        command
+    }
+
+    #[test]
+    fn test_run() {
+        let _running = RUNNING.lock().unwrap();
+        let device = MockDevice::new("/dev/ttyACM0");
+        let daemon = Daemon::new(Box::new(MockSource::new(vec![device.clone()])));
+        let mut shown = Vec::new();
+        let code = wrapper().run(
+            &mut shell("exit 0"),
+            |request| {
+                if let Action::Foreground(led_color) = &request.action {
+                    shown.push((led_color.color, led_color.seconds));
+                }
+                daemon.handle(request)
+            },
+            |line| panic!("Unexpected log: {}", line),
+        );
+        assert_eq!(code, 0);
+        assert_eq!(shown, [(BLUE, BUSY_SECONDS), (GREEN, 60)]);
+        assert_eq!(device.state().foreground[1], GREEN);
+
+        let code = wrapper().run(&mut shell("exit 3"), |r| daemon.handle(r), |_| {});
+        assert_eq!(code, 3);
+        assert_eq!(device.state().foreground[1], RED);
+
+        let mut logs = Vec::new();
+        let code = wrapper().run(
+            &mut Command::new("./does-not-exist"),
+            |r| daemon.handle(r),
+            |line| logs.push(line),
+        );
+        assert_eq!(code, 127);
+        assert!(logs[0].starts_with("Unable to run \"./does-not-exist\""));
+        assert_eq!(device.state().foreground[1], RED);
+    }
+
+    #[test]
+    fn test_effect() {
+        let _running = RUNNING.lock().unwrap();
+        let device = MockDevice::new("/dev/ttyACM0");
+        let daemon = Daemon::new(Box::new(MockSource::new(vec![device.clone()])));
+        let effect = Effect::from_name("pulse", BLUE);
+        let mut effects = Vec::new();
+        let code = Wrapper {
+            effect: effect.clone(),
+            ..wrapper()
+        }
+        .run(
+            &mut shell("exit 0"),
+            |request| {
+                if let Action::Effect(led_effect) = &request.action {
+                    effects.push(led_effect.effect.clone());
+                }
+                daemon.handle(request)
+            },
+            |line| panic!("Unexpected log: {}", line),
+        );
+        assert_eq!(code, 0);
+        assert_eq!(effects, vec![effect.clone()]);
+        assert_eq!(device.state().foreground[1], GREEN);
+
+        // Without the daemon the busy color is shown instead
+        let mut shown = Vec::new();
+        let mut logs = Vec::new();
+        Wrapper {
+            effect,
+            ..wrapper()
+        }
+        .run(
+            &mut shell("exit 0"),
+            |request| {
+                let mut device = device.clone();
+                if let Action::Foreground(led_color) = &request.action {
+                    shown.push(led_color.color);
+                }
+                request
+                    .action
+                    .run([&mut device as &mut dyn crate::device::Device])
+            },
+            |line| logs.push(line),
+        );
+        assert_eq!(shown, [BLUE, GREEN]);
+        assert_eq!(logs.len(), 1);
+    }
+
+    #[test]
+    fn test_signals() {
+        let _running = RUNNING.lock().unwrap();
+        let device = MockDevice::new("/dev/ttyACM0");
+        let daemon = Daemon::new(Box::new(MockSource::new(vec![device])));
+        let wrapper = thread::spawn(move || {
+            wrapper().run(
+                &mut shell("trap 'exit 7' TERM; sleep 5 & wait"),
+                |r| daemon.handle(r),
+                |_| {},
+            )
+        });
+        thread::sleep(Duration::from_millis(500));
+        // Safety: the wrapper has a handler for SIGTERM installed while the command runs
+        unsafe { libc::kill(libc::getpid(), libc::SIGTERM) };
+        assert_eq!(wrapper.join().unwrap(), 7);
+
+        let status = shell("kill -KILL $$").status().unwrap();
+        assert_eq!(exit_code(status), 128 + libc::SIGKILL);
+    }
+}
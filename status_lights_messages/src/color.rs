@@ -0,0 +1,498 @@
+//! Colors are sent to the USB device as 8 bit red, green and blue values. `Rgb` is that
+//! representation, shared by the firmware and anything talking to it.
+//!
+//! As well as building an `Rgb` directly, one can be parsed from a string in any of these forms:
+//! ```text
+//! #ff8000                 hex
+//! #f80                    short hex
+//! orange                  any CSS named color (case insensitive)
+//! hsv(30, 100%, 100%)     hue in degrees, saturation and value as a percentage or 0.0 - 1.0
+//! ```
+//!
+//! The leds respond linearly to the values they're given but our eyes don't, so brightness
+//! changes should go through `Rgb::scale` or `Rgb::gamma_corrected` rather than multiplying the
+//! channels directly.
+
+use core::fmt;
+use core::str::FromStr;
+
+/// A color as red, green and blue intensities
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Rgb {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+}
+
+impl Rgb {
+    /// All channels off. On the USB device this means "not set" rather than "black".
+    pub const OFF: Rgb = Rgb::new(0, 0, 0);
+
+    pub const fn new(red: u8, green: u8, blue: u8) -> Rgb {
+        Rgb { red, green, blue }
+    }
+
+    pub fn is_off(&self) -> bool {
+        *self == Self::OFF
+    }
+
+    /// Parses `#rrggbb` or `#rgb`
+    pub fn from_hex(hex: &str) -> Result<Rgb, ColorParseError> {
+        let digits = hex.strip_prefix('#').ok_or(ColorParseError::InvalidHex)?;
+        if !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(ColorParseError::InvalidHex);
+        }
+        let channel = |range: core::ops::Range<usize>| {
+            u8::from_str_radix(&digits[range], 16).map_err(|_| ColorParseError::InvalidHex)
+        };
+        match digits.len() {
+            6 => Ok(Rgb::new(channel(0..2)?, channel(2..4)?, channel(4..6)?)),
+            3 => Ok(Rgb::new(
+                channel(0..1)? * 17,
+                channel(1..2)? * 17,
+                channel(2..3)? * 17,
+            )),
+            _ => Err(ColorParseError::InvalidHex),
+        }
+    }
+
+    /// Looks up a CSS named color, ignoring case. `off` is also accepted.
+    pub fn from_name(name: &str) -> Option<Rgb> {
+        if name.eq_ignore_ascii_case("off") {
+            return Some(Self::OFF);
+        }
+        NAMED_COLORS
+            .iter()
+            .find(|(known, _)| known.eq_ignore_ascii_case(name))
+            .map(|(_, color)| *color)
+    }
+
+    /// Converts from hue (degrees), saturation (0.0 - 1.0) and value (0.0 - 1.0)
+    pub fn from_hsv(hue: f32, saturation: f32, value: f32) -> Rgb {
+        let mut hue = hue % 360.0;
+        if hue < 0.0 {
+            hue += 360.0;
+        }
+        // A tiny negative hue rounds up to exactly 360 when wrapped, which is red again
+        if hue >= 360.0 {
+            hue = 0.0;
+        }
+        let saturation = saturation.clamp(0.0, 1.0);
+        let value = value.clamp(0.0, 1.0);
+
+        let sector = hue / 60.0;
+        let whole = sector as u8; // hue is positive so this truncates towards the floor
+        let fraction = sector - whole as f32;
+        let p = value * (1.0 - saturation);
+        let q = value * (1.0 - saturation * fraction);
+        let t = value * (1.0 - saturation * (1.0 - fraction));
+
+        let (red, green, blue) = match whole {
+            0 => (value, t, p),
+            1 => (q, value, p),
+            2 => (p, value, t),
+            3 => (p, q, value),
+            4 => (t, p, value),
+            _ => (value, p, q),
+        };
+        Rgb::new(unit_to_u8(red), unit_to_u8(green), unit_to_u8(blue))
+    }
+
+    /// Maps each channel through a gamma curve so that evenly spaced values look evenly spaced
+    pub fn gamma_corrected(&self) -> Rgb {
+        Rgb::new(
+            GAMMA[self.red as usize],
+            GAMMA[self.green as usize],
+            GAMMA[self.blue as usize],
+        )
+    }
+
+    /// Dims the color to a perceived brightness between 0.0 and 1.0.
+    ///
+    /// Channels that were on stay at least 1 so long as brightness is above 0, the neo trinkey
+    /// leds are bright enough that this is often the level you actually want.
+    pub fn scale(&self, brightness: f32) -> Rgb {
+        let level = GAMMA[unit_to_u8(brightness) as usize] as u16;
+        let scale_channel = |channel: u8| {
+            let scaled = ((channel as u16 * level + 127) / 255) as u8;
+            if scaled == 0 && channel > 0 && brightness > 0.0 {
+                1
+            } else {
+                scaled
+            }
+        };
+        Rgb::new(
+            scale_channel(self.red),
+            scale_channel(self.green),
+            scale_channel(self.blue),
+        )
+    }
+
+    fn from_hsv_str(args: &str) -> Result<Rgb, ColorParseError> {
+        let mut parts = args.split(',').map(str::trim);
+        let mut next = || parts.next().ok_or(ColorParseError::InvalidHsv);
+        let hue = next()?
+            .parse::<f32>()
+            .map_err(|_| ColorParseError::InvalidHsv)?;
+        let saturation = parse_unit(next()?)?;
+        let value = parse_unit(next()?)?;
+        if parts.next().is_some() {
+            return Err(ColorParseError::InvalidHsv);
+        }
+        Ok(Rgb::from_hsv(hue, saturation, value))
+    }
+}
+
+fn unit_to_u8(unit: f32) -> u8 {
+    (unit.clamp(0.0, 1.0) * 255.0 + 0.5) as u8
+}
+
+/// Parses either a percentage (`50%`) or a fraction (`0.5`)
+fn parse_unit(s: &str) -> Result<f32, ColorParseError> {
+    let (number, divisor) = match s.strip_suffix('%') {
+        Some(percent) => (percent, 100.0),
+        None => (s, 1.0),
+    };
+    let unit = number
+        .trim()
+        .parse::<f32>()
+        .map_err(|_| ColorParseError::InvalidHsv)?
+        / divisor;
+    if (0.0..=1.0).contains(&unit) {
+        Ok(unit)
+    } else {
+        Err(ColorParseError::InvalidHsv)
+    }
+}
+
+impl FromStr for Rgb {
+    type Err = ColorParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.starts_with('#') {
+            return Rgb::from_hex(s);
+        }
+        if let Some(args) = s
+            .get(..4)
+            .filter(|prefix| prefix.eq_ignore_ascii_case("hsv("))
+            .and_then(|_| s[4..].strip_suffix(')'))
+        {
+            return Rgb::from_hsv_str(args);
+        }
+        Rgb::from_name(s).ok_or(ColorParseError::UnknownColor)
+    }
+}
+
+impl fmt::Display for Rgb {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{:02x}{:02x}{:02x}", self.red, self.green, self.blue)
+    }
+}
+
+#[cfg(feature = "rgb")]
+impl From<Rgb> for rgb::RGB8 {
+    fn from(color: Rgb) -> Self {
+        rgb::RGB8::new(color.red, color.green, color.blue)
+    }
+}
+
+#[cfg(feature = "rgb")]
+impl From<rgb::RGB8> for Rgb {
+    fn from(color: rgb::RGB8) -> Self {
+        Rgb::new(color.r, color.g, color.b)
+    }
+}
+
+/// Colors are serialized as `#rrggbb` strings, and can be deserialized from any string `FromStr`
+/// accepts
+#[cfg(feature = "serde")]
+impl serde::Serialize for Rgb {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Rgb {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct RgbVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for RgbVisitor {
+            type Value = Rgb;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "a color name, hex code or hsv value")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, s: &str) -> Result<Rgb, E> {
+                s.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_str(RgbVisitor)
+    }
+}
+
+/// Possible errors from parsing a color
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ColorParseError {
+    InvalidHex,
+    InvalidHsv,
+    UnknownColor,
+}
+
+impl fmt::Display for ColorParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidHex => write!(f, "hex colors must look like #rrggbb or #rgb"),
+            Self::InvalidHsv => write!(
+                f,
+                "hsv colors must look like hsv(<degrees>, <0-100%>, <0-100%>)"
+            ),
+            Self::UnknownColor => write!(f, "not a known color name, hex code or hsv value"),
+        }
+    }
+}
+
+/// Gamma 2.8 lookup table
+const GAMMA: [u8; 256] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1,
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2, 3, 3, 3, 3, 3, 3, 3, 4, 4, 4, 4, 4, 5, 5, 5,
+    5, 6, 6, 6, 6, 7, 7, 7, 7, 8, 8, 8, 9, 9, 9, 10, 10, 10, 11, 11, 11, 12, 12, 13, 13, 13, 14,
+    14, 15, 15, 16, 16, 17, 17, 18, 18, 19, 19, 20, 20, 21, 21, 22, 22, 23, 24, 24, 25, 25, 26, 27,
+    27, 28, 29, 29, 30, 31, 32, 32, 33, 34, 35, 35, 36, 37, 38, 39, 39, 40, 41, 42, 43, 44, 45, 46,
+    47, 48, 49, 50, 50, 51, 52, 54, 55, 56, 57, 58, 59, 60, 61, 62, 63, 64, 66, 67, 68, 69, 70, 72,
+    73, 74, 75, 77, 78, 79, 81, 82, 83, 85, 86, 87, 89, 90, 92, 93, 95, 96, 98, 99, 101, 102, 104,
+    105, 107, 109, 110, 112, 114, 115, 117, 119, 120, 122, 124, 126, 127, 129, 131, 133, 135, 137,
+    138, 140, 142, 144, 146, 148, 150, 152, 154, 156, 158, 160, 162, 164, 167, 169, 171, 173, 175,
+    177, 180, 182, 184, 186, 189, 191, 193, 196, 198, 200, 203, 205, 208, 210, 213, 215, 218, 220,
+    223, 225, 228, 231, 233, 236, 239, 241, 244, 247, 249, 252, 255,
+];
+
+/// CSS named colors
+const NAMED_COLORS: [(&str, Rgb); 148] = [
+    ("aliceblue", Rgb::new(240, 248, 255)),
+    ("antiquewhite", Rgb::new(250, 235, 215)),
+    ("aqua", Rgb::new(0, 255, 255)),
+    ("aquamarine", Rgb::new(127, 255, 212)),
+    ("azure", Rgb::new(240, 255, 255)),
+    ("beige", Rgb::new(245, 245, 220)),
+    ("bisque", Rgb::new(255, 228, 196)),
+    ("black", Rgb::new(0, 0, 0)),
+    ("blanchedalmond", Rgb::new(255, 235, 205)),
+    ("blue", Rgb::new(0, 0, 255)),
+    ("blueviolet", Rgb::new(138, 43, 226)),
+    ("brown", Rgb::new(165, 42, 42)),
+    ("burlywood", Rgb::new(222, 184, 135)),
+    ("cadetblue", Rgb::new(95, 158, 160)),
+    ("chartreuse", Rgb::new(127, 255, 0)),
+    ("chocolate", Rgb::new(210, 105, 30)),
+    ("coral", Rgb::new(255, 127, 80)),
+    ("cornflowerblue", Rgb::new(100, 149, 237)),
+    ("cornsilk", Rgb::new(255, 248, 220)),
+    ("crimson", Rgb::new(220, 20, 60)),
+    ("cyan", Rgb::new(0, 255, 255)),
+    ("darkblue", Rgb::new(0, 0, 139)),
+    ("darkcyan", Rgb::new(0, 139, 139)),
+    ("darkgoldenrod", Rgb::new(184, 134, 11)),
+    ("darkgray", Rgb::new(169, 169, 169)),
+    ("darkgreen", Rgb::new(0, 100, 0)),
+    ("darkgrey", Rgb::new(169, 169, 169)),
+    ("darkkhaki", Rgb::new(189, 183, 107)),
+    ("darkmagenta", Rgb::new(139, 0, 139)),
+    ("darkolivegreen", Rgb::new(85, 107, 47)),
+    ("darkorange", Rgb::new(255, 140, 0)),
+    ("darkorchid", Rgb::new(153, 50, 204)),
+    ("darkred", Rgb::new(139, 0, 0)),
+    ("darksalmon", Rgb::new(233, 150, 122)),
+    ("darkseagreen", Rgb::new(143, 188, 143)),
+    ("darkslateblue", Rgb::new(72, 61, 139)),
+    ("darkslategray", Rgb::new(47, 79, 79)),
+    ("darkslategrey", Rgb::new(47, 79, 79)),
+    ("darkturquoise", Rgb::new(0, 206, 209)),
+    ("darkviolet", Rgb::new(148, 0, 211)),
+    ("deeppink", Rgb::new(255, 20, 147)),
+    ("deepskyblue", Rgb::new(0, 191, 255)),
+    ("dimgray", Rgb::new(105, 105, 105)),
+    ("dimgrey", Rgb::new(105, 105, 105)),
+    ("dodgerblue", Rgb::new(30, 144, 255)),
+    ("firebrick", Rgb::new(178, 34, 34)),
+    ("floralwhite", Rgb::new(255, 250, 240)),
+    ("forestgreen", Rgb::new(34, 139, 34)),
+    ("fuchsia", Rgb::new(255, 0, 255)),
+    ("gainsboro", Rgb::new(220, 220, 220)),
+    ("ghostwhite", Rgb::new(248, 248, 255)),
+    ("gold", Rgb::new(255, 215, 0)),
+    ("goldenrod", Rgb::new(218, 165, 32)),
+    ("gray", Rgb::new(128, 128, 128)),
+    ("green", Rgb::new(0, 128, 0)),
+    ("greenyellow", Rgb::new(173, 255, 47)),
+    ("grey", Rgb::new(128, 128, 128)),
+    ("honeydew", Rgb::new(240, 255, 240)),
+    ("hotpink", Rgb::new(255, 105, 180)),
+    ("indianred", Rgb::new(205, 92, 92)),
+    ("indigo", Rgb::new(75, 0, 130)),
+    ("ivory", Rgb::new(255, 255, 240)),
+    ("khaki", Rgb::new(240, 230, 140)),
+    ("lavender", Rgb::new(230, 230, 250)),
+    ("lavenderblush", Rgb::new(255, 240, 245)),
+    ("lawngreen", Rgb::new(124, 252, 0)),
+    ("lemonchiffon", Rgb::new(255, 250, 205)),
+    ("lightblue", Rgb::new(173, 216, 230)),
+    ("lightcoral", Rgb::new(240, 128, 128)),
+    ("lightcyan", Rgb::new(224, 255, 255)),
+    ("lightgoldenrodyellow", Rgb::new(250, 250, 210)),
+    ("lightgray", Rgb::new(211, 211, 211)),
+    ("lightgreen", Rgb::new(144, 238, 144)),
+    ("lightgrey", Rgb::new(211, 211, 211)),
+    ("lightpink", Rgb::new(255, 182, 193)),
+    ("lightsalmon", Rgb::new(255, 160, 122)),
+    ("lightseagreen", Rgb::new(32, 178, 170)),
+    ("lightskyblue", Rgb::new(135, 206, 250)),
+    ("lightslategray", Rgb::new(119, 136, 153)),
+    ("lightslategrey", Rgb::new(119, 136, 153)),
+    ("lightsteelblue", Rgb::new(176, 196, 222)),
+    ("lightyellow", Rgb::new(255, 255, 224)),
+    ("lime", Rgb::new(0, 255, 0)),
+    ("limegreen", Rgb::new(50, 205, 50)),
+    ("linen", Rgb::new(250, 240, 230)),
+    ("magenta", Rgb::new(255, 0, 255)),
+    ("maroon", Rgb::new(128, 0, 0)),
+    ("mediumaquamarine", Rgb::new(102, 205, 170)),
+    ("mediumblue", Rgb::new(0, 0, 205)),
+    ("mediumorchid", Rgb::new(186, 85, 211)),
+    ("mediumpurple", Rgb::new(147, 112, 219)),
+    ("mediumseagreen", Rgb::new(60, 179, 113)),
+    ("mediumslateblue", Rgb::new(123, 104, 238)),
+    ("mediumspringgreen", Rgb::new(0, 250, 154)),
+    ("mediumturquoise", Rgb::new(72, 209, 204)),
+    ("mediumvioletred", Rgb::new(199, 21, 133)),
+    ("midnightblue", Rgb::new(25, 25, 112)),
+    ("mintcream", Rgb::new(245, 255, 250)),
+    ("mistyrose", Rgb::new(255, 228, 225)),
+    ("moccasin", Rgb::new(255, 228, 181)),
+    ("navajowhite", Rgb::new(255, 222, 173)),
+    ("navy", Rgb::new(0, 0, 128)),
+    ("oldlace", Rgb::new(253, 245, 230)),
+    ("olive", Rgb::new(128, 128, 0)),
+    ("olivedrab", Rgb::new(107, 142, 35)),
+    ("orange", Rgb::new(255, 165, 0)),
+    ("orangered", Rgb::new(255, 69, 0)),
+    ("orchid", Rgb::new(218, 112, 214)),
+    ("palegoldenrod", Rgb::new(238, 232, 170)),
+    ("palegreen", Rgb::new(152, 251, 152)),
+    ("paleturquoise", Rgb::new(175, 238, 238)),
+    ("palevioletred", Rgb::new(219, 112, 147)),
+    ("papayawhip", Rgb::new(255, 239, 213)),
+    ("peachpuff", Rgb::new(255, 218, 185)),
+    ("peru", Rgb::new(205, 133, 63)),
+    ("pink", Rgb::new(255, 192, 203)),
+    ("plum", Rgb::new(221, 160, 221)),
+    ("powderblue", Rgb::new(176, 224, 230)),
+    ("purple", Rgb::new(128, 0, 128)),
+    ("rebeccapurple", Rgb::new(102, 51, 153)),
+    ("red", Rgb::new(255, 0, 0)),
+    ("rosybrown", Rgb::new(188, 143, 143)),
+    ("royalblue", Rgb::new(65, 105, 225)),
+    ("saddlebrown", Rgb::new(139, 69, 19)),
+    ("salmon", Rgb::new(250, 128, 114)),
+    ("sandybrown", Rgb::new(244, 164, 96)),
+    ("seagreen", Rgb::new(46, 139, 87)),
+    ("seashell", Rgb::new(255, 245, 238)),
+    ("sienna", Rgb::new(160, 82, 45)),
+    ("silver", Rgb::new(192, 192, 192)),
+    ("skyblue", Rgb::new(135, 206, 235)),
+    ("slateblue", Rgb::new(106, 90, 205)),
+    ("slategray", Rgb::new(112, 128, 144)),
+    ("slategrey", Rgb::new(112, 128, 144)),
+    ("snow", Rgb::new(255, 250, 250)),
+    ("springgreen", Rgb::new(0, 255, 127)),
+    ("steelblue", Rgb::new(70, 130, 180)),
+    ("tan", Rgb::new(210, 180, 140)),
+    ("teal", Rgb::new(0, 128, 128)),
+    ("thistle", Rgb::new(216, 191, 216)),
+    ("tomato", Rgb::new(255, 99, 71)),
+    ("turquoise", Rgb::new(64, 224, 208)),
+    ("violet", Rgb::new(238, 130, 238)),
+    ("wheat", Rgb::new(245, 222, 179)),
+    ("white", Rgb::new(255, 255, 255)),
+    ("whitesmoke", Rgb::new(245, 245, 245)),
+    ("yellow", Rgb::new(255, 255, 0)),
+    ("yellowgreen", Rgb::new(154, 205, 50)),
+];
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use std::string::ToString;
+
+    #[test]
+    fn test_parse_hex() {
+        assert_eq!("#ff8000".parse(), Ok(Rgb::new(255, 128, 0)));
+        assert_eq!("#F80".parse(), Ok(Rgb::new(255, 136, 0)));
+        assert_eq!("#ff80".parse::<Rgb>(), Err(ColorParseError::InvalidHex));
+        assert_eq!("#gg0000".parse::<Rgb>(), Err(ColorParseError::InvalidHex));
+    }
+
+    #[test]
+    fn test_parse_name() {
+        assert_eq!("green".parse(), Ok(Rgb::new(0, 128, 0)));
+        assert_eq!("RebeccaPurple".parse(), Ok(Rgb::new(102, 51, 153)));
+        assert_eq!("off".parse(), Ok(Rgb::OFF));
+        assert_eq!(
+            "not-a-color".parse::<Rgb>(),
+            Err(ColorParseError::UnknownColor)
+        );
+    }
+
+    #[test]
+    fn test_parse_hsv() {
+        assert_eq!("hsv(0, 100%, 100%)".parse(), Ok(Rgb::new(255, 0, 0)));
+        assert_eq!("HSV(120,1,0.5)".parse(), Ok(Rgb::new(0, 128, 0)));
+        assert_eq!(
+            "hsv(120, 1)".parse::<Rgb>(),
+            Err(ColorParseError::InvalidHsv)
+        );
+        assert_eq!(
+            "hsv(120, 150%, 1)".parse::<Rgb>(),
+            Err(ColorParseError::InvalidHsv)
+        );
+    }
+
+    #[test]
+    fn test_from_hsv_wraps_hue() {
+        assert_eq!(Rgb::from_hsv(240.0, 1.0, 1.0), Rgb::new(0, 0, 255));
+        assert_eq!(Rgb::from_hsv(-120.0, 1.0, 1.0), Rgb::new(0, 0, 255));
+        assert_eq!(Rgb::from_hsv(600.0, 1.0, 1.0), Rgb::new(0, 0, 255));
+    }
+
+    #[test]
+    fn test_from_hsv_tiny_negative_hue() {
+        assert_eq!(Rgb::from_hsv(-1e-8, 1.0, 1.0), Rgb::new(255, 0, 0));
+    }
+
+    #[test]
+    fn test_gamma_corrected() {
+        assert_eq!(
+            Rgb::new(0, 128, 255).gamma_corrected(),
+            Rgb::new(0, 37, 255)
+        );
+    }
+
+    #[test]
+    fn test_scale() {
+        let color = Rgb::new(255, 128, 0);
+        assert_eq!(color.scale(1.0), color);
+        assert_eq!(color.scale(0.0), Rgb::OFF);
+        assert_eq!(color.scale(0.5), Rgb::new(37, 19, 0));
+        assert_eq!(color.scale(0.01), Rgb::new(1, 1, 0));
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(Rgb::new(255, 128, 0).to_string(), "#ff8000");
+    }
+}
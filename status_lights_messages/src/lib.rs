@@ -1,7 +1,9 @@
 #![no_std]
 
+mod color;
 mod message;
 
+pub use color::*;
 pub use message::*;
 
 pub const DEVICE_MANUFACTURER: &str = "Gisleburt";
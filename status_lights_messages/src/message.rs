@@ -19,6 +19,7 @@
 //! 5 is the minor version
 //! 6 is the patch version
 
+use crate::Rgb;
 use core::convert::TryFrom;
 
 mod request;
@@ -33,6 +34,7 @@ pub trait Message: TryFrom<RawMessage> + Into<RawMessage> {}
 
 /// Version number represents the version of the software running on the USB device.
 #[derive(PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct VersionNumber {
     pub major: u8,
     pub minor: u8,
@@ -51,41 +53,32 @@ impl VersionNumber {
 
 /// Represents the color of a specific led
 #[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct LedColor {
     pub led: u8,
-    pub red: u8,
-    pub green: u8,
-    pub blue: u8,
+    pub color: Rgb,
 }
 
 impl LedColor {
-    pub fn new(led: u8, red: u8, green: u8, blue: u8) -> LedColor {
-        LedColor {
-            led,
-            red,
-            green,
-            blue,
-        }
+    pub fn new(led: u8, color: Rgb) -> LedColor {
+        LedColor { led, color }
     }
 }
 
 /// Represents the color of a specific led and how long it will remain that color
 #[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct LedColorTimed {
     pub led: u8,
-    pub red: u8,
-    pub green: u8,
-    pub blue: u8,
+    pub color: Rgb,
     pub seconds: u8,
 }
 
 impl LedColorTimed {
-    pub fn new(led: u8, red: u8, green: u8, blue: u8, seconds: u8) -> LedColorTimed {
+    pub fn new(led: u8, color: Rgb, seconds: u8) -> LedColorTimed {
         LedColorTimed {
             led,
-            red,
-            green,
-            blue,
+            color,
             seconds,
         }
     }
@@ -1,5 +1,5 @@
 use super::RawMessage;
-use crate::{LedColor, LedColorTimed, Message};
+use crate::{LedColor, LedColorTimed, Message, Rgb};
 use core::convert::TryFrom;
 
 #[repr(u8)]
@@ -8,6 +8,7 @@ enum RequestId {
     Version = 1,
     Background = 2,
     Foreground = 3,
+    Uptime = 4,
 }
 
 /// A request that can be made of a usb device
@@ -17,6 +18,7 @@ pub enum Request {
     Version,
     Background(LedColor),
     Foreground(LedColorTimed),
+    Uptime,
 }
 
 impl Request {
@@ -25,6 +27,7 @@ impl Request {
             Request::Version => RequestId::Version as u8,
             Request::Background { .. } => RequestId::Background as u8,
             Request::Foreground { .. } => RequestId::Foreground as u8,
+            Request::Uptime => RequestId::Uptime as u8,
         }
     }
 
@@ -34,9 +37,9 @@ impl Request {
             Self::Background(led) => [
                 self.get_id(),
                 led.led,
-                led.red,
-                led.green,
-                led.blue,
+                led.color.red,
+                led.color.green,
+                led.color.blue,
                 0,
                 0,
                 0,
@@ -44,13 +47,14 @@ impl Request {
             Self::Foreground(led) => [
                 self.get_id(),
                 led.led,
-                led.red,
-                led.green,
-                led.blue,
+                led.color.red,
+                led.color.green,
+                led.color.blue,
                 led.seconds,
                 0,
                 0,
             ],
+            Self::Uptime => [self.get_id(), 0, 0, 0, 0, 0, 0, 0],
         }
     }
 }
@@ -79,14 +83,19 @@ impl TryFrom<RawMessage> for Request {
         match msg {
             [1, 0, 0, 0, 0, 0, 0, 0] => Ok(Self::Version),
             [1, _, _, _, _, _, _, _] => Err(RequestError::MalformedRequest(msg)),
-            [2, led, red, green, blue, 0, 0, 0] => {
-                Ok(Self::Background(LedColor::new(led, red, green, blue)))
-            }
+            [2, led, red, green, blue, 0, 0, 0] => Ok(Self::Background(LedColor::new(
+                led,
+                Rgb::new(red, green, blue),
+            ))),
             [2, _, _, _, _, _, _, _] => Err(RequestError::MalformedRequest(msg)),
             [3, led, red, green, blue, seconds, 0, 0] => Ok(Self::Foreground(LedColorTimed::new(
-                led, red, green, blue, seconds,
+                led,
+                Rgb::new(red, green, blue),
+                seconds,
             ))),
             [3, _, _, _, _, _, _, _] => Err(RequestError::MalformedRequest(msg)),
+            [4, 0, 0, 0, 0, 0, 0, 0] => Ok(Self::Uptime),
+            [4, _, _, _, _, _, _, _] => Err(RequestError::MalformedRequest(msg)),
             _ => Err(RequestError::InvalidRequest(msg)),
         }
     }
@@ -119,7 +128,7 @@ mod test {
 
     #[test]
     fn test_background_request_to_bytes() {
-        let message = Request::Background(LedColor::new(1, 255, 255, 255));
+        let message = Request::Background(LedColor::new(1, Rgb::new(255, 255, 255)));
         assert_eq!(message.to_bytes(), [2, 1, 255, 255, 255, 0, 0, 0]);
     }
 
@@ -129,13 +138,13 @@ mod test {
         let message = Request::try_from(raw_message).unwrap();
         assert_eq!(
             message,
-            Request::Background(LedColor::new(1, 255, 255, 255))
+            Request::Background(LedColor::new(1, Rgb::new(255, 255, 255)))
         );
     }
 
     #[test]
     fn test_foreground_request_to_bytes() {
-        let message = Request::Foreground(LedColorTimed::new(1, 255, 255, 255, 10));
+        let message = Request::Foreground(LedColorTimed::new(1, Rgb::new(255, 255, 255), 10));
         assert_eq!(message.to_bytes(), [3, 1, 255, 255, 255, 10, 0, 0]);
     }
 
@@ -145,7 +154,20 @@ mod test {
         let message = Request::try_from(raw_message).unwrap();
         assert_eq!(
             message,
-            Request::Foreground(LedColorTimed::new(1, 255, 255, 255, 10))
+            Request::Foreground(LedColorTimed::new(1, Rgb::new(255, 255, 255), 10))
         );
     }
+
+    #[test]
+    fn test_uptime_request_to_bytes() {
+        let message = Request::Uptime;
+        assert_eq!(message.to_bytes(), [4, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_uptime_request_from_bytes() {
+        let raw_message: [u8; 8] = [4, 0, 0, 0, 0, 0, 0, 0];
+        let message = Request::try_from(raw_message).unwrap();
+        assert_eq!(message, Request::Uptime);
+    }
 }
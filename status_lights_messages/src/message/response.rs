@@ -11,6 +11,8 @@ pub enum Response {
     Version(VersionNumber),
     Background,
     Foreground,
+    /// How many seconds the device has been running for, which goes back to 0 when it restarts
+    Uptime(u32),
 }
 
 impl Response {
@@ -20,6 +22,7 @@ impl Response {
             Self::Version { .. } => 1,
             Self::Background { .. } => 2,
             Self::Foreground { .. } => 3,
+            Self::Uptime { .. } => 4,
         }
     }
 
@@ -50,6 +53,10 @@ impl Response {
             Self::Version(v) => [self.get_id(), v.major, v.minor, v.patch, 0, 0, 0, 0],
             Self::Background => [self.get_id(), 0, 0, 0, 0, 0, 0, 0],
             Self::Foreground => [self.get_id(), 0, 0, 0, 0, 0, 0, 0],
+            Self::Uptime(seconds) => {
+                let [a, b, c, d] = seconds.to_le_bytes();
+                [self.get_id(), a, b, c, d, 0, 0, 0]
+            }
         }
     }
 }
@@ -116,6 +123,10 @@ impl TryFrom<RawMessage> for Response {
 
             3 => Ok(Self::Foreground),
 
+            4 => Ok(Self::Uptime(u32::from_le_bytes([
+                msg[1], msg[2], msg[3], msg[4],
+            ]))),
+
             // This handles both errors returned from the device (messages starting with 0) and
             // errors from not understanding the response
             _ => Err(ResponseError::from(msg)),
@@ -147,4 +158,17 @@ mod test {
         let message = Response::try_from(raw_message).unwrap();
         assert_eq!(message, Response::Version(VersionNumber::new(3, 4, 5)));
     }
+
+    #[test]
+    fn test_uptime_response_to_bytes() {
+        let message = Response::Uptime(0x0102_0304);
+        assert_eq!(message.to_bytes(), [4, 4, 3, 2, 1, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_uptime_response_from_bytes() {
+        let raw_message: [u8; 8] = [4, 4, 3, 2, 1, 0, 0, 0];
+        let message = Response::try_from(raw_message).unwrap();
+        assert_eq!(message, Response::Uptime(0x0102_0304));
+    }
 }
@@ -19,11 +19,11 @@ use hal::usb::UsbBus;
 
 use smart_leds::SmartLedsWrite;
 use status_lights_messages::{
-    ErrorResponse, Request, Response, VersionNumber, DEVICE_MANUFACTURER, DEVICE_PRODUCT,
+    ErrorResponse, Request, Response, Rgb, VersionNumber, DEVICE_MANUFACTURER, DEVICE_PRODUCT,
 };
 use ws2812_timer_delay::Ws2812;
 
-use crate::led::{Color, ColorTimed};
+use crate::led::ColorTimed;
 use core::convert::TryFrom;
 
 mod led;
@@ -33,18 +33,18 @@ static mut USB_BUS: Option<UsbDevice<UsbBus>> = None;
 static mut USB_SERIAL: Option<SerialPort<UsbBus>> = None;
 const LOOP_WAIT: u32 = 500;
 const NUM_LEDS: usize = 4;
-static mut LED_BACKGROUND: [Color; NUM_LEDS] = [
-    Color::default(),
-    Color::default(),
-    Color::default(),
-    Color::default(),
-];
+static mut LED_BACKGROUND: [Rgb; NUM_LEDS] = [Rgb::OFF; NUM_LEDS];
 static mut LED_FOREGROUND: [ColorTimed; NUM_LEDS] = [
     ColorTimed::default(),
     ColorTimed::default(),
     ColorTimed::default(),
     ColorTimed::default(),
 ];
+/// The SAMD21's 128 bit unique serial number is split across these four words
+const SERIAL_NUMBER_WORDS: [u32; 4] = [0x0080_A00C, 0x0080_A040, 0x0080_A044, 0x0080_A048];
+static mut SERIAL_NUMBER: [u8; 32] = [0; 32];
+/// Roughly how long the device has been running, so the host can tell when it's restarted
+static mut UPTIME_MS: u64 = 0;
 
 #[entry]
 fn main() -> ! {
@@ -85,7 +85,7 @@ fn main() -> ! {
             UsbDeviceBuilder::new(bus_allocator, UsbVidPid(0x0, 0x0))
                 .manufacturer(DEVICE_MANUFACTURER)
                 .product(DEVICE_PRODUCT)
-                .serial_number("Gisleburt Neo Trinkey Status Lights")
+                .serial_number(serial_number())
                 .device_class(USB_CLASS_CDC)
                 .build(),
         );
@@ -101,31 +101,49 @@ fn main() -> ! {
             let leds = [
                 LED_FOREGROUND[0]
                     .to_rgb()
-                    .or_else(|| LED_BACKGROUND[0].to_rgb())
+                    .or_else(|| led::to_rgb(LED_BACKGROUND[0]))
                     .unwrap_or_default(),
                 LED_FOREGROUND[1]
                     .to_rgb()
-                    .or_else(|| LED_BACKGROUND[1].to_rgb())
+                    .or_else(|| led::to_rgb(LED_BACKGROUND[1]))
                     .unwrap_or_default(),
                 LED_FOREGROUND[2]
                     .to_rgb()
-                    .or_else(|| LED_BACKGROUND[2].to_rgb())
+                    .or_else(|| led::to_rgb(LED_BACKGROUND[2]))
                     .unwrap_or_default(),
                 LED_FOREGROUND[3]
                     .to_rgb()
-                    .or_else(|| LED_BACKGROUND[3].to_rgb())
+                    .or_else(|| led::to_rgb(LED_BACKGROUND[3]))
                     .unwrap_or_default(),
             ];
             ws2812.write(leds.iter().cloned()).unwrap();
             LED_FOREGROUND
                 .iter_mut()
                 .for_each(|fg| fg.reduce_time(LOOP_WAIT));
+            // The USB interrupt reads this, so don't let it see it half written
+            cortex_m::interrupt::free(|_| UPTIME_MS += LOOP_WAIT as u64);
         }
 
         delay.delay_ms(LOOP_WAIT);
     }
 }
 
+/// The chip's unique serial number in hex, so the host can tell devices apart
+fn serial_number() -> &'static str {
+    const HEX: &[u8; 16] = b"0123456789ABCDEF";
+    unsafe {
+        for (word_index, address) in SERIAL_NUMBER_WORDS.iter().enumerate() {
+            let word = core::ptr::read_volatile(*address as *const u32);
+            for (byte_index, byte) in word.to_be_bytes().iter().enumerate() {
+                let at = word_index * 8 + byte_index * 2;
+                SERIAL_NUMBER[at] = HEX[(byte >> 4) as usize];
+                SERIAL_NUMBER[at + 1] = HEX[(byte & 0xf) as usize];
+            }
+        }
+        core::str::from_utf8_unchecked(&SERIAL_NUMBER)
+    }
+}
+
 fn create_version_number_response() -> Response {
     Response::Version(VersionNumber {
         major: env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap(),
@@ -134,6 +152,11 @@ fn create_version_number_response() -> Response {
     })
 }
 
+fn create_uptime_response() -> Response {
+    let seconds = unsafe { UPTIME_MS } / 1000;
+    Response::Uptime(seconds.min(u32::MAX as u64) as u32)
+}
+
 fn poll_usb() {
     unsafe {
         if let Some(usb_dev) = USB_BUS.as_mut() {
@@ -149,12 +172,20 @@ fn poll_usb() {
                             let response = create_version_number_response();
                             serial.write(&response.to_bytes()).ok();
                         }
-                        Ok(Request::Background(led_color)) => {
-                            LED_BACKGROUND[led_color.led as usize] = led_color.into();
+                        Ok(Request::Uptime) => {
+                            let response = create_uptime_response();
+                            serial.write(&response.to_bytes()).ok();
+                        }
+                        Ok(Request::Background(led_color))
+                            if (led_color.led as usize) < NUM_LEDS =>
+                        {
+                            LED_BACKGROUND[led_color.led as usize] = led_color.color;
                             let response = Response::Background;
                             serial.write(&response.to_bytes()).ok();
                         }
-                        Ok(Request::Foreground(led_color_timed)) => {
+                        Ok(Request::Foreground(led_color_timed))
+                            if (led_color_timed.led as usize) < NUM_LEDS =>
+                        {
                             LED_FOREGROUND[led_color_timed.led as usize] = led_color_timed.into();
                             let response = Response::Foreground;
                             serial.write(&response.to_bytes()).ok();
@@ -164,7 +195,12 @@ fn poll_usb() {
                                 ErrorResponse::UnknownRequestId(error.get_id()).into();
                             serial.write(&response.to_bytes()).ok();
                         }
-                        _ => {}
+                        Ok(request) => {
+                            // Asked for an led this device doesn't have
+                            let response: Response =
+                                ErrorResponse::MalformedRequestForId(request.to_bytes()[0]).into();
+                            serial.write(&response.to_bytes()).ok();
+                        }
                     }
                 };
             };